@@ -0,0 +1,45 @@
+#ifndef LENOVO_THROTTLING_H
+#define LENOVO_THROTTLING_H
+
+/*
+ * C interface to lenovo-throttling-rust's power limit encoder; build the
+ * library with:
+ *
+ *   cargo rustc --lib --release --features ffi --crate-type cdylib
+ *
+ * Functions returning int return 0 on success and a negative value on invalid
+ * arguments; functions returning double return NaN on invalid arguments.
+ */
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct {
+    double watts;
+    int enabled;
+    int clamping;
+    double window_sec;
+} lt_power_limit;
+
+int lt_decode_units(uint64_t raw, double *power_unit, double *time_unit);
+
+double lt_encode_time_window(double seconds, double time_unit, uint32_t *y, uint32_t *z);
+double lt_decode_time_window(uint32_t y, uint32_t z, double time_unit);
+
+/* index is 1 for PL1, 2 for PL2; returns -1 if watts doesn't fit in the field */
+int lt_encode_power_limit(uint64_t raw, int index, double watts, double seconds,
+                          double power_unit, double time_unit, uint64_t *out);
+int lt_decode_power_limit(uint64_t raw, int index, double power_unit, double time_unit,
+                          lt_power_limit *out);
+
+/* profile is "ac" or "battery" */
+int lt_apply_profile(const char *config_toml, const char *profile);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,214 @@
+//! The interfaces that settings can be applied through, and which of them is used for each
+//! setting. Several settings can be made through more than one interface (e.g. the package power
+//! limits through the MSR, the kernel's powercap interface, or the MCHBAR mirror), and which ones
+//! work depends on the kernel and firmware.
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use mchbar;
+use msr;
+use platform;
+use powercap;
+use quirks;
+use rapl;
+use temps;
+use turbo;
+use INTEL_PSTATE_NO_TURBO;
+
+
+/// Something that a profile can set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// PL1 and PL2, with their time windows.
+    PackagePowerLimit,
+    /// The integrated GPU (PP1) power limit.
+    GpuPowerLimit,
+    /// The temperature at which the CPU starts throttling.
+    TemperatureTarget,
+    /// Whether Turbo Boost is enabled.
+    Turbo,
+    /// The ACPI platform profile.
+    PlatformProfile,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::PackagePowerLimit,
+        Capability::GpuPowerLimit,
+        Capability::TemperatureTarget,
+        Capability::Turbo,
+        Capability::PlatformProfile,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Capability::PackagePowerLimit => "power limits",
+            Capability::GpuPowerLimit => "GPU power limit",
+            Capability::TemperatureTarget => "temperature target",
+            Capability::Turbo => "turbo",
+            Capability::PlatformProfile => "platform profile",
+        }
+    }
+
+    /// Returns the MSR that the MSR backend reads and writes for the capability, if it has one.
+    /// Some CPUs don't have all of them (e.g. no PP1 limit without an integrated GPU), and reading
+    /// one that's missing faults.
+    pub fn msr(&self) -> Option<u64> {
+        match *self {
+            Capability::PackagePowerLimit => Some(rapl::MSR_PKG_POWER_LIMIT),
+            Capability::GpuPowerLimit => Some(rapl::MSR_PP1_POWER_LIMIT),
+            Capability::TemperatureTarget => Some(temps::MSR_TEMPERATURE_TARGET),
+            Capability::Turbo => Some(turbo::IA32_MISC_ENABLE),
+            Capability::PlatformProfile => None,
+        }
+    }
+}
+
+/// An interface that settings can be applied through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The model-specific registers.
+    Msr,
+    /// The intel_pstate driver's sysfs attributes.
+    Cpufreq,
+    /// The kernel's powercap interface to RAPL.
+    Powercap,
+    /// The MCHBAR mirror of MSR_PKG_POWER_LIMIT.
+    Mchbar,
+    /// The ACPI platform profile.
+    PlatformProfile,
+}
+
+/// Every backend, in order of preference for the capabilities they share.
+const BACKENDS: &[Backend] = &[
+    // The driver overwrites the turbo MSR behind our back, so it goes first.
+    Backend::Cpufreq,
+    Backend::Msr,
+    Backend::Powercap,
+    Backend::Mchbar,
+    Backend::PlatformProfile,
+];
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::Msr => "msr",
+            Backend::Cpufreq => "cpufreq",
+            Backend::Powercap => "powercap",
+            Backend::Mchbar => "mchbar",
+            Backend::PlatformProfile => "acpi",
+        }
+    }
+
+    /// Returns the capabilities that the backend provides, where it's available.
+    pub fn provides(&self) -> &'static [Capability] {
+        match *self {
+            Backend::Msr => &[
+                Capability::PackagePowerLimit,
+                Capability::GpuPowerLimit,
+                Capability::TemperatureTarget,
+                Capability::Turbo,
+            ],
+            Backend::Cpufreq => &[Capability::Turbo],
+            Backend::Powercap => &[Capability::PackagePowerLimit],
+            Backend::Mchbar => &[Capability::PackagePowerLimit],
+            Backend::PlatformProfile => &[Capability::PlatformProfile],
+        }
+    }
+
+    /// Returns whether the backend can be used on this system.
+    fn is_available(&self, msr_caps: msr::Capabilities) -> bool {
+        match *self {
+            Backend::Msr => msr_caps.write,
+            Backend::Cpufreq => Path::new(INTEL_PSTATE_NO_TURBO).exists(),
+            Backend::Powercap => powercap::package_zone().is_some(),
+            // The units and time windows still have to be read from the MSRs. Only Lenovo's EC is
+            // known to need the mirror, and poking /dev/mem elsewhere isn't worth the risk.
+            Backend::Mchbar => !quirks::generic() && msr_caps.read && mchbar::is_available(),
+            Backend::PlatformProfile => Path::new(platform::PLATFORM_PROFILE).exists(),
+        }
+    }
+}
+
+/// The backends that are available, in order of preference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registry {
+    available: Vec<Backend>,
+    /// Capabilities that the MSR backend can't provide, since this CPU doesn't have their MSR.
+    unsupported: Vec<Capability>,
+}
+
+impl Registry {
+    /// Finds the backends that are available, given what kind of MSR access we have, and probes
+    /// the MSR behind each capability that the MSR backend provides.
+    pub fn probe(msr_caps: msr::Capabilities) -> Registry {
+        let available: Vec<Backend> = BACKENDS.iter().cloned().filter(|b| b.is_available(msr_caps)).collect();
+        let unsupported = if msr_caps.read {
+            Backend::Msr.provides().iter().cloned()
+                .filter(|c| c.msr().is_some_and(is_unsupported_msr))
+                .collect()
+        } else {
+            vec![]
+        };
+        Registry { available, unsupported }
+    }
+
+    /// Returns the backend to apply a capability through, if any can.
+    pub fn best(&self, capability: Capability) -> Option<Backend> {
+        self.available.iter().cloned()
+            .filter(|&b| b != Backend::Msr || !self.unsupported.contains(&capability))
+            .find(|b| b.provides().contains(&capability))
+    }
+
+    /// Prints which backend each capability is applied through.
+    pub fn report(&self) {
+        let routes: Vec<String> = Capability::ALL.iter()
+            .map(|&c| {
+                let route = format!("{} = {}", c.name(), self.best(c).map_or("unavailable", |b| b.name()));
+                match c.msr() {
+                    Some(m) if self.unsupported.contains(&c) => format!("{} (no {})", route, msr::Name(m)),
+                    _ => route,
+                }
+            })
+            .collect();
+        println!("backends: {}", routes.join(", "));
+    }
+}
+
+/// Returns whether reading a MSR on the first CPU fails because the CPU doesn't have it.
+fn is_unsupported_msr(msr: u64) -> bool {
+    match msr::ReadMsrBuilder::new(msr).read_first() {
+        Err(ref e) => msr::is_unsupported(e),
+        Ok(_) => false,
+    }
+}
+
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+/// Returns the backends that are available, probing them the first time.
+pub fn registry() -> Registry {
+    REGISTRY.lock().unwrap()
+        .get_or_insert_with(|| Registry::probe(msr::Capabilities::probe()))
+        .clone()
+}
+
+/// Records that this CPU doesn't have the MSR behind a capability, e.g. after reading it failed
+/// while building updates, so that it's routed through another backend (or ignored) from now on.
+pub fn mark_unsupported(capability: Capability) {
+    let mut current = REGISTRY.lock().unwrap();
+    let registry = current.get_or_insert_with(|| Registry::probe(msr::Capabilities::probe()));
+    if !registry.unsupported.contains(&capability) {
+        registry.unsupported.push(capability);
+    }
+}
+
+/// Probes the backends again after MSR access has changed, returning whether the routing did.
+pub fn reprobe(msr_caps: msr::Capabilities) -> bool {
+    let registry = Registry::probe(msr_caps);
+    let mut current = REGISTRY.lock().unwrap();
+    let changed = current.as_ref() != Some(&registry);
+    *current = Some(registry);
+    changed
+}
@@ -0,0 +1,72 @@
+//! Keyboard and display backlights, so that switching to a battery-saving profile can dim the
+//! whole machine and not just slow down the CPU.
+
+use std::fs;
+use std::path::Path;
+
+use sysfs;
+
+
+/// Directory containing the LED class devices, which include keyboard backlights.
+pub const LEDS_DIR: &str = "/sys/class/leds";
+
+/// Directory containing the display backlight devices.
+pub const BACKLIGHT_DIR: &str = "/sys/class/backlight";
+
+/// Name of the brightness attribute of both kinds of device.
+pub const BRIGHTNESS: &str = "brightness";
+
+/// Suffix of the LED device names of keyboard backlights, e.g. "tpacpi::kbd_backlight".
+const KBD_BACKLIGHT_SUFFIX: &str = "::kbd_backlight";
+
+
+/// A backlight device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backlight {
+    /// Path to its brightness attribute.
+    pub brightness: String,
+    /// Highest brightness it supports.
+    pub max: u64,
+}
+
+impl Backlight {
+    /// Returns the brightness for the given percentage of the maximum, rounded to the nearest
+    /// level; keyboard backlights often only have two or three.
+    pub fn level_for(&self, pct: u8) -> u64 {
+        (self.max * pct as u64 + 50) / 100
+    }
+}
+
+/// Returns every keyboard backlight.
+pub fn keyboard_backlights() -> Vec<Backlight> {
+    find(LEDS_DIR, |name| name.ends_with(KBD_BACKLIGHT_SUFFIX))
+}
+
+/// Returns every display backlight.
+pub fn display_backlights() -> Vec<Backlight> {
+    find(BACKLIGHT_DIR, |_| true)
+}
+
+fn find(dir: &str, matches: fn(&str) -> bool) -> Vec<Backlight> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+
+    let mut backlights: Vec<Backlight> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| matches(&e.file_name().to_string_lossy()))
+        .filter_map(|e| read_device(&e.path()))
+        .collect();
+    backlights.sort_by(|a, b| a.brightness.cmp(&b.brightness));
+    backlights
+}
+
+fn read_device(dir: &Path) -> Option<Backlight> {
+    let max = sysfs::read_value(&dir.join("max_brightness").display().to_string()).ok()?.parse().ok()?;
+    let brightness = dir.join(BRIGHTNESS);
+    if !brightness.exists() {
+        return None;
+    }
+    Some(Backlight { brightness: brightness.display().to_string(), max })
+}
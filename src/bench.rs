@@ -0,0 +1,288 @@
+//! A built-in benchmark for comparing profiles, so that tuning can be checked without installing
+//! separate stress and monitoring tools.
+//!
+//! Each profile is applied in turn, and then every CPU is kept busy for a while; the package power,
+//! temperature and (effective) frequency are sampled once a second. The "sustained" figures are averaged over
+//! the second half of the run, by which point PL2 has usually expired and the cooling has caught up.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use num_cpus;
+
+use freq;
+use msr;
+use presets;
+use rapl;
+use sysfs;
+use temps;
+use topology;
+use {Config, ModeConfig, build_profile_updates};
+
+
+/// How often to sample power, temperature and frequency.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_DURATION_SEC: u64 = 60;
+const DEFAULT_COOLDOWN_SEC: u64 = 30;
+
+
+/// Options for a benchmark run, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Profiles to run, by name.
+    profiles: Vec<String>,
+    /// Number of threads to spin.
+    threads: usize,
+    /// How long to run each profile for.
+    duration: Duration,
+    /// How long to idle between profiles, so that each starts from a similar temperature.
+    cooldown: Duration,
+}
+
+impl Options {
+    /// Parses the arguments following `bench`: optionally "ac", "battery", preset names and names of
+    /// [profiles.*] sections, then any of `--threads N`, `--duration SECS` and `--cooldown SECS`.
+    /// Profile names are only checked once the config has been read.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+        let mut opts = Options {
+            profiles: vec![],
+            threads: num_cpus::get(),
+            duration: Duration::from_secs(DEFAULT_DURATION_SEC),
+            cooldown: Duration::from_secs(DEFAULT_COOLDOWN_SEC),
+        };
+
+        while let Some(arg) = args.next() {
+            let mut number = |name: &str| -> Result<u64, Error> {
+                let value = args.next().ok_or_else(|| format_err!("{} needs a value", name))?;
+                value.parse::<u64>().map_err(|_| format_err!("invalid value for {}: {}", name, value))
+            };
+
+            match arg.as_str() {
+                "--threads" => opts.threads = number("--threads")? as usize,
+                "--duration" => opts.duration = Duration::from_secs(number("--duration")?),
+                "--cooldown" => opts.cooldown = Duration::from_secs(number("--cooldown")?),
+                _ if !arg.starts_with("--") => opts.profiles.push(arg.clone()),
+                _ => bail!("unknown bench argument: {} \
+                            (usage: bench [ac] [battery] [PROFILE...] [--threads N] [--duration SECS] [--cooldown SECS])", arg),
+            }
+        }
+
+        if opts.profiles.is_empty() {
+            opts.profiles = vec!["battery".to_string(), "ac".to_string()];
+        }
+        if opts.threads == 0 || opts.duration < SAMPLE_INTERVAL * 2 {
+            bail!("the benchmark needs at least one thread and a duration of at least 2 seconds");
+        }
+        Ok(opts)
+    }
+}
+
+/// One second's worth of measurements.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    /// Work done by the spinning threads, in millions of iterations per second.
+    work: f64,
+    /// Average package power, in Watts.
+    watts: Option<f64>,
+    /// Package temperature at the end of the sample, in degrees Celsius.
+    temp_c: Option<u64>,
+    /// Average frequency across all CPUs, in MHz.
+    freq_mhz: Option<f64>,
+}
+
+/// Summary of one profile's run.
+#[derive(Debug, Clone, PartialEq)]
+struct Report {
+    profile: String,
+    /// Averages over the second half of the run.
+    sustained: Sample,
+    /// Averages over the first few seconds, while PL2 applies.
+    initial: Sample,
+    max_temp_c: Option<u64>,
+}
+
+
+/// Runs the benchmark and prints a report.
+pub fn run(config: &Config, opts: &Options) -> Result<(), Error> {
+    let units = rapl::Units::read()?;
+    for name in opts.profiles.iter() {
+        if name != "ac" && name != "battery" && presets::find(name).is_none() && !config.profiles.contains_key(name) {
+            bail!("unknown profile {}; profiles are ac, battery, a preset or a [profiles.*] section", name);
+        }
+    }
+
+    println!("Running {} thread(s) for {}s per profile; this will make the machine hot and loud.",
+             opts.threads, opts.duration.as_secs());
+
+    let mut reports = vec![];
+    for (i, name) in opts.profiles.iter().enumerate() {
+        if i > 0 {
+            println!("Cooling down for {}s...", opts.cooldown.as_secs());
+            thread::sleep(opts.cooldown);
+        }
+
+        let conf = match (name.as_str(), config.profiles.get(name), presets::find(name)) {
+            ("ac", _, _) => config.ac.clone(),
+            ("battery", _, _) => config.battery.clone(),
+            // A section in the config takes precedence over a preset of the same name.
+            (_, Some(profile), _) => profile.conf.clone(),
+            (_, None, Some(preset)) => preset.resolve(presets::tdp_w().0),
+            (_, None, None) => bail!("unknown profile {}", name),
+        };
+        apply_profile(config, &conf)?;
+
+        println!("Running profile {}...", name);
+        let samples = spin(opts, &units);
+        reports.push(summarize(name, &samples));
+    }
+
+    println!();
+    println!("{:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+             "profile", "Mops/s", "MHz", "W", "C", "max C", "initial W");
+    for r in reports.iter() {
+        println!("{:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                 r.profile,
+                 format!("{:.0}", r.sustained.work),
+                 fmt_opt(r.sustained.freq_mhz.map(|f| format!("{:.0}", f))),
+                 fmt_opt(r.sustained.watts.map(|w| format!("{:.1}", w))),
+                 fmt_opt(r.sustained.temp_c.map(|t| t.to_string())),
+                 fmt_opt(r.max_temp_c.map(|t| t.to_string())),
+                 fmt_opt(r.initial.watts.map(|w| format!("{:.1}", w))));
+    }
+
+    println!();
+    println!("The last profile is still applied; the daemon will apply the right one when it starts.");
+    Ok(())
+}
+
+fn fmt_opt(val: Option<String>) -> String {
+    val.unwrap_or_else(|| "-".to_string())
+}
+
+fn apply_profile(config: &Config, conf: &ModeConfig) -> Result<(), Error> {
+    let updates = build_profile_updates(config, conf)?;
+    let failed = updates.iter().filter(|u| u.apply().is_err()).count();
+    if failed > 0 {
+        eprintln!("WARNING: {} setting(s) couldn't be applied; results won't reflect the profile", failed);
+    }
+    Ok(())
+}
+
+/// Keeps `opts.threads` threads busy for `opts.duration`, sampling as it goes.
+fn spin(opts: &Options, units: &rapl::Units) -> Vec<Sample> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let work = Arc::new(AtomicU64::new(0));
+
+    let workers: Vec<_> = (0..opts.threads).map(|i| {
+        let stop = stop.clone();
+        let work = work.clone();
+        thread::spawn(move || {
+            // A cheap xorshift keeps the ALUs busy without being optimized away.
+            let mut x: u64 = 0x9E3779B97F4A7C15 ^ i as u64;
+            while !stop.load(Ordering::Relaxed) {
+                for _ in 0..100000 {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                }
+                work.fetch_add(100000, Ordering::Relaxed);
+            }
+            x
+        })
+    }).collect();
+
+    // Open everything up front, so that sampling doesn't disturb the measurements.
+    let mut energy_sampler = msr::Sampler::new(&[(msr::first_cpu(), rapl::MSR_PKG_ENERGY_STATUS)]).ok();
+    let mut temp_sampler = temps::TemperatureSampler::new().ok();
+    let mut freq_sampler = freq::FrequencySampler::new().ok();
+    if let Some(ref mut s) = freq_sampler {
+        let _ = s.sample();
+    }
+    let mut read_energy = || energy_sampler.as_mut().and_then(|s| s.sample().ok()).map(|v| v[0] as u32);
+
+    let mut samples = vec![];
+    let start = Instant::now();
+    let mut last_work = 0;
+    let mut last_energy = read_energy();
+    let mut last_time = Instant::now();
+    while start.elapsed() < opts.duration {
+        thread::sleep(SAMPLE_INTERVAL);
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(last_time).as_secs_f64();
+        let total_work = work.load(Ordering::Relaxed);
+        let energy = read_energy();
+
+        // The energy counter is 32 bits wide, and wraps every few minutes under load.
+        let watts = match (last_energy, energy) {
+            (Some(a), Some(b)) => Some(b.wrapping_sub(a) as f64 * units.energy / elapsed),
+            _ => None,
+        };
+
+        samples.push(Sample {
+            work: (total_work - last_work) as f64 / elapsed / 1e6,
+            watts,
+            temp_c: temp_sampler.as_mut().and_then(|s| s.read().ok()).and_then(|t| t.package),
+            freq_mhz: freq_sampler.as_mut()
+                .and_then(|s| s.sample().ok().and_then(|f| f?.average_mhz))
+                .or_else(average_freq_mhz),
+        });
+
+        last_work = total_work;
+        last_energy = energy;
+        last_time = now;
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for w in workers {
+        let _ = w.join();
+    }
+    samples
+}
+
+/// Returns the average current frequency of the online CPUs, according to cpufreq; used if
+/// APERF/MPERF can't be read.
+fn average_freq_mhz() -> Option<f64> {
+    let freqs: Vec<f64> = topology::online_cpus_or_default().into_iter()
+        .filter_map(|cpu| {
+            let path = format!("/sys/devices/system/cpu/cpu{}/cpufreq/scaling_cur_freq", cpu);
+            sysfs::read_value(&path).ok()?.parse::<f64>().ok()
+        })
+        .collect();
+
+    if freqs.is_empty() {
+        return None;
+    }
+    Some(freqs.iter().sum::<f64>() / freqs.len() as f64 / 1000.0)
+}
+
+fn summarize(profile: &str, samples: &[Sample]) -> Report {
+    let initial = average(&samples[..samples.len().min(3)]);
+    let sustained = average(&samples[samples.len() / 2..]);
+
+    Report {
+        profile: profile.to_string(),
+        sustained,
+        initial,
+        max_temp_c: samples.iter().filter_map(|s| s.temp_c).max(),
+    }
+}
+
+/// Averages a set of samples, ignoring missing values.
+fn average(samples: &[Sample]) -> Sample {
+    fn mean<I: Iterator<Item = f64>>(values: I) -> Option<f64> {
+        let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+        if count == 0 { None } else { Some(sum / count as f64) }
+    }
+
+    Sample {
+        work: mean(samples.iter().map(|s| s.work)).unwrap_or(0.0),
+        watts: mean(samples.iter().filter_map(|s| s.watts)),
+        temp_c: mean(samples.iter().filter_map(|s| s.temp_c.map(|t| t as f64))).map(|t| t.round() as u64),
+        freq_mhz: mean(samples.iter().filter_map(|s| s.freq_mhz)),
+    }
+}
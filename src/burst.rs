@@ -0,0 +1,67 @@
+//! Adaptive PL2 ("burst budget") management.
+//!
+//! This keeps a moving average of the package power, as measured by the RAPL energy counter. After
+//! a quiet period the heatsink is cold and can soak up a long burst, so PL2 is raised; after
+//! sustained load it's already warm, so PL2 is lowered to avoid bouncing off the thermal limit.
+//! Between the two thresholds, the last decision sticks.
+
+use std::time::Duration;
+
+use BurstBudgetConfig;
+
+
+/// Which PL2 the burst budget is currently asking for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// The system has been mostly idle; allow a long burst.
+    Boost,
+    /// The system has been under sustained load.
+    Sustained,
+}
+
+/// The state of the burst budget.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Budget {
+    /// Exponentially weighted moving average of the package power, in Watts.
+    average_w: Option<f64>,
+    /// The current decision, if one has been made yet.
+    level: Option<Level>,
+}
+
+impl Budget {
+    /// Records a new sample of the average package power over the last `interval`, returning
+    /// whether the PL2 to apply has changed.
+    pub fn record(&mut self, conf: &BurstBudgetConfig, watts: f64, interval: Duration) -> bool {
+        let alpha = 1.0 - (-interval.as_secs_f64() / conf.window_sec as f64).exp();
+        let average = match self.average_w {
+            Some(avg) => avg + alpha * (watts - avg),
+            None => watts,
+        };
+        self.average_w = Some(average);
+
+        let level = if average < conf.cool_below_w {
+            Some(Level::Boost)
+        } else if average > conf.hot_above_w {
+            Some(Level::Sustained)
+        } else {
+            self.level
+        };
+
+        let changed = level != self.level;
+        self.level = level;
+        changed
+    }
+
+    /// Returns the moving average of the package power, in Watts.
+    pub fn average_w(&self) -> Option<f64> {
+        self.average_w
+    }
+
+    /// Returns the PL2 to apply, in Watts, or `None` to leave the profile's PL2 alone.
+    pub fn pl2(&self, conf: &BurstBudgetConfig) -> Option<u64> {
+        self.level.map(|l| match l {
+            Level::Boost => conf.boost_pl2_w,
+            Level::Sustained => conf.sustained_pl2_w,
+        })
+    }
+}
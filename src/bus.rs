@@ -0,0 +1,382 @@
+//! D-Bus control interface, for the types in `control`.
+//!
+//! Anyone on the system bus may read the daemon's status, but methods that change its behaviour
+//! are gated behind polkit actions (see `dist/ca.nham.du.LenovoThrottling.policy`), so that desktop
+//! users can switch profiles from their session without needing sudo. Changes that clients are
+//! likely to care about are also broadcast as signals, so that indicators don't have to poll.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ::channel;
+use dbus::{self, BusType, Connection, Message, NameFlag};
+use dbus::arg::Variant;
+use dbus::tree::{Factory, MethodErr};
+use failure::Error;
+
+use control::{self, Command, Signal, Status};
+use rules::Profile;
+
+
+/// Well-known name that we request on the system bus.
+pub const BUS_NAME: &str = "ca.nham.du.LenovoThrottling";
+
+/// Object path of the control object.
+pub const OBJECT_PATH: &str = "/ca/nham/du/LenovoThrottling";
+
+/// Interface implemented by the control object.
+pub const INTERFACE: &str = "ca.nham.du.LenovoThrottling";
+
+/// Interface for trying out power limits before committing to them, implemented by the control
+/// object. Its methods would clash with the main interface's `SetLimits` otherwise.
+pub const OVERRIDE_INTERFACE: &str = "ca.nham.du.LenovoThrottling.Override";
+
+/// polkit action required to force a profile.
+const ACTION_SET_PROFILE: &str = "ca.nham.du.LenovoThrottling.set-profile";
+
+/// polkit action required to pause or resume the daemon.
+const ACTION_PAUSE: &str = "ca.nham.du.LenovoThrottling.pause";
+
+/// polkit action required to set transient power limits.
+const ACTION_SET_LIMITS: &str = "ca.nham.du.LenovoThrottling.set-limits";
+
+/// polkit action required to switch travel mode on or off.
+const ACTION_TRAVEL: &str = "ca.nham.du.LenovoThrottling.travel";
+
+/// polkit action required to reload the configuration.
+const ACTION_RELOAD: &str = "ca.nham.du.LenovoThrottling.reload";
+
+/// How long to wait for polkit, in milliseconds. This is long since polkit may need to prompt the
+/// user for a password.
+const POLKIT_TIMEOUT_MS: i32 = 120 * 1000;
+
+/// How long to wait for the daemon to answer GetStatus, in milliseconds.
+const STATUS_TIMEOUT_MS: i32 = 2000;
+
+/// How long to wait for the daemon to answer ReloadConfig, PreviewConfig and the override methods.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for method calls before checking for signals to send, in milliseconds.
+const SIGNAL_POLL_MS: u32 = 100;
+
+
+/// Starts serving the control interface on the system bus, returning a channel of commands
+/// received from clients. Signals sent on `signals` are emitted from the control object.
+///
+/// Failing to connect to the bus isn't fatal; the error is logged and the channel is simply
+/// disconnected.
+pub fn serve(status: Arc<Mutex<Status>>, signals: channel::Receiver<Signal>) -> channel::Receiver<Command> {
+    let (send, recv) = channel::unbounded();
+
+    thread::spawn(move || {
+        if let Err(e) = run(status, send, signals) {
+            eprintln!("error in D-Bus control interface: {}", e);
+        }
+    });
+
+    recv
+}
+
+/// Checks that the system bus can be reached, so that failing to serve the interface can be
+/// reported at startup.
+pub fn check_bus() -> Result<(), Error> {
+    Connection::get_private(BusType::System)?;
+    Ok(())
+}
+
+/// Asks the daemon for its status, as a client. This doesn't need any privileges.
+pub fn fetch_status(conn: &Connection) -> Result<Status, Error> {
+    let msg = Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE, "GetStatus")
+        .map_err(|e| format_err!("{}", e))?;
+    let reply = conn.send_with_reply_and_block(msg, STATUS_TIMEOUT_MS)?;
+    let map: HashMap<String, String> = reply.read1().map_err(dbus::Error::from)?;
+    Ok(Status::from_map(&map))
+}
+
+/// Asks the daemon to switch travel mode on or off, as a client. polkit may prompt for a password.
+pub fn set_travel(enabled: bool) -> Result<(), Error> {
+    let conn = Connection::get_private(BusType::System)?;
+    let msg = Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE, "SetTravel")
+        .map_err(|e| format_err!("{}", e))?
+        .append1(enabled);
+    conn.send_with_reply_and_block(msg, POLKIT_TIMEOUT_MS)?;
+    Ok(())
+}
+
+fn run(
+    status: Arc<Mutex<Status>>,
+    send: channel::Sender<Command>,
+    signals: channel::Receiver<Signal>,
+) -> Result<(), Error> {
+    let conn = Connection::get_private(BusType::System)?;
+    conn.register_name(BUS_NAME, NameFlag::DoNotQueue as u32)?;
+
+    // polkit calls are made on their own connection so we don't have to re-enter the one that's
+    // currently dispatching a method call.
+    let polkit = Rc::new(Connection::get_private(BusType::System)?);
+
+    let f = Factory::new_fn::<()>();
+
+    let get_status = f.method("GetStatus", (), move |m| {
+        let map = status.lock().unwrap().to_map();
+        Ok(vec![m.msg.method_return().append1(map)])
+    }).outarg::<HashMap<&str, &str>, _>("status");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let set_profile = f.method("SetProfile", (), move |m| {
+        let name: &str = m.msg.read1()?;
+        let profile = parse_profile(name)?;
+
+        authorize(&pk, m.msg, ACTION_SET_PROFILE)?;
+        request_profile(&tx, profile, None)?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<&str, _>("profile");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let set_profile_for = f.method("SetProfileFor", (), move |m| {
+        let (name, secs): (&str, u32) = m.msg.read2()?;
+        let profile = parse_profile(name)?;
+        if secs == 0 {
+            return Err(MethodErr::invalid_arg(&secs));
+        }
+
+        authorize(&pk, m.msg, ACTION_SET_PROFILE)?;
+        request_profile(&tx, profile, Some(Duration::from_secs(secs as u64)))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<&str, _>("profile").inarg::<u32, _>("seconds");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let pause = f.method("Pause", (), move |m| {
+        let paused: bool = m.msg.read1()?;
+
+        authorize(&pk, m.msg, ACTION_PAUSE)?;
+        dispatch(&tx, Command::Pause(paused))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<bool, _>("paused");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let set_limits = f.method("SetLimits", (), move |m| {
+        let (pl1, pl2): (u32, u32) = m.msg.read2()?;
+        control::check_limits(pl1 as u64, pl2 as u64).map_err(|e| MethodErr::invalid_arg(&e))?;
+
+        authorize(&pk, m.msg, ACTION_SET_LIMITS)?;
+        dispatch(&tx, Command::SetLimits(pl1 as u64, pl2 as u64))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<u32, _>("pl1_w").inarg::<u32, _>("pl2_w");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let reload = f.method("Reload", (), move |m| {
+        authorize(&pk, m.msg, ACTION_RELOAD)?;
+        dispatch(&tx, Command::Reload)?;
+        Ok(vec![m.msg.method_return()])
+    });
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let reload_config = f.method("ReloadConfig", (), move |m| {
+        authorize(&pk, m.msg, ACTION_RELOAD)?;
+        let (reply, recv) = channel::bounded(1);
+        dispatch(&tx, Command::ReloadConfig(reply))?;
+        wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))?;
+        Ok(vec![m.msg.method_return()])
+    });
+
+    // Previewing is gated like reloading, since it has the daemon read a file of the caller's
+    // choosing. That's done by the unprivileged worker, so it can't read anything root-only, but
+    // it can still read the worker's own files.
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let preview_config = f.method("PreviewConfig", (), move |m| {
+        let path: &str = m.msg.read1()?;
+
+        authorize(&pk, m.msg, ACTION_RELOAD)?;
+        let (reply, recv) = channel::bounded(1);
+        dispatch(&tx, Command::PreviewConfig(path.to_string(), reply))?;
+        let preview = wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))?;
+        let profiles: HashMap<String, String> = preview.profiles.into_iter().collect();
+        Ok(vec![m.msg.method_return().append2(profiles, preview.changes)])
+    }).inarg::<&str, _>("path")
+        .outarg::<HashMap<&str, &str>, _>("profiles").outarg::<Vec<&str>, _>("changes");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let set_travel = f.method("SetTravel", (), move |m| {
+        let enabled: bool = m.msg.read1()?;
+
+        authorize(&pk, m.msg, ACTION_TRAVEL)?;
+        dispatch(&tx, Command::Travel(enabled))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<bool, _>("enabled");
+
+    // Overrides are rolled back unless they're committed, if the client doesn't call again within
+    // a minute (e.g. since it crashed, or the machine hung and was restarted); see `transaction`.
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let begin_override = f.method("BeginOverride", (), move |m| {
+        authorize(&pk, m.msg, ACTION_SET_LIMITS)?;
+        let (reply, recv) = channel::bounded(1);
+        dispatch(&tx, Command::BeginOverride(reply))?;
+        let token = wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))?;
+        Ok(vec![m.msg.method_return().append1(token)])
+    }).outarg::<u64, _>("token");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let override_limits = f.method("SetLimits", (), move |m| {
+        let (token, pl1, pl2): (u64, u32, u32) = m.msg.read3()?;
+        control::check_limits(pl1 as u64, pl2 as u64).map_err(|e| MethodErr::invalid_arg(&e))?;
+
+        authorize(&pk, m.msg, ACTION_SET_LIMITS)?;
+        let (reply, recv) = channel::bounded(1);
+        dispatch(&tx, Command::OverrideLimits(token, pl1 as u64, pl2 as u64, reply))?;
+        wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<u64, _>("token").inarg::<u32, _>("pl1_w").inarg::<u32, _>("pl2_w");
+
+    let (pk, tx) = (polkit.clone(), send.clone());
+    let commit_override = f.method("Commit", (), move |m| {
+        let token: u64 = m.msg.read1()?;
+
+        authorize(&pk, m.msg, ACTION_SET_LIMITS)?;
+        let (reply, recv) = channel::bounded(1);
+        dispatch(&tx, Command::CommitOverride(token, reply))?;
+        wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<u64, _>("token");
+
+    let (pk, tx) = (polkit, send);
+    let abort_override = f.method("Abort", (), move |m| {
+        let token: u64 = m.msg.read1()?;
+
+        authorize(&pk, m.msg, ACTION_SET_LIMITS)?;
+        let (reply, recv) = channel::bounded(1);
+        dispatch(&tx, Command::AbortOverride(token, reply))?;
+        wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))?;
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<u64, _>("token");
+
+    let profile_changed = Arc::new(f.signal("ProfileChanged", ())
+        .sarg::<&str, _>("profile").sarg::<&str, _>("reason"));
+    let throttle_detected = Arc::new(f.signal("ThrottleDetected", ())
+        .sarg::<Vec<&str>, _>("reasons"));
+    let write_failed = Arc::new(f.signal("WriteFailed", ())
+        .sarg::<&str, _>("profile").sarg::<&str, _>("error"));
+
+    let tree = f.tree(()).add(f.object_path(OBJECT_PATH, ()).introspectable().add(
+        f.interface(INTERFACE, ())
+            .add_m(get_status)
+            .add_m(set_profile)
+            .add_m(set_profile_for)
+            .add_m(pause)
+            .add_m(set_limits)
+            .add_m(reload)
+            .add_m(reload_config)
+            .add_m(preview_config)
+            .add_m(set_travel)
+            .add_s(profile_changed.clone())
+            .add_s(throttle_detected.clone())
+            .add_s(write_failed.clone())
+    ).add(
+        f.interface(OVERRIDE_INTERFACE, ())
+            .add_m(begin_override)
+            .add_m(override_limits)
+            .add_m(commit_override)
+            .add_m(abort_override)
+    ));
+
+    tree.set_registered(&conn, true)?;
+    conn.add_handler(tree);
+
+    let (path, iface) = (OBJECT_PATH.into(), INTERFACE.into());
+    loop {
+        conn.incoming(SIGNAL_POLL_MS).next();
+
+        loop {
+            let signal = match signals.try_recv() {
+                Ok(s) => s,
+                Err(channel::TryRecvError::Empty) => break,
+                // The daemon has exited.
+                Err(channel::TryRecvError::Disconnected) => return Ok(()),
+            };
+            let msg = match signal {
+                Signal::ProfileChanged(profile, reason) => {
+                    profile_changed.msg(&path, &iface).append2(profile, reason)
+                },
+                Signal::ThrottleDetected(reasons) => throttle_detected.msg(&path, &iface).append1(reasons),
+                Signal::WriteFailed(profile, error) => {
+                    write_failed.msg(&path, &iface).append2(profile, error)
+                },
+            };
+            if conn.send(msg).is_err() {
+                eprintln!("error sending D-Bus signal");
+            }
+        }
+    }
+}
+
+/// Parses a profile name given by a client; "auto" means automatic selection.
+fn parse_profile(name: &str) -> Result<Option<Profile>, MethodErr> {
+    match name {
+        "auto" => Ok(None),
+        "" => Err(MethodErr::invalid_arg(&name)),
+        _ => Ok(Some(Profile::parse(name))),
+    }
+}
+
+/// Forces a profile, failing if the daemon turns it away (e.g. a named profile that isn't in the
+/// config).
+fn request_profile(send: &channel::Sender<Command>, profile: Option<Profile>, expires: Option<Duration>) -> Result<(), MethodErr> {
+    let (reply, recv) = channel::bounded(1);
+    dispatch(send, Command::SetProfile(profile, expires, reply))?;
+    wait_for_reply(&recv)?.map_err(|e| MethodErr::failed(&e))
+}
+
+fn dispatch(send: &channel::Sender<Command>, cmd: Command) -> Result<(), MethodErr> {
+    send.send(cmd).map_err(|_| MethodErr::failed(&"daemon is shutting down"))
+}
+
+/// Waits for the daemon to answer a command that was sent with a reply channel.
+fn wait_for_reply<T>(recv: &channel::Receiver<T>) -> Result<T, MethodErr> {
+    recv.recv_timeout(REPLY_TIMEOUT).map_err(|_| MethodErr::failed(&"daemon didn't answer"))
+}
+
+/// Asks polkit whether the sender of the given message is allowed to perform an action.
+fn authorize(conn: &Connection, msg: &Message, action: &str) -> Result<(), MethodErr> {
+    let sender = match msg.sender() {
+        Some(s) => s,
+        None => return Err(MethodErr::failed(&"message has no sender")),
+    };
+
+    match check_authorization(conn, &sender, action) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(("org.freedesktop.DBus.Error.AccessDenied", "not authorized").into()),
+        Err(e) => {
+            eprintln!("error checking polkit authorization: {}", e);
+            Err(MethodErr::failed(&"unable to check authorization"))
+        },
+    }
+}
+
+fn check_authorization(conn: &Connection, sender: &str, action: &str) -> Result<bool, Error> {
+    // Subject is a (sa{sv}) struct identifying the caller by its unique bus name.
+    let mut subject_details = HashMap::new();
+    subject_details.insert("name", Variant(sender));
+    let subject = ("system-bus-name", subject_details);
+
+    let details: HashMap<&str, &str> = HashMap::new();
+
+    // Flag 0x1 is AllowUserInteraction.
+    let msg = Message::new_method_call(
+        "org.freedesktop.PolicyKit1",
+        "/org/freedesktop/PolicyKit1/Authority",
+        "org.freedesktop.PolicyKit1.Authority",
+        "CheckAuthorization",
+    ).map_err(|e| format_err!("{}", e))?
+        .append3(subject, action, details)
+        .append2(1u32, "");
+
+    let reply = conn.send_with_reply_and_block(msg, POLKIT_TIMEOUT_MS)?;
+
+    // Result is a (bba{ss}) struct; we only care whether we're authorized.
+    let (authorized, _challenge, _details): (bool, bool, HashMap<&str, &str>) = reply.read1()
+        .map_err(dbus::Error::from)?;
+    Ok(authorized)
+}
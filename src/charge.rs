@@ -0,0 +1,68 @@
+//! Battery charging policy knobs exposed by the Lenovo ACPI platform drivers.
+
+use std::fs;
+
+use paths;
+use sysfs;
+
+
+/// Directory that the ideapad_acpi driver's devices are found in.
+pub const IDEAPAD_ACPI_DRIVER: &str = "/sys/bus/platform/drivers/ideapad_acpi";
+
+/// Battery attributes holding the charge thresholds, in percent, as exposed by thinkpad_acpi.
+pub const START_THRESHOLD: &str = "charge_control_start_threshold";
+pub const STOP_THRESHOLD: &str = "charge_control_end_threshold";
+
+
+/// Returns the path to the given attribute of the ideapad_acpi device, if the driver is loaded and
+/// exposes it.
+///
+/// The device is named after its ACPI ID (usually `VPC2004:00`), so we look for it rather than
+/// hard-coding the name.
+pub fn ideapad_attribute(name: &str) -> Option<String> {
+    let entries = fs::read_dir(IDEAPAD_ACPI_DRIVER).ok()?;
+
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("VPC2004"))
+        .map(|e| e.path().join(name))
+        .find(|p| p.exists())
+        .map(|p| p.display().to_string())
+}
+
+/// Returns the directories of the batteries that have charge thresholds.
+pub fn threshold_batteries() -> Vec<String> {
+    let root = &paths::get().power_supply;
+    let mut batteries: Vec<String> = match fs::read_dir(root) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| format!("{}/{}", root, e.file_name().to_string_lossy()))
+            .filter(|dir| sysfs::read_value(&format!("{}/type", dir)).is_ok_and(|t| t == "Battery"))
+            .filter(|dir| fs::metadata(format!("{}/{}", dir, STOP_THRESHOLD)).is_ok())
+            .collect(),
+        Err(_) => vec![],
+    };
+    batteries.sort();
+    batteries
+}
+
+/// Reads the first battery's charge thresholds, as (start, stop).
+pub fn read_thresholds() -> Option<(u8, u8)> {
+    let battery = threshold_batteries().into_iter().next()?;
+    let read = |name: &str| sysfs::read_value(&format!("{}/{}", battery, name)).ok()?.parse::<u8>().ok();
+    Some((read(START_THRESHOLD)?, read(STOP_THRESHOLD)?))
+}
+
+/// Returns whether a path is a battery's charge threshold, for the privileged helper to check.
+pub fn is_threshold_attribute(path: &str) -> bool {
+    let root = &paths::get().power_supply;
+    let rest = match path.strip_prefix(root.as_str()).and_then(|p| p.strip_prefix('/')) {
+        Some(r) => r,
+        None => return false,
+    };
+    match rest.split_once('/') {
+        Some((battery, attr)) => {
+            battery.starts_with("BAT") && (attr == START_THRESHOLD || attr == STOP_THRESHOLD)
+        },
+        None => false,
+    }
+}
@@ -0,0 +1,327 @@
+//! Shell completions and a man page, for packagers to install. The command line is parsed by hand
+//! in `run`, so both are generated from the table of commands here; keep the two in sync.
+
+use presets;
+
+
+/// Name of the installed binary.
+const BIN: &str = "lenovo-throttling-rust";
+
+/// Shells that completions can be generated for.
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+
+/// What a command's arguments can be completed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Values {
+    None,
+    /// A preset name.
+    Presets,
+    /// "ac", "battery" or a preset name.
+    Profiles,
+    /// A shell name.
+    Shells,
+}
+
+struct Command {
+    name: &'static str,
+    /// Arguments, for the usage line.
+    args: &'static str,
+    about: &'static str,
+    values: Values,
+    /// Options, as (flag, value name, description).
+    options: &'static [(&'static str, &'static str, &'static str)],
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "status",
+        args: "",
+        about: "Print the current power limits, temperatures and related registers.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "why-throttle",
+        args: "",
+        about: "Sample the limits, power and temperatures for a few seconds and say why the CPU is (or isn't) throttling.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "monitor",
+        args: "",
+        about: "Follow the daemon's status and the temperatures without root, e.g. as a user service.",
+        values: Values::None,
+        options: &[
+            ("--metrics", "ADDR", "Serve Prometheus metrics on this address instead of printing a summary."),
+            ("--interval", "SECS", "How often to fetch the daemon's status (default: 5)."),
+        ],
+    },
+    Command {
+        name: "travel",
+        args: "<on|off>",
+        about: "Switch travel mode (conservative limits, 60-80% charging, no Turbo Boost) on or off.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "setup",
+        args: "",
+        about: "Interactively write a starting config for this machine.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "check-config",
+        args: "",
+        about: "Check the config for errors and likely mistakes.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "list-presets",
+        args: "",
+        about: "List the built-in presets, with their values for this CPU.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "apply-preset",
+        args: "<preset>",
+        about: "Apply a built-in preset once, until the daemon next applies the configured profile.",
+        values: Values::Presets,
+        options: &[],
+    },
+    Command {
+        name: "set",
+        args: "",
+        about: "Apply power limits straight away, without the config, for quick experiments.",
+        values: Values::None,
+        options: &[
+            ("--pl1", "W", "PL1, in Watts."),
+            ("--pl1-window", "SECS", "PL1 time window, in seconds or with units (default: unchanged)."),
+            ("--pl2", "W", "PL2, in Watts."),
+            ("--pl2-window", "SECS", "PL2 time window, in seconds or with units (default: unchanged)."),
+            ("--for", "DURATION", "Put back the previous limits after this long, e.g. 10m."),
+        ],
+    },
+    Command {
+        name: "bench",
+        args: "[<profile>...]",
+        about: "Run a load on each profile (ac, battery, a preset or a [profiles.*] section) and compare sustained power and clocks.",
+        values: Values::Profiles,
+        options: &[
+            ("--threads", "N", "Number of threads to load (default: every CPU)."),
+            ("--duration", "SECS", "How long to run each profile for."),
+            ("--cooldown", "SECS", "How long to idle between profiles."),
+        ],
+    },
+    Command {
+        name: "stress-verify",
+        args: "",
+        about: "Find the largest stable undervolt down to a candidate offset, stepping back on wrong results or machine checks.",
+        values: Values::None,
+        options: &[
+            ("--offset", "MV", "Candidate core and cache offset, in millivolts, e.g. -80."),
+            ("--step", "MV", "How far to step back after errors (default: 5)."),
+            ("--duration", "SECS", "How long each offset has to run without errors (default: 300)."),
+            ("--threads", "N", "Number of threads to load (default: every CPU)."),
+        ],
+    },
+    Command {
+        name: "explain",
+        args: "<msr> <value>",
+        about: "Break a raw MSR value (hex, as printed by rdmsr) down into its fields.",
+        values: Values::None,
+        options: &[
+            ("--units", "RAW", "MSR_RAPL_POWER_UNIT value to decode power limits with (default: this CPU's)."),
+        ],
+    },
+    Command {
+        name: "export-profile",
+        args: "",
+        about: "Print the limits applied right now (e.g. by the BIOS) as a [profiles.*] section.",
+        values: Values::None,
+        options: &[
+            ("--name", "NAME", "Name of the profile (default: current)."),
+            ("--append", "", "Add the section to the config file instead of printing it."),
+        ],
+    },
+    Command {
+        name: "completions",
+        args: "<shell>",
+        about: "Print shell completions for bash, zsh or fish.",
+        values: Values::Shells,
+        options: &[],
+    },
+    Command {
+        name: "manpage",
+        args: "",
+        about: "Print this man page, in roff.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "--coexist",
+        args: "",
+        about: "Run the daemon even though another tool that rewrites the power limits (e.g. throttled) is running.",
+        values: Values::None,
+        options: &[],
+    },
+    Command {
+        name: "--dump-msrs",
+        args: "",
+        about: "Print the raw values of the relevant MSRs and powercap settings, for bug reports.",
+        values: Values::None,
+        options: &[],
+    },
+];
+
+
+/// Returns the completion words for a command's arguments.
+fn words(values: Values) -> Vec<&'static str> {
+    match values {
+        Values::None => vec![],
+        Values::Presets => presets::PRESETS.iter().map(|p| p.name).collect(),
+        Values::Profiles => ["ac", "battery"].iter().cloned().chain(presets::PRESETS.iter().map(|p| p.name)).collect(),
+        Values::Shells => SHELLS.to_vec(),
+    }
+}
+
+/// Returns the completion words for a command's arguments and options.
+fn all_words(c: &Command) -> Vec<&'static str> {
+    words(c.values).into_iter().chain(c.options.iter().map(|o| o.0)).collect()
+}
+
+/// Returns completions for the given shell, or `None` if it isn't supported.
+pub fn generate(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash()),
+        "zsh" => Some(zsh()),
+        "fish" => Some(fish()),
+        _ => None,
+    }
+}
+
+fn bash() -> String {
+    let func = format!("_{}", BIN.replace('-', "_"));
+    let names: Vec<&str> = COMMANDS.iter().map(|c| c.name).collect();
+
+    let mut s = format!("{}() {{\n", func);
+    s += "    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n";
+    s += "    if [ \"$COMP_CWORD\" -eq 1 ]; then\n";
+    s += &format!("        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n", names.join(" "));
+    s += "        return\n";
+    s += "    fi\n";
+    s += "    case \"${COMP_WORDS[1]}\" in\n";
+    for c in COMMANDS.iter() {
+        let words = all_words(c);
+        if !words.is_empty() {
+            s += &format!("        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;\n", c.name, words.join(" "));
+        }
+    }
+    s += "    esac\n";
+    s += "}\n";
+    s += &format!("complete -F {} {}\n", func, BIN);
+    s
+}
+
+fn zsh() -> String {
+    let mut s = format!("#compdef {}\n\n", BIN);
+    s += "local -a commands\n";
+    s += "commands=(\n";
+    for c in COMMANDS.iter() {
+        s += &format!("    '{}:{}'\n", c.name.replace(':', "\\:"), c.about.replace('\'', "'\\''"));
+    }
+    s += ")\n\n";
+    s += "if (( CURRENT == 2 )); then\n";
+    s += "    _describe 'command' commands\n";
+    s += "    return\n";
+    s += "fi\n\n";
+    s += "case $words[2] in\n";
+    for c in COMMANDS.iter() {
+        let words = all_words(c);
+        if !words.is_empty() {
+            s += &format!("    {}) compadd -- {} ;;\n", c.name, words.join(" "));
+        }
+    }
+    s += "esac\n";
+    s
+}
+
+fn fish() -> String {
+    let names: Vec<&str> = COMMANDS.iter().map(|c| c.name).collect();
+    let none = format!("not __fish_seen_subcommand_from {}", names.join(" "));
+
+    let mut s = format!("complete -c {} -f\n", BIN);
+    for c in COMMANDS.iter() {
+        s += &format!("complete -c {} -n '{}' -a {} -d '{}'\n", BIN, none, c.name, c.about.replace('\'', "\\'"));
+    }
+    for c in COMMANDS.iter() {
+        let seen = format!("__fish_seen_subcommand_from {}", c.name);
+        let values = words(c.values);
+        if !values.is_empty() {
+            s += &format!("complete -c {} -n '{}' -a '{}'\n", BIN, seen, values.join(" "));
+        }
+        for &(flag, value, about) in c.options.iter() {
+            // Options without a value, like --append, are switches.
+            s += &format!("complete -c {} -n '{}' -l {}{} -d '{}'\n", BIN, seen, flag.trim_start_matches('-'),
+                          if value.is_empty() { "" } else { " -r" }, about.replace('\'', "\\'"));
+        }
+    }
+    s
+}
+
+/// Escapes text for roff, where a leading "." or "'" starts a request and "-" is a hyphen.
+fn roff(text: &str) -> String {
+    let text = text.replace('\\', "\\\\").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+/// Returns the man page, in roff.
+pub fn manpage() -> String {
+    let mut s = format!(".TH {} 8 \"\" \"{} {}\"\n", BIN.to_uppercase(), BIN, env!("CARGO_PKG_VERSION"));
+    s += ".SH NAME\n";
+    s += &format!("{} \\- manage CPU power limits and temperature targets on Lenovo laptops\n", roff(BIN));
+    s += ".SH SYNOPSIS\n";
+    s += &format!(".B {}\n.br\n.B {}\n.I command\n[\\fIargs\\fR]\n", roff(BIN), roff(BIN));
+    s += ".SH DESCRIPTION\n";
+    s += "Without a command, runs the daemon: it applies the [battery] or [ac] profile from \
+          its config whenever the power source changes, and reapplies it periodically so that \
+          firmware can't revert it.\n";
+    s += ".SH COMMANDS\n";
+    for c in COMMANDS.iter() {
+        s += &format!(".TP\n\\fB{}\\fR{}\n{}\n", roff(c.name),
+                      if c.args.is_empty() { String::new() } else { format!(" \\fI{}\\fR", roff(c.args)) },
+                      roff(c.about));
+        for &(flag, value, about) in c.options.iter() {
+            let value = if value.is_empty() { String::new() } else { format!(" \\fI{}\\fR", roff(value)) };
+            s += &format!(".RS\n.TP\n\\fB{}\\fR{}\n{}\n.RE\n", roff(flag), value, roff(about));
+        }
+    }
+    s += ".SH FILES\n";
+    s += ".TP\n.I /etc/lenovo-throttling/config.toml\nThe configuration, unless \\fB--config=\\fR\\fIPATH\\fR (given before \
+          the command) or the LT_CONFIG environment variable names another file; see the commented example shipped with \
+          the package. It may be written in JSON instead, with the same layout, if its name ends in .json. If it doesn't \
+          exist, config.toml in the working directory is read instead, with a warning; that's deprecated.\n";
+    s += ".SH EXIT STATUS\n";
+    for &(code, about) in [
+        (0, "Success, or the daemon was asked to exit."),
+        (1, "Some other failure."),
+        (2, "Unknown command or invalid arguments."),
+        (3, "The configuration couldn't be read or is invalid."),
+        (4, "The MSR devices can't be accessed."),
+        (5, "The CPU isn't one that we know how to control."),
+        (6, "The system bus couldn't be reached, but the D-Bus interface is enabled."),
+        (7, "The daemon stopped while settings were failing to apply."),
+        (8, "Another tool that rewrites the power limits is running; see --coexist."),
+    ].iter() {
+        s += &format!(".TP\n.B {}\n{}\n", code, roff(about));
+    }
+    s
+}
@@ -0,0 +1,115 @@
+//! Detecting other tools that rewrite the same registers. Two daemons fighting over
+//! MSR_PKG_POWER_LIMIT make the limits flip back and forth in ways that are hard to diagnose, so
+//! it's better to say so up front.
+
+use std::fs;
+use std::path::Path;
+
+
+/// TLP's configuration files.
+const TLP_CONFIG: &str = "/etc/tlp.conf";
+const TLP_CONFIG_DIR: &str = "/etc/tlp.d";
+
+
+/// Another tool that changes the settings we manage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// What was found, e.g. "throttled (pid 1234)".
+    pub tool: String,
+    /// What it changes.
+    pub writes: &'static str,
+    /// Whether it's a running daemon that will keep rewriting them, rather than a setting that's
+    /// applied once.
+    pub running: bool,
+}
+
+impl Conflict {
+    pub fn report(&self) {
+        println!("  - {}: {}", self.tool, self.writes);
+    }
+}
+
+/// Returns every conflicting tool that's running or configured.
+pub fn detect() -> Vec<Conflict> {
+    let mut conflicts = vec![];
+
+    for (pid, comm, cmdline) in processes() {
+        // throttled is a Python script, so it has to be found by its arguments; it was called
+        // lenovo_fix before it was renamed.
+        let args: Vec<&str> = cmdline.split('\0').collect();
+        let script = |name: &str| args.iter().take(3).any(|a| Path::new(a).file_name().is_some_and(|f| f == name));
+        if script("throttled.py") || script("lenovo_fix.py") {
+            conflicts.push(Conflict {
+                tool: format!("throttled (pid {})", pid),
+                writes: "rewrites MSR_PKG_POWER_LIMIT, MSR_TEMPERATURE_TARGET and the MCHBAR power limit periodically",
+                running: true,
+            });
+        } else if comm == "intel-undervolt" && args.contains(&"daemon") {
+            conflicts.push(Conflict {
+                tool: format!("intel-undervolt daemon (pid {})", pid),
+                writes: "rewrites MSR_PKG_POWER_LIMIT and MSR_TEMPERATURE_TARGET periodically",
+                running: true,
+            });
+        }
+    }
+
+    for (path, key) in tlp_rapl_settings() {
+        conflicts.push(Conflict {
+            tool: format!("TLP ({} in {})", key, path),
+            writes: "sets RAPL power limits when the power source changes",
+            running: false,
+        });
+    }
+
+    conflicts
+}
+
+/// Returns whether a process with the given name is running.
+pub fn process_running(name: &str) -> bool {
+    processes().iter().any(|p| p.1 == name)
+}
+
+/// Returns the pid, name and NUL-separated arguments of every process we can see.
+fn processes() -> Vec<(u32, String, String)> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    entries.filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let pid = e.file_name().to_str()?.parse::<u32>().ok()?;
+            let comm = fs::read_to_string(e.path().join("comm")).ok()?;
+            let cmdline = fs::read_to_string(e.path().join("cmdline")).unwrap_or_default();
+            Some((pid, comm.trim_end().to_string(), cmdline))
+        })
+        .collect()
+}
+
+/// Returns the uncommented RAPL settings in TLP's configuration, as (file, key) pairs.
+fn tlp_rapl_settings() -> Vec<(String, String)> {
+    let mut files = vec![TLP_CONFIG.to_string()];
+    if let Ok(entries) = fs::read_dir(TLP_CONFIG_DIR) {
+        let mut extra: Vec<String> = entries.filter_map(|e| e.ok())
+            .map(|e| e.path().display().to_string())
+            .filter(|p| p.ends_with(".conf"))
+            .collect();
+        extra.sort();
+        files.extend(extra);
+    }
+
+    let mut settings = vec![];
+    for file in files {
+        let contents = match fs::read_to_string(&file) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        for line in contents.lines().map(|l| l.trim()).filter(|l| !l.starts_with('#')) {
+            if let Some((key, _)) = line.split_once('=') {
+                if key.contains("RAPL") {
+                    settings.push((file.clone(), key.trim().to_string()));
+                }
+            }
+        }
+    }
+    settings
+}
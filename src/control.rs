@@ -0,0 +1,257 @@
+//! The daemon's control interface, independent of how clients reach it: the commands they can send,
+//! the signals they're sent and the status they can read. Clients connect over D-Bus (see `bus`,
+//! when built with the "dbus" feature) or the JSON-RPC socket (see `rpc`).
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::channel;
+use daemon;
+use energy;
+use metrics;
+use power::PowerState;
+use revert;
+use rules::Profile;
+use temps;
+
+
+/// The highest transient power limit that clients may ask for, in Watts. It's far above what any
+/// laptop CPU draws, and only there so that nonsense is turned away before it gets anywhere near
+/// the power limit field.
+pub const MAX_LIMIT_W: u64 = 1000;
+
+/// Checks transient limits given by a client, where 0 leaves a limit as the profile sets it.
+pub fn check_limits(pl1: u64, pl2: u64) -> Result<(), String> {
+    for &(name, watts) in [("pl1_w", pl1), ("pl2_w", pl2)].iter() {
+        if watts > MAX_LIMIT_W {
+            return Err(format!("{} must be at most {} W, not {}", name, MAX_LIMIT_W, watts));
+        }
+    }
+    Ok(())
+}
+
+/// A request from a client to change the daemon's behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Force the given profile, or return to automatic selection if `None`. The override lasts
+    /// until cleared, or for the given duration. Named profiles that aren't in the config are
+    /// turned away.
+    SetProfile(Option<Profile>, Option<Duration>, channel::Sender<Result<(), String>>),
+    /// Stop (or resume) applying settings.
+    Pause(bool),
+    /// Override PL1 and PL2 (in Watts) for the active profile. Zero means "use the profile value".
+    SetLimits(u64, u64),
+    /// Reload the configuration file.
+    Reload,
+    /// Reload the configuration file, sending back whether it worked (and why not).
+    ReloadConfig(channel::Sender<Result<(), String>>),
+    /// Read and check the config file at the given path without applying it, sending back how it
+    /// compares to the running configuration.
+    PreviewConfig(String, channel::Sender<Result<Preview, String>>),
+    /// Switch travel mode on or off.
+    Travel(bool),
+    /// Start trying out power limits, sending back the token for the rest of the override's calls.
+    /// It's rolled back unless committed, if the client doesn't call again for a while.
+    BeginOverride(channel::Sender<Result<u64, String>>),
+    /// Set PL1 and PL2 (in Watts, with zero meaning "use the profile value") for the override with
+    /// the given token, sending back whether they were applied.
+    OverrideLimits(u64, u64, u64, channel::Sender<Result<(), String>>),
+    /// Keep the override's limits, as if they'd been set with `SetLimits`.
+    CommitOverride(u64, channel::Sender<Result<(), String>>),
+    /// Put back the limits from before the override.
+    AbortOverride(u64, channel::Sender<Result<(), String>>),
+}
+
+/// A change in the daemon that's broadcast to clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    /// A different profile (named here) was applied, for the given reason.
+    ProfileChanged(String, String),
+    /// The package was throttled, for the given reasons (e.g. "thermal" or "power_limit").
+    ThrottleDetected(Vec<&'static str>),
+    /// Applying the named profile failed, with the error.
+    WriteFailed(String, String),
+}
+
+/// A candidate configuration, as checked by `Command::PreviewConfig`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Preview {
+    /// Each profile ("battery", "ac" and the named ones) with its presets, inherited settings
+    /// and model limits resolved, as TOML.
+    pub profiles: Vec<(String, String)>,
+    /// How the profiles differ from the running configuration, one change per line, e.g.
+    /// "[ac] pl1_tdp_w: 44 -> 51".
+    pub changes: Vec<String>,
+}
+
+/// The daemon's current state, as reported to clients.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    /// Lifecycle state of the daemon.
+    pub state: Option<daemon::State>,
+    /// Current power source.
+    pub power_state: Option<PowerState>,
+    /// Name of the profile that's currently applied.
+    pub profile: Option<String>,
+    /// Whether the profile was forced by a client.
+    pub forced: bool,
+    /// When the forced profile expires, if it does.
+    pub forced_until: Option<SystemTime>,
+    /// Whether the daemon is paused.
+    pub paused: bool,
+    /// Transient PL1/PL2 override, in Watts.
+    pub limits: Option<(u64, u64)>,
+    /// Whether a client is trying out limits that haven't been committed yet.
+    pub override_pending: bool,
+    /// Whether travel mode is on.
+    pub travel: bool,
+    /// Whether the emergency constraints are applied, since the CPU is too hot.
+    pub emergency: bool,
+    /// Throttling episodes seen since the daemon started, if they're being counted.
+    pub throttle: Option<temps::ThrottleCounts>,
+    /// Latest average and maximum effective frequency, in MHz, if it's being sampled.
+    pub frequency: Option<(f64, f64)>,
+    /// Energy used by each profile since the daemon started, if it's being counted.
+    pub energy: Option<energy::Ledger>,
+    /// Latest smoothed CPU load, in percent, if it's being sampled.
+    pub load: Option<f64>,
+    /// Why the active profile was picked.
+    pub rule: Option<String>,
+    /// When the power source last changed.
+    pub last_power_change: Option<SystemTime>,
+    /// Time taken to apply the new profile after recent power source changes.
+    pub power_latency: Option<metrics::Summary>,
+    /// How many times a profile has been applied, and how many of those failed.
+    pub applies: u64,
+    pub apply_failures: u64,
+    /// Time taken by recent applies' writes, and how many applies have been slow.
+    pub apply_time: Option<metrics::Summary>,
+    pub slow_applies: u32,
+    /// How many power source changes took longer than the latency budget to apply.
+    pub power_latency_over_budget: u32,
+    /// How many times something else has changed the power limits since the daemon started.
+    pub power_limit_changes: u32,
+    /// What probably changed them last.
+    pub power_limit_changed_by: Option<revert::Source>,
+}
+
+impl Status {
+    /// Returns the status as a map of field names to values, for clients.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        if let Some(state) = self.state {
+            map.insert("state".to_string(), format!("{:?}", state));
+        }
+        map.insert("power_state".to_string(), state_name(self.power_state).to_string());
+        map.insert("profile".to_string(), self.profile.clone().unwrap_or_else(|| "auto".to_string()));
+        if let Some(PowerState::AC { watts: Some(watts) }) = self.power_state {
+            map.insert("charger_w".to_string(), watts.to_string());
+        }
+        map.insert("forced".to_string(), self.forced.to_string());
+        if let Some(until) = self.forced_until {
+            let secs = until.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            map.insert("forced_until".to_string(), secs.to_string());
+        }
+        map.insert("paused".to_string(), self.paused.to_string());
+        map.insert("travel".to_string(), self.travel.to_string());
+        map.insert("emergency".to_string(), self.emergency.to_string());
+        if let Some((pl1, pl2)) = self.limits {
+            map.insert("pl1_override_w".to_string(), pl1.to_string());
+            map.insert("pl2_override_w".to_string(), pl2.to_string());
+        }
+        map.insert("override_pending".to_string(), self.override_pending.to_string());
+        if let Some(ref rule) = self.rule {
+            map.insert("rule".to_string(), rule.clone());
+        }
+        if let Some(counts) = self.throttle {
+            map.insert("throttle_thermal".to_string(), counts.thermal.to_string());
+            map.insert("throttle_prochot".to_string(), counts.prochot.to_string());
+            map.insert("throttle_critical".to_string(), counts.critical.to_string());
+            map.insert("throttle_power_limit".to_string(), counts.power_limit.to_string());
+        }
+        if let Some((average, max)) = self.frequency {
+            map.insert("effective_mhz".to_string(), format!("{:.0}", average));
+            map.insert("effective_mhz_max".to_string(), format!("{:.0}", max));
+        }
+        if let Some(ref ledger) = self.energy {
+            for &(ref profile, usage) in ledger.totals() {
+                map.insert(format!("energy_{}_j", profile), format!("{:.1}", usage.joules));
+                map.insert(format!("energy_{}_wh", profile), format!("{:.3}", usage.watt_hours()));
+                map.insert(format!("energy_{}_sec", profile), usage.time.as_secs().to_string());
+                map.insert(format!("energy_{}_sessions", profile), usage.sessions.to_string());
+            }
+            if let Some((_, session)) = ledger.session() {
+                map.insert("session_energy_j".to_string(), format!("{:.1}", session.joules));
+                map.insert("session_energy_wh".to_string(), format!("{:.3}", session.watt_hours()));
+            }
+        }
+        if let Some(load) = self.load {
+            map.insert("load_percent".to_string(), format!("{:.0}", load));
+        }
+        if let Some(changed) = self.last_power_change {
+            let secs = changed.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            map.insert("last_power_change".to_string(), secs.to_string());
+        }
+        if let Some(latency) = self.power_latency {
+            map.insert("power_latency_count".to_string(), latency.count.to_string());
+            map.insert("power_latency_p50_ms".to_string(), latency.p50.as_millis().to_string());
+            map.insert("power_latency_p90_ms".to_string(), latency.p90.as_millis().to_string());
+            map.insert("power_latency_p99_ms".to_string(), latency.p99.as_millis().to_string());
+            map.insert("power_latency_max_ms".to_string(), latency.max.as_millis().to_string());
+            map.insert("power_latency_over_budget".to_string(), self.power_latency_over_budget.to_string());
+        }
+        map.insert("applies".to_string(), self.applies.to_string());
+        map.insert("apply_failures".to_string(), self.apply_failures.to_string());
+        if let Some(time) = self.apply_time {
+            map.insert("apply_time_p50_ms".to_string(), time.p50.as_millis().to_string());
+            map.insert("apply_time_p99_ms".to_string(), time.p99.as_millis().to_string());
+            map.insert("apply_time_max_ms".to_string(), time.max.as_millis().to_string());
+        }
+        map.insert("slow_applies".to_string(), self.slow_applies.to_string());
+        map.insert("power_limit_changes".to_string(), self.power_limit_changes.to_string());
+        if let Some(source) = self.power_limit_changed_by {
+            map.insert("power_limit_changed_by".to_string(), source.to_string());
+        }
+        map
+    }
+
+    /// Parses the fields of a `to_map` map that unprivileged monitors need: the profile, the
+    /// counters and whether the daemon is paused, in travel mode or applying the emergency
+    /// constraints. Missing or invalid fields are left unset.
+    #[cfg(feature = "dbus")]
+    pub fn from_map(map: &HashMap<String, String>) -> Status {
+        let number = |key: &str| map.get(key).and_then(|v| v.parse::<u64>().ok());
+        let state = |key: &str| match map.get(key).map(|v| v.as_str()) {
+            Some("ac") => Some(PowerState::AC { watts: number("charger_w") }),
+            Some("battery") => Some(PowerState::Battery),
+            _ => None,
+        };
+
+        let throttle = number("throttle_thermal").map(|thermal| temps::ThrottleCounts {
+            thermal,
+            prochot: number("throttle_prochot").unwrap_or(0),
+            critical: number("throttle_critical").unwrap_or(0),
+            power_limit: number("throttle_power_limit").unwrap_or(0),
+        });
+
+        Status {
+            power_state: state("power_state"),
+            profile: map.get("profile").filter(|p| *p != "auto").cloned(),
+            forced: map.get("forced").is_some_and(|v| v == "true"),
+            paused: map.get("paused").is_some_and(|v| v == "true"),
+            travel: map.get("travel").is_some_and(|v| v == "true"),
+            emergency: map.get("emergency").is_some_and(|v| v == "true"),
+            rule: map.get("rule").cloned(),
+            throttle,
+            applies: number("applies").unwrap_or(0),
+            apply_failures: number("apply_failures").unwrap_or(0),
+            power_limit_changes: number("power_limit_changes").unwrap_or(0) as u32,
+            ..Status::default()
+        }
+    }
+}
+
+fn state_name(state: Option<PowerState>) -> &'static str {
+    state.map_or("auto", |s| s.name())
+}
+
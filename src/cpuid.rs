@@ -0,0 +1,89 @@
+//! CPU feature detection with the CPUID instruction, so that features the CPU doesn't have can be
+//! skipped up front instead of by trying them and looking at the errors.
+
+use std::sync::OnceLock;
+
+
+/// Leaf with the thermal and power management features.
+const LEAF_POWER_MANAGEMENT: u32 = 0x6;
+
+/// Leaf with the structured extended features.
+const LEAF_EXTENDED_FEATURES: u32 = 0x7;
+
+
+static FEATURES: OnceLock<Features> = OnceLock::new();
+
+/// Power management features of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features {
+    /// Turbo Boost. The CPU reports this as missing while it's disabled in IA32_MISC_ENABLE, so
+    /// this alone can't tell whether Turbo Boost can be re-enabled.
+    pub turbo_boost: bool,
+    /// Turbo Boost Max 3.0, i.e. some cores can turbo higher than others.
+    pub turbo_boost_max_3: bool,
+    /// Hardware P-states.
+    pub hwp: bool,
+    /// The energy/performance preference field of the HWP requests.
+    pub hwp_epp: bool,
+    /// The package-level HWP request.
+    pub hwp_pkg_req: bool,
+    /// Power limit notifications. There's no CPUID bit for RAPL itself; this arrived with it (in
+    /// Sandy Bridge), so it's the best indication we have.
+    pub rapl: bool,
+    /// A hybrid part, with both performance and efficiency cores.
+    pub hybrid: bool,
+}
+
+impl Features {
+    pub fn detect() -> Features {
+        let max_leaf = cpuid(0, 0)[0];
+        let leaf = |leaf: u32| if leaf <= max_leaf { cpuid(leaf, 0) } else { [0; 4] };
+
+        let pm = leaf(LEAF_POWER_MANAGEMENT)[0];
+        let extended_edx = leaf(LEAF_EXTENDED_FEATURES)[3];
+        let bit = |reg: u32, n: u32| reg & (1 << n) != 0;
+
+        Features {
+            turbo_boost: bit(pm, 1),
+            rapl: bit(pm, 4),
+            hwp: bit(pm, 7),
+            hwp_epp: bit(pm, 10),
+            hwp_pkg_req: bit(pm, 11),
+            turbo_boost_max_3: bit(pm, 14),
+            hybrid: bit(extended_edx, 15),
+        }
+    }
+
+    /// Prints the features, for status output.
+    pub fn report(&self) {
+        let features = [
+            ("turbo boost", self.turbo_boost),
+            ("turbo boost max 3.0", self.turbo_boost_max_3),
+            ("HWP", self.hwp),
+            ("HWP EPP", self.hwp_epp),
+            ("HWP package request", self.hwp_pkg_req),
+            ("RAPL", self.rapl),
+            ("hybrid", self.hybrid),
+        ];
+        let present: Vec<&str> = features.iter().filter(|f| f.1).map(|f| f.0).collect();
+        println!("CPU features = {}", if present.is_empty() { "none".to_string() } else { present.join(", ") });
+    }
+}
+
+/// Returns the CPU's features, detecting them the first time.
+pub fn get() -> &'static Features {
+    FEATURES.get_or_init(Features::detect)
+}
+
+/// Returns EAX, EBX, ECX and EDX for the given leaf and subleaf.
+#[cfg(target_arch = "x86_64")]
+fn cpuid(leaf: u32, subleaf: u32) -> [u32; 4] {
+    let r = ::std::arch::x86_64::__cpuid_count(leaf, subleaf);
+    [r.eax, r.ebx, r.ecx, r.edx]
+}
+
+/// Without CPUID, nothing is supported.
+#[cfg(not(target_arch = "x86_64"))]
+fn cpuid(_leaf: u32, _subleaf: u32) -> [u32; 4] {
+    [0; 4]
+}
@@ -0,0 +1,1511 @@
+//! The daemon's main loop.
+//!
+//! This is structured as an explicit state machine driven by events arriving on a single channel,
+//! which every input source (power changes, battery level, CPU hotplug, D-Bus commands, timers and
+//! signals)
+//! feeds into:
+//!
+//!   Initializing -> Applying -> Steady <-> Applying
+//!                        |                    ^
+//!                        v                    |
+//!                     Degraded ---------------+
+//!
+//! Any state moves to ShuttingDown on SIGINT/SIGTERM, or when every event source has gone away.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use ::channel;
+use failure::Error;
+use libc;
+
+use backends;
+use burst;
+use control;
+use emergency;
+use energy;
+use exit::ExitCode;
+use freq;
+use idle;
+use load;
+use mchbar;
+use metrics;
+use msr;
+use paths;
+use persist;
+use plan::ApplyPlan;
+use power::{self, PowerState};
+use preview;
+use quirks;
+use ramp::Ramp;
+use rapl;
+use ratelimit;
+use revert;
+use rules;
+use runtime;
+use temps;
+use topology;
+use transaction::{self, Transaction};
+use travel;
+use {Config, HwpConfig, IdleConfig, LoadConfig, ModeConfig, Update, apply_quirk, build_profile_updates, read_config};
+
+
+/// How often to retry applying settings after a failure, if nothing else is configured.
+const DEFAULT_TIMER_PERIOD_SEC: u64 = 30;
+
+/// How many applies in a row may fail with MSR access errors before we re-probe MSR access.
+const MSR_ACCESS_FAILURE_LIMIT: u32 = 3;
+
+/// How often to check whether MSR writes have become possible again, after losing access.
+const MSR_REPROBE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long the power source has to stay the same before a change is applied; some docks cause
+/// AC -> battery -> AC blips that only last milliseconds. It's shortened to a fifth of the latency
+/// budget if that's smaller.
+const POWER_SETTLE: Duration = Duration::from_millis(200);
+
+/// Upper bound on how long to wait for the power source to settle, within the latency budget.
+const POWER_SETTLE_MAX: Duration = Duration::from_secs(2);
+
+/// How long an apply's writes may take before they count as slow. They normally take a few
+/// milliseconds; much longer means that something underneath (e.g. a stuck embedded controller, or
+/// an odd /dev in a container) is holding them up.
+const SLOW_APPLY: Duration = Duration::from_millis(500);
+
+/// How often to reapply periodically while applies are slow, at most.
+const SLOW_REAPPLY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How far the load has to drop below `load.high_percent` before `hwp_mode` stops asking for
+/// performance, in percentage points.
+const LOAD_HYSTERESIS_PERCENT: f64 = 10.0;
+
+
+/// An input to the state machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The power source changed, as noticed at the given time.
+    Power(PowerState, Instant),
+    /// The battery charge level changed.
+    BatteryLevel(u8),
+    /// A new sample of the battery discharge rate, in Watts.
+    Discharge(f64),
+    /// A new sample of the average package power, in Watts.
+    PackagePower(f64),
+    /// A new sample of the effective frequency.
+    Frequency(freq::Frequencies),
+    /// The package used the given energy, in Joules, over the given time.
+    Energy(f64, Duration),
+    /// A new value of the smoothed CPU load, in percent.
+    Load(f64),
+    /// A new sample of the hottest package or core temperature, in degrees C.
+    Temperature(u64),
+    /// Throttling occurred since the last sample.
+    Throttle(temps::ThermFlags),
+    /// A CPU came online.
+    CpuOnline(usize),
+    /// The system resumed after being suspended for the given time.
+    Resume(Duration),
+    /// The ACPI platform profile was changed to the given value.
+    PlatformProfile(String),
+    /// A D-Bus client sent a command.
+    Control(control::Command),
+    /// Periodic tick, used to reapply settings.
+    Timer,
+    /// The configuration should be reloaded (SIGHUP).
+    Reload,
+    /// The daemon should exit (SIGINT/SIGTERM).
+    Shutdown,
+    /// A source that the daemon can't work without has gone away.
+    SourceLost(&'static str),
+}
+
+/// The lifecycle state of the daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Started, but nothing has been applied yet.
+    Initializing,
+    /// Currently writing settings.
+    Applying,
+    /// All settings for the active profile were applied successfully.
+    Steady,
+    /// The last attempt to apply settings failed; we'll retry on the next timer tick.
+    Degraded,
+    /// Exiting.
+    ShuttingDown,
+}
+
+
+pub struct Daemon {
+    state: State,
+
+    config: Config,
+    /// Updates for each base profile, or `None` if they couldn't be built; they're rebuilt on
+    /// every apply until they can be.
+    updates_battery: Option<Vec<Update>>,
+    updates_ac: Option<Vec<Update>>,
+
+    power_state: PowerState,
+    battery_level: Option<u8>,
+    /// Profile forced by a client, which is persisted across restarts.
+    forced: Option<persist::Override>,
+    paused: bool,
+
+    /// The profile picked by the rules, and why.
+    selection: rules::Selection,
+
+    limits: Option<(u64, u64)>,
+    /// Limits that a client is trying out, which are rolled back unless they're committed.
+    transaction: Option<Transaction>,
+
+    /// The settings to put back once travel mode is switched off, while it's on. This is
+    /// persisted across restarts.
+    travel: Option<travel::Saved>,
+    /// Settings to put back on the next apply, since travel mode was just switched off.
+    travel_restore: Option<travel::Saved>,
+
+    /// PL1 cap imposed by the discharge guard, in Watts.
+    discharge_cap: Option<u64>,
+    /// Whether the battery is worn enough for the battery_wear constraints to apply.
+    battery_worn: bool,
+
+    /// Recent package power, used to pick PL2 when the burst budget is enabled.
+    burst: burst::Budget,
+
+    /// Whether the emergency constraints are applied, going by recent temperatures.
+    emergency: emergency::Clamp,
+
+    /// Throttling episodes seen so far, if we're watching for them.
+    throttle: Option<temps::ThrottleCounts>,
+
+    /// Latest average and maximum effective frequency, in MHz, if it's being sampled.
+    frequency: Option<(f64, f64)>,
+
+    /// Energy used by each profile, if it's being sampled.
+    energy: energy::Ledger,
+
+    /// Latest smoothed CPU load, in percent, if it's being sampled.
+    load: Option<f64>,
+    /// Whether the load is high enough for `hwp_mode` to ask for performance.
+    high_load: bool,
+
+    last_apply: Option<Instant>,
+    /// Settings that were last applied successfully, to ramp from.
+    last_applied: Option<ModeConfig>,
+    /// The ramp to the active profile's limits, while it's in progress.
+    ramp: Option<Ramp>,
+    /// How many times a profile has been applied, and how many of those failed.
+    applies: u64,
+    apply_failures: u64,
+    /// How long recent applies' writes took.
+    apply_time: metrics::Latencies,
+    /// Whether the last apply's writes were slow, in which case optional work (checking for
+    /// power limit changes, ramps and frequent periodic reapplies) is skipped until they aren't,
+    /// so that power source changes are still applied promptly.
+    slow: bool,
+    /// How many applies have been slow since the daemon started.
+    slow_applies: u32,
+
+    /// When the power source last changed.
+    last_power_change: Option<SystemTime>,
+    /// Time from noticing a power source change to having applied the new profile.
+    power_latency: metrics::Latencies,
+    /// How many of those took longer than the latency budget.
+    power_latency_over_budget: u32,
+
+    /// What kind of MSR access we have; MSR updates are skipped if we can't write them.
+    msr_caps: msr::Capabilities,
+    /// Consecutive applies that failed because MSRs couldn't be accessed, e.g. after the msr
+    /// module was reloaded or a udev rule changed.
+    msr_access_failures: u32,
+    /// When MSR access was last probed.
+    last_probe: Instant,
+    /// The CPUs that MSRs were last written on, to notice CPUs coming and going (e.g. when SMT is
+    /// switched off or on) and MSR devices that appear some time after their CPU comes online.
+    cpus: Vec<usize>,
+    /// Whether power limits are also written to the MCHBAR mirror, since the firmware has locked
+    /// MSR_PKG_POWER_LIMIT.
+    mchbar_fallback: bool,
+    /// Power limits we've written, to notice when something else changes them.
+    revert: revert::Detector,
+    /// How many times something else has changed the power limits, and what did it last.
+    power_limit_changes: u32,
+    power_limit_changed_by: Option<revert::Source>,
+
+    /// State that's shared with the D-Bus control interface.
+    status: Arc<Mutex<control::Status>>,
+    /// Signals for the D-Bus control interface. Nothing receives them if it's disabled.
+    signals: channel::Sender<control::Signal>,
+    /// Name of the profile that was last applied successfully, for signalling changes.
+    applied: Option<String>,
+
+    /// What to exit with once shut down.
+    exit_code: ExitCode,
+}
+
+impl Daemon {
+    /// Creates a new daemon with the given configuration and initial power state.
+    pub fn new(
+        config: Config,
+        power_state: PowerState,
+        battery_level: Option<u8>,
+        msr_caps: msr::Capabilities,
+        status: Arc<Mutex<control::Status>>,
+        signals: channel::Sender<control::Signal>,
+    ) -> Result<Daemon, Error> {
+        let (updates_battery, updates_ac) = build_base_updates(&config)?;
+        let throttle = config.therm_log.as_ref().map(|_| temps::ThrottleCounts::default());
+        let selection = rules::Selection { profile: power_state, named: None, reason: "power source".to_string() };
+
+        let battery_worn = check_battery_wear(&config);
+        let forced = match persist::load_override() {
+            Some(o) if config.lacks_profile(&o.profile) => {
+                println!("not restoring forced profile {}, since the config has no [profiles.{}]", o.describe(), o.profile.name());
+                None
+            },
+            Some(o) => {
+                println!("restored forced profile: {}", o.describe());
+                Some(o)
+            },
+            None => None,
+        };
+        let travel = persist::load_travel();
+        if travel.is_some() {
+            println!("restored travel mode");
+        }
+
+        Ok(Daemon {
+            state: State::Initializing,
+            config,
+            updates_battery,
+            updates_ac,
+            power_state,
+            battery_level,
+            forced,
+            paused: false,
+            selection,
+            limits: None,
+            transaction: None,
+            travel,
+            travel_restore: None,
+            discharge_cap: None,
+            battery_worn,
+            burst: burst::Budget::default(),
+            emergency: emergency::Clamp::default(),
+            throttle,
+            frequency: None,
+            energy: energy::Ledger::default(),
+            load: None,
+            high_load: false,
+            last_apply: None,
+            last_applied: None,
+            ramp: None,
+            applies: 0,
+            apply_failures: 0,
+            apply_time: metrics::Latencies::default(),
+            slow: false,
+            slow_applies: 0,
+            last_power_change: None,
+            power_latency: metrics::Latencies::default(),
+            power_latency_over_budget: 0,
+            msr_caps,
+            msr_access_failures: 0,
+            last_probe: Instant::now(),
+            cpus: topology::online_cpus_or_default(),
+            mchbar_fallback: false,
+            revert: revert::Detector::new(msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first().ok()),
+            power_limit_changes: 0,
+            power_limit_changed_by: None,
+            status,
+            signals,
+            applied: None,
+            exit_code: ExitCode::Success,
+        })
+    }
+
+    /// Runs the state machine until it's shut down, returning the code to exit with.
+    pub fn run(mut self, events: channel::Receiver<Event>) -> ExitCode {
+        self.apply();
+
+        // Events that arrived while waiting for the power source to settle.
+        let mut deferred = VecDeque::new();
+
+        while self.state != State::ShuttingDown {
+            // Take the next step of a ramp, or roll back an override that its client has stopped
+            // calling on, when it's due, unless something else comes up first. Overrides can't
+            // wait for the timer, which may be minutes away while the system is idle.
+            let ramp = self.ramp.as_ref().map(|r| r.until_next());
+            let expiry = self.transaction.as_ref().map(|t| t.until_expiry(Instant::now()));
+            let next = match (deferred.pop_front(), ramp.into_iter().chain(expiry).min()) {
+                (Some(e), _) => Ok(e),
+                (None, Some(timeout)) => match events.recv_timeout(timeout) {
+                    Err(channel::RecvTimeoutError::Timeout) => {
+                        if expiry == Some(timeout) {
+                            if self.check_transaction() {
+                                self.apply();
+                            }
+                        } else {
+                            self.step_ramp();
+                        }
+                        continue;
+                    },
+                    res => res.map_err(|_| ()),
+                },
+                (None, None) => events.recv().map_err(|_| ()),
+            };
+
+            // If every source has gone away, there's nothing left to do.
+            let event = match next {
+                Ok(e) => e,
+                Err(_) => {
+                    eprintln!("every event source has gone away, exiting");
+                    return if self.state == State::Degraded { ExitCode::WriteFailure } else { ExitCode::Failure };
+                },
+            };
+            let event = match event {
+                Event::Power(..) => coalesce_power(event, &events, &mut deferred, self.config.power_latency_budget()),
+                e => e,
+            };
+            self.handle(event);
+        }
+
+        self.exit_code
+    }
+
+    fn handle(&mut self, event: Event) {
+        // Discharge samples are frequent, so don't log them.
+        match event {
+            Event::Discharge(_) | Event::PackagePower(_) | Event::Frequency(_) | Event::Energy(..) |
+            Event::Load(_) | Event::Temperature(_) => {},
+            _ => println!("event: {:?}", event),
+        }
+
+        match event {
+            Event::Power(state, _) if state == self.power_state => {
+                println!("power source is unchanged after a transient blip; not reapplying");
+            },
+
+            Event::Power(state, noticed) => {
+                self.power_state = state;
+                self.last_power_change = Some(SystemTime::now());
+                self.apply();
+
+                // Only count changes that were actually applied.
+                if self.state == State::Steady && !self.paused {
+                    let latency = noticed.elapsed();
+                    let budget = self.config.power_latency_budget();
+                    if latency > budget {
+                        eprintln!("power change applied in {} ms, over the {} ms budget",
+                                  latency.as_millis(), budget.as_millis());
+                        self.power_latency_over_budget += 1;
+                    } else {
+                        println!("power change applied in {} ms", latency.as_millis());
+                    }
+                    self.power_latency.record(latency);
+                }
+                self.publish_status();
+            },
+
+            Event::BatteryLevel(level) => {
+                self.battery_level = Some(level);
+                self.apply();
+            },
+
+            Event::Discharge(rate) => {
+                let cap = self.discharge_cap(rate);
+                if cap != self.discharge_cap {
+                    println!("discharging at {:.1} W; PL1 cap is now {:?}", rate, cap);
+                    self.discharge_cap = cap;
+                    self.apply();
+                }
+            },
+
+            Event::PackagePower(watts) => {
+                if let Some(ref conf) = self.config.burst_budget {
+                    let interval = Duration::from_secs(conf.interval_sec);
+                    if self.burst.record(conf, watts, interval) {
+                        println!("average package power is {:.1} W; burst budget PL2 is now {:?}",
+                                 self.burst.average_w().unwrap_or(watts), self.burst.pl2(conf));
+                        self.apply();
+                    }
+                }
+            },
+
+            Event::Temperature(celsius) => {
+                if let Some(ref conf) = self.config.emergency {
+                    if self.emergency.record(conf, celsius, Instant::now()) {
+                        if self.emergency.is_active() {
+                            eprintln!("temperature has been at least {} C for {}s; applying the emergency constraints",
+                                      conf.enter_temp_c, conf.enter_sec);
+                        } else {
+                            println!("temperature has been at most {} C for {}s; lifting the emergency constraints",
+                                     conf.exit_temp_c, conf.exit_sec);
+                        }
+                        self.apply();
+                    }
+                }
+            },
+
+            Event::Frequency(f) => {
+                self.frequency = f.average_mhz.map(|avg| (avg, f.max_mhz().unwrap_or(avg)));
+                self.publish_status();
+            },
+
+            Event::Energy(joules, time) => {
+                let profile = self.selection.name().to_string();
+                self.energy.record(&profile, joules, time);
+                self.publish_status();
+            },
+
+            Event::Load(percent) => {
+                self.load = Some(percent);
+
+                let high = self.is_high_load(percent);
+                let hwp_changed = high != self.high_load && self.base_config().hwp_mode == Some(true);
+                if hwp_changed {
+                    println!("load is {:.0}%; HWP hints are now {}", percent, if high { "performance" } else { "the profile's" });
+                }
+                self.high_load = high;
+
+                let selection_changed = rules::needs_load(&self.config.rules) && self.select_profile();
+                if hwp_changed || selection_changed {
+                    self.apply();
+                } else {
+                    self.publish_status();
+                }
+            },
+
+            Event::Throttle(flags) => {
+                if let Some(ref mut counts) = self.throttle {
+                    counts.record(&flags);
+                }
+                self.publish_status();
+                self.signal(control::Signal::ThrottleDetected(flags.names()));
+            },
+
+            Event::CpuOnline(cpu) => {
+                // The CPU's MSR device may not be there yet, in which case the next tick notices
+                // it and reapplies.
+                self.check_cpus();
+                self.apply_cpu(cpu);
+            },
+
+            Event::Resume(suspended) => {
+                self.check_resume(suspended);
+                self.apply();
+            },
+
+            Event::PlatformProfile(platform_profile) => {
+                let follow = match self.config.follow_platform_profile {
+                    Some(ref f) => f,
+                    None => return,
+                };
+
+                let profile = follow.profile_for(&platform_profile);
+                match profile {
+                    Some(ref p) => println!("platform profile is now {}; forcing the {} profile", platform_profile, p.name()),
+                    None => println!("platform profile is now {}; returning to automatic selection", platform_profile),
+                }
+                self.set_override(profile.map(|profile| persist::Override { profile, until: None }));
+                self.apply();
+            },
+
+            Event::Control(cmd) => {
+                match cmd {
+                    control::Command::SetProfile(p, expires, reply) => {
+                        if let Some(ref profile) = p {
+                            if self.config.lacks_profile(profile) {
+                                let _ = reply.send(Err(format!("there's no [profiles.{}] section in the config", profile.name())));
+                                return;
+                            }
+                        }
+                        let o = p.map(|profile| persist::Override {
+                            profile,
+                            until: expires.map(|d| SystemTime::now() + d),
+                        });
+                        self.set_override(o);
+                        let _ = reply.send(Ok(()));
+                    },
+                    control::Command::Pause(p) => self.paused = p,
+                    control::Command::SetLimits(pl1, pl2) => self.limits = transient_limits(pl1, pl2),
+                    control::Command::Reload => return self.handle(Event::Reload),
+                    control::Command::ReloadConfig(reply) => {
+                        let res = self.reload().map_err(|e| e.to_string());
+                        if let Err(ref e) = res {
+                            eprintln!("error reloading config, keeping old one: {}", e);
+                        }
+                        let failed = res.is_err();
+                        // The client may have given up waiting.
+                        let _ = reply.send(res);
+                        if failed {
+                            return;
+                        }
+                    },
+                    control::Command::PreviewConfig(path, reply) => {
+                        let _ = reply.send(preview::preview(&self.config, &path).map_err(|e| e.to_string()));
+                        return;
+                    },
+                    control::Command::Travel(enabled) => self.set_travel(enabled),
+                    control::Command::BeginOverride(reply) => {
+                        let rolled_back = self.check_transaction();
+                        let res = match self.transaction {
+                            Some(_) => Err("another override is in progress".to_string()),
+                            None => {
+                                let t = Transaction::begin(self.limits, Instant::now());
+                                println!("override {} started", t.token());
+                                self.transaction = Some(t);
+                                Ok(t.token())
+                            },
+                        };
+                        let _ = reply.send(res);
+                        if !rolled_back {
+                            self.publish_status();
+                            return;
+                        }
+                    },
+                    control::Command::OverrideLimits(token, pl1, pl2, reply) => {
+                        if let Err(e) = self.touch_transaction(token) {
+                            let _ = reply.send(Err(e));
+                            return;
+                        }
+                        if self.paused {
+                            let _ = reply.send(Err("the daemon is paused".to_string()));
+                            return;
+                        }
+                        self.limits = transient_limits(pl1, pl2);
+                        self.apply();
+                        let res = match self.state {
+                            State::Degraded => Err("applying the limits failed".to_string()),
+                            _ => Ok(()),
+                        };
+                        let _ = reply.send(res);
+                        return;
+                    },
+                    control::Command::CommitOverride(token, reply) => {
+                        let res = self.touch_transaction(token);
+                        if res.is_ok() {
+                            println!("override {} committed, keeping limits {:?}", token, self.limits);
+                            self.transaction = None;
+                            self.publish_status();
+                        }
+                        let _ = reply.send(res);
+                        return;
+                    },
+                    control::Command::AbortOverride(token, reply) => {
+                        let res = self.touch_transaction(token);
+                        let failed = res.is_err();
+                        let _ = reply.send(res);
+                        if failed {
+                            return;
+                        }
+                        println!("override {} aborted", token);
+                        self.roll_back();
+                    },
+                }
+                self.apply();
+            },
+
+            Event::Timer => {
+                // Some rule inputs (e.g. the lid and time) are only checked here.
+                let rolled_back = self.check_transaction();
+                let changed = self.select_profile();
+                let regained = !self.msr_caps.write && self.last_probe.elapsed() >= MSR_REPROBE_INTERVAL
+                    && self.reprobe_msr();
+                let cpus_changed = self.check_cpus();
+                if changed || rolled_back || regained || cpus_changed || self.state == State::Degraded || self.reapply_due() {
+                    self.apply();
+                }
+            },
+
+            Event::Reload => {
+                match self.reload() {
+                    Ok(_) => self.apply(),
+                    Err(e) => eprintln!("error reloading config, keeping old one: {}", e),
+                }
+            },
+
+            Event::Shutdown => self.transition(State::ShuttingDown),
+
+            Event::SourceLost(name) => {
+                // Exit rather than carry on without it, so that the service manager restarts us.
+                eprintln!("the {} watcher has stopped, exiting", name);
+                self.exit_code = ExitCode::Failure;
+                self.transition(State::ShuttingDown);
+            },
+        }
+    }
+
+    fn transition(&mut self, to: State) {
+        if self.state != to {
+            println!("state: {:?} -> {:?}", self.state, to);
+            self.state = to;
+        }
+        self.publish_status();
+    }
+
+    fn publish_status(&self) {
+        *self.status.lock().unwrap() = control::Status {
+            state: Some(self.state),
+            power_state: Some(self.power_state),
+            profile: Some(self.selection.name().to_string()),
+            forced: self.forced.is_some(),
+            forced_until: self.forced.as_ref().and_then(|o| o.until),
+            paused: self.paused,
+            limits: self.limits,
+            override_pending: self.transaction.is_some(),
+            travel: self.travel.is_some(),
+            emergency: self.emergency.is_active(),
+            throttle: self.throttle,
+            frequency: self.frequency,
+            energy: self.config.energy.as_ref().map(|_| self.energy.clone()),
+            load: self.load,
+            rule: Some(self.selection.reason.clone()),
+            last_power_change: self.last_power_change,
+            power_latency: self.power_latency.summary(),
+            applies: self.applies,
+            apply_failures: self.apply_failures,
+            apply_time: self.apply_time.summary(),
+            slow_applies: self.slow_applies,
+            power_latency_over_budget: self.power_latency_over_budget,
+            power_limit_changes: self.power_limit_changes,
+            power_limit_changed_by: self.power_limit_changed_by,
+        };
+    }
+
+    fn signal(&self, signal: control::Signal) {
+        // Sending only fails if the control interface isn't running, and then there's no one to tell.
+        let _ = self.signals.send(signal);
+    }
+
+    /// Replaces the forced profile, persisting the new one.
+    fn set_override(&mut self, o: Option<persist::Override>) {
+        self.forced = o;
+        if let Err(e) = persist::save_override(self.forced.as_ref()) {
+            eprintln!("error saving forced profile to {}: {}", paths::get().state, e);
+        }
+    }
+
+    /// Checks the token of a call on the override in progress, rolling it back first if it's
+    /// expired.
+    fn touch_transaction(&mut self, token: u64) -> Result<(), String> {
+        if self.check_transaction() {
+            self.apply();
+        }
+        match self.transaction {
+            Some(ref mut t) => t.touch(token, Instant::now()),
+            None => Err("no override is in progress".to_string()),
+        }
+    }
+
+    /// Rolls back the override in progress if its client hasn't called for too long, returning
+    /// whether it did (and so needs applying).
+    fn check_transaction(&mut self) -> bool {
+        match self.transaction {
+            Some(t) if t.is_expired(Instant::now()) => {
+                eprintln!("override {} wasn't committed within {}s of the last call, rolling it back",
+                          t.token(), transaction::timeout().as_secs());
+                self.roll_back();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Ends the override in progress, putting back the transient limits from before it started.
+    fn roll_back(&mut self) {
+        if let Some(t) = self.transaction.take() {
+            self.limits = t.previous();
+        }
+    }
+
+    /// Switches travel mode on or off, persisting it. Switching it on saves the settings that it
+    /// changes, so that they can be put back when it's switched off.
+    fn set_travel(&mut self, enabled: bool) {
+        if enabled == self.travel.is_some() {
+            return;
+        }
+        if enabled {
+            let saved = travel::Saved::read(&travel::overlay(&self.config));
+            println!("travel mode on; settings to put back afterwards: {:?}", saved);
+            self.travel = Some(saved);
+            self.travel_restore = None;
+        } else {
+            println!("travel mode off");
+            self.travel_restore = self.travel.take();
+        }
+        if let Err(e) = persist::save_travel(self.travel.as_ref()) {
+            eprintln!("error saving travel mode to {}: {}", paths::get().state, e);
+        }
+    }
+
+    /// Returns whether the load counts as high for `hwp_mode`, with some hysteresis so that a load
+    /// hovering around the threshold doesn't flip the hints on every sample.
+    fn is_high_load(&self, percent: f64) -> bool {
+        let threshold = self.config.load.as_ref().map_or(LoadConfig::default().high_percent, |l| l.high_percent);
+        if self.high_load {
+            percent > threshold - LOAD_HYSTERESIS_PERCENT
+        } else {
+            percent > threshold
+        }
+    }
+
+    /// Re-evaluates the profile selection rules, returning whether the selection changed.
+    fn select_profile(&mut self) -> bool {
+        if self.forced.as_ref().is_some_and(|o| o.is_expired()) {
+            println!("forced profile expired; returning to automatic selection");
+            self.set_override(None);
+        }
+
+        let inputs = rules::Inputs::gather(&self.config.rules, self.power_state, self.battery_level, self.load);
+        let selection = rules::select(&self.config.rules, self.forced.as_ref().map(|o| &o.profile), &inputs);
+        if selection == self.selection {
+            return false;
+        }
+
+        // Don't log changes in the charger's wattage alone.
+        if selection.reason != self.selection.reason || selection.name() != self.selection.name() {
+            println!("profile: {} (selected by {})", selection.name(), selection.reason);
+        }
+        self.selection = selection;
+        true
+    }
+
+    /// Returns the configuration of the selected profile, before anything is layered over it.
+    fn base_config(&self) -> &ModeConfig {
+        if let Some(ref name) = self.selection.named {
+            return &self.config.profiles[name].conf;
+        }
+        match self.selection.profile {
+            PowerState::Battery   => &self.config.battery,
+            PowerState::AC { .. } => &self.config.ac,
+        }
+    }
+
+    /// Probes MSR access again, returning whether it changed.
+    fn reprobe_msr(&mut self) -> bool {
+        self.msr_access_failures = 0;
+        self.last_probe = Instant::now();
+
+        let caps = msr::Capabilities::probe();
+        if caps == self.msr_caps {
+            return false;
+        }
+
+        println!("MSR access changed:");
+        caps.report();
+        self.msr_caps = caps;
+
+        // Settings may be routed to different backends now.
+        if backends::reprobe(caps) {
+            backends::registry().report();
+            match build_base_updates(&self.config) {
+                Ok((battery, ac)) => {
+                    self.updates_battery = battery;
+                    self.updates_ac = ac;
+                },
+                Err(e) => eprintln!("error rebuilding updates: {}", e),
+            }
+        }
+        true
+    }
+
+    /// Returns whether the active profile wants its settings periodically reapplied.
+    fn reapply_due(&self) -> bool {
+        let rate = match self.base_config().update_rate_sec {
+            Some(r) if self.slow => cmp::max(Duration::from_secs(r as u64), SLOW_REAPPLY_INTERVAL),
+            Some(r) => Duration::from_secs(r as u64),
+            None => return false,
+        };
+
+        self.last_apply.is_none_or(|t| t.elapsed() >= rate)
+    }
+
+    fn reload(&mut self) -> Result<(), Error> {
+        let mut config = read_config()?;
+        if let Some(q) = quirks::detect() {
+            apply_quirk(q, &mut config);
+        }
+
+        let (updates_battery, updates_ac) = build_base_updates(&config)?;
+
+        println!("config = {:?}", config);
+        self.config = config;
+        self.updates_battery = updates_battery;
+        self.updates_ac = updates_ac;
+        self.battery_worn = check_battery_wear(&self.config);
+        self.reconcile_after_reload();
+        Ok(())
+    }
+
+    /// Checks what's layered over the profiles against a newly loaded configuration. Overrides
+    /// are kept if they still make sense, and the discharge guard's cap is kept unless the guard
+    /// itself was removed, so that a reload can't lift it while the battery is still draining fast.
+    fn reconcile_after_reload(&mut self) {
+        if let Some(o) = self.forced.clone() {
+            let error = match o.profile {
+                rules::Profile::Ac => self.config.profile_error(PowerState::AC { watts: None }).map(str::to_string),
+                rules::Profile::Battery => self.config.profile_error(PowerState::Battery).map(str::to_string),
+                ref p if self.config.lacks_profile(p) => Some("it was removed".to_string()),
+                rules::Profile::Named(_) => None,
+            };
+            match error {
+                Some(e) => {
+                    println!("clearing forced profile {} after reload, since [{}] is now invalid: {}",
+                             o.describe(), o.profile.name(), e);
+                    self.set_override(None);
+                },
+                None => println!("keeping forced profile {} after reload", o.describe()),
+            }
+        }
+
+        if let Some(cap) = self.discharge_cap {
+            if self.config.discharge_guard.is_some() {
+                println!("keeping the discharge guard's PL1 cap of {} W after reload", cap);
+            } else {
+                println!("lifting the discharge guard's PL1 cap of {} W, since discharge_guard was removed", cap);
+                self.discharge_cap = None;
+            }
+        }
+
+        if self.emergency.is_active() && self.config.emergency.is_none() {
+            println!("lifting the emergency constraints, since emergency was removed");
+            self.emergency = emergency::Clamp::default();
+        }
+
+        if let Some((pl1, pl2)) = self.limits {
+            println!("keeping transient limits (PL1 {} W, PL2 {} W) after reload", pl1, pl2);
+        }
+        if self.paused {
+            println!("still paused after reload");
+        }
+    }
+
+    /// Works out the new discharge guard PL1 cap, given the latest discharge rate.
+    fn discharge_cap(&self, rate: f64) -> Option<u64> {
+        let guard = self.config.discharge_guard.as_ref()?;
+
+        // Start stepping down from whatever PL1 would otherwise be.
+        let uncapped = self.base_config().pl1_tdp_w;
+        let current = self.discharge_cap.or(uncapped)?;
+
+        if rate > guard.max_discharge_w {
+            Some(cmp::max(current.saturating_sub(guard.step_w), guard.min_pl1_w))
+        } else if rate < guard.max_discharge_w * 0.8 {
+            // Comfortably below the threshold; step back up until the cap is gone.
+            self.discharge_cap.and_then(|c| {
+                let raised = c + guard.step_w;
+                if uncapped.is_none_or(|u| raised < u) { Some(raised) } else { None }
+            })
+        } else {
+            self.discharge_cap
+        }
+    }
+
+    /// Builds the full configuration for the active profile, including any overlays.
+    fn effective_config(&self) -> ModeConfig {
+        let profile = self.selection.profile;
+        let mut conf = self.base_config().clone();
+
+        // Layer on any constraints for the current battery level.
+        if profile == PowerState::Battery {
+            if let Some(level) = self.battery_level {
+                for threshold in self.config.battery_levels.iter().filter(|t| level < t.below_percent) {
+                    conf = conf.constrain(&threshold.constraints);
+                }
+            }
+        }
+
+        if profile == PowerState::Battery && self.battery_worn {
+            if let Some(ref wear) = self.config.battery_wear {
+                conf = conf.constrain(&wear.constraints);
+            }
+        }
+
+        // Likewise for a weak charger; this only applies when we're actually on AC, not when the AC
+        // profile is forced.
+        if let (PowerState::AC { .. }, PowerState::AC { watts: Some(watts) }) = (profile, self.power_state) {
+            for threshold in self.config.charger_levels.iter().filter(|t| watts < t.below_w) {
+                conf = conf.constrain(&threshold.constraints);
+            }
+        }
+
+        // Travel mode goes over everything the configuration layers on, and once it's switched off,
+        // what it changed is put back unless the profile sets it.
+        if self.travel.is_some() {
+            conf = conf.constrain(&travel::overlay(&self.config));
+        } else if let Some(saved) = self.travel_restore {
+            conf = conf.or(&saved.as_config());
+        }
+
+        if let Some(cap) = self.discharge_cap {
+            conf.pl1_tdp_w = Some(conf.pl1_tdp_w.map_or(cap, |pl1| cmp::min(pl1, cap)));
+        }
+
+        // Only adapt PL2 where the profile sets its window, since it can't be written otherwise.
+        if let Some(ref burst) = self.config.burst_budget {
+            if conf.pl2_duration.is_some() {
+                if let Some(pl2) = self.burst.pl2(burst) {
+                    conf.pl2_tdp_w = Some(pl2);
+                }
+            }
+        }
+
+        // Ask for performance while the load is high, if the profile wants that.
+        if conf.hwp_mode == Some(true) && self.high_load {
+            let performance = HwpConfig { epp: Some(0), min_perf_pct: None, scope: None };
+            conf.hwp = Some(match conf.hwp {
+                Some(ref h) => h.constrain(&performance),
+                None => performance,
+            });
+        }
+
+        // Transient limits override whatever the profile would otherwise set.
+        if let Some((pl1, pl2)) = self.limits {
+            if pl1 != 0 {
+                conf.pl1_tdp_w = Some(pl1);
+            }
+            if pl2 != 0 {
+                conf.pl2_tdp_w = Some(pl2);
+            }
+        }
+
+        // Except for the emergency constraints, which keep the CPU from overheating.
+        if let Some(ref e) = self.config.emergency {
+            if self.emergency.is_active() {
+                conf = conf.constrain(&e.constraints);
+            }
+        }
+
+        conf
+    }
+
+    /// Returns the settings to apply now: the effective configuration, or a step toward it while
+    /// ramping.
+    fn current_config(&self) -> ModeConfig {
+        let conf = self.effective_config();
+        match self.ramp {
+            Some(ref ramp) => ramp.current(&conf),
+            None => conf,
+        }
+    }
+
+    /// Starts ramping to the profile's limits if it's different from the one that was last
+    /// applied. A ramp that's already in progress starts again from wherever it got to.
+    fn start_ramp(&mut self, profile: &str) {
+        let conf = match self.config.ramp {
+            // Each step is another apply, so go straight to the limits while they're slow.
+            Some(_) if self.slow => return,
+            Some(ref c) => c,
+            None => return,
+        };
+        let from = match self.last_applied {
+            Some(ref from) if self.applied.as_ref().is_some_and(|p| p != profile) => from.clone(),
+            _ => return,
+        };
+
+        let target = self.effective_config();
+        let duration = conf.duration(&from, &target);
+        self.ramp = Ramp::start(from, duration);
+        if self.ramp.is_some() {
+            println!("ramping to the {} profile's limits over {:.1}s", profile, duration.as_secs_f64());
+        }
+    }
+
+    /// Applies the next step of the ramp in progress.
+    fn step_ramp(&mut self) {
+        if let Some(ref mut ramp) = self.ramp {
+            ramp.advance();
+        }
+        if self.paused {
+            self.ramp = None;
+            return;
+        }
+        self.apply();
+    }
+
+    /// Returns the updates for the active profile, only rebuilding them if we've changed anything.
+    fn current_updates(&self) -> Result<Vec<Update>, Error> {
+        let profile = self.selection.profile;
+        if self.selection.named.is_none() {
+            if let Some(e) = self.config.profile_error(profile) {
+                bail!("the {} profile is invalid: {}", profile.name(), e);
+            }
+        }
+
+        // Only the base profiles have their updates cached.
+        let conf = self.current_config();
+        let cached = match (&self.selection.named, profile) {
+            (&Some(_), _) => &None,
+            (&None, PowerState::Battery)   => &self.updates_battery,
+            (&None, PowerState::AC { .. }) => &self.updates_ac,
+        };
+        let mut updates = match *cached {
+            Some(ref updates) if conf == *self.base_config() => updates.clone(),
+            _ => build_profile_updates(&self.config, &conf)?,
+        };
+
+        if self.mchbar_fallback {
+            let mirrored: Vec<Update> = updates.iter().filter_map(|u| match *u {
+                Update::Msr(rapl::MSR_PKG_POWER_LIMIT, value) => Some(Update::Mchbar(value & !mchbar::LOCK)),
+                _ => None,
+            }).collect();
+            updates.extend(mirrored);
+        }
+        Ok(updates)
+    }
+
+    /// Checks whether the firmware locked MSR_PKG_POWER_LIMIT while the system was suspended, and
+    /// switches to writing the MCHBAR mirror if it did (or back, if it's since been unlocked). The
+    /// findings are logged as one block, since they're only interesting together.
+    fn check_resume(&mut self, suspended: Duration) {
+        let mut report = vec![];
+
+        let msr_locked = match msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first() {
+            Ok(v) => {
+                let locked = v & rapl::POWER_LIMIT_LOCK != 0;
+                report.push(format!("MSR_PKG_POWER_LIMIT: {}", if locked { "locked by the firmware" } else { "unlocked" }));
+                Some(locked)
+            },
+            Err(e) => {
+                report.push(format!("MSR_PKG_POWER_LIMIT: can't be read ({})", e));
+                None
+            },
+        };
+        let mchbar_unlocked = if quirks::generic() {
+            report.push("MCHBAR power limit: not used in generic mode".to_string());
+            false
+        } else {
+            match mchbar::read_power_limit() {
+                Ok(v) => {
+                    let locked = v & mchbar::LOCK != 0;
+                    report.push(format!("MCHBAR power limit: {}", if locked { "locked" } else { "unlocked" }));
+                    !locked
+                },
+                Err(e) => {
+                    report.push(format!("MCHBAR power limit: can't be read ({})", e));
+                    false
+                },
+            }
+        };
+
+        let fallback = match msr_locked {
+            Some(true) => mchbar_unlocked,
+            Some(false) => false,
+            // Leave things as they were if we can't tell.
+            None => self.mchbar_fallback,
+        };
+        report.push(match (self.mchbar_fallback, fallback) {
+            (false, true) => "writing power limits to MCHBAR from now on".to_string(),
+            (true, false) => "writing power limits to the MSR only again".to_string(),
+            (true, true) => "still writing power limits to MCHBAR".to_string(),
+            (false, false) if msr_locked == Some(true) => "power limits can't be changed until the next reboot".to_string(),
+            (false, false) => "nothing to work around".to_string(),
+        });
+        report.push(format!("reapplying the {} profile", self.selection.name()));
+        self.mchbar_fallback = fallback;
+
+        println!("resumed after {}s suspended:\n  {}", suspended.as_secs(), report.join("\n  "));
+    }
+
+    /// Records how long an apply's writes took, warning when they become slow and noting when
+    /// they recover.
+    fn record_apply_time(&mut self, profile: &str, took: Duration) {
+        self.apply_time.record(took);
+        let slow = took >= SLOW_APPLY;
+        if slow {
+            self.slow_applies += 1;
+            if !self.slow {
+                eprintln!("WARNING: applying the {} profile took {} ms, so the MSR or MCHBAR writes are unusually slow; \
+                           skipping checks for power limit changes, ramps and frequent reapplies until they speed up",
+                          profile, took.as_millis());
+            }
+        } else if self.slow {
+            println!("applying the {} profile took {} ms; writes are back to normal", profile, took.as_millis());
+        }
+        self.slow = slow;
+    }
+
+    /// Checks whether something else has changed MSR_PKG_POWER_LIMIT since we last wrote it, and
+    /// logs what probably did.
+    fn check_power_limit_changed(&mut self) {
+        let current = match msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first() {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        if let Some(change) = self.revert.check(current) {
+            println!("MSR_PKG_POWER_LIMIT was changed from {:#x} to {:#x} since we last wrote it [source: {}]",
+                     change.from, change.to, change.source);
+            self.power_limit_changes += 1;
+            self.power_limit_changed_by = Some(change.source);
+        }
+    }
+
+    /// Re-reads the CPUs that MSRs can be written on, returning whether they've changed since
+    /// the last check.
+    fn check_cpus(&mut self) -> bool {
+        let cpus = topology::online_cpus_or_default();
+        if cpus == self.cpus {
+            return false;
+        }
+        println!("usable CPUs changed from {} to {}", topology::format_cpu_list(&self.cpus),
+                 topology::format_cpu_list(&cpus));
+        self.cpus = cpus;
+        true
+    }
+
+    /// Writes the per-CPU settings of the active profile to a CPU that has just come online; it
+    /// won't have any of the settings that were applied before it went offline.
+    fn apply_cpu(&mut self, cpu: usize) {
+        if self.paused || !self.msr_caps.write {
+            return;
+        }
+
+        let updates = match self.current_updates() {
+            Ok(u) => u,
+            Err(e) => {
+                ratelimit::eprintln(format!("error building updates: {}", e));
+                self.transition(State::Degraded);
+                return;
+            },
+        };
+
+        let failed = updates.iter()
+            .filter_map(|u| u.apply_to_cpu(cpu))
+            .filter(|res| res.is_err())
+            .count();
+        if failed > 0 {
+            self.transition(State::Degraded);
+        }
+    }
+
+    fn apply(&mut self) {
+        self.select_profile();
+
+        if self.paused {
+            self.transition(State::Steady);
+            return;
+        }
+
+        self.transition(State::Applying);
+        self.last_apply = Some(Instant::now());
+        self.check_cpus();
+
+        let profile = self.selection.name().to_string();
+        self.start_ramp(&profile);
+        let updates = match self.current_updates() {
+            Ok(u) => u,
+            Err(e) => {
+                ratelimit::eprintln(format!("error building updates: {}", e));
+                self.transition(State::Degraded);
+                return;
+            },
+        };
+
+        // Write our MSRs and sysfs attributes, all or nothing.
+        let updates: Vec<Update> = updates.into_iter()
+            .filter(|u| !u.is_msr() || self.msr_caps.write)
+            .collect();
+        let writes_power_limit = updates.iter().any(|u| matches!(*u, Update::Msr(rapl::MSR_PKG_POWER_LIMIT, _)));
+        if writes_power_limit && !self.slow {
+            self.check_power_limit_changed();
+        }
+        let started = Instant::now();
+        let (failed, access_failed) = match ApplyPlan::prepare(&updates) {
+            Ok(plan) => match plan.commit() {
+                Ok(()) => (false, false),
+                Err(e) => {
+                    self.signal(control::Signal::WriteFailed(profile.clone(), e.to_string()));
+                    (true, msr::is_access_error(&e))
+                },
+            },
+            Err(failures) => {
+                for (update, e) in failures.iter() {
+                    ratelimit::eprintln(format!("not applying the {} profile, {:?} can't be written: {}", profile, update, e));
+                }
+                let errors: Vec<String> = failures.iter().map(|(u, e)| format!("{:?}: {}", u, e)).collect();
+                self.signal(control::Signal::WriteFailed(profile.clone(), errors.join("; ")));
+                let access = failures.iter().any(|(u, e)| u.is_msr() && msr::is_access_error(e));
+                (true, access)
+            },
+        };
+
+        self.record_apply_time(&profile, started.elapsed());
+        self.applies += 1;
+        if failed {
+            self.apply_failures += 1;
+        }
+
+        if !failed && writes_power_limit {
+            if let Ok(v) = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first() {
+                self.revert.record(v);
+            }
+        }
+
+        if !failed {
+            self.travel_restore = None;
+            self.last_applied = Some(self.current_config());
+            if self.ramp.as_ref().is_some_and(|r| r.is_done()) {
+                println!("reached the {} profile's limits", profile);
+                self.ramp = None;
+            }
+        }
+
+        if !failed && self.applied.as_ref().is_none_or(|p| *p != profile) {
+            self.applied = Some(profile.clone());
+            self.signal(control::Signal::ProfileChanged(profile.clone(), self.selection.reason.clone()));
+        }
+
+        // If MSR access keeps failing, the kernel side has probably changed under us; find out
+        // what still works rather than failing the same way forever.
+        self.msr_access_failures = if access_failed { self.msr_access_failures + 1 } else { 0 };
+        if self.msr_access_failures >= MSR_ACCESS_FAILURE_LIMIT && self.reprobe_msr() {
+            return self.apply();
+        }
+
+        if let Err(e) = runtime::export(&profile) {
+            ratelimit::eprintln(format!("error exporting state to {}: {}", paths::get().runtime, e));
+        }
+
+        self.transition(if failed { State::Degraded } else { State::Steady });
+    }
+}
+
+
+/// Returns transient limits from a client the way `limits` holds them: `None` if both are 0, and
+/// clamped to the machine's safe PL2 like `apply_quirk` clamps the config's.
+fn transient_limits(pl1: u64, pl2: u64) -> Option<(u64, u64)> {
+    if (pl1, pl2) == (0, 0) {
+        return None;
+    }
+    match quirks::detect().and_then(|q| q.max_safe_pl2_w) {
+        Some(max) if pl1 > max || pl2 > max => {
+            eprintln!("clamping transient limits (PL1 {} W, PL2 {} W) to {} W", pl1, pl2, max);
+            Some((cmp::min(pl1, max), cmp::min(pl2, max)))
+        },
+        _ => Some((pl1, pl2)),
+    }
+}
+
+/// Reads the battery's health, and returns whether the battery_wear constraints apply to it.
+fn check_battery_wear(config: &Config) -> bool {
+    let wear = match config.battery_wear {
+        Some(ref w) => w,
+        None => return false,
+    };
+    let health = match power::battery_health() {
+        Ok(Some(h)) => h,
+        Ok(None) => {
+            println!("battery_wear is set, but no battery reports its capacity");
+            return false;
+        },
+        Err(e) => {
+            eprintln!("error reading battery health, battery_wear won't apply: {}", e);
+            return false;
+        },
+    };
+
+    let worn = wear.is_worn(&health);
+    println!("battery health = {:.0}%{}; {}", health.health_pct,
+             health.cycles.map_or(String::new(), |c| format!(" after {} cycles", c)),
+             if worn { "applying the battery_wear constraints on battery" } else { "not worn" });
+    worn
+}
+
+/// Updates for the battery and AC profiles, or `None` for a profile whose updates couldn't be built.
+type BaseUpdates = (Option<Vec<Update>>, Option<Vec<Update>>);
+
+/// Builds the updates for the battery and AC profiles. A profile whose updates can't be built
+/// (e.g. because its section is invalid) is reported and left out, so that the other one can still
+/// be applied; it's only an error if neither can.
+fn build_base_updates(config: &Config) -> Result<BaseUpdates, Error> {
+    let build = |profile: PowerState, conf: &ModeConfig| -> Result<Vec<Update>, Error> {
+        if let Some(e) = config.profile_error(profile) {
+            bail!("{}", e);
+        }
+        build_profile_updates(config, conf)
+    };
+
+    match (build(PowerState::Battery, &config.battery), build(PowerState::AC { watts: None }, &config.ac)) {
+        (Err(e), Err(_)) => Err(e),
+        (battery, ac) => {
+            let report = |name: &str, res: Result<Vec<Update>, Error>| {
+                res.map_err(|e| eprintln!("error building updates for the {} profile, it won't be applied: {}", name, e)).ok()
+            };
+            Ok((report("battery", battery), report("ac", ac)))
+        },
+    }
+}
+
+
+/// Waits for the power source to settle after a change, returning the last change. Anything else
+/// that arrives meanwhile is deferred, so that a burst of power changes only causes one apply.
+fn coalesce_power(first: Event, events: &channel::Receiver<Event>, deferred: &mut VecDeque<Event>, budget: Duration) -> Event {
+    // Noticing the change can take half of the budget; settle within a quarter of it, leaving the
+    // rest to apply it.
+    let settle = cmp::min(POWER_SETTLE, budget / 5);
+    let deadline = Instant::now() + cmp::min(POWER_SETTLE_MAX, budget / 4);
+    let mut last = first;
+    let mut merged = 0;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        match events.recv_timeout(cmp::min(settle, deadline - now)) {
+            Ok(e @ Event::Power(..)) => {
+                last = e;
+                merged += 1;
+            },
+            Ok(Event::Shutdown) => {
+                deferred.push_back(Event::Shutdown);
+                break;
+            },
+            Ok(e) => deferred.push_back(e),
+            // Settled (or every source has gone away, which the main loop will notice).
+            Err(_) => break,
+        }
+    }
+
+    if merged > 0 {
+        println!("coalesced {} power changes", merged + 1);
+    }
+    last
+}
+
+/// Returns how often the timer should tick for the given configuration.
+pub fn timer_period(config: &Config) -> Duration {
+    let secs = [config.battery.update_rate_sec, config.ac.update_rate_sec].iter()
+        .filter_map(|r| r.map(|r| r as u64))
+        .chain(Some(DEFAULT_TIMER_PERIOD_SEC))
+        .min()
+        .unwrap();
+
+    Duration::from_secs(secs)
+}
+
+/// Sends a timer event on the given channel every `period`.
+///
+/// If `idle` is given, ticks are skipped while the system is idle, with the period doubling after
+/// each one up to `max_period_sec` so that an idle system is woken up less and less, and the timer
+/// is allowed to fire late so that the kernel can coalesce its wakeups. This also defers retries
+/// from the degraded state until the system is busy again (or something else changes).
+pub fn spawn_timer(events: channel::Sender<Event>, period: Duration, idle: Option<IdleConfig>) {
+    thread::spawn(move || {
+        if let Some(idle) = idle {
+            match idle_timer(&events, period, &idle) {
+                Ok(_) => return,
+                Err(e) => eprintln!("error setting up idle-aware timer, falling back to sleeping: {}", e),
+            }
+        }
+
+        loop {
+            thread::sleep(period);
+            if events.send(Event::Timer).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Runs an idle-aware timer; this only returns early if it can't be set up.
+fn idle_timer(events: &channel::Sender<Event>, period: Duration, conf: &IdleConfig) -> io::Result<()> {
+    idle::set_timer_slack(Duration::from_millis(conf.timer_slack_ms))?;
+    let timer = idle::Timer::new(period)?;
+    let mut usage = load::CpuUsage::new()?;
+    let max_period = cmp::max(period, Duration::from_secs(conf.max_period_sec));
+    let mut current = period;
+
+    loop {
+        timer.wait()?;
+
+        let idle = match usage.sample() {
+            Ok(busy) => busy * 100.0 < conf.busy_percent,
+            Err(e) => {
+                ratelimit::eprintln(format!("error reading CPU usage: {}", e));
+                false
+            },
+        };
+        let next = if idle { cmp::min(current * 2, max_period) } else { period };
+        if next != current {
+            timer.set_period(next)?;
+            current = next;
+        }
+        if idle {
+            continue;
+        }
+
+        if events.send(Event::Timer).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Forwards everything received on `from` into the event channel, until either side goes away.
+pub fn forward<T: Send + 'static>(
+    from: channel::Receiver<T>,
+    events: channel::Sender<Event>,
+    wrap: fn(T) -> Event,
+) {
+    thread::spawn(move || {
+        for val in from.iter() {
+            if events.send(wrap(val)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Like `forward`, but for a source the daemon can't work without: if it goes away, the daemon
+/// is told to exit, instead of carrying on without it.
+pub fn forward_required<T: Send + 'static>(
+    from: channel::Receiver<T>,
+    events: channel::Sender<Event>,
+    wrap: fn(T) -> Event,
+    name: &'static str,
+) {
+    thread::spawn(move || {
+        for val in from.iter() {
+            if events.send(wrap(val)).is_err() {
+                return;
+            }
+        }
+        let _ = events.send(Event::SourceLost(name));
+    });
+}
+
+/// Converts SIGHUP, SIGINT and SIGTERM into events.
+///
+/// This must be called before any other threads are started, since it blocks these signals in the
+/// calling thread and relies on every other thread inheriting that mask.
+pub fn handle_signals(events: channel::Sender<Event>) {
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        set
+    };
+
+    thread::spawn(move || {
+        loop {
+            let mut sig = 0;
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                continue;
+            }
+
+            let event = if sig == libc::SIGHUP { Event::Reload } else { Event::Shutdown };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    });
+}
@@ -0,0 +1,194 @@
+//! `--dump-msrs`: a machine profile to attach to bug reports.
+//!
+//! Only what's needed to reproduce power limit problems is included: the CPU and model, the
+//! firmware versions (whether the power limits get locked or reverted depends on them), the raw
+//! values of the relevant MSRs and the powercap (intel_rapl) settings. Nothing that identifies the
+//! machine, like serial numbers or UUIDs, is read.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::process::Command;
+
+use msr;
+use paths;
+use quirks;
+use rapl;
+use sysfs;
+use temps;
+use turbo;
+
+
+/// MSRs to dump.
+const MSRS: &[u64] = &[
+    IA32_BIOS_SIGN_ID,
+    turbo::MSR_PLATFORM_INFO,
+    turbo::MSR_FLEX_RATIO,
+    temps::MSR_TEMPERATURE_TARGET,
+    turbo::MSR_TURBO_RATIO_LIMIT,
+    rapl::MSR_RAPL_POWER_UNIT,
+    rapl::MSR_PKG_POWER_LIMIT,
+    rapl::MSR_PKG_POWER_INFO,
+    turbo::MSR_CONFIG_TDP_CONTROL,
+];
+
+/// IA32_BIOS_SIGN_ID, which holds the loaded microcode revision in its upper 32 bits.
+pub const IA32_BIOS_SIGN_ID: u64 = 0x8B;
+
+/// DMI attributes describing the firmware, and what they are.
+const DMI_FIRMWARE: &[(&str, &str)] = &[
+    ("/sys/class/dmi/id/bios_version", "bios version"),
+    ("/sys/class/dmi/id/bios_date", "bios date"),
+    ("/sys/class/dmi/id/bios_release", "bios release"),
+    // The embedded controller's firmware; thinkpad_acpi only logs its own copy of this.
+    ("/sys/class/dmi/id/ec_firmware_release", "ec firmware"),
+];
+
+/// Fields of `/proc/cpuinfo` to include, for the first CPU.
+const CPUINFO_FIELDS: &[&str] = &["vendor_id", "cpu family", "model", "model name", "stepping", "microcode"];
+
+/// Powercap attributes that change constantly and don't help with bug reports.
+const POWERCAP_SKIPPED: &[&str] = &["energy_uj", "uevent"];
+
+
+/// Prints the machine profile as a single block that can be pasted into a GitHub issue.
+pub fn run() {
+    println!("```");
+    println!("lenovo-throttling-rust {}", env!("CARGO_PKG_VERSION"));
+    if let Ok(release) = sysfs::read_value("/proc/sys/kernel/osrelease") {
+        println!("kernel: {}", release);
+    }
+    for product in quirks::dmi_products() {
+        println!("dmi product: {}", product);
+    }
+    dump_firmware();
+
+    println!();
+    for (key, value) in cpuinfo() {
+        println!("{}: {}", key, value);
+    }
+
+    println!();
+    for &addr in MSRS.iter() {
+        let name = msr::Name(addr).to_string();
+        match msr::ReadMsrBuilder::new(addr).read_first() {
+            Ok(v) => println!("{:#05x} {:<24} {:#018x}", addr, name, v),
+            Err(e) => println!("{:#05x} {:<24} unreadable ({})", addr, name, e),
+        }
+    }
+
+    println!();
+    dump_powercap();
+
+    println!();
+    dump_hwmon();
+    println!("```");
+}
+
+/// Prints the BIOS, EC and microcode versions.
+fn dump_firmware() {
+    for &(path, name) in DMI_FIRMWARE.iter() {
+        if let Ok(v) = sysfs::read_value(path) {
+            println!("{}: {}", name, v);
+        }
+    }
+    // Older kernels only have the embedded controller version in the log.
+    if sysfs::read_value("/sys/class/dmi/id/ec_firmware_release").is_err() {
+        if let Some(line) = thinkpad_ec_log() {
+            println!("ec firmware: {}", line);
+        }
+    }
+    match msr::ReadMsrBuilder::new(IA32_BIOS_SIGN_ID).read_first() {
+        Ok(v) => println!("microcode revision: {:#x}", v >> 32),
+        Err(e) => println!("microcode revision: unreadable ({})", e),
+    }
+}
+
+/// Returns the embedded controller firmware version that thinkpad_acpi logged when it loaded
+/// ("ThinkPad BIOS N2HET77W (1.60 ), EC N2HHT29W"), if the kernel log can be read.
+fn thinkpad_ec_log() -> Option<String> {
+    let output = Command::new("dmesg").output().ok()?;
+    String::from_utf8_lossy(&output.stdout).lines().rev()
+        .find(|l| l.contains("thinkpad_acpi") && l.contains("ThinkPad BIOS"))
+        .and_then(|l| l.rsplit_once(", EC "))
+        .map(|(_, version)| version.trim().to_string())
+}
+
+/// Returns the interesting fields of `/proc/cpuinfo` for the first CPU.
+fn cpuinfo() -> Vec<(String, String)> {
+    let f = match File::open("/proc/cpuinfo") {
+        Ok(f) => f,
+        Err(_) => return vec![],
+    };
+
+    BufReader::new(f).lines()
+        .map_while(Result::ok)
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| {
+            let (key, value) = l.split_once(':')?;
+            let key = key.trim();
+            if CPUINFO_FIELDS.contains(&key) {
+                Some((key.to_string(), value.trim().to_string()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Prints the attributes of each intel_rapl powercap zone.
+fn dump_powercap() {
+    let mut zones: Vec<_> = match fs::read_dir(&paths::get().powercap) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("intel-rapl"))
+            .collect(),
+        Err(e) => {
+            println!("powercap: unavailable ({})", e);
+            return;
+        },
+    };
+    zones.sort();
+
+    if zones.is_empty() {
+        println!("powercap: no intel-rapl zones");
+    }
+    for zone in zones {
+        let dir = format!("{}/{}", paths::get().powercap, zone);
+        let mut attrs: Vec<_> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok())
+                .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .filter(|name| !POWERCAP_SKIPPED.contains(&name.as_str()))
+                .collect(),
+            Err(_) => continue,
+        };
+        attrs.sort();
+
+        for attr in attrs {
+            match sysfs::read_value(&format!("{}/{}", dir, attr)) {
+                Ok(v) => println!("{}/{} = {}", zone, attr, v),
+                Err(e) => println!("{}/{} unreadable ({})", zone, attr, e),
+            }
+        }
+    }
+}
+
+/// Prints the name of each hardware monitoring chip, which shows which sensor drivers are loaded.
+fn dump_hwmon() {
+    let root = &paths::get().hwmon;
+    let mut chips: Vec<_> = match fs::read_dir(root) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(e) => {
+            println!("hwmon: unavailable ({})", e);
+            return;
+        },
+    };
+    chips.sort();
+
+    for chip in chips {
+        let name = sysfs::read_value(&format!("{}/{}/name", root, chip)).unwrap_or_else(|_| "?".to_string());
+        println!("{} = {}", chip, name);
+    }
+}
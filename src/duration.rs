@@ -0,0 +1,227 @@
+//! Durations in the configuration file, which can be given either as a plain number in the
+//! setting's own unit (e.g. `pl1_duration = 28`, in seconds) or as a string with units, like
+//! `"28s"`, `"2.44ms"` or `"1m 30s"`, and shown to users in the same notation.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::de::{self, Deserializer, Visitor};
+
+
+/// Unit suffixes, and how many seconds each one is.
+const UNITS: &[(&[&str], f64)] = &[
+    (&["ns", "nsec"], 1e-9),
+    (&["us", "usec", "µs"], 1e-6),
+    (&["ms", "msec"], 1e-3),
+    (&["s", "sec", "secs", "second", "seconds"], 1.0),
+    (&["m", "min", "mins", "minute", "minutes"], 60.0),
+    (&["h", "hr", "hrs", "hour", "hours"], 3600.0),
+    (&["d", "day", "days"], 86400.0),
+];
+
+
+/// Parses a duration with units, such as "2.44ms" or "1h 30m", into seconds.
+pub fn parse(s: &str) -> Result<f64, String> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("empty duration".to_string());
+    }
+
+    let mut total = 0.0;
+    while !rest.is_empty() {
+        let number_len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse()
+            .map_err(|_| format!("expected a number at {:?}", rest))?;
+        rest = rest[number_len..].trim_start();
+
+        let unit_len = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        let scale = match UNITS.iter().find(|&&(names, _)| names.contains(&unit)) {
+            Some(&(_, scale)) => scale,
+            None if unit.is_empty() => return Err(format!("missing unit after {}", number)),
+            None => return Err(format!("unknown unit {:?}", unit)),
+        };
+        total += number * scale;
+        rest = rest[unit_len..].trim_start();
+    }
+
+    if !total.is_finite() {
+        return Err("duration is too long".to_string());
+    }
+    Ok(total)
+}
+
+/// Formats a duration in seconds in the unit that suits it, e.g. "976.563µs", "2.441ms" or "28s",
+/// in the notation that `parse` reads. PL2's time window is usually a few milliseconds, which is
+/// hard to make sense of as a fraction of a second.
+///
+/// The value is rounded down to three decimal places, since time windows are rounded up to the
+/// next one the hardware can express when they're written: reading the shown value back gives the
+/// same window.
+pub fn format(secs: f64) -> String {
+    let (value, unit) = if secs > 0.0 && secs < 1e-3 {
+        (secs * 1e6, "µs")
+    } else if secs > 0.0 && secs < 1.0 {
+        (secs * 1e3, "ms")
+    } else {
+        (secs, "s")
+    };
+    // The small margin keeps e.g. 28 from showing as 27.999 when it isn't exact in binary.
+    let value = format!("{:.3}", (value * 1e3 + 1e-6).floor() / 1e3);
+    format!("{}{}", value.trim_end_matches('0').trim_end_matches('.'), unit)
+}
+
+
+/// Deserializes a duration as a number of `unit`s, where `unit` is in seconds.
+struct DurationVisitor {
+    unit: f64,
+}
+
+impl<'de> Visitor<'de> for DurationVisitor {
+    type Value = f64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a non-negative number, or a duration with units such as \"28s\" or \"2.44ms\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<f64, E> {
+        Ok(v as f64)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<f64, E> {
+        if v < 0 {
+            return Err(E::custom(format!("duration {} is negative", v)));
+        }
+        Ok(v as f64)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<f64, E> {
+        if v < 0.0 || !v.is_finite() {
+            return Err(E::custom(format!("invalid duration {}", v)));
+        }
+        Ok(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<f64, E> {
+        // A bare number in a string is in the setting's unit, like one outside of a string.
+        if let Ok(n) = v.trim().parse::<f64>() {
+            return self.visit_f64(n);
+        }
+        parse(v)
+            .map(|secs| secs / self.unit)
+            .map_err(|e| E::custom(format!("invalid duration {:?}: {}", v, e)))
+    }
+}
+
+/// Converts a duration to a whole number of units, rejecting fractions.
+fn whole<T: TryFrom<u64>, E: de::Error>(v: f64, unit: &str) -> Result<T, E> {
+    // Allow for rounding errors in the unit conversion, e.g. "0.1s" as milliseconds.
+    let rounded = v.round();
+    if (v - rounded).abs() > 1e-6 {
+        return Err(E::custom(format!("duration must be a whole number of {}, not {}", unit, v)));
+    }
+    // Casting would saturate rather than fail.
+    if rounded >= u64::MAX as f64 {
+        return Err(E::custom(format!("duration of {} {} is too long", rounded, unit)));
+    }
+    T::try_from(rounded as u64).map_err(|_| E::custom(format!("duration of {} {} is too long", rounded, unit)))
+}
+
+/// Deserializes a duration in (possibly fractional) seconds.
+pub fn secs_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    deserializer.deserialize_any(DurationVisitor { unit: 1.0 })
+}
+
+/// Like `secs_f64`, for optional settings; these also need `#[serde(default)]`.
+pub fn opt_secs_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    secs_f64(deserializer).map(Some)
+}
+
+/// Deserializes a duration in whole seconds.
+pub fn secs<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    whole(deserializer.deserialize_any(DurationVisitor { unit: 1.0 })?, "seconds")
+}
+
+/// Like `secs`, for optional settings; these also need `#[serde(default)]`.
+pub fn opt_secs<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    secs(deserializer).map(Some)
+}
+
+/// Deserializes a duration in whole milliseconds.
+pub fn millis<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    whole(deserializer.deserialize_any(DurationVisitor { unit: 1e-3 })?, "milliseconds")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    #[test]
+    fn durations_parse() {
+        let cases: &[(&str, Result<f64, &str>)] = &[
+            ("28s", Ok(28.0)),
+            ("2.44ms", Ok(0.00244)),
+            ("1m 30s", Ok(90.0)),
+            ("1m30s", Ok(90.0)),
+            (" 1 hour 2 min ", Ok(3720.0)),
+            ("500us", Ok(0.0005)),
+            ("500µs", Ok(0.0005)),
+            ("1d", Ok(86400.0)),
+            ("", Err("empty duration")),
+            ("28", Err("missing unit after 28")),
+            ("28 fortnights", Err("unknown unit \"fortnights\"")),
+            ("28S", Err("unknown unit \"S\"")),
+            ("s", Err("expected a number at \"s\"")),
+            ("-5s", Err("expected a number at \"-5s\"")),
+            ("1.2.3s", Err("expected a number at \"1.2.3s\"")),
+        ];
+        for &(s, ref expected) in cases.iter() {
+            match (parse(s), expected) {
+                (Ok(secs), &Ok(e)) => assert!((secs - e).abs() < 1e-12, "{:?}: {} != {}", s, secs, e),
+                (Err(ref err), &Err(e)) => assert_eq!(err, e, "{:?}", s),
+                (res, _) => panic!("{:?}: got {:?}, expected {:?}", s, res, expected),
+            }
+        }
+        assert!(parse(&format!("{}d", "9".repeat(400))).is_err());
+    }
+
+    #[test]
+    fn durations_format_in_a_suitable_unit() {
+        let cases = [
+            (28.0, "28s"),
+            (0.0, "0s"),
+            (90.0, "90s"),
+            (2.5 / 1024.0, "2.441ms"),
+            (1.0 / 1024.0, "976.562µs"),
+            (0.5, "500ms"),
+        ];
+        for &(secs, expected) in cases.iter() {
+            assert_eq!(format(secs), expected, "{}", secs);
+            assert!(parse(expected).unwrap() <= secs + 1e-12, "{}", expected);
+        }
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Settings {
+        #[serde(default, deserialize_with = "opt_secs")]
+        interval_sec: Option<u64>,
+        #[serde(default, deserialize_with = "opt_secs_f64")]
+        window: Option<f64>,
+    }
+
+    #[test]
+    fn settings_take_numbers_in_their_own_unit_or_durations() {
+        let parse = |s: &str| toml::from_str::<Settings>(s).map(|s| (s.interval_sec, s.window)).map_err(|e| e.to_string());
+        assert_eq!(parse("interval_sec = 30"), Ok((Some(30), None)));
+        assert_eq!(parse("interval_sec = \"1m 30s\""), Ok((Some(90), None)));
+        assert_eq!(parse("interval_sec = \"30\""), Ok((Some(30), None)));
+        assert_eq!(parse("window = \"2ms\""), Ok((None, Some(0.002))));
+        assert_eq!(parse("window = 0.5"), Ok((None, Some(0.5))));
+        assert!(parse("interval_sec = \"1.5s\"").is_err());
+        assert!(parse("interval_sec = -1").is_err());
+        assert!(parse("interval_sec = \"1000000000000000d\"").is_err());
+        assert!(parse("window = \"inf\"").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Emergency clamps: extra constraints layered over the active profile while the CPU is too hot.
+//!
+//! The clamp has separate enter and exit temperatures, and the temperature has to stay past each
+//! for a while before the clamp changes, so that a reading that hovers around one threshold (or a
+//! single hot sample) doesn't switch the limits back and forth.
+
+use std::time::{Duration, Instant};
+
+use EmergencyConfig;
+
+
+/// The state of the emergency clamp.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Clamp {
+    /// Whether the clamp is applied.
+    active: bool,
+    /// When the temperature crossed the threshold for changing state, while it's stayed past it.
+    crossed: Option<Instant>,
+}
+
+impl Clamp {
+    /// Records a new temperature sample taken at `now`, returning whether the clamp was applied
+    /// or lifted.
+    pub fn record(&mut self, conf: &EmergencyConfig, temp_c: u64, now: Instant) -> bool {
+        let (past, dwell) = if self.active {
+            (temp_c <= conf.exit_temp_c, conf.exit_sec)
+        } else {
+            (temp_c >= conf.enter_temp_c, conf.enter_sec)
+        };
+        if !past {
+            self.crossed = None;
+            return false;
+        }
+
+        let crossed = *self.crossed.get_or_insert(now);
+        if now.duration_since(crossed) < Duration::from_secs_f64(dwell) {
+            return false;
+        }
+        self.active = !self.active;
+        self.crossed = None;
+        true
+    }
+
+    /// Returns whether the clamp is applied.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ModeConfig;
+
+    #[test]
+    fn clamp_switches_only_after_dwelling_past_each_threshold() {
+        let conf = EmergencyConfig {
+            enter_temp_c: 90,
+            enter_sec: 3.0,
+            exit_temp_c: 80,
+            exit_sec: 30.0,
+            interval_sec: 1.0,
+            constraints: ModeConfig::default(),
+        };
+        // (seconds since the start, temperature, whether the clamp changes, active afterwards)
+        let samples = [
+            (0, 85, false, false),
+            (1, 95, false, false),
+            // A dip below enter_temp_c starts the dwell over.
+            (2, 89, false, false),
+            (3, 90, false, false),
+            (5, 99, false, false),
+            (6, 92, true, true),
+            // Between the thresholds, nothing changes.
+            (7, 85, false, true),
+            (40, 81, false, true),
+            (41, 80, false, true),
+            (60, 70, false, true),
+            // Going back up resets the exit dwell.
+            (70, 85, false, true),
+            (71, 79, false, true),
+            (100, 79, false, true),
+            (101, 75, true, false),
+            (102, 85, false, false),
+        ];
+
+        let start = Instant::now();
+        let mut clamp = Clamp::default();
+        for &(secs, temp_c, changed, active) in samples.iter() {
+            let now = start + Duration::from_secs(secs);
+            assert_eq!(clamp.record(&conf, temp_c, now), changed, "{} C at {}s", temp_c, secs);
+            assert_eq!(clamp.is_active(), active, "{} C at {}s", temp_c, secs);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Energy used while each profile is applied, as measured by the package energy counter, so that
+//! users can see what e.g. the battery profile actually saves.
+//!
+//! Each sample is counted against the profile that's applied when it arrives, so a sample that
+//! spans a profile change is counted against the new profile; at the default interval that's a
+//! few seconds' worth at most.
+
+use std::time::Duration;
+
+
+/// Energy used while a profile was applied.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    /// Energy used, in Joules.
+    pub joules: f64,
+    /// Time the energy was measured over.
+    pub time: Duration,
+    /// How many separate times the profile was applied.
+    pub sessions: u32,
+}
+
+impl Usage {
+    /// Returns the energy used, in Watt-hours.
+    pub fn watt_hours(&self) -> f64 {
+        self.joules / 3600.0
+    }
+
+    fn add(&mut self, joules: f64, time: Duration) {
+        self.joules += joules;
+        self.time += time;
+    }
+}
+
+/// Energy used by each profile since the daemon started.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Ledger {
+    /// Totals by profile name, in the order the profiles were first applied.
+    totals: Vec<(String, Usage)>,
+    /// The profile that the last sample was counted against, and what it's used since.
+    session: Option<(String, Usage)>,
+}
+
+impl Ledger {
+    /// Records `joules` used over `time` while `profile` was applied. A new session starts
+    /// whenever the profile differs from the previous sample's.
+    pub fn record(&mut self, profile: &str, joules: f64, time: Duration) {
+        let new_session = self.session.as_ref().is_none_or(|(p, _)| p != profile);
+
+        if new_session {
+            self.session = Some((profile.to_string(), Usage { sessions: 1, ..Usage::default() }));
+        }
+        if let Some((_, ref mut usage)) = self.session {
+            usage.add(joules, time);
+        }
+
+        let i = match self.totals.iter().position(|(p, _)| p == profile) {
+            Some(i) => i,
+            None => {
+                self.totals.push((profile.to_string(), Usage::default()));
+                self.totals.len() - 1
+            },
+        };
+        let total = &mut self.totals[i].1;
+        total.add(joules, time);
+        if new_session {
+            total.sessions += 1;
+        }
+    }
+
+    /// Returns the energy used by each profile that's been applied, with its name.
+    pub fn totals(&self) -> &[(String, Usage)] {
+        &self.totals
+    }
+
+    /// Returns the profile that's applied and the energy used since it was.
+    pub fn session(&self) -> Option<(&str, Usage)> {
+        self.session.as_ref().map(|&(ref p, usage)| (p.as_str(), usage))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_start_when_the_profile_changes() {
+        let secs = Duration::from_secs;
+        let mut ledger = Ledger::default();
+        ledger.record("battery", 50.0, secs(10));
+        ledger.record("battery", 30.0, secs(10));
+        ledger.record("ac", 200.0, secs(10));
+        ledger.record("battery", 40.0, secs(10));
+
+        assert_eq!(ledger.totals(), &[
+            ("battery".to_string(), Usage { joules: 120.0, time: secs(30), sessions: 2 }),
+            ("ac".to_string(), Usage { joules: 200.0, time: secs(10), sessions: 1 }),
+        ]);
+        assert_eq!(ledger.session(), Some(("battery", Usage { joules: 40.0, time: secs(10), sessions: 1 })));
+    }
+}
@@ -0,0 +1,53 @@
+//! Process exit codes, so that service managers and scripts can tell failures apart.
+//!
+//! For example, a systemd unit can use `Restart=on-failure` together with
+//! `RestartPreventExitStatus=3 4 5` to keep retrying while D-Bus comes up, but not when the
+//! configuration is broken or the machine can't be supported.
+
+use std::process;
+
+
+/// Why the process exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Everything went fine, or the daemon was asked to exit.
+    Success,
+    /// Some other failure.
+    Failure,
+    /// Unknown command or invalid arguments.
+    Usage,
+    /// The configuration couldn't be read or is invalid.
+    Config,
+    /// The MSR devices can't be accessed.
+    NoMsrAccess,
+    /// The CPU isn't one that we know how to control.
+    UnsupportedCpu,
+    /// The system bus couldn't be reached, but the D-Bus interface is enabled.
+    DbusUnavailable,
+    /// The daemon stopped while settings were failing to apply.
+    WriteFailure,
+    /// Another tool that rewrites the same registers is running.
+    Conflict,
+}
+
+impl ExitCode {
+    /// Returns the numeric exit status.
+    pub fn code(&self) -> i32 {
+        match *self {
+            ExitCode::Success         => 0,
+            ExitCode::Failure         => 1,
+            ExitCode::Usage           => 2,
+            ExitCode::Config          => 3,
+            ExitCode::NoMsrAccess     => 4,
+            ExitCode::UnsupportedCpu  => 5,
+            ExitCode::DbusUnavailable => 6,
+            ExitCode::WriteFailure    => 7,
+            ExitCode::Conflict        => 8,
+        }
+    }
+
+    /// Exits the process with this code.
+    pub fn exit(self) -> ! {
+        process::exit(self.code())
+    }
+}
@@ -0,0 +1,300 @@
+//! The `explain` subcommand, which breaks a raw MSR value (e.g. as printed by `rdmsr`) down into
+//! its fields, for bug reports and for learning what the registers do.
+
+use failure::Error;
+
+use duration;
+use freq;
+use hwp;
+use msr;
+use rapl;
+use temps;
+use turbo;
+
+/// Bus clock that ratios are multiplied by, in MHz.
+const BCLK_MHZ: u64 = 100;
+
+
+/// How to show a field's value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decode {
+    /// As a number.
+    Raw,
+    /// As yes or no.
+    Flag,
+    /// In RAPL power units.
+    Watts,
+    /// As a RAPL time window.
+    Window,
+    /// In degrees Celsius.
+    Celsius,
+    /// As a multiple of the bus clock.
+    Ratio,
+    /// As one of the exponents in MSR_RAPL_POWER_UNIT.
+    PowerUnit,
+    EnergyUnit,
+    TimeUnit,
+}
+
+/// A field of a register, as (high bit, low bit, name, how to show it).
+type Field = (u32, u32, &'static str, Decode);
+
+/// A register that we know the layout of.
+struct Register {
+    addr: u64,
+    fields: &'static [Field],
+    /// Prints anything that needs more than one field to work out.
+    summary: Option<fn(u64, &rapl::Units)>,
+}
+
+const REGISTERS: &[Register] = &[
+    Register {
+        addr: turbo::MSR_PLATFORM_INFO,
+        fields: &[
+            (15, 8, "maximum non-turbo ratio", Decode::Ratio),
+            (28, 28, "turbo ratio limits programmable", Decode::Flag),
+            (29, 29, "TDP limits programmable", Decode::Flag),
+            (47, 40, "maximum efficiency ratio", Decode::Ratio),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: freq::MSR_MPERF,
+        fields: &[(63, 0, "cycles at the base frequency", Decode::Raw)],
+        summary: None,
+    },
+    Register {
+        addr: freq::MSR_APERF,
+        fields: &[(63, 0, "actual cycles", Decode::Raw)],
+        summary: None,
+    },
+    Register {
+        addr: turbo::MSR_FLEX_RATIO,
+        fields: &[
+            (15, 8, "flex ratio", Decode::Ratio),
+            (16, 16, "flex ratio enabled", Decode::Flag),
+            (20, 20, "overclocking locked", Decode::Flag),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: temps::MSR_THERM_STATUS,
+        fields: &[
+            (0, 0, "thermal throttling", Decode::Flag),
+            (1, 1, "thermal throttling (logged)", Decode::Flag),
+            (2, 2, "PROCHOT# asserted", Decode::Flag),
+            (3, 3, "PROCHOT# asserted (logged)", Decode::Flag),
+            (4, 4, "critical temperature", Decode::Flag),
+            (5, 5, "critical temperature (logged)", Decode::Flag),
+            (10, 10, "power limit throttling", Decode::Flag),
+            (11, 11, "power limit throttling (logged)", Decode::Flag),
+            (22, 16, "degrees below TjMax", Decode::Celsius),
+            (31, 31, "reading valid", Decode::Flag),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: turbo::IA32_MISC_ENABLE,
+        fields: &[
+            (16, 16, "Enhanced SpeedStep enabled", Decode::Flag),
+            (38, 38, "turbo mode disabled", Decode::Flag),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: temps::MSR_TEMPERATURE_TARGET,
+        fields: &[
+            (23, 16, "critical temperature (TjMax)", Decode::Celsius),
+            (29, 24, "throttle offset below TjMax", Decode::Celsius),
+        ],
+        summary: Some(summarize_temperature_target),
+    },
+    Register {
+        addr: turbo::MSR_TURBO_RATIO_LIMIT,
+        fields: &[
+            (7, 0, "1 core active", Decode::Ratio),
+            (15, 8, "2 cores active", Decode::Ratio),
+            (23, 16, "3 cores active", Decode::Ratio),
+            (31, 24, "4 cores active", Decode::Ratio),
+            (39, 32, "5 cores active", Decode::Ratio),
+            (47, 40, "6 cores active", Decode::Ratio),
+            (55, 48, "7 cores active", Decode::Ratio),
+            (63, 56, "8 cores active", Decode::Ratio),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: temps::MSR_PACKAGE_THERM_STATUS,
+        fields: &[
+            (0, 0, "thermal throttling", Decode::Flag),
+            (1, 1, "thermal throttling (logged)", Decode::Flag),
+            (2, 2, "PROCHOT# asserted", Decode::Flag),
+            (3, 3, "PROCHOT# asserted (logged)", Decode::Flag),
+            (4, 4, "critical temperature", Decode::Flag),
+            (5, 5, "critical temperature (logged)", Decode::Flag),
+            (10, 10, "power limit throttling", Decode::Flag),
+            (11, 11, "power limit throttling (logged)", Decode::Flag),
+            (22, 16, "degrees below TjMax", Decode::Celsius),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: rapl::MSR_RAPL_POWER_UNIT,
+        fields: &[
+            (3, 0, "power units", Decode::PowerUnit),
+            (12, 8, "energy status units", Decode::EnergyUnit),
+            (19, 16, "time units", Decode::TimeUnit),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: rapl::MSR_PKG_POWER_LIMIT,
+        fields: &[
+            (14, 0, "PL1 power limit", Decode::Watts),
+            (15, 15, "PL1 enabled", Decode::Flag),
+            (16, 16, "PL1 clamping", Decode::Flag),
+            (23, 17, "PL1 time window", Decode::Window),
+            (46, 32, "PL2 power limit", Decode::Watts),
+            (47, 47, "PL2 enabled", Decode::Flag),
+            (48, 48, "PL2 clamping", Decode::Flag),
+            (55, 49, "PL2 time window", Decode::Window),
+            (63, 63, "locked until reset", Decode::Flag),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: rapl::MSR_PKG_POWER_INFO,
+        fields: &[
+            (14, 0, "thermal design power", Decode::Watts),
+            (30, 16, "minimum power", Decode::Watts),
+            (46, 32, "maximum power", Decode::Watts),
+            (53, 48, "maximum time window", Decode::Window),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: rapl::MSR_PP1_POWER_LIMIT,
+        fields: &[
+            (14, 0, "GPU power limit", Decode::Watts),
+            (15, 15, "enabled", Decode::Flag),
+            (16, 16, "clamping", Decode::Flag),
+            (23, 17, "time window", Decode::Window),
+            (31, 31, "locked until reset", Decode::Flag),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: hwp::MSR_PM_ENABLE,
+        fields: &[(0, 0, "HWP enabled", Decode::Flag)],
+        summary: None,
+    },
+    Register {
+        addr: hwp::MSR_HWP_REQUEST_PKG,
+        fields: &[
+            (7, 0, "minimum performance", Decode::Raw),
+            (15, 8, "maximum performance", Decode::Raw),
+            (23, 16, "desired performance (0 = automatic)", Decode::Raw),
+            (31, 24, "energy/performance preference", Decode::Raw),
+            (41, 32, "activity window", Decode::Raw),
+        ],
+        summary: None,
+    },
+    Register {
+        addr: hwp::MSR_HWP_REQUEST,
+        fields: &[
+            (7, 0, "minimum performance", Decode::Raw),
+            (15, 8, "maximum performance", Decode::Raw),
+            (23, 16, "desired performance (0 = automatic)", Decode::Raw),
+            (31, 24, "energy/performance preference", Decode::Raw),
+            (41, 32, "activity window", Decode::Raw),
+            (42, 42, "follow the package request", Decode::Flag),
+            (59, 59, "activity window valid", Decode::Flag),
+            (60, 60, "EPP valid", Decode::Flag),
+            (61, 61, "desired performance valid", Decode::Flag),
+            (62, 62, "maximum performance valid", Decode::Flag),
+            (63, 63, "minimum performance valid", Decode::Flag),
+        ],
+        summary: None,
+    },
+];
+
+
+/// Parses a hex number, with or without a leading "0x" (rdmsr prints them without).
+fn parse_hex(s: &str) -> Result<u64, Error> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u64::from_str_radix(digits, 16).map_err(|_| format_err!("not a hex number: {}", s))
+}
+
+/// Prints the fields of each register given in `args`: an address, a value, and optionally
+/// `--units <MSR_RAPL_POWER_UNIT>` to decode power limits with another machine's units.
+pub fn run<I: Iterator<Item = String>>(mut args: I) -> Result<(), Error> {
+    const USAGE: &str = "usage: explain <msr> <value> [--units <MSR_RAPL_POWER_UNIT value>]";
+
+    let addr = parse_hex(&args.next().ok_or_else(|| format_err!("{}", USAGE))?)?;
+    let value = parse_hex(&args.next().ok_or_else(|| format_err!("{}", USAGE))?)?;
+    let units = match (args.next().as_deref(), args.next()) {
+        (Some("--units"), Some(raw)) => Some(parse_hex(&raw)?),
+        (None, _) => None,
+        _ => bail!("{}", USAGE),
+    };
+
+    let register = match REGISTERS.iter().find(|r| r.addr == addr) {
+        Some(r) => r,
+        None => {
+            let known: Vec<String> = REGISTERS.iter().map(|r| format!("{:#x} ({})", r.addr, msr::Name(r.addr))).collect();
+            bail!("don't know the layout of MSR {:#x}; known registers are:\n  {}", addr, known.join("\n  "));
+        },
+    };
+
+    println!("{} ({:#x}) = {:#018x}", msr::Name(addr), addr, value);
+
+    // Power and time fields need the RAPL units, which differ between CPUs.
+    let needs_units = register.fields.iter().any(|f| f.3 == Decode::Watts || f.3 == Decode::Window);
+    let units = match units {
+        Some(raw) => rapl::Units::from_raw(raw, rapl::Encoding::detect()),
+        None => match rapl::Units::read() {
+            Ok(u) => u,
+            Err(_) => {
+                if needs_units {
+                    println!("  (this CPU's RAPL units can't be read; assuming {:#x}, pass --units to override)",
+                             rapl::DEFAULT_POWER_UNIT);
+                }
+                rapl::Units::from_raw(rapl::DEFAULT_POWER_UNIT, rapl::Encoding::detect())
+            },
+        },
+    };
+    if needs_units {
+        println!("  (using {} W and {} s RAPL units)", units.power, units.time);
+    }
+
+    for &(hi, lo, name, decode) in register.fields.iter() {
+        let width = hi - lo + 1;
+        let field = if width == 64 { value } else { (value >> lo) & ((1 << width) - 1) };
+        let bits = if hi == lo { format!("{}", hi) } else { format!("{}:{}", hi, lo) };
+        println!("  {:>5}  {:<38} = {:<#8x} {}", bits, name, field, describe(field, decode, &units));
+    }
+
+    if let Some(summary) = register.summary {
+        summary(value, &units);
+    }
+    Ok(())
+}
+
+fn describe(field: u64, decode: Decode, units: &rapl::Units) -> String {
+    match decode {
+        Decode::Raw => field.to_string(),
+        Decode::Flag => (if field != 0 { "yes" } else { "no" }).to_string(),
+        Decode::Watts => format!("{} W", field as f64 * units.power),
+        Decode::Window => duration::format(units.decode_window(field)),
+        Decode::Celsius => format!("{} C", field),
+        Decode::Ratio => format!("{}x ({} MHz)", field, field * BCLK_MHZ),
+        Decode::PowerUnit => format!("1/{} W", 1u64 << field),
+        Decode::EnergyUnit => format!("1/{} J", 1u64 << field),
+        Decode::TimeUnit => format!("1/{} s", 1u64 << field),
+    }
+}
+
+fn summarize_temperature_target(value: u64, _: &rapl::Units) {
+    let target = temps::TemperatureTarget::from_raw(value);
+    println!("  throttles at {} C", target.throttle_temp());
+}
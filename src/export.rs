@@ -0,0 +1,177 @@
+//! The `export-profile` subcommand, which reads back the limits that are applied right now (e.g.
+//! by the BIOS or another tool) and writes them out as a `[profiles.*]` section, so that they can
+//! be kept under the daemon's management.
+//!
+//! The daemon doesn't undervolt, so any voltage offsets are only noted in comments after the
+//! section; they'd need to be kept with whatever tool set them.
+
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+
+use failure::Error;
+use toml::{self, Value};
+
+use duration;
+use hwp;
+use msr;
+use paths;
+use rapl;
+use temps;
+use travel;
+use undervolt::{self, Plane};
+use {ConfigFormat, HwpConfig, ModeConfig, read_config_from};
+
+
+const USAGE: &str = "usage: export-profile [--name NAME] [--append]";
+
+/// Section name used unless `--name` is given.
+const DEFAULT_NAME: &str = "current";
+
+
+/// What to export, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Name of the profile, i.e. the section is [profiles.NAME].
+    name: String,
+    /// Whether to add the section to the config file, rather than print it.
+    append: bool,
+}
+
+impl Options {
+    /// Parses the arguments following `export-profile`.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+        let mut opts = Options { name: DEFAULT_NAME.to_string(), append: false };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--name" => opts.name = args.next().ok_or_else(|| format_err!("--name needs a value ({})", USAGE))?,
+                "--append" => opts.append = true,
+                _ => bail!("unknown export-profile argument: {} ({})", arg, USAGE),
+            }
+        }
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if opts.name.is_empty() || !opts.name.chars().all(valid) {
+            bail!("profile names can only contain letters, digits, '-' and '_', not {:?}", opts.name);
+        }
+        Ok(opts)
+    }
+}
+
+
+/// Exports the applied settings, printing them or appending them to the config file.
+pub fn run(opts: &Options) -> Result<(), Error> {
+    let section = to_toml(&opts.name, &read_applied()?)? + &offsets_comment();
+    if !opts.append {
+        print!("{}", section);
+        return Ok(());
+    }
+
+    let path = &paths::get().config;
+    let contents = fs::read_to_string(path).map_err(|e| format_err!("{}: {}", path, e))?;
+    if ConfigFormat::detect(path, &contents) == ConfigFormat::Json {
+        bail!("{} is JSON, which export-profile can't add to; leave out --append and convert the section", path);
+    }
+    if read_config_from(path)?.profiles.contains_key(&opts.name) {
+        bail!("{} already has a [profiles.{}] section; pick another --name", path, opts.name);
+    }
+
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    let separator = if contents.is_empty() || contents.ends_with("\n\n") { "" } else if contents.ends_with('\n') { "\n" } else { "\n\n" };
+    write!(file, "{}# Exported from the settings applied when export-profile ran.\n{}", separator, section)?;
+    println!("added [profiles.{}] to {}", opts.name, path);
+    Ok(())
+}
+
+/// Reads back the settings that a profile could set to the values they have now. Anything that
+/// can't be read, or that's at the hardware's default, is left unset.
+fn read_applied() -> Result<ModeConfig, Error> {
+    let raw = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first()?;
+    let units = rapl::Units::read()?;
+    let pl1 = rapl::PowerLimit::decode(raw, 0, &units);
+    let pl2 = rapl::PowerLimit::decode(raw, 32, &units);
+
+    let mut conf = ModeConfig {
+        turbo_enabled: travel::read_turbo_enabled(),
+        ..ModeConfig::default()
+    };
+    if pl1.enabled {
+        conf.pl1_tdp_w = Some(pl1.watts.round() as u64);
+        conf.pl1_duration = Some(pl1.window);
+    }
+    if pl2.enabled {
+        conf.pl2_tdp_w = Some(pl2.watts.round() as u64);
+        conf.pl2_duration = Some(pl2.window);
+    }
+
+    if rapl::domains().has(rapl::Domain::Graphics) {
+        if let Ok(raw) = msr::ReadMsrBuilder::new(rapl::MSR_PP1_POWER_LIMIT).read_first() {
+            let limit = rapl::PowerLimit::decode(raw, 0, &units);
+            if limit.enabled && limit.watts > 0.0 {
+                conf.gpu_pl_w = Some(limit.watts.round() as u64);
+            }
+        }
+    }
+
+    // Without an offset the CPU throttles at TjMax, which is what leaving it unset does too.
+    if let Ok(target) = temps::TemperatureTarget::read() {
+        if target.offset > 0 {
+            conf.maximum_temp_c = Some(target.throttle_temp());
+        }
+    }
+
+    if hwp::is_enabled() {
+        conf.hwp = read_hwp();
+    }
+    Ok(conf)
+}
+
+/// Reads the first CPU's HWP request, which is the one that the per-CPU scope writes to all of them.
+fn read_hwp() -> Option<HwpConfig> {
+    let request = msr::ReadMsrBuilder::new(hwp::MSR_HWP_REQUEST).read_first().ok()?;
+    let range = hwp::PerformanceRange::read().ok()?;
+
+    let min = (request & hwp::MIN_PERF_MASK) as u8;
+    // Profiles that don't set a floor put back the lowest level, so there's no need to give it.
+    let min_perf_pct = if min > range.lowest && range.highest > 0 {
+        Some(((u32::from(min) * 100 + u32::from(range.highest) / 2) / u32::from(range.highest)) as u8)
+    } else {
+        None
+    };
+    let epp = Some(((request & hwp::EPP_MASK) >> 24) as u8);
+    Some(HwpConfig { epp, min_perf_pct, scope: None })
+}
+
+/// Returns comment lines giving the core and cache voltage offsets, or nothing if neither is set
+/// (or the mailbox can't be read).
+fn offsets_comment() -> String {
+    let offsets: Vec<(Plane, f64)> = [Plane::Core, Plane::Cache].iter()
+        .filter_map(|&plane| undervolt::read_offset(plane).ok().map(|mv| (plane, mv)))
+        .collect();
+    if offsets.iter().all(|&(_, mv)| mv == 0.0) {
+        return String::new();
+    }
+    let mut comment = "# Voltage offsets, which the daemon doesn't apply; keep them with the tool that set them:\n".to_string();
+    for &(plane, mv) in offsets.iter() {
+        comment += &format!("#   {}: {} mV\n", plane.name(), mv);
+    }
+    comment
+}
+
+/// Formats the settings as a [profiles.NAME] section, with time windows in units.
+fn to_toml(name: &str, conf: &ModeConfig) -> Result<String, Error> {
+    let mut value = Value::try_from(conf)?;
+    if let Some(table) = value.as_table_mut() {
+        for &key in ["pl1_duration", "pl2_duration"].iter() {
+            if let Some(secs) = table.get(key).and_then(|v| v.as_float()) {
+                table.insert(key.to_string(), Value::String(duration::format(secs)));
+            }
+        }
+    }
+
+    let mut profiles = toml::value::Table::new();
+    profiles.insert(name.to_string(), value);
+    let mut root = toml::value::Table::new();
+    root.insert("profiles".to_string(), Value::Table(profiles));
+    Ok(toml::to_string(&Value::Table(root))?)
+}
@@ -0,0 +1,189 @@
+//! C-compatible interface to the limits encoder, enabled with the `ffi` feature.
+//!
+//! Build a shared library with:
+//!
+//!   cargo rustc --lib --release --features ffi --crate-type cdylib
+//!
+//! and see `include/lenovo_throttling.h` for the matching declarations. All functions return a
+//! negative value (or NaN, for floating point results) on invalid arguments.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+use rapl;
+use {ConfigFormat, build_profile_updates, config_from_str};
+
+
+/// A decoded power limit, as returned by `lt_decode_power_limit`.
+#[repr(C)]
+pub struct LtPowerLimit {
+    pub watts: f64,
+    pub enabled: c_int,
+    pub clamping: c_int,
+    pub window_sec: f64,
+}
+
+fn units(power_unit: f64, time_unit: f64) -> rapl::Units {
+    rapl::Units {
+        power: power_unit,
+        energy: 0.0,
+        time: time_unit,
+        encoding: rapl::Encoding::Core,
+    }
+}
+
+/// Decodes a raw MSR_RAPL_POWER_UNIT value into power (Watts) and time (seconds) units.
+///
+/// # Safety
+///
+/// `power_unit` and `time_unit` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn lt_decode_units(raw: u64, power_unit: *mut f64, time_unit: *mut f64) -> c_int {
+    if power_unit.is_null() || time_unit.is_null() {
+        return -1;
+    }
+
+    let units = rapl::Units::from_raw(raw, rapl::Encoding::Core);
+    *power_unit = units.power;
+    *time_unit = units.time;
+    0
+}
+
+/// Encodes a duration as a RAPL time window, storing the Y and Z fields in the given pointers and
+/// returning the duration that they actually represent.
+///
+/// # Safety
+///
+/// `y` and `z` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn lt_encode_time_window(seconds: f64, time_unit: f64, y: *mut u32, z: *mut u32) -> f64 {
+    if y.is_null() || z.is_null() {
+        return f64::NAN;
+    }
+
+    let (ey, ez, realized) = rapl::encode_time_window(seconds, time_unit);
+    *y = ey;
+    *z = ez;
+    realized
+}
+
+/// Decodes a RAPL time window into a duration in seconds.
+#[no_mangle]
+pub extern "C" fn lt_decode_time_window(y: u32, z: u32, time_unit: f64) -> f64 {
+    if y > 31 || z > 3 {
+        return f64::NAN;
+    }
+
+    rapl::decode_time_window(y, z, time_unit)
+}
+
+/// Returns `raw` (a MSR_PKG_POWER_LIMIT value) with PL1 (`index` 1) or PL2 (`index` 2) replaced by
+/// the given limit and time window. Returns -1 if the limit doesn't fit in the field.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn lt_encode_power_limit(
+    raw: u64,
+    index: c_int,
+    watts: f64,
+    seconds: f64,
+    power_unit: f64,
+    time_unit: f64,
+    out: *mut u64,
+) -> c_int {
+    let offset = match index {
+        1 => 0,
+        2 => 32,
+        _ => return -1,
+    };
+    if out.is_null() {
+        return -1;
+    }
+
+    match rapl::encode_power_limit(raw, offset, watts, seconds, &units(power_unit, time_unit)) {
+        Ok(v) => *out = v,
+        Err(_) => return -1,
+    }
+    0
+}
+
+/// Decodes PL1 (`index` 1) or PL2 (`index` 2) from a raw MSR_PKG_POWER_LIMIT value.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn lt_decode_power_limit(
+    raw: u64,
+    index: c_int,
+    power_unit: f64,
+    time_unit: f64,
+    out: *mut LtPowerLimit,
+) -> c_int {
+    let offset = match index {
+        1 => 0,
+        2 => 32,
+        _ => return -1,
+    };
+    if out.is_null() {
+        return -1;
+    }
+
+    let limit = rapl::PowerLimit::decode(raw, offset, &units(power_unit, time_unit));
+    *out = LtPowerLimit {
+        watts: limit.watts,
+        enabled: limit.enabled as c_int,
+        clamping: limit.clamping as c_int,
+        window_sec: limit.window,
+    };
+    0
+}
+
+/// Parses a config (in the same TOML format as config.toml), checking it as the daemon does, and
+/// applies the named profile ("ac" or "battery") to the hardware. Returns 0 if every setting was
+/// written successfully.
+///
+/// # Safety
+///
+/// Both arguments must be null or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn lt_apply_profile(config_toml: *const c_char, profile: *const c_char) -> c_int {
+    if config_toml.is_null() || profile.is_null() {
+        return -1;
+    }
+
+    let (config, profile) = match (CStr::from_ptr(config_toml).to_str(), CStr::from_ptr(profile).to_str()) {
+        (Ok(c), Ok(p)) => (c, p),
+        _ => return -1,
+    };
+
+    let config = match config_from_str(ConfigFormat::Toml, config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("error in config: {}", e);
+            return -1;
+        },
+    };
+
+    if config.profile_errors.iter().any(|e| e.0 == profile) {
+        return -1;
+    }
+    let conf = match profile {
+        "ac" => &config.ac,
+        "battery" => &config.battery,
+        _ => return -1,
+    };
+
+    let updates = match build_profile_updates(&config, conf) {
+        Ok(u) => u,
+        Err(e) => {
+            eprintln!("error building updates: {}", e);
+            return -1;
+        },
+    };
+
+    let failed = updates.iter().filter(|u| u.apply().is_err()).count();
+    if failed == 0 { 0 } else { -1 }
+}
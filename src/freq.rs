@@ -0,0 +1,148 @@
+//! Effective frequency, from the APERF/MPERF counters. Unlike cpufreq's current frequency, this is
+//! what each CPU actually ran at while it wasn't idle, including any throttling, so it shows the
+//! effect of the power limits on sustained clocks directly.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use ::channel;
+
+use msr;
+use priority;
+use topology;
+use turbo;
+
+
+/// Address of IA32_MPERF, which counts at the base frequency while the CPU isn't idle.
+pub const MSR_MPERF: u64 = 0xE7;
+
+/// Address of IA32_APERF, which counts at the actual frequency while the CPU isn't idle.
+pub const MSR_APERF: u64 = 0xE8;
+
+/// Bus clock that ratios are multiplied by, in MHz.
+const BCLK_MHZ: f64 = 100.0;
+
+
+/// Effective frequency of a single logical CPU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuFrequency {
+    pub cpu: usize,
+    pub mhz: f64,
+}
+
+/// Effective frequencies over a sampling period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frequencies {
+    /// Every CPU that wasn't idle for the whole period.
+    pub cpus: Vec<CpuFrequency>,
+    /// Average across the CPUs, weighted by how long each one was busy.
+    pub average_mhz: Option<f64>,
+}
+
+impl Frequencies {
+    pub fn max_mhz(&self) -> Option<f64> {
+        self.cpus.iter().map(|c| c.mhz).fold(None, |max, f| Some(max.map_or(f, |m: f64| m.max(f))))
+    }
+}
+
+/// Reads the counters of every online CPU, for working out the frequency between two samples. The
+/// CPUs are re-read on every sample, so that ones that come or go (e.g. when SMT is switched off
+/// or on) are picked up.
+pub struct FrequencySampler {
+    base_mhz: f64,
+    cpus: Vec<usize>,
+    sampler: msr::Sampler,
+    /// Counters from the previous sample, as (MPERF, APERF) pairs in the order of `cpus`.
+    last: Option<Vec<(u64, u64)>>,
+}
+
+impl FrequencySampler {
+    pub fn new() -> io::Result<FrequencySampler> {
+        let platform_info = msr::ReadMsrBuilder::new(turbo::MSR_PLATFORM_INFO).read_first()?;
+        let base_mhz = ((platform_info >> 8) & 0xFF) as f64 * BCLK_MHZ;
+
+        let cpus = topology::online_cpus_or_default();
+        let sampler = msr::Sampler::new(&counter_reads(&cpus))?;
+
+        Ok(FrequencySampler { base_mhz, cpus, sampler, last: None })
+    }
+
+    /// Returns the frequencies since the previous call, or `None` on the first call (or the
+    /// first since the CPUs changed).
+    pub fn sample(&mut self) -> io::Result<Option<Frequencies>> {
+        let cpus = topology::online_cpus_or_default();
+        if cpus != self.cpus {
+            self.sampler = msr::Sampler::new(&counter_reads(&cpus))?;
+            self.cpus = cpus;
+            self.last = None;
+        }
+
+        let counters: Vec<(u64, u64)> = self.sampler.sample()?.chunks(2).map(|c| (c[0], c[1])).collect();
+        let last = match self.last.replace(counters.clone()) {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+
+        let (mut total_mperf, mut total_aperf) = (0u64, 0u64);
+        let mut cpus = vec![];
+        for ((&cpu, &(mperf, aperf)), &(last_mperf, last_aperf)) in self.cpus.iter().zip(counters.iter()).zip(last.iter()) {
+            let (mperf, aperf) = (mperf.wrapping_sub(last_mperf), aperf.wrapping_sub(last_aperf));
+            if mperf == 0 {
+                continue;
+            }
+            total_mperf += mperf;
+            total_aperf += aperf;
+            cpus.push(CpuFrequency { cpu, mhz: self.base_mhz * aperf as f64 / mperf as f64 });
+        }
+
+        let average_mhz = if total_mperf > 0 {
+            Some(self.base_mhz * total_aperf as f64 / total_mperf as f64)
+        } else {
+            None
+        };
+        Ok(Some(Frequencies { cpus, average_mhz }))
+    }
+}
+
+/// Returns the reads for each CPU's MPERF and APERF, in that order.
+fn counter_reads(cpus: &[usize]) -> Vec<(usize, u64)> {
+    cpus.iter().flat_map(|&cpu| vec![(cpu, MSR_MPERF), (cpu, MSR_APERF)]).collect()
+}
+
+/// Measures the frequencies over the given period.
+pub fn measure(period: Duration) -> io::Result<Frequencies> {
+    let mut sampler = FrequencySampler::new()?;
+    sampler.sample()?;
+    thread::sleep(period);
+    sampler.sample()?.ok_or_else(|| io::Error::other("the online CPUs changed while measuring"))
+}
+
+/// Returns a channel that emits the frequencies over every `interval`.
+pub fn notify_on_frequency(interval: Duration) -> channel::Receiver<Frequencies> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        priority::lower_sampler();
+        let mut sampler = match FrequencySampler::new() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("error opening MSR devices for APERF/MPERF: {}", e);
+                return;
+            },
+        };
+        loop {
+            match sampler.sample() {
+                Ok(Some(f)) => {
+                    if send.send(f).is_err() {
+                        return;
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => eprintln!("error reading APERF/MPERF: {}", e),
+            }
+            thread::sleep(interval);
+        }
+    });
+
+    recv
+}
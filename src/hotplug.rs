@@ -0,0 +1,144 @@
+//! Notification of CPUs coming online, via the kernel's uevent netlink socket.
+
+use std::io;
+use std::mem;
+use std::thread;
+
+use ::channel;
+use libc;
+
+
+/// Multicast group that the kernel sends uevents to (as opposed to udev's rebroadcasts).
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// Prefix of the device path of each logical CPU.
+const CPU_DEVPATH_PREFIX: &str = "/devices/system/cpu/cpu";
+
+
+/// A netlink socket subscribed to kernel uevents.
+struct UeventSocket {
+    fd: libc::c_int,
+}
+
+impl UeventSocket {
+    fn open() -> io::Result<UeventSocket> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = UeventSocket { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_UEVENT_GROUP;
+        let ret = unsafe {
+            libc::bind(
+                sock.fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(sock)
+    }
+
+    /// Waits for the next uevent from the kernel, returning its raw contents.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            let n = unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                    &mut addr as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                // ENOBUFS means that some uevents were dropped; there's no getting them back, but
+                // later ones are still worth reading.
+                if err.kind() == io::ErrorKind::Interrupted || err.raw_os_error() == Some(libc::ENOBUFS) {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            // Only trust messages that come from the kernel itself.
+            if addr.nl_pid == 0 {
+                return Ok(n as usize);
+            }
+        }
+    }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Parses a kernel uevent, returning the CPU number if it reports a CPU coming online.
+///
+/// Kernel uevents are a header ("ACTION@DEVPATH") followed by NUL-separated KEY=VALUE pairs.
+fn parse_cpu_online(msg: &[u8]) -> Option<usize> {
+    let mut action = None;
+    let mut devpath = None;
+    for field in msg.split(|&b| b == 0).skip(1) {
+        let field = match ::std::str::from_utf8(field) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        if let Some(v) = field.strip_prefix("ACTION=") {
+            action = Some(v);
+        } else if let Some(v) = field.strip_prefix("DEVPATH=") {
+            devpath = Some(v);
+        }
+    }
+
+    if action != Some("online") {
+        return None;
+    }
+    devpath?.strip_prefix(CPU_DEVPATH_PREFIX)?.parse::<usize>().ok()
+}
+
+/// Returns a channel that emits the number of each CPU as it comes online, e.g. when SMT is
+/// re-enabled.
+///
+/// The socket is opened before returning, so that failing to subscribe can be reported.
+pub fn notify_on_cpu_online() -> io::Result<channel::Receiver<usize>> {
+    let sock = UeventSocket::open()?;
+
+    let (send, recv) = channel::unbounded();
+    thread::spawn(move || {
+        // Uevents are small, but leave plenty of room for ones with lots of variables.
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = match sock.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    eprintln!("error reading uevents, CPU hotplug will be ignored: {}", e);
+                    return;
+                },
+            };
+
+            if let Some(cpu) = parse_cpu_online(&buf[..n]) {
+                if send.send(cpu).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(recv)
+}
@@ -0,0 +1,135 @@
+//! Hardware P-state (HWP) requests, which tell the CPU how to trade performance for energy.
+//!
+//! Each logical CPU has its own request MSR, and CPUs that support it also have a package-level
+//! request; a CPU follows the package request unless its own request overrides it. Writing the
+//! package request once is much cheaper than writing every thread's request, and leaves the
+//! per-CPU requests to whatever governor manages them.
+
+use std::cmp;
+use std::io;
+
+use cpuid;
+use msr;
+use topology;
+
+
+/// Address of IA32_PM_ENABLE; bit 0 is set once HWP has been enabled.
+pub const MSR_PM_ENABLE: u64 = 0x770;
+
+/// Address of IA32_HWP_CAPABILITIES, which has the range of performance levels that can be requested.
+pub const MSR_HWP_CAPABILITIES: u64 = 0x771;
+
+/// Address of IA32_HWP_REQUEST_PKG, the package-level request.
+pub const MSR_HWP_REQUEST_PKG: u64 = 0x772;
+
+/// Address of IA32_HWP_REQUEST, each logical CPU's request.
+pub const MSR_HWP_REQUEST: u64 = 0x774;
+
+/// Minimum performance field of both request MSRs (bits 7:0).
+pub const MIN_PERF_MASK: u64 = 0xFF;
+
+/// Energy/performance preference field of both request MSRs (bits 31:24).
+pub const EPP_MASK: u64 = 0xFF << 24;
+
+/// Bit of IA32_HWP_REQUEST that makes the CPU follow the package request.
+const PACKAGE_CONTROL: u64 = 1 << 42;
+
+/// Bits of IA32_HWP_REQUEST that make its minimum performance and EPP fields override the
+/// package request's. Only defined when package requests are supported; writing them otherwise
+/// faults.
+pub const MIN_PERF_VALID: u64 = 1 << 59;
+pub const EPP_VALID: u64 = 1 << 62;
+
+
+/// Which request MSR to write.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestScope {
+    /// The package request if it's supported and no CPU overrides it, otherwise each CPU's.
+    #[default]
+    Auto,
+    /// Only the package request.
+    Package,
+    /// Each logical CPU's request.
+    Cpu,
+}
+
+/// Returns whether HWP is enabled; until it is, the request MSRs can't be written.
+pub fn is_enabled() -> bool {
+    match msr::ReadMsrBuilder::new(MSR_PM_ENABLE).read_first() {
+        Ok(v) => v & 1 != 0,
+        Err(_) => false,
+    }
+}
+
+/// The range of performance levels that can be requested, in the CPU's own units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerformanceRange {
+    pub lowest: u8,
+    pub highest: u8,
+}
+
+impl PerformanceRange {
+    /// Reads the range from IA32_HWP_CAPABILITIES.
+    pub fn read() -> io::Result<PerformanceRange> {
+        let caps = msr::ReadMsrBuilder::new(MSR_HWP_CAPABILITIES).read_first()?;
+        Ok(PerformanceRange { lowest: (caps >> 24) as u8, highest: caps as u8 })
+    }
+
+    /// Returns the level that's the given percentage of the highest, as intel_pstate's
+    /// min_perf_pct counts it, but not below the lowest.
+    pub fn level_for(&self, pct: u8) -> u8 {
+        let level = (u32::from(self.highest) * u32::from(pct) + 50) / 100;
+        cmp::max(level as u8, self.lowest)
+    }
+}
+
+/// Returns whether the CPU has the package-level request MSR.
+pub fn package_request_supported() -> bool {
+    cpuid::get().hwp_pkg_req
+}
+
+/// Returns whether a CPU's own request overrides the package request's field with the given
+/// valid bit.
+fn overrides_package(request: u64, valid: u64) -> bool {
+    request & PACKAGE_CONTROL == 0 || request & valid != 0
+}
+
+/// Returns the online CPUs whose own request overrides the package request's field with the
+/// given valid bit (e.g. `EPP_VALID`). CPUs whose request can't be read are assumed not to.
+pub fn overriding_cpus(valid: u64) -> Vec<usize> {
+    let reader = msr::ReadMsrBuilder::new(MSR_HWP_REQUEST);
+    topology::online_cpus_or_default().into_iter()
+        .filter(|&cpu| reader.read_one(cpu).map(|r| overrides_package(r, valid)).unwrap_or(false))
+        .collect()
+}
+
+/// Picks which request MSR to write a field (with the given valid bit) to for the requested
+/// scope, explaining the choice if it isn't the one that was asked for.
+pub fn choose_scope(requested: RequestScope, valid: u64) -> msr::Scope {
+    if requested == RequestScope::Cpu {
+        return msr::Scope::Cpu;
+    }
+    if !package_request_supported() {
+        if requested == RequestScope::Package {
+            eprintln!("hwp scope is \"package\", but this CPU doesn't have a package-level HWP request; \
+                       writing each CPU's request instead");
+        }
+        return msr::Scope::Cpu;
+    }
+
+    let overriding = overriding_cpus(valid);
+    if overriding.is_empty() {
+        return msr::Scope::Package;
+    }
+    if requested == RequestScope::Package {
+        eprintln!("WARNING: the HWP requests of CPUs {:?} override the package request, so they will \
+                   ignore it; set the hwp scope to \"cpu\" (or \"auto\") to write each CPU's request",
+                  overriding);
+        msr::Scope::Package
+    } else {
+        eprintln!("the HWP requests of CPUs {:?} override the package request; writing each CPU's request",
+                  overriding);
+        msr::Scope::Cpu
+    }
+}
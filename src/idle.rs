@@ -0,0 +1,76 @@
+//! Helpers for keeping periodic wakeups cheap while the system is idle.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::time::Duration;
+
+use libc;
+
+
+/// A periodic timer backed by a timerfd.
+///
+/// This uses CLOCK_MONOTONIC, which doesn't advance while the system is suspended, so resuming
+/// doesn't immediately fire the timer; a power change event will arrive instead if one is needed.
+pub struct Timer {
+    fd: libc::c_int,
+}
+
+impl Timer {
+    /// Creates a timer that fires every `period`, starting one period from now.
+    pub fn new(period: Duration) -> io::Result<Timer> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timer = Timer { fd };
+        timer.set_period(period)?;
+        Ok(timer)
+    }
+
+    /// Changes the period, with the next tick one new period from now.
+    pub fn set_period(&self, period: Duration) -> io::Result<()> {
+        let spec = libc::timespec {
+            tv_sec: period.as_secs() as libc::time_t,
+            tv_nsec: period.subsec_nanos() as libc::c_long,
+        };
+        let value = libc::itimerspec {
+            it_interval: spec,
+            it_value: spec,
+        };
+        if unsafe { libc::timerfd_settime(self.fd, 0, &value, ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until the timer next fires, returning the number of periods that have elapsed.
+    pub fn wait(&self) -> io::Result<u64> {
+        let mut expirations: u64 = 0;
+        let size = mem::size_of::<u64>();
+        let ret = unsafe {
+            libc::read(self.fd, &mut expirations as *mut u64 as *mut libc::c_void, size)
+        };
+
+        if ret as usize != size {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(expirations)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Allows the kernel to delay the calling thread's timers by up to `slack`, so that our wakeups
+/// can be coalesced with others.
+pub fn set_timer_slack(slack: Duration) -> io::Result<()> {
+    let ns = slack.as_secs() * 1_000_000_000 + slack.subsec_nanos() as u64;
+    if unsafe { libc::prctl(libc::PR_SET_TIMERSLACK, ns as libc::c_ulong) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
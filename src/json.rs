@@ -0,0 +1,463 @@
+//! A minimal JSON implementation; just enough for the JSON-RPC control socket and config files
+//! written in JSON.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use toml;
+
+
+/// A parsed JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// An object, with its members in the order they appeared.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Returns the member of an object with the given name.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.iter().find(|m| m.0 == name).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an unsigned integer, if it is one.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            // u64::MAX rounds up to 2^64 as a float, which is out of range.
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < u64::MAX as f64 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    /// Converts the value to TOML, so that a config file written in JSON can be read like one
+    /// written in TOML. Numbers without a fractional part become integers. TOML has no null, so
+    /// nulls are an error; settings are left out to leave them unset.
+    pub fn to_toml(&self) -> Result<toml::Value, String> {
+        self.to_toml_at("")
+    }
+
+    fn to_toml_at(&self, path: &str) -> Result<toml::Value, String> {
+        Ok(match *self {
+            Value::Null => return Err(format!("{}: null isn't allowed; leave the setting out instead", describe(path))),
+            Value::Bool(b) => toml::Value::Boolean(b),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => toml::Value::Integer(n as i64),
+            Value::Number(n) => toml::Value::Float(n),
+            Value::String(ref s) => toml::Value::String(s.clone()),
+            Value::Array(ref values) => toml::Value::Array(
+                values.iter().enumerate()
+                    .map(|(i, v)| v.to_toml_at(&format!("{}[{}]", path, i)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(ref members) => {
+                let mut table = toml::value::Table::new();
+                for (name, v) in members.iter() {
+                    let path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+                    if table.insert(name.clone(), v.to_toml_at(&path)?).is_some() {
+                        return Err(format!("{}: duplicate key", path));
+                    }
+                }
+                toml::Value::Table(table)
+            },
+        })
+    }
+}
+
+fn describe(path: &str) -> &str {
+    if path.is_empty() { "the document" } else { path }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Value::Number(_) => f.write_str("null"),
+            Value::String(ref s) => write_string(f, s),
+            Value::Array(ref values) => {
+                f.write_str("[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                f.write_str("]")
+            },
+            Value::Object(ref members) => {
+                f.write_str("{")?;
+                for (i, (name, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, name)?;
+                    write!(f, ":{}", v)?;
+                }
+                f.write_str("}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+
+/// Parses a complete JSON document.
+pub fn parse(input: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: input.chars().peekable(), depth: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected {:?} after value", c)),
+    }
+}
+
+/// How deeply arrays and objects may be nested, so that hostile input can't overflow the stack.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !matches!(c, ' ' | '\t' | '\n' | '\r') {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected {:?}, found {:?}", expected, c)),
+            None => Err(format!("expected {:?}, found end of input", expected)),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('n') => self.literal("null", Value::Null),
+            Some('t') => self.literal("true", Value::Bool(true)),
+            Some('f') => self.literal("false", Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') => self.nested(Parser::array),
+            Some('{') => self.nested(Parser::object),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+
+    fn nested(&mut self, f: fn(&mut Parser<'a>) -> Result<Value, String>) -> Result<Value, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("nested too deeply".to_string());
+        }
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = vec![];
+
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err("expected ',' or ']' in array".to_string()),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let name = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            members.push((name, self.value()?));
+
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(members)),
+                _ => return Err("expected ',' or '}' in object".to_string()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E') {
+                break;
+            }
+            s.push(c);
+            self.chars.next();
+        }
+
+        // Rust accepts more than JSON does, e.g. "01" and "1."; "1e999" is infinite, which we can't
+        // write back out.
+        match s.parse::<f64>() {
+            Ok(n) if is_json_number(&s) && n.is_finite() => Ok(Value::Number(n)),
+            _ => Err(format!("invalid number {:?}", s)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode_escape()?,
+                        _ => return Err("invalid escape in string".to_string()),
+                    };
+                    s.push(c);
+                },
+                Some(c) if (c as u32) < 0x20 => return Err("unescaped control character in string".to_string()),
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let first = self.hex4()?;
+        if !(0xD800..0xDC00).contains(&first) {
+            return ::std::char::from_u32(first).ok_or_else(|| "invalid \\u escape".to_string());
+        }
+
+        // A high surrogate, which must be followed by an escaped low surrogate.
+        self.expect('\\')?;
+        self.expect('u')?;
+        let second = self.hex4()?;
+        if !(0xDC00..0xE000).contains(&second) {
+            return Err("invalid surrogate pair".to_string());
+        }
+        let c = 0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00);
+        ::std::char::from_u32(c).ok_or_else(|| "invalid \\u escape".to_string())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut val = 0;
+        for _ in 0..4 {
+            let digit = self.chars.next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| "invalid \\u escape".to_string())?;
+            val = val * 16 + digit;
+        }
+        Ok(val)
+    }
+}
+
+/// Returns whether a number is written the way JSON allows: an optional minus sign, an integer part
+/// without leading zeros, and optional fraction and exponent parts with at least one digit each.
+fn is_json_number(s: &str) -> bool {
+    fn digits(s: &str) -> (&str, &str) {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        s.split_at(end)
+    }
+
+    let s = s.strip_prefix('-').unwrap_or(s);
+    let (int, mut rest) = digits(s);
+    if int.is_empty() || (int.len() > 1 && int.starts_with('0')) {
+        return false;
+    }
+    if let Some(r) = rest.strip_prefix('.') {
+        let (frac, r) = digits(r);
+        if frac.is_empty() {
+            return false;
+        }
+        rest = r;
+    }
+    if let Some(r) = rest.strip_prefix(|c| c == 'e' || c == 'E') {
+        let (exp, r) = digits(r.strip_prefix(|c| c == '+' || c == '-').unwrap_or(r));
+        if exp.is_empty() {
+            return false;
+        }
+        rest = r;
+    }
+    rest.is_empty()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn values_parse() {
+        let cases = [
+            ("null", Value::Null),
+            (" true ", Value::Bool(true)),
+            ("false", Value::Bool(false)),
+            ("0", Value::Number(0.0)),
+            ("-0.5", Value::Number(-0.5)),
+            ("1e3", Value::Number(1000.0)),
+            ("2.5E-1", Value::Number(0.25)),
+            ("[]", Value::Array(vec![])),
+            ("[1, [2]]", Value::Array(vec![Value::Number(1.0), Value::Array(vec![Value::Number(2.0)])])),
+            ("{}", Value::Object(vec![])),
+            ("{\"b\": 1, \"a\": null}", Value::Object(vec![("b".to_string(), Value::Number(1.0)), ("a".to_string(), Value::Null)])),
+            (r#""a\"b\\c\/d""#, string("a\"b\\c/d")),
+            (r#""\b\f\n\r\t""#, string("\u{8}\u{c}\n\r\t")),
+            (r#""\u00e9\u20AC""#, string("é€")),
+            // U+1F600, as a surrogate pair.
+            (r#""\ud83d\ude00""#, string("\u{1F600}")),
+            (r#""é""#, string("é")),
+        ];
+        for &(input, ref expected) in cases.iter() {
+            assert_eq!(parse(input).as_ref(), Ok(expected), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn invalid_documents_are_rejected() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let cases = [
+            "",
+            "nul",
+            "True",
+            "01",
+            "1.",
+            ".5",
+            "-",
+            "1e",
+            "1e+",
+            "+1",
+            "1e999",
+            "0x10",
+            "[1,]",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "{\"a\": 1,}",
+            "\"unterminated",
+            "\"tab\there\"",
+            r#""\x41""#,
+            r#""\u12""#,
+            r#""\ud83d""#,
+            r#""\ud83d\u0041""#,
+            r#""\ude00""#,
+            "1 2",
+            "{} x",
+            "null\u{a0}",
+        ];
+        for &input in cases.iter() {
+            assert!(parse(input).is_err(), "{:?} was accepted", input);
+        }
+
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(parse(&nested(MAX_DEPTH + 1)), Err("nested too deeply".to_string()));
+    }
+
+    #[test]
+    fn only_whole_non_negative_numbers_in_range_are_u64() {
+        let cases = [
+            (0.0, Some(0)),
+            (42.0, Some(42)),
+            (9007199254740992.0, Some(1 << 53)),
+            (-1.0, None),
+            (1.5, None),
+            // 2^64, which a saturating cast would turn into u64::MAX.
+            (18446744073709551616.0, None),
+            (1e300, None),
+        ];
+        for &(n, expected) in cases.iter() {
+            assert_eq!(Value::Number(n).as_u64(), expected, "{}", n);
+        }
+        assert_eq!(string("1").as_u64(), None);
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        for &s in ["plain", "quote \" and \\ backslash", "line\nbreak\ttab", "\u{1}control", "é\u{1F600}"].iter() {
+            let written = string(s).to_string();
+            assert_eq!(parse(&written), Ok(string(s)), "{}", written);
+        }
+    }
+}
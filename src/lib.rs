@@ -0,0 +1,2474 @@
+extern crate byteorder;
+extern crate crossbeam_channel as channel;
+#[cfg(feature = "dbus")]
+extern crate dbus;
+#[macro_use]
+extern crate failure;
+extern crate libc;
+extern crate num_cpus;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use serde::de::{self, Deserializer, Visitor};
+
+use backends::{Backend, Capability};
+use exit::ExitCode;
+
+mod backends;
+mod backlight;
+mod bench;
+mod burst;
+#[cfg(feature = "dbus")]
+mod bus;
+mod charge;
+mod completions;
+mod conflicts;
+mod control;
+mod cpuid;
+mod daemon;
+mod dump;
+mod duration;
+mod emergency;
+mod energy;
+pub mod exit;
+mod explain;
+mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod freq;
+mod hotplug;
+mod hwp;
+mod idle;
+mod json;
+mod lint;
+mod load;
+mod mchbar;
+mod metrics;
+#[cfg(feature = "dbus")]
+mod monitor;
+mod msr;
+mod paths;
+mod platform;
+mod persist;
+mod plan;
+mod power;
+mod powercap;
+mod preflight;
+mod privsep;
+mod presets;
+mod preview;
+mod priority;
+#[cfg(feature = "metrics")]
+mod prometheus;
+mod pstate;
+mod quirks;
+mod ramp;
+mod ratelimit;
+pub mod rapl;
+mod revert;
+mod rpc;
+mod rules;
+mod runtime;
+mod sandbox;
+mod setup;
+#[cfg(feature = "sim")]
+pub mod sim;
+mod status;
+mod stress;
+mod suspend;
+mod sysfs;
+mod temps;
+mod topology;
+mod transaction;
+mod transient;
+mod travel;
+mod turbo;
+mod undervolt;
+mod why;
+// mod util;
+
+
+/// Path to the intel_pstate knob that disables Turbo Boost.
+const INTEL_PSTATE_NO_TURBO: &str = "/sys/devices/system/cpu/intel_pstate/no_turbo";
+
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    /// Configuration to apply when on battery.
+    battery: ModeConfig,
+
+    /// Configuration to apply when on AC power.
+    ac: ModeConfig,
+
+    /// Named variations on the battery and AC profiles, e.g. [profiles.gaming].
+    #[serde(default)]
+    profiles: BTreeMap<String, NamedProfileConfig>,
+
+    /// Additional constraints to layer over the battery configuration at low charge levels.
+    #[serde(default)]
+    battery_levels: Vec<BatteryLevelConfig>,
+
+    /// Additional constraints to layer over the AC configuration with low-powered chargers.
+    #[serde(default)]
+    charger_levels: Vec<ChargerLevelConfig>,
+
+    /// Additional constraints to layer over the battery configuration when the battery is worn.
+    battery_wear: Option<BatteryWearConfig>,
+
+    /// Automatically lowers PL1 when the battery is draining too quickly.
+    discharge_guard: Option<DischargeGuardConfig>,
+
+    /// Raises PL2 after idle periods and lowers it after sustained load.
+    burst_budget: Option<BurstBudgetConfig>,
+
+    /// Additional constraints to layer over the active profile while the CPU is too hot.
+    emergency: Option<EmergencyConfig>,
+
+    /// Steps the limits over a few seconds when switching profiles.
+    ramp: Option<RampConfig>,
+
+    /// Settings layered over the active profile while travel mode is on, replacing its defaults.
+    #[serde(default)]
+    travel: ModeConfig,
+
+    /// Forces a profile when the ACPI platform profile is changed, e.g. from the desktop.
+    follow_platform_profile: Option<FollowPlatformProfileConfig>,
+
+    /// Skips periodic reapplication while the system is idle.
+    idle: Option<IdleConfig>,
+
+    /// Samples the effective frequency from APERF/MPERF, for clients.
+    effective_frequency: Option<EffectiveFrequencyConfig>,
+
+    /// Counts the package energy used while each profile is applied, for clients.
+    energy: Option<EnergyConfig>,
+
+    /// Samples the CPU load, for `hwp_mode` and rules with `load_above_percent`.
+    load: Option<LoadConfig>,
+
+    /// Counts throttling episodes using the package thermal status log bits.
+    therm_log: Option<ThermLogConfig>,
+
+    /// How long a power source change may take to be noticed and applied, in seconds.
+    #[serde(default = "default_power_latency_budget", deserialize_with = "duration::secs_f64")]
+    power_latency_budget: f64,
+
+    /// Whether to use the Lenovo-specific workarounds.
+    #[serde(default)]
+    vendor: quirks::Vendor,
+
+    /// How to keep the kernel's powercap constraints consistent with power limits written to the
+    /// MSR.
+    #[serde(default)]
+    powercap_sync: powercap::Sync,
+
+    /// Which control interfaces to serve.
+    #[serde(default)]
+    control: ControlConfig,
+
+    /// The daemon's own CPU and I/O priority.
+    #[serde(default)]
+    priority: priority::PriorityConfig,
+
+    /// Whether to install a seccomp filter once the daemon is running.
+    #[serde(default)]
+    seccomp: bool,
+
+    /// Arbitrary MSR writes applied along with every profile.
+    #[serde(default)]
+    custom_msr: Vec<CustomMsrConfig>,
+
+    /// Rules for picking the profile, in priority order.
+    #[serde(default)]
+    rules: Vec<rules::Rule>,
+
+    /// Profile sections ("battery" or "ac") that are invalid, and why; they're left empty and
+    /// never applied, so that the other profile still works.
+    #[serde(skip)]
+    profile_errors: Vec<(&'static str, String)>,
+}
+
+fn default_power_latency_budget() -> f64 { 1.0 }
+
+impl Config {
+    /// Returns how long a power source change may take to be noticed and applied.
+    fn power_latency_budget(&self) -> Duration {
+        Duration::from_secs_f64(self.power_latency_budget)
+    }
+
+    /// Returns the load sampling settings, if anything uses the load.
+    fn load(&self) -> Option<LoadConfig> {
+        let hwp_mode = self.battery.hwp_mode == Some(true) || self.ac.hwp_mode == Some(true);
+        if hwp_mode || rules::needs_load(&self.rules) {
+            Some(self.load.clone().unwrap_or_default())
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether any section sets an HWP frequency floor (`hwp.min_perf_pct`), which the
+    /// profiles that don't set one have to lift.
+    fn sets_hwp_floor(&self) -> bool {
+        [&self.battery, &self.ac, &self.travel].iter().cloned()
+            .chain(self.profiles.values().map(|p| &p.conf))
+            .chain(self.battery_levels.iter().map(|l| &l.constraints))
+            .chain(self.charger_levels.iter().map(|l| &l.constraints))
+            .chain(self.battery_wear.iter().map(|w| &w.constraints))
+            .chain(self.emergency.iter().map(|e| &e.constraints))
+            .any(|c| c.hwp.as_ref().is_some_and(|h| h.min_perf_pct.is_some()))
+    }
+
+    /// Returns why the profile's section is invalid, if it is.
+    fn profile_error(&self, profile: power::PowerState) -> Option<&str> {
+        self.profile_errors.iter().find(|e| e.0 == profile.name()).map(|e| e.1.as_str())
+    }
+
+    /// Returns whether the profile is a named one that has no [profiles.*] section.
+    fn lacks_profile(&self, profile: &rules::Profile) -> bool {
+        match *profile {
+            rules::Profile::Named(ref name) => !self.profiles.contains_key(name),
+            _ => false,
+        }
+    }
+}
+
+// Configuration for a specific power configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+struct ModeConfig {
+    /// Built-in preset to start from; anything else that's set overrides it.
+    preset: Option<String>,
+
+    /// How often to reset configuration, in seconds.
+    #[serde(default, deserialize_with = "duration::opt_secs")]
+    update_rate_sec: Option<usize>,
+
+    /// Maximum package power for time window #1.
+    pl1_tdp_w: Option<u64>,
+    /// Time window #1 duration, in seconds.
+    #[serde(default, deserialize_with = "duration::opt_secs_f64")]
+    pl1_duration: Option<f64>,
+
+    /// Maximum package power for time window #2.
+    pl2_tdp_w: Option<u64>,
+    /// Time window #2 duration, in seconds.
+    #[serde(default, deserialize_with = "duration::opt_secs_f64")]
+    pl2_duration: Option<f64>,
+
+    /// Sets PL2 to this multiple of PL1, instead of giving it directly.
+    pl2_factor: Option<f64>,
+    /// Sets PL1 to this fraction of PL2, instead of giving it directly.
+    pl1_factor: Option<f64>,
+
+    /// Maximum integrated GPU (PP1 domain) power.
+    gpu_pl_w: Option<u64>,
+
+    /// Maximum CPU temperature before throttling.
+    maximum_temp_c: Option<u64>,
+
+    /// Whether to set HWP performance hints to 'performance' at high load.
+    hwp_mode: Option<bool>,
+
+    /// Whether Turbo Boost should be enabled.
+    turbo_enabled: Option<bool>,
+
+    /// Whether to keep the battery at a reduced charge level to extend its lifespan (IdeaPad).
+    conservation_mode: Option<bool>,
+    /// Whether to charge the battery faster, at the cost of battery wear (IdeaPad).
+    rapid_charge: Option<bool>,
+    /// Battery charge level, in percent, below which charging starts (ThinkPad).
+    charge_start_threshold: Option<u8>,
+    /// Battery charge level, in percent, at which charging stops (ThinkPad).
+    charge_stop_threshold: Option<u8>,
+
+    /// PCIe Active State Power Management policy.
+    pcie_aspm_policy: Option<platform::AspmPolicy>,
+    /// SATA link power management policy, applied to every SATA host.
+    sata_link_policy: Option<platform::SataLinkPolicy>,
+    /// ACPI platform profile, e.g. "low-power", "balanced" or "performance".
+    platform_profile: Option<String>,
+
+    /// Keyboard backlight brightness, as a percentage of its maximum.
+    kbd_backlight_pct: Option<u8>,
+    /// Highest display backlight brightness, as a percentage of its maximum. Brighter displays are
+    /// dimmed to this; dimmer ones are left alone.
+    display_brightness_max_pct: Option<u8>,
+
+    /// intel_pstate driver parameters.
+    intel_pstate: Option<IntelPstateConfig>,
+
+    /// Hardware P-state request settings.
+    hwp: Option<HwpConfig>,
+}
+
+impl ModeConfig {
+    /// Layers another configuration over this one. Power and temperature limits are clamped to
+    /// the lower of the two values; everything else is taken from `other` if it's set.
+    fn constrain(&self, other: &ModeConfig) -> ModeConfig {
+        fn min<T: Ord + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        ModeConfig {
+            preset: other.preset.clone().or_else(|| self.preset.clone()),
+            update_rate_sec: other.update_rate_sec.or(self.update_rate_sec),
+            pl1_tdp_w: min(self.pl1_tdp_w, other.pl1_tdp_w),
+            pl1_duration: other.pl1_duration.or(self.pl1_duration),
+            pl2_tdp_w: min(self.pl2_tdp_w, other.pl2_tdp_w),
+            pl2_duration: other.pl2_duration.or(self.pl2_duration),
+            pl2_factor: other.pl2_factor.or(self.pl2_factor),
+            pl1_factor: other.pl1_factor.or(self.pl1_factor),
+            gpu_pl_w: min(self.gpu_pl_w, other.gpu_pl_w),
+            maximum_temp_c: min(self.maximum_temp_c, other.maximum_temp_c),
+            hwp_mode: other.hwp_mode.or(self.hwp_mode),
+            turbo_enabled: other.turbo_enabled.or(self.turbo_enabled),
+            conservation_mode: other.conservation_mode.or(self.conservation_mode),
+            rapid_charge: other.rapid_charge.or(self.rapid_charge),
+            charge_start_threshold: other.charge_start_threshold.or(self.charge_start_threshold),
+            charge_stop_threshold: other.charge_stop_threshold.or(self.charge_stop_threshold),
+            pcie_aspm_policy: other.pcie_aspm_policy.or(self.pcie_aspm_policy),
+            sata_link_policy: other.sata_link_policy.or(self.sata_link_policy),
+            platform_profile: other.platform_profile.clone().or_else(|| self.platform_profile.clone()),
+            kbd_backlight_pct: other.kbd_backlight_pct.or(self.kbd_backlight_pct),
+            display_brightness_max_pct: min(self.display_brightness_max_pct, other.display_brightness_max_pct),
+            intel_pstate: match (&self.intel_pstate, &other.intel_pstate) {
+                (Some(a), Some(b)) => Some(a.constrain(b)),
+                (a, b) => b.clone().or_else(|| a.clone()),
+            },
+            hwp: match (&self.hwp, &other.hwp) {
+                (Some(a), Some(b)) => Some(a.constrain(b)),
+                (a, b) => b.clone().or_else(|| a.clone()),
+            },
+        }
+    }
+
+    /// Fills in anything that isn't set here from `defaults`, e.g. a preset.
+    fn or(&self, defaults: &ModeConfig) -> ModeConfig {
+        ModeConfig {
+            preset: self.preset.clone().or_else(|| defaults.preset.clone()),
+            update_rate_sec: self.update_rate_sec.or(defaults.update_rate_sec),
+            pl1_tdp_w: self.pl1_tdp_w.or(defaults.pl1_tdp_w),
+            pl1_duration: self.pl1_duration.or(defaults.pl1_duration),
+            pl2_tdp_w: self.pl2_tdp_w.or(defaults.pl2_tdp_w),
+            pl2_duration: self.pl2_duration.or(defaults.pl2_duration),
+            pl2_factor: self.pl2_factor.or(defaults.pl2_factor),
+            pl1_factor: self.pl1_factor.or(defaults.pl1_factor),
+            gpu_pl_w: self.gpu_pl_w.or(defaults.gpu_pl_w),
+            maximum_temp_c: self.maximum_temp_c.or(defaults.maximum_temp_c),
+            hwp_mode: self.hwp_mode.or(defaults.hwp_mode),
+            turbo_enabled: self.turbo_enabled.or(defaults.turbo_enabled),
+            conservation_mode: self.conservation_mode.or(defaults.conservation_mode),
+            rapid_charge: self.rapid_charge.or(defaults.rapid_charge),
+            charge_start_threshold: self.charge_start_threshold.or(defaults.charge_start_threshold),
+            charge_stop_threshold: self.charge_stop_threshold.or(defaults.charge_stop_threshold),
+            pcie_aspm_policy: self.pcie_aspm_policy.or(defaults.pcie_aspm_policy),
+            sata_link_policy: self.sata_link_policy.or(defaults.sata_link_policy),
+            platform_profile: self.platform_profile.clone().or_else(|| defaults.platform_profile.clone()),
+            kbd_backlight_pct: self.kbd_backlight_pct.or(defaults.kbd_backlight_pct),
+            display_brightness_max_pct: self.display_brightness_max_pct.or(defaults.display_brightness_max_pct),
+            intel_pstate: self.intel_pstate.clone().or_else(|| defaults.intel_pstate.clone()),
+            hwp: self.hwp.clone().or_else(|| defaults.hwp.clone()),
+        }
+    }
+
+    /// Checks `pl1_factor` and `pl2_factor` against the limits set in the same section. A factor
+    /// can't be given along with the limit it sets, and has to keep PL2 at or above PL1.
+    fn check_power_factors(&self) -> Result<(), Error> {
+        match (self.pl1_factor, self.pl2_factor) {
+            (Some(_), Some(_)) => bail!("pl1_factor and pl2_factor can't both be set"),
+            (Some(_), None) if self.pl1_tdp_w.is_some() => bail!("pl1_tdp_w and pl1_factor can't both be set"),
+            (None, Some(_)) if self.pl2_tdp_w.is_some() => bail!("pl2_tdp_w and pl2_factor can't both be set"),
+            (Some(f), None) if !(f > 0.0 && f <= 1.0) => {
+                bail!("pl1_factor must be above 0 and at most 1, not {}, since PL1 is never reached with PL2 below it", f)
+            },
+            (None, Some(f)) if !(f >= 1.0 && f.is_finite()) => {
+                bail!("pl2_factor must be at least 1, not {}, since PL1 is never reached with PL2 below it", f)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the backlight settings and charge thresholds are percentages, and that
+    /// charging starts below where it stops.
+    fn check_percentages(&self) -> Result<(), Error> {
+        for &(name, pct) in [("kbd_backlight_pct", self.kbd_backlight_pct),
+                             ("display_brightness_max_pct", self.display_brightness_max_pct),
+                             ("hwp.min_perf_pct", self.hwp.as_ref().and_then(|h| h.min_perf_pct)),
+                             ("charge_start_threshold", self.charge_start_threshold),
+                             ("charge_stop_threshold", self.charge_stop_threshold)].iter() {
+            if let Some(pct) = pct {
+                if pct > 100 {
+                    bail!("{} must be at most 100, not {}", name, pct);
+                }
+            }
+        }
+        if let (Some(start), Some(stop)) = (self.charge_start_threshold, self.charge_stop_threshold) {
+            if start >= stop {
+                bail!("charge_start_threshold ({}) must be below charge_stop_threshold ({})", start, stop);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets PL1 or PL2 from the other one, if a factor is given.
+    fn derive_power_limits(&mut self) -> Result<(), Error> {
+        if let Some(f) = self.pl2_factor {
+            match self.pl1_tdp_w {
+                Some(pl1) => self.pl2_tdp_w = Some((pl1 as f64 * f).round() as u64),
+                None => bail!("pl2_factor is set, but there's no pl1_tdp_w to derive PL2 from"),
+            }
+        }
+        if let Some(f) = self.pl1_factor {
+            match self.pl2_tdp_w {
+                Some(pl2) => self.pl1_tdp_w = Some((pl2 as f64 * f).round() as u64),
+                None => bail!("pl1_factor is set, but there's no pl2_tdp_w to derive PL1 from"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a named profile's own settings over those of the profile it extends. Factors are
+    /// relative to the limits of the profile they're set in, so the parent's aren't inherited
+    /// (the limits they derived are), and neither is a limit that one of this profile's factors
+    /// derives.
+    fn extend(&self, section: &str, parent: &ModeConfig) -> Result<ModeConfig, Error> {
+        let mut conf = self.clone();
+        conf.check_power_factors()?;
+        conf.resolve_preset(section)?;
+
+        let mut parent = ModeConfig { preset: None, pl1_factor: None, pl2_factor: None, ..parent.clone() };
+        if conf.pl1_factor.is_some() {
+            parent.pl1_tdp_w = None;
+        }
+        if conf.pl2_factor.is_some() {
+            parent.pl2_tdp_w = None;
+        }
+        conf = conf.or(&parent);
+
+        conf.derive_power_limits()?;
+        if let Some(ref p) = conf.intel_pstate {
+            p.validate()?;
+        }
+        conf.check_percentages()?;
+        Ok(conf)
+    }
+
+    /// Replaces a `preset` with its settings for this CPU.
+    fn resolve_preset(&mut self, section: &str) -> Result<(), Error> {
+        let name = match self.preset {
+            Some(ref name) => name.clone(),
+            None => return Ok(()),
+        };
+        let preset = match presets::find(&name) {
+            Some(p) => p,
+            None => {
+                let names: Vec<_> = presets::PRESETS.iter().map(|p| p.name).collect();
+                bail!("[{}]: unknown preset {:?}; the presets are {}", section, name, names.join(", "));
+            },
+        };
+
+        let (tdp, _) = presets::tdp_w();
+        *self = self.or(&preset.resolve(tdp));
+        Ok(())
+    }
+}
+
+// Hardware P-state request settings to set along with a profile.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct HwpConfig {
+    /// Energy/performance preference, from 0 (performance) to 255 (energy saving).
+    epp: Option<u8>,
+
+    /// Lowest performance to request, as a percentage of the highest, so that clocks don't dip
+    /// while the CPU is mostly idle. Profiles that don't set it go back to the lowest level.
+    min_perf_pct: Option<u8>,
+
+    /// Whether to write the package-level request, each CPU's request, or pick automatically.
+    scope: Option<hwp::RequestScope>,
+}
+
+impl HwpConfig {
+    /// Layers another configuration over this one; settings are taken from `other` if set.
+    fn constrain(&self, other: &HwpConfig) -> HwpConfig {
+        HwpConfig {
+            epp: other.epp.or(self.epp),
+            min_perf_pct: other.min_perf_pct.or(self.min_perf_pct),
+            scope: other.scope.or(self.scope),
+        }
+    }
+}
+
+// intel_pstate parameters to set along with a profile.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct IntelPstateConfig {
+    /// Operation mode of the driver.
+    status: Option<pstate::Status>,
+
+    /// Lower limit on P-states, as a percentage of the maximum.
+    min_perf_pct: Option<u8>,
+    /// Upper limit on P-states, as a percentage of the maximum.
+    max_perf_pct: Option<u8>,
+
+    /// Whether to boost performance after I/O waits (active mode with HWP only).
+    hwp_dynamic_boost: Option<bool>,
+}
+
+impl IntelPstateConfig {
+    /// Layers another configuration over this one; settings are taken from `other` if set.
+    fn constrain(&self, other: &IntelPstateConfig) -> IntelPstateConfig {
+        IntelPstateConfig {
+            status: other.status.or(self.status),
+            min_perf_pct: other.min_perf_pct.or(self.min_perf_pct),
+            max_perf_pct: other.max_perf_pct.or(self.max_perf_pct),
+            hwp_dynamic_boost: other.hwp_dynamic_boost.or(self.hwp_dynamic_boost),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for pct in self.min_perf_pct.iter().chain(self.max_perf_pct.iter()) {
+            if *pct > 100 {
+                bail!("intel_pstate: {}% is not a valid percentage", pct);
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_perf_pct, self.max_perf_pct) {
+            if min > max {
+                bail!("intel_pstate: min_perf_pct ({}) is above max_perf_pct ({})", min, max);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Constraints that apply while on battery and below a given charge level.
+#[derive(Deserialize, Debug, Clone)]
+struct BatteryLevelConfig {
+    /// Battery percentage below which these constraints apply.
+    below_percent: u8,
+
+    /// Constraints to layer over the battery configuration.
+    #[serde(flatten)]
+    constraints: ModeConfig,
+}
+
+// Constraints that apply while on AC with a charger that supplies less than a given power.
+#[derive(Deserialize, Debug, Clone)]
+struct ChargerLevelConfig {
+    /// Charger power, in Watts, below which these constraints apply.
+    below_w: u64,
+
+    /// Constraints to layer over the AC configuration.
+    #[serde(flatten)]
+    constraints: ModeConfig,
+}
+
+// A named profile, built on top of another one.
+#[derive(Deserialize, Debug, Clone)]
+struct NamedProfileConfig {
+    /// Profile to start from: "ac", "battery" or another named profile. It's cleared once the
+    /// profile has been resolved, since the settings it adds are then included.
+    extends: Option<String>,
+
+    /// Settings that this profile adds or changes.
+    #[serde(flatten)]
+    conf: ModeConfig,
+}
+
+// Constraints that apply on battery once the battery is worn, since a worn battery's voltage can
+// sag far enough under a PL2 burst to shut the machine off.
+#[derive(Deserialize, Debug, Clone)]
+struct BatteryWearConfig {
+    /// Full charge capacity, as a percentage of the design capacity, below which the battery
+    /// counts as worn.
+    below_health_percent: Option<u8>,
+    /// Charge cycle count above which the battery counts as worn.
+    above_cycles: Option<u32>,
+
+    /// Constraints to layer over the battery configuration.
+    #[serde(flatten)]
+    constraints: ModeConfig,
+}
+
+impl BatteryWearConfig {
+    /// Returns whether a battery in the given health counts as worn.
+    fn is_worn(&self, health: &power::BatteryHealth) -> bool {
+        self.below_health_percent.is_some_and(|pct| health.health_pct < pct as f64)
+            || self.above_cycles.is_some_and(|max| health.cycles.is_some_and(|c| c > max))
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.below_health_percent.is_none() && self.above_cycles.is_none() {
+            bail!("battery_wear: below_health_percent or above_cycles must be set");
+        }
+        if self.below_health_percent.is_some_and(|pct| pct > 100) {
+            bail!("battery_wear: below_health_percent must be at most 100");
+        }
+        Ok(())
+    }
+}
+
+// Settings for stepping PL1 down while the battery discharges faster than a threshold, which can
+// happen under load even on AC with a weak (e.g. USB-C PD) charger.
+#[derive(Deserialize, Debug, Clone)]
+struct DischargeGuardConfig {
+    /// Discharge rate, in Watts, above which PL1 is lowered.
+    max_discharge_w: f64,
+
+    /// How much to lower (or raise) PL1 by at each step, in Watts.
+    #[serde(default = "default_discharge_step_w")]
+    step_w: u64,
+
+    /// PL1 will never be lowered below this, in Watts.
+    #[serde(default = "default_discharge_min_pl1_w")]
+    min_pl1_w: u64,
+
+    /// How often to sample the discharge rate, in seconds.
+    #[serde(default = "default_discharge_interval_sec", deserialize_with = "duration::secs")]
+    interval_sec: u64,
+}
+
+fn default_discharge_step_w() -> u64 { 2 }
+fn default_discharge_min_pl1_w() -> u64 { 8 }
+fn default_discharge_interval_sec() -> u64 { 5 }
+
+// Settings for stepping the power and temperature limits to a new profile's over a few seconds,
+// rather than all at once.
+#[derive(Deserialize, Debug, Clone)]
+struct RampConfig {
+    /// How long to take when the limits go down, e.g. when unplugging, in seconds.
+    #[serde(default, deserialize_with = "duration::secs_f64")]
+    down_sec: f64,
+
+    /// How long to take when the limits go up, in seconds.
+    #[serde(default, deserialize_with = "duration::secs_f64")]
+    up_sec: f64,
+}
+
+impl RampConfig {
+    fn validate(&self) -> Result<(), Error> {
+        for &(name, secs) in [("down_sec", self.down_sec), ("up_sec", self.up_sec)].iter() {
+            if !(0.0..=30.0).contains(&secs) {
+                bail!("ramp.{} must be between 0 and 30 seconds, not {}", name, secs);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how long to take going from `from` to `to`.
+    fn duration(&self, from: &ModeConfig, to: &ModeConfig) -> Duration {
+        let secs = if ramp::Ramp::is_down(from, to) { self.down_sec } else { self.up_sec };
+        Duration::from_secs_f64(secs)
+    }
+}
+
+// Settings for adapting PL2 to recent package power: a cold heatsink can absorb a longer, higher
+// burst than a warm one. PL2 is only changed in profiles that set pl2_duration.
+#[derive(Deserialize, Debug, Clone)]
+struct BurstBudgetConfig {
+    /// PL2 to use after the system has been mostly idle, in Watts.
+    boost_pl2_w: u64,
+
+    /// PL2 to use after sustained load, in Watts.
+    sustained_pl2_w: u64,
+
+    /// Average package power, in Watts, below which the system counts as idle.
+    #[serde(default = "default_burst_cool_below_w")]
+    cool_below_w: f64,
+
+    /// Average package power, in Watts, above which the system counts as under sustained load.
+    #[serde(default = "default_burst_hot_above_w")]
+    hot_above_w: f64,
+
+    /// Time constant of the moving average, in seconds.
+    #[serde(default = "default_burst_window_sec", deserialize_with = "duration::secs")]
+    window_sec: u64,
+
+    /// How often to sample the package power, in seconds.
+    #[serde(default = "default_burst_interval_sec", deserialize_with = "duration::secs")]
+    interval_sec: u64,
+}
+
+fn default_burst_cool_below_w() -> f64 { 8.0 }
+fn default_burst_hot_above_w() -> f64 { 20.0 }
+fn default_burst_window_sec() -> u64 { 60 }
+fn default_burst_interval_sec() -> u64 { 2 }
+
+impl BurstBudgetConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.sustained_pl2_w > self.boost_pl2_w {
+            bail!("burst_budget: sustained_pl2_w ({} W) is above boost_pl2_w ({} W)",
+                  self.sustained_pl2_w, self.boost_pl2_w);
+        }
+        if self.cool_below_w >= self.hot_above_w {
+            bail!("burst_budget: cool_below_w ({} W) must be below hot_above_w ({} W)",
+                  self.cool_below_w, self.hot_above_w);
+        }
+        if self.window_sec == 0 || self.interval_sec == 0 {
+            bail!("burst_budget: window_sec and interval_sec must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+// Constraints that are layered over the active profile, whichever it is, once the CPU has been
+// hotter than a threshold for a while, and lifted once it's been cooler than a lower one for a
+// while, e.g. for machines whose fan can't keep up with the AC profile in a warm room.
+#[derive(Deserialize, Debug, Clone)]
+struct EmergencyConfig {
+    /// Hottest package or core temperature, in degrees C, at or above which the clamp is applied.
+    enter_temp_c: u64,
+    /// How long the temperature has to stay there first, in seconds.
+    #[serde(default = "default_emergency_enter_sec", deserialize_with = "duration::secs_f64")]
+    enter_sec: f64,
+
+    /// Temperature at or below which the clamp is lifted.
+    exit_temp_c: u64,
+    /// How long the temperature has to stay there first, in seconds.
+    #[serde(default = "default_emergency_exit_sec", deserialize_with = "duration::secs_f64")]
+    exit_sec: f64,
+
+    /// How often to sample the temperature, in seconds.
+    #[serde(default = "default_emergency_interval_sec", deserialize_with = "duration::secs_f64")]
+    interval_sec: f64,
+
+    /// Constraints to layer over the active profile.
+    #[serde(flatten)]
+    constraints: ModeConfig,
+}
+
+fn default_emergency_enter_sec() -> f64 { 3.0 }
+fn default_emergency_exit_sec() -> f64 { 30.0 }
+fn default_emergency_interval_sec() -> f64 { 1.0 }
+
+impl EmergencyConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.exit_temp_c >= self.enter_temp_c {
+            bail!("emergency: exit_temp_c ({} C) must be below enter_temp_c ({} C)",
+                  self.exit_temp_c, self.enter_temp_c);
+        }
+        if self.enter_sec < 0.0 || self.exit_sec < 0.0 {
+            bail!("emergency: enter_sec and exit_sec can't be negative");
+        }
+        if self.interval_sec < 0.1 {
+            bail!("emergency: interval_sec must be at least 100 ms");
+        }
+        Ok(())
+    }
+}
+
+// Settings for following changes to the ACPI platform profile, so that picking a power mode in the
+// desktop (or with Fn+L/M/H) also picks the matching profile here.
+#[derive(Deserialize, Debug, Clone)]
+struct FollowPlatformProfileConfig {
+    /// Platform profiles that force the battery profile.
+    #[serde(default)]
+    battery: Vec<String>,
+
+    /// Platform profiles that force the AC profile.
+    #[serde(default)]
+    ac: Vec<String>,
+}
+
+impl FollowPlatformProfileConfig {
+    /// Returns the profile to force for a platform profile, or `None` to return to automatic
+    /// selection.
+    fn profile_for(&self, platform_profile: &str) -> Option<rules::Profile> {
+        if self.battery.iter().any(|p| p == platform_profile) {
+            Some(rules::Profile::Battery)
+        } else if self.ac.iter().any(|p| p == platform_profile) {
+            Some(rules::Profile::Ac)
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(p) = self.battery.iter().find(|p| self.ac.contains(p)) {
+            bail!("follow_platform_profile: {:?} is listed for both battery and ac", p);
+        }
+        Ok(())
+    }
+}
+
+// Settings for deferring periodic reapplication, and backing off the timer's wakeups, while the
+// system is idle. Firmware only tends to reset the power limits under load, so waking up to
+// rewrite them on an idle system just costs battery.
+#[derive(Deserialize, Debug, Clone)]
+struct IdleConfig {
+    /// Overall CPU utilisation, in percent, below which the system is considered idle.
+    #[serde(default = "default_idle_busy_percent")]
+    busy_percent: f64,
+
+    /// How late the timer is allowed to fire, in milliseconds, so that the kernel can batch our
+    /// wakeups with others.
+    #[serde(default = "default_idle_timer_slack_ms", deserialize_with = "duration::millis")]
+    timer_slack_ms: u64,
+
+    /// Longest that the timer's period grows to, in seconds. Each tick that finds the system idle
+    /// doubles it, and the first busy one puts it back to the update rate.
+    #[serde(default = "default_idle_max_period_sec", deserialize_with = "duration::secs")]
+    max_period_sec: u64,
+}
+
+fn default_idle_busy_percent() -> f64 { 5.0 }
+fn default_idle_timer_slack_ms() -> u64 { 1000 }
+fn default_idle_max_period_sec() -> u64 { 300 }
+
+// Settings for the interfaces that clients can use to query and control the daemon.
+#[derive(Deserialize, Debug, Clone)]
+struct ControlConfig {
+    /// Whether to serve the D-Bus interface on the system bus. Defaults to whether it was built in.
+    #[serde(default = "default_control_dbus")]
+    dbus: bool,
+
+    /// Path of a Unix socket to serve the JSON-RPC interface on, if any.
+    socket: Option<String>,
+
+    /// Address to serve Prometheus metrics on, if any.
+    metrics: Option<String>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> ControlConfig {
+        ControlConfig {
+            dbus: default_control_dbus(),
+            socket: None,
+            metrics: None,
+        }
+    }
+}
+
+fn default_control_dbus() -> bool { cfg!(feature = "dbus") }
+
+// Settings for sampling the sticky log bits of IA32_PACKAGE_THERM_STATUS.
+#[derive(Deserialize, Debug, Clone)]
+struct ThermLogConfig {
+    /// How often to sample the log bits, in seconds.
+    #[serde(default = "default_therm_log_interval_sec", deserialize_with = "duration::secs")]
+    interval_sec: u64,
+
+    /// Whether to clear the log bits after each sample. Without this, repeated episodes of the
+    /// same kind can't be told apart.
+    #[serde(default = "default_therm_log_clear")]
+    clear: bool,
+}
+
+fn default_therm_log_interval_sec() -> u64 { 10 }
+fn default_therm_log_clear() -> bool { true }
+
+// Settings for sampling the CPU load, which `hwp_mode` and load rules use. The defaults are used
+// if either is configured without this section.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+struct LoadConfig {
+    /// How often to sample /proc/stat, in seconds.
+    #[serde(default = "default_load_interval_sec", deserialize_with = "duration::secs_f64")]
+    interval_sec: f64,
+
+    /// How quickly the smoothed load follows the samples: a sample's weight decays by a factor of e
+    /// over this many seconds.
+    #[serde(default = "default_load_window_sec", deserialize_with = "duration::secs_f64")]
+    window_sec: f64,
+
+    /// Smoothed load, in percent, above which `hwp_mode` asks for performance. It goes back once the
+    /// load drops 10 points below this.
+    #[serde(default = "default_load_high_percent")]
+    high_percent: f64,
+}
+
+fn default_load_interval_sec() -> f64 { 2.0 }
+fn default_load_window_sec() -> f64 { 10.0 }
+fn default_load_high_percent() -> f64 { 70.0 }
+
+impl Default for LoadConfig {
+    fn default() -> LoadConfig {
+        LoadConfig {
+            interval_sec: default_load_interval_sec(),
+            window_sec: default_load_window_sec(),
+            high_percent: default_load_high_percent(),
+        }
+    }
+}
+
+impl LoadConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if !(0.1..=60.0).contains(&self.interval_sec) {
+            bail!("load: interval_sec must be between 100 ms and 60 s");
+        }
+        if !(0.0..=600.0).contains(&self.window_sec) {
+            bail!("load: window_sec must be at most 10 minutes");
+        }
+        if !(10.0..=100.0).contains(&self.high_percent) {
+            bail!("load: high_percent must be between 10 and 100");
+        }
+        Ok(())
+    }
+}
+
+// Settings for sampling the effective frequency, which is reported to clients.
+#[derive(Deserialize, Debug, Clone)]
+struct EffectiveFrequencyConfig {
+    /// How often to sample the APERF/MPERF counters, in seconds.
+    #[serde(default = "default_effective_frequency_interval_sec", deserialize_with = "duration::secs")]
+    interval_sec: u64,
+}
+
+fn default_effective_frequency_interval_sec() -> u64 { 5 }
+
+// Settings for counting the energy used by each profile, which is reported to clients.
+#[derive(Deserialize, Debug, Clone)]
+struct EnergyConfig {
+    /// How often to sample the package energy counter, in seconds.
+    #[serde(default = "default_energy_interval_sec", deserialize_with = "duration::secs")]
+    interval_sec: u64,
+}
+
+fn default_energy_interval_sec() -> u64 { 10 }
+
+// An arbitrary masked MSR write, for registers that aren't otherwise supported. The numbers may be
+// given as strings (e.g. "0x1FC"), since TOML has no hex literals and can't represent values with
+// the top bit set.
+#[derive(Deserialize, Debug, Clone)]
+struct CustomMsrConfig {
+    /// Address of the MSR.
+    #[serde(deserialize_with = "deserialize_u64")]
+    msr: u64,
+
+    /// Bits to change; all others are preserved.
+    #[serde(deserialize_with = "deserialize_u64")]
+    mask: u64,
+
+    /// New value of the bits in `mask`, in place (i.e. not shifted down).
+    #[serde(deserialize_with = "deserialize_u64")]
+    value: u64,
+
+    /// Which CPUs to write to.
+    #[serde(default)]
+    scope: msr::Scope,
+}
+
+impl CustomMsrConfig {
+    /// Checks that the mask and value make sense together.
+    fn validate(&self) -> Result<(), Error> {
+        if self.mask == 0 {
+            bail!("custom MSR {:#x}: mask is zero, so nothing would be written", self.msr);
+        }
+        if self.value & !self.mask != 0 {
+            bail!("custom MSR {:#x}: value {:#x} has bits set outside of mask {:#x} (bits {:#x}); \
+                   note that the value is not shifted into place",
+                  self.msr, self.value, self.mask, self.value & !self.mask);
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes a u64 from either an integer or a (decimal or 0x-prefixed hex) string.
+fn deserialize_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    struct U64Visitor;
+
+    impl<'de> Visitor<'de> for U64Visitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a non-negative integer, or a string containing one")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<u64, E> {
+            Ok(v)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<u64, E> {
+            if v < 0 {
+                return Err(E::custom(format!("{} is negative", v)));
+            }
+            Ok(v as u64)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<u64, E> {
+            let v = v.trim().replace('_', "");
+            let res = match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => v.parse::<u64>(),
+            };
+            res.map_err(|e| E::custom(format!("invalid number {:?}: {}", v, e)))
+        }
+    }
+
+    deserializer.deserialize_any(U64Visitor)
+}
+
+/// A single setting to apply when switching to a power configuration.
+#[derive(Debug, Clone)]
+enum Update {
+    /// Write a value to a MSR on every CPU in its scope (see `msr::scope_of`).
+    Msr(u64, u64),
+    /// Write a value to a sysfs attribute.
+    Sysfs(String, String),
+    /// Read-modify-write the given (mask, value) bits of a MSR on the CPUs in the scope.
+    MaskedMsr(u64, u64, u64, msr::Scope),
+    /// Write a value to the MCHBAR mirror of MSR_PKG_POWER_LIMIT.
+    Mchbar(u64),
+    /// Lower a numeric sysfs attribute to at most the given value, leaving it if it's already lower.
+    SysfsMax(String, u64),
+}
+
+impl Update {
+    /// Returns whether this update writes a MSR.
+    fn is_msr(&self) -> bool {
+        match *self {
+            Update::Msr(..) | Update::MaskedMsr(..) => true,
+            Update::Sysfs(..) | Update::SysfsMax(..) | Update::Mchbar(..) => false,
+        }
+    }
+
+    /// Applies the part of this update that's specific to a single CPU, e.g. one that has just
+    /// come online. Returns `None` if there's nothing CPU-specific to apply.
+    fn apply_to_cpu(&self, cpu: usize) -> Option<io::Result<()>> {
+        let (res, msr) = match *self {
+            // A package-scoped MSR only needs writing if the CPU is the first of a package that
+            // has just come online.
+            Update::Msr(msr, value) if msr::scope_of(msr).cpus().contains(&cpu) =>
+                (msr::WriteMsrBuilder::new(msr, value).write_one(cpu), msr),
+            Update::MaskedMsr(msr, mask, value, scope) if scope.cpus().contains(&cpu) =>
+                (msr::update_masked_one(cpu, msr, mask, value), msr),
+            Update::Msr(..) | Update::MaskedMsr(..) | Update::Sysfs(..) | Update::SysfsMax(..) |
+            Update::Mchbar(..) => return None,
+        };
+
+        if let Err(ref e) = res {
+            eprintln!("error writing {} on cpu {}: {}", msr::Name(msr), cpu, e);
+        }
+        Some(res)
+    }
+
+    fn apply(&self) -> io::Result<()> {
+        match *self {
+            Update::Msr(msr, value) => {
+                let res = msr::WriteMsrBuilder::new(msr, value).write();
+                match res {
+                    Err(ref e) => ratelimit::eprintln(format!("error writing {}: {}", msr::Name(msr), e)),
+                    Ok(_) => eprintln!("set {} successfully", msr::Name(msr)),
+                }
+                if res.is_ok() && msr == rapl::MSR_PKG_POWER_LIMIT {
+                    verify_power_limit(value);
+                }
+                res
+            },
+            Update::MaskedMsr(msr, mask, value, scope) => {
+                let res = msr::update_masked(msr, mask, value, scope);
+                match res {
+                    Err(ref e) => ratelimit::eprintln(format!("error writing {}: {}", msr::Name(msr), e)),
+                    Ok(_) => eprintln!("set {} bits {:#x} successfully", msr::Name(msr), mask),
+                }
+                res
+            },
+            Update::Sysfs(ref path, ref value) => {
+                let res = sysfs::write_value(path, value);
+                match res {
+                    Err(ref e) => ratelimit::eprintln(format!("error writing {}: {}", path, e)),
+                    Ok(_) => eprintln!("set {} successfully", path),
+                }
+                res
+            },
+            Update::SysfsMax(ref path, max) => {
+                let res = sysfs::read_value(path).and_then(|current| match current.parse::<u64>() {
+                    Ok(v) if v <= max => Ok(()),
+                    _ => sysfs::write_value(path, &max.to_string()),
+                });
+                match res {
+                    Err(ref e) => ratelimit::eprintln(format!("error writing {}: {}", path, e)),
+                    Ok(_) => eprintln!("capped {} at {} successfully", path, max),
+                }
+                res
+            },
+            Update::Mchbar(value) => {
+                let res = mchbar::write_power_limit(value);
+                match res {
+                    Err(ref e) => ratelimit::eprintln(format!("error writing the MCHBAR power limit: {}", e)),
+                    Ok(_) => eprintln!("set the MCHBAR power limit successfully"),
+                }
+                res
+            },
+        }
+    }
+}
+
+/// Reads back MSR_PKG_POWER_LIMIT after writing it, since the hardware can ignore parts of a write
+/// without reporting an error.
+fn verify_power_limit(written: u64) {
+    let read = match msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first() {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    match rapl::check_readback(written, read) {
+        rapl::Readback::Matches => {},
+        rapl::Readback::WindowsLocked => {
+            ratelimit::eprintln("MSR_PKG_POWER_LIMIT time windows are fixed by the hardware; \
+                                 pl1_duration and pl2_duration will be ignored, but the power limits still apply".to_string());
+        },
+        rapl::Readback::Mismatch(bits) => {
+            ratelimit::eprintln(format!("WARNING: MSR_PKG_POWER_LIMIT ignored part of the write (bits {:#x}); is it locked?", bits));
+        },
+    }
+}
+
+
+/// Runs the startup checks, returning the exit code to fail with if one doesn't pass.
+fn check_access() -> Result<(), ExitCode> {
+    #[cfg(feature = "sim")]
+    let simulated = sim::is_active();
+    #[cfg(not(feature = "sim"))]
+    let simulated = false;
+
+    // A simulated machine is an Intel one, whatever the tests are running on.
+    if !simulated {
+        if let Err(e) = preflight::check_cpu() {
+            eprintln!("{}", e);
+            return Err(ExitCode::UnsupportedCpu);
+        }
+    }
+    if let Err(e) = preflight::check() {
+        eprintln!("{}", e);
+        return Err(ExitCode::NoMsrAccess);
+    }
+    Ok(())
+}
+
+/// Entry point for the `lenovo-throttling-rust` binary, returning the code to exit with.
+pub fn run() -> ExitCode {
+    let args = paths::init(env::args().skip(1).collect());
+
+    match args.first().map(|a| a.as_str()) {
+        Some("status") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            if let Err(e) = status::run() {
+                eprintln!("error reading status: {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        #[cfg(feature = "dbus")]
+        Some("monitor") => {
+            // Deliberately no check_access(): this only reads, and is meant to run unprivileged.
+            let opts = match monitor::Options::parse(args.into_iter().skip(1)) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::Usage;
+                },
+            };
+            if let Err(e) = monitor::run(&opts) {
+                eprintln!("{}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        #[cfg(feature = "dbus")]
+        Some("travel") => {
+            let enabled = match args.get(1).map(|a| a.as_str()) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    eprintln!("usage: travel <on|off>");
+                    return ExitCode::Usage;
+                },
+            };
+            if let Err(e) = bus::set_travel(enabled) {
+                eprintln!("error switching travel mode (is the daemon running?): {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        #[cfg(not(feature = "dbus"))]
+        Some(command @ "monitor") | Some(command @ "travel") => {
+            eprintln!("{} needs D-Bus support, which this build doesn't include (the \"dbus\" feature)", command);
+            return ExitCode::Usage;
+        },
+        Some("setup") => {
+            if let Err(e) = setup::run() {
+                eprintln!("error running setup: {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        Some("check-config") => {
+            let config = match read_config() {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("error reading config: {}", e);
+                    return ExitCode::Config;
+                },
+            };
+            let lints = lint::check(&config);
+            for l in lints.iter() {
+                l.report();
+            }
+            if !config.profile_errors.is_empty() {
+                return ExitCode::Config;
+            }
+            if lints.is_empty() {
+                println!("{} looks good", paths::get().config);
+            }
+            return ExitCode::Success;
+        },
+        Some("--dump-msrs") => {
+            // Dump whatever can be read, even without MSR access.
+            if let Err(e) = preflight::check() {
+                eprintln!("{}", e);
+            }
+            dump::run();
+            return ExitCode::Success;
+        },
+        Some("list-presets") => {
+            presets::list();
+            return ExitCode::Success;
+        },
+        Some("apply-preset") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            let preset = match args.get(1).and_then(|n| presets::find(n)) {
+                Some(p) => p,
+                None => {
+                    eprintln!("usage: apply-preset <name>; see list-presets for the names");
+                    return ExitCode::Usage;
+                },
+            };
+            let (tdp, _) = presets::tdp_w();
+            let failed = match build_updates(&preset.resolve(tdp)) {
+                Ok(updates) => updates.iter().filter(|u| u.apply().is_err()).count(),
+                Err(e) => {
+                    eprintln!("error building updates: {}", e);
+                    return ExitCode::Failure;
+                },
+            };
+            if failed > 0 {
+                return ExitCode::WriteFailure;
+            }
+            println!("applied preset {}; the daemon will replace it with the configured profile when it next applies", preset.name);
+            return ExitCode::Success;
+        },
+        Some("set") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            let opts = match transient::Options::parse(args.into_iter().skip(1)) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::Usage;
+                },
+            };
+            if let Err(e) = transient::run(&opts) {
+                eprintln!("error setting limits: {}", e);
+                return ExitCode::WriteFailure;
+            }
+            return ExitCode::Success;
+        },
+        Some("bench") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            let opts = match bench::Options::parse(args.into_iter().skip(1)) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::Usage;
+                },
+            };
+            let mut config = match read_config() {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("error reading config: {}", e);
+                    return ExitCode::Config;
+                },
+            };
+            if let Some(q) = quirks::detect() {
+                apply_quirk(q, &mut config);
+            }
+            if let Err(e) = bench::run(&config, &opts) {
+                eprintln!("error running benchmark: {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        Some("stress-verify") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            let opts = match stress::Options::parse(args.into_iter().skip(1)) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::Usage;
+                },
+            };
+            if let Err(e) = stress::run(&opts) {
+                eprintln!("error verifying the undervolt: {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        Some("why-throttle") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            if let Err(e) = why::run() {
+                eprintln!("error sampling: {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        Some("explain") => {
+            if let Err(e) = explain::run(args.into_iter().skip(1)) {
+                eprintln!("{}", e);
+                return ExitCode::Usage;
+            }
+            return ExitCode::Success;
+        },
+        Some("export-profile") => {
+            if let Err(code) = check_access() {
+                return code;
+            }
+            let opts = match export::Options::parse(args.into_iter().skip(1)) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::Usage;
+                },
+            };
+            if let Err(e) = export::run(&opts) {
+                eprintln!("error exporting profile: {}", e);
+                return ExitCode::Failure;
+            }
+            return ExitCode::Success;
+        },
+        Some("completions") => {
+            match args.get(1).and_then(|s| completions::generate(s)) {
+                Some(c) => print!("{}", c),
+                None => {
+                    eprintln!("usage: completions <{}>", completions::SHELLS.join("|"));
+                    return ExitCode::Usage;
+                },
+            }
+            return ExitCode::Success;
+        },
+        Some("manpage") => {
+            print!("{}", completions::manpage());
+            return ExitCode::Success;
+        },
+        // Runs the daemon, below.
+        Some("--coexist") => {},
+        Some(other) => {
+            eprintln!("unknown command: {}", other);
+            return ExitCode::Usage;
+        },
+        None => {},
+    }
+    let coexist = args.first().is_some_and(|a| a == "--coexist");
+
+    if let Err(code) = check_access() {
+        return code;
+    }
+
+    if let Err(e) = runtime::prepare() {
+        eprintln!("error creating {}: {}", paths::get().runtime, e);
+    }
+    if let Err(e) = persist::prepare() {
+        eprintln!("error creating {}, forced profiles won't survive restarts: {}", paths::get().state, e);
+    }
+
+    // Split off the privileged helper before we parse anything.
+    if let Err(e) = privsep::start() {
+        eprintln!("error dropping privileges: {}", e);
+        return ExitCode::Failure;
+    }
+
+    let mut config = match read_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("error reading config: {}", e);
+            return ExitCode::Config;
+        },
+    };
+
+    // The config is trusted (it can already set arbitrary power limits), and nothing else has been
+    // looked at yet, so this is the point to tell the helper about any custom MSRs.
+    let custom_msrs: Vec<u64> = config.custom_msr.iter().map(|c| c.msr).collect();
+    if let Err(e) = privsep::allow_msrs(&custom_msrs) {
+        eprintln!("error allowing the custom MSRs: {}", e);
+        return ExitCode::Failure;
+    }
+
+    quirks::set_vendor(config.vendor);
+
+    // Before any threads are started, so that they all inherit it.
+    priority::apply(&config.priority);
+
+    for l in lint::check(&config) {
+        l.report();
+    }
+
+    let conflicts = conflicts::detect();
+    if !conflicts.is_empty() {
+        println!("WARNING: other tools that change the power limits were found:");
+        for c in conflicts.iter() {
+            c.report();
+        }
+        if conflicts.iter().any(|c| c.running) && !coexist {
+            eprintln!("refusing to start while another daemon rewrites the same registers; stop it, \
+                       or pass --coexist to run anyway");
+            return ExitCode::Conflict;
+        }
+    }
+
+    println!("config = {:?}", config);
+
+    if quirks::generic() {
+        println!("generic mode: the Lenovo model quirks and the MCHBAR mirror aren't used");
+    }
+    let quirk = quirks::detect();
+    if let Some(q) = quirk {
+        q.report();
+        apply_quirk(q, &mut config);
+    }
+
+    // All event sources feed into a single channel; signals need to be set up before anything
+    // else spawns a thread.
+    let (events_tx, events) = channel::unbounded();
+    daemon::handle_signals(events_tx.clone());
+    daemon::spawn_timer(events_tx.clone(), daemon::timer_period(&config), config.idle.clone());
+
+    let (initial, power_change) = match power::notify_on_power_change(config.power_latency_budget()) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("error reading power state: {}", e);
+            return ExitCode::Failure;
+        },
+    };
+    println!("initial power state is: {:?}", initial);
+    // Stamp power changes as they're noticed, so that the time spent applying them can be measured.
+    daemon::forward_required(power_change, events_tx.clone(), |state| daemon::Event::Power(state, Instant::now()),
+                             "power source");
+
+    // Only watch the battery level if there's something that depends on it.
+    let mut battery_level = None;
+    if !config.battery_levels.is_empty() || rules::needs_battery_level(&config.rules) {
+        match power::notify_on_battery_level() {
+            Ok((level, battery_change)) => {
+                battery_level = level;
+                daemon::forward(battery_change, events_tx.clone(), daemon::Event::BatteryLevel);
+            },
+            Err(e) => eprintln!("error reading battery level: {}", e),
+        }
+    }
+
+    if let Some(ref guard) = config.discharge_guard {
+        let interval = std::time::Duration::from_secs(guard.interval_sec);
+        let discharge = power::notify_on_discharge_rate(interval);
+        daemon::forward(discharge, events_tx.clone(), daemon::Event::Discharge);
+    }
+
+    if let Some(ref burst) = config.burst_budget {
+        match rapl::Units::read() {
+            Ok(units) => {
+                let interval = std::time::Duration::from_secs(burst.interval_sec);
+                let power = rapl::notify_on_package_power(interval, units);
+                daemon::forward(power, events_tx.clone(), daemon::Event::PackagePower);
+            },
+            Err(e) => eprintln!("error reading RAPL units, burst budget is disabled: {}", e),
+        }
+    }
+
+    if let Some(ref emergency) = config.emergency {
+        let temperature = temps::notify_on_temperature(Duration::from_secs_f64(emergency.interval_sec));
+        daemon::forward(temperature, events_tx.clone(), daemon::Event::Temperature);
+    }
+
+    if let Some(ref therm_log) = config.therm_log {
+        let interval = std::time::Duration::from_secs(therm_log.interval_sec);
+        let throttle = temps::notify_on_throttle(interval, therm_log.clear);
+        daemon::forward(throttle, events_tx.clone(), daemon::Event::Throttle);
+    }
+
+    if let Some(ref conf) = config.effective_frequency {
+        let interval = std::time::Duration::from_secs(conf.interval_sec);
+        let frequency = freq::notify_on_frequency(interval);
+        daemon::forward(frequency, events_tx.clone(), daemon::Event::Frequency);
+    }
+
+    if let Some(ref conf) = config.energy {
+        match rapl::Units::read() {
+            Ok(units) => {
+                let energy = rapl::notify_on_package_energy(std::time::Duration::from_secs(conf.interval_sec), units);
+                daemon::forward(energy, events_tx.clone(), |(joules, time)| daemon::Event::Energy(joules, time));
+            },
+            Err(e) => eprintln!("error reading RAPL units, energy accounting is disabled: {}", e),
+        }
+    }
+
+    // Enabling this needs a restart, like the other samplers.
+    if let Some(conf) = config.load() {
+        let load = load::notify_on_load(Duration::from_secs_f64(conf.interval_sec), Duration::from_secs_f64(conf.window_sec));
+        daemon::forward(load, events_tx.clone(), daemon::Event::Load);
+    }
+
+    // State that's shared with the D-Bus control interface.
+    let status = Arc::new(Mutex::new(control::Status::default()));
+    // D-Bus signals about the daemon, as opposed to the Unix ones above.
+    let (dbus_signals_tx, dbus_signals) = channel::unbounded();
+    if config.control.dbus {
+        #[cfg(feature = "dbus")]
+        {
+            // Running without the interface would be surprising, so fail in a way that lets the
+            // service manager retry once the bus is up.
+            if let Err(e) = bus::check_bus() {
+                eprintln!("error connecting to the system bus (set control.dbus = false to run without it): {}", e);
+                return ExitCode::DbusUnavailable;
+            }
+            daemon::forward(bus::serve(status.clone(), dbus_signals), events_tx.clone(), daemon::Event::Control);
+        }
+        #[cfg(not(feature = "dbus"))]
+        {
+            eprintln!("control.dbus is set, but this build doesn't include D-Bus support (the \"dbus\" feature)");
+            return ExitCode::Config;
+        }
+    } else {
+        // Otherwise the signals would pile up unread.
+        drop(dbus_signals);
+    }
+    if let Some(ref path) = config.control.socket {
+        daemon::forward(rpc::serve(path.clone(), status.clone()), events_tx.clone(), daemon::Event::Control);
+    }
+    if let Some(ref addr) = config.control.metrics {
+        // Not being able to serve metrics isn't fatal.
+        #[cfg(feature = "metrics")]
+        if let Err(e) = prometheus::serve(addr, status.clone()) {
+            eprintln!("error listening for metrics scrapes on {}: {}", addr, e);
+        }
+        #[cfg(not(feature = "metrics"))]
+        eprintln!("not serving metrics on {}: this build doesn't include the metrics server (the \"metrics\" feature)", addr);
+    }
+    if config.follow_platform_profile.is_some() {
+        match platform::notify_on_platform_profile() {
+            Ok(changes) => daemon::forward(changes, events_tx.clone(), daemon::Event::PlatformProfile),
+            Err(e) => eprintln!("error watching the platform profile, follow_platform_profile is disabled: {}", e),
+        }
+    }
+    match suspend::notify_on_resume() {
+        Ok(resumes) => daemon::forward(resumes, events_tx.clone(), daemon::Event::Resume),
+        Err(e) => eprintln!("error watching for resumes, a firmware lock after suspend won't be noticed: {}", e),
+    }
+    match hotplug::notify_on_cpu_online() {
+        Ok(online) => daemon::forward(online, events_tx.clone(), daemon::Event::CpuOnline),
+        Err(e) => eprintln!("error watching for CPU hotplug, new CPUs won't be updated until the next reapply: {}", e),
+    }
+    drop(events_tx);
+
+    topology::report();
+    let msr_caps = msr::Capabilities::probe();
+    msr_caps.report();
+    backends::reprobe(msr_caps);
+    backends::registry().report();
+    rapl::domains().report();
+
+    // Only while we write the MSR ourselves; otherwise, powercap is how we write the limits.
+    let writes_msr = backends::registry().best(Capability::PackagePowerLimit) == Some(Backend::Msr);
+    // Held until we return, however that happens, so that the control type is switched back on.
+    let _control_type = if config.powercap_sync == powercap::Sync::Disable && writes_msr && powercap::package_zone().is_some() {
+        match powercap::disable_control_type() {
+            Ok(guard) => {
+                println!("switched off the intel-rapl powercap control type");
+                Some(guard)
+            },
+            Err(e) => {
+                eprintln!("error switching off the intel-rapl powercap control type, \
+                           keeping its constraints up to date instead: {}", e);
+                None
+            },
+        }
+    } else {
+        None
+    };
+
+    let seccomp = config.seccomp;
+    let daemon = match daemon::Daemon::new(config, initial, battery_level, msr_caps, status, dbus_signals_tx) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("error building updates: {}", e);
+            let access = e.downcast_ref::<io::Error>().is_some_and(msr::is_access_error);
+            return if access { ExitCode::NoMsrAccess } else { ExitCode::Failure };
+        },
+    };
+
+    // Every thread has been started by now, though some (e.g. the D-Bus server and the UPower
+    // watcher) may still be connecting; the sandbox allows for that.
+    if seccomp {
+        match sandbox::install() {
+            Ok(_) => println!("seccomp sandbox installed"),
+            Err(e) => eprintln!("error installing seccomp sandbox, continuing without it: {}", e),
+        }
+    }
+
+    daemon.run(events)
+}
+
+/// Adjusts the configuration to respect the known limits of this model.
+fn apply_quirk(quirk: &quirks::Quirk, config: &mut Config) {
+    if let Some(max) = quirk.max_safe_pl2_w {
+        // Every section that can set PL2, including the named profiles, which have been resolved
+        // by now and so don't get it from the section they extend.
+        let confs = vec![&mut config.battery, &mut config.ac, &mut config.travel].into_iter()
+            .chain(config.profiles.values_mut().map(|p| &mut p.conf))
+            .chain(config.battery_levels.iter_mut().map(|l| &mut l.constraints))
+            .chain(config.charger_levels.iter_mut().map(|l| &mut l.constraints))
+            .chain(config.battery_wear.iter_mut().map(|w| &mut w.constraints))
+            .chain(config.emergency.iter_mut().map(|e| &mut e.constraints));
+        for conf in confs {
+            if conf.pl2_tdp_w.is_some_and(|pl2| pl2 > max) {
+                eprintln!("clamping PL2 from {} W to {} W", conf.pl2_tdp_w.unwrap(), max);
+                conf.pl2_tdp_w = Some(max);
+            }
+        }
+        if let Some(ref mut burst) = config.burst_budget {
+            if burst.boost_pl2_w > max {
+                eprintln!("clamping burst budget PL2 from {} W to {} W", burst.boost_pl2_w, max);
+                burst.boost_pl2_w = max;
+                burst.sustained_pl2_w = cmp::min(burst.sustained_pl2_w, max);
+            }
+        }
+    }
+}
+
+fn read_config() -> Result<Config, Error> {
+    read_config_from(&paths::get().config)
+}
+
+/// Like `read_config`, but for a config file somewhere else.
+fn read_config_from(path: &str) -> Result<Config, Error> {
+    let mut file = File::open(path).map_err(|e| format_err!("{}: {}", path, e))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    config_from_str(ConfigFormat::detect(path, &contents), &contents)
+}
+
+/// Parses and validates a config that's already been read, as `read_config_from` does.
+fn config_from_str(format: ConfigFormat, contents: &str) -> Result<Config, Error> {
+    let mut config = match format {
+        ConfigFormat::Toml => parse_config(contents)?,
+        ConfigFormat::Json => parse_json_config(contents)?,
+    };
+    for &name in ["battery", "ac"].iter() {
+        let conf = if name == "battery" { &mut config.battery } else { &mut config.ac };
+        if config.profile_errors.iter().any(|e| e.0 == name) {
+            continue;
+        }
+        if let Err(e) = check_profile(name, conf) {
+            config.profile_errors.push((name, e.to_string()));
+            *conf = ModeConfig::default();
+        }
+    }
+    for &(name, ref e) in config.profile_errors.iter() {
+        eprintln!("error in [{}], it won't be applied: {}", name, e);
+    }
+    resolve_profiles(&mut config)?;
+    for (i, rule) in config.rules.iter().enumerate() {
+        if config.lacks_profile(&rule.profile) {
+            bail!("rule {} picks profile {:?}, which isn't a [profiles.*] section", i + 1, rule.profile.name());
+        }
+    }
+
+    let levels = config.battery_levels.iter().map(|l| &l.constraints)
+        .chain(config.charger_levels.iter().map(|l| &l.constraints))
+        .chain(config.battery_wear.iter().map(|w| &w.constraints))
+        .chain(config.emergency.iter().map(|e| &e.constraints))
+        .chain(Some(&config.travel));
+    for conf in levels {
+        if let Some(ref name) = conf.preset {
+            bail!("preset {:?} in a battery or charger level, [battery_wear], [emergency] or [travel]; presets can only be used in [battery] and [ac]", name);
+        }
+        if conf.pl1_factor.is_some() || conf.pl2_factor.is_some() {
+            bail!("pl1_factor or pl2_factor in a battery or charger level, [battery_wear], [emergency] or [travel]; they can only be used in [battery] and [ac]");
+        }
+    }
+
+    let base = [&config.battery, &config.ac];
+    let levels = config.battery_levels.iter().map(|l| &l.constraints)
+        .chain(config.charger_levels.iter().map(|l| &l.constraints))
+        .chain(config.battery_wear.iter().map(|w| &w.constraints))
+        .chain(config.emergency.iter().map(|e| &e.constraints))
+        .chain(Some(&config.travel));
+    for conf in levels {
+        if let Some(ref p) = conf.intel_pstate {
+            p.validate()?;
+        }
+        conf.check_percentages()?;
+    }
+
+    if !(0.1..=60.0).contains(&config.power_latency_budget) {
+        bail!("power_latency_budget must be between 100 ms and 60 s");
+    }
+
+    if let Some(ref wear) = config.battery_wear {
+        wear.validate()?;
+    }
+
+    if let Some(ref burst) = config.burst_budget {
+        burst.validate()?;
+    }
+
+    if let Some(ref emergency) = config.emergency {
+        emergency.validate()?;
+    }
+
+    config.priority.validate()?;
+
+    if let Some(ref ramp) = config.ramp {
+        ramp.validate()?;
+    }
+
+    if let Some(ref load) = config.load {
+        load.validate()?;
+    }
+
+    if let Some(ref follow) = config.follow_platform_profile {
+        follow.validate()?;
+
+        // Our own writes would look like the user changing it.
+        let profiles = base.iter().cloned()
+            .chain(config.battery_levels.iter().map(|l| &l.constraints))
+            .chain(config.charger_levels.iter().map(|l| &l.constraints))
+            .chain(config.battery_wear.iter().map(|w| &w.constraints))
+            .chain(config.emergency.iter().map(|e| &e.constraints));
+        for conf in profiles {
+            if conf.platform_profile.is_some() {
+                bail!("platform_profile can't be set in a profile when follow_platform_profile is enabled");
+            }
+        }
+    }
+
+    for custom in config.custom_msr.iter() {
+        custom.validate()?;
+        eprintln!("WARNING: writing custom MSR {:#x} (mask {:#x}, value {:#x}) with every profile; \
+                   writing the wrong MSR can hang or damage your machine",
+                  custom.msr, custom.mask, custom.value);
+    }
+
+    Ok(config)
+}
+
+/// Resolves the `extends` chain of every named profile, so that each holds its complete settings.
+fn resolve_profiles(config: &mut Config) -> Result<(), Error> {
+    let mut resolved = BTreeMap::new();
+    for name in config.profiles.keys() {
+        resolve_profile(name, config, &mut resolved, &mut vec![])?;
+    }
+    for (name, profile) in config.profiles.iter_mut() {
+        profile.extends = None;
+        profile.conf = resolved.remove(name).unwrap();
+    }
+    Ok(())
+}
+
+/// Resolves a named profile, and the ones it extends first. `chain` holds the profiles being
+/// resolved that (indirectly) extend this one, to catch cycles.
+fn resolve_profile(
+    name: &str,
+    config: &Config,
+    resolved: &mut BTreeMap<String, ModeConfig>,
+    chain: &mut Vec<String>,
+) -> Result<ModeConfig, Error> {
+    if let Some(conf) = resolved.get(name) {
+        return Ok(conf.clone());
+    }
+    chain.push(name.to_string());
+    if chain[..chain.len() - 1].iter().any(|n| n == name) {
+        bail!("[profiles.{}] extends itself: {}", name, chain.join(" -> "));
+    }
+    if name == "ac" || name == "battery" {
+        bail!("[profiles.{}] would be confused with [{}]; pick another name", name, name);
+    }
+    // "auto" means automatic selection to clients, and forced profiles are saved space-separated.
+    if name == "auto" || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("[profiles.{}] can't be selected by that name; use letters, digits, '-' and '_', other than \"auto\"", name);
+    }
+
+    let profile = &config.profiles[name];
+    let parent = match profile.extends.as_deref() {
+        None => ModeConfig::default(),
+        Some(base @ "ac") | Some(base @ "battery") => {
+            let state = if base == "ac" { power::PowerState::AC { watts: None } } else { power::PowerState::Battery };
+            if config.profile_error(state).is_some() {
+                bail!("[profiles.{}] extends [{}], which is invalid", name, base);
+            }
+            if base == "ac" { config.ac.clone() } else { config.battery.clone() }
+        },
+        Some(other) if config.profiles.contains_key(other) => resolve_profile(other, config, resolved, chain)?,
+        Some(other) => {
+            bail!("[profiles.{}] extends {:?}, which doesn't exist; profiles can extend ac, battery or another \
+                   [profiles.*] section", name, other);
+        },
+    };
+    chain.pop();
+
+    let section = format!("profiles.{}", name);
+    let conf = profile.conf.extend(&section, &parent).map_err(|e| format_err!("error in [{}]: {}", section, e))?;
+    resolved.insert(name.to_string(), conf.clone());
+    Ok(conf)
+}
+
+/// Parses the configuration file. If only the [battery] or [ac] section is invalid (or missing),
+/// it's replaced with an empty one and the error is recorded in `profile_errors`, so that the
+/// daemon can still apply the other profile.
+fn parse_config(contents: &str) -> Result<Config, Error> {
+    let err = match toml::from_str::<Config>(contents) {
+        Ok(c) => return Ok(c),
+        Err(e) => e,
+    };
+    config_from_value(toml::from_str(contents)?, err.into())
+}
+
+/// The formats that the config file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Toml,
+    /// JSON, with the same layout as the TOML, for configs generated by other tools.
+    Json,
+}
+
+impl ConfigFormat {
+    /// Works out a config file's format: JSON if it's named `*.json` or starts like a JSON object
+    /// (no TOML document can start with "{"), and TOML otherwise.
+    fn detect(path: &str, contents: &str) -> ConfigFormat {
+        let json = Path::new(path).extension().is_some_and(|e| e == "json") || contents.trim_start().starts_with('{');
+        if json { ConfigFormat::Json } else { ConfigFormat::Toml }
+    }
+}
+
+/// Parses a config file written in JSON.
+fn parse_json_config(contents: &str) -> Result<Config, Error> {
+    let value = json::parse(contents).and_then(|v| v.to_toml()).map_err(|e| format_err!("invalid JSON: {}", e))?;
+    match value.clone().try_into::<Config>() {
+        Ok(c) => Ok(c),
+        Err(e) => config_from_value(value, e.into()),
+    }
+}
+
+/// Reads a config that failed to parse with `err`. If that's because the [battery] or [ac]
+/// section is invalid, the section is replaced with an empty one and the problem recorded in
+/// `profile_errors`, so that the other profile can still be applied; otherwise it's `err`.
+fn config_from_value(mut value: toml::Value, err: Error) -> Result<Config, Error> {
+    let mut profile_errors = vec![];
+    if let Some(table) = value.as_table_mut() {
+        for &name in ["battery", "ac"].iter() {
+            let res = match table.get(name) {
+                Some(section) => section.clone().try_into::<ModeConfig>().map(|_| ()).map_err(|e| e.to_string()),
+                None => Err("the section is missing".to_string()),
+            };
+            if let Err(e) = res {
+                profile_errors.push((name, e));
+                table.insert(name.to_string(), toml::Value::Table(toml::value::Table::new()));
+            }
+        }
+    }
+
+    // If the profiles weren't the problem, the original error (with its line number) is the
+    // most useful one.
+    if profile_errors.is_empty() {
+        return Err(err);
+    }
+    let mut config: Config = value.try_into().map_err(|_| err)?;
+    config.profile_errors = profile_errors;
+    Ok(config)
+}
+
+/// Resolves and validates a [battery] or [ac] section.
+fn check_profile(name: &str, conf: &mut ModeConfig) -> Result<(), Error> {
+    // A factor replaces the preset's value for the limit it sets, so check it before the preset
+    // fills that in.
+    conf.check_power_factors()?;
+    conf.resolve_preset(name)?;
+    conf.derive_power_limits()?;
+    if let Some(ref p) = conf.intel_pstate {
+        p.validate()?;
+    }
+    conf.check_percentages()?;
+    if conf.hwp_mode == Some(true) && conf.hwp.as_ref().is_none_or(|h| h.epp.is_none()) {
+        bail!("hwp_mode needs [{}.hwp] epp to be set, to go back to once the load drops", name);
+    }
+    Ok(())
+}
+
+/// Adds the updates for intel_pstate parameters.
+fn build_pstate_updates(p: &IntelPstateConfig, updates: &mut Vec<Update>) {
+    // Switching modes resets the other parameters, so it has to come first.
+    if let Some(status) = p.status {
+        updates.push(Update::Sysfs(pstate::STATUS.to_string(), status.value().to_string()));
+    }
+
+    // The driver rejects a minimum above the current maximum (and vice versa), so order the writes
+    // to keep the two consistent at every step.
+    let min = p.min_perf_pct.map(|v| Update::Sysfs(pstate::MIN_PERF_PCT.to_string(), v.to_string()));
+    let max = p.max_perf_pct.map(|v| Update::Sysfs(pstate::MAX_PERF_PCT.to_string(), v.to_string()));
+    let min_first = match (p.min_perf_pct, pstate::read_perf_pct(pstate::MAX_PERF_PCT)) {
+        (Some(new_min), Some(current_max)) => new_min <= current_max,
+        _ => true,
+    };
+    if min_first {
+        updates.extend(min.into_iter().chain(max));
+    } else {
+        updates.extend(max.into_iter().chain(min));
+    }
+
+    if let Some(boost) = p.hwp_dynamic_boost {
+        // This only exists in active mode with HWP, which a status change above might switch to;
+        // only complain if it isn't going to appear.
+        if Path::new(pstate::HWP_DYNAMIC_BOOST).exists() || p.status == Some(pstate::Status::Active) {
+            let value = if boost { "1" } else { "0" };
+            updates.push(Update::Sysfs(pstate::HWP_DYNAMIC_BOOST.to_string(), value.to_string()));
+        } else {
+            eprintln!("hwp_dynamic_boost is set, but isn't supported in this intel_pstate mode");
+        }
+    }
+}
+
+/// Adds the updates for HWP request settings.
+fn build_hwp_updates(h: &HwpConfig, updates: &mut Vec<Update>) {
+    let scope = h.scope.unwrap_or_default();
+    if let Some(epp) = h.epp {
+        if !cpuid::get().hwp_epp {
+            eprintln!("hwp.epp is set, but this CPU doesn't support HWP energy/performance preferences");
+        } else if !hwp::is_enabled() {
+            eprintln!("hwp.epp is set, but HWP isn't enabled on this CPU");
+        } else {
+            push_hwp_request(scope, hwp::EPP_MASK, hwp::EPP_VALID, u64::from(epp) << 24, updates);
+        }
+    }
+    if let Some(pct) = h.min_perf_pct {
+        if hwp::is_enabled() {
+            build_hwp_min_update(scope, Some(pct), updates);
+        } else {
+            eprintln!("hwp.min_perf_pct is set, but HWP isn't enabled on this CPU");
+        }
+    }
+}
+
+/// Adds the update that sets the HWP minimum performance to the given percentage of the highest
+/// level, or to the lowest level if `None`.
+fn build_hwp_min_update(scope: hwp::RequestScope, pct: Option<u8>, updates: &mut Vec<Update>) {
+    match hwp::PerformanceRange::read() {
+        Ok(range) => {
+            let level = pct.map_or(range.lowest, |pct| range.level_for(pct));
+            push_hwp_request(scope, hwp::MIN_PERF_MASK, hwp::MIN_PERF_VALID, u64::from(level), updates);
+        },
+        Err(e) => eprintln!("error reading the HWP performance range, not setting the minimum: {}", e),
+    }
+}
+
+/// Adds the update for a field of the HWP request, in the request MSR that `scope` picks.
+fn push_hwp_request(scope: hwp::RequestScope, mask: u64, valid: u64, value: u64, updates: &mut Vec<Update>) {
+    match hwp::choose_scope(scope, valid) {
+        msr::Scope::Package | msr::Scope::Die | msr::Scope::Platform => {
+            updates.push(Update::MaskedMsr(hwp::MSR_HWP_REQUEST_PKG, mask, value, msr::Scope::Package));
+        },
+        msr::Scope::Cpu => {
+            // If the CPU follows the package request, its own fields are only used when marked valid.
+            let (mask, value) = if hwp::package_request_supported() {
+                (mask | valid, value | valid)
+            } else {
+                (mask, value)
+            };
+            updates.push(Update::MaskedMsr(hwp::MSR_HWP_REQUEST, mask, value, msr::Scope::Cpu));
+        },
+    }
+}
+
+/// Builds the updates that set the power limits through powercap, for when the MSR can't be
+/// written. The kernel works out the register encoding itself. Parts with several dies per
+/// package have a zone for each, which all get the same limits, like the MSR on each die does.
+fn build_powercap_updates(conf: &ModeConfig, updates: &mut Vec<Update>) {
+    let windows_locked = rapl::windows_locked();
+
+    let limits = [
+        (powercap::PL1, conf.pl1_tdp_w, conf.pl1_duration),
+        (powercap::PL2, conf.pl2_tdp_w, conf.pl2_duration),
+    ];
+    for zone in powercap::package_zones() {
+        for &(constraint, tdp, duration) in limits.iter() {
+            if let (Some(tdp), Some(duration)) = (tdp, duration) {
+                updates.push(Update::Sysfs(powercap::power_limit_attribute(&zone, constraint), (tdp * 1_000_000).to_string()));
+                if !windows_locked {
+                    let us = (duration * 1_000_000.0).round() as u64;
+                    updates.push(Update::Sysfs(powercap::time_window_attribute(&zone, constraint), us.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Builds the updates for one profile, including any custom MSR writes.
+fn build_profile_updates(config: &Config, conf: &ModeConfig) -> Result<Vec<Update>, Error> {
+    let mut updates = build_updates(conf)?;
+
+    // The constraints go first: intel_rapl writes them to the MSR in its own encoding, which the
+    // MSR write then replaces with ours.
+    let writes_msr = updates.iter().any(|u| matches!(*u, Update::Msr(rapl::MSR_PKG_POWER_LIMIT, _)));
+    if writes_msr && config.powercap_sync.mirrors() {
+        let mut mirrored = vec![];
+        build_powercap_updates(conf, &mut mirrored);
+        updates.splice(0..0, mirrored);
+    }
+
+    // A frequency floor set by another profile has to be lifted when this one is applied.
+    let (floor, scope) = conf.hwp.as_ref().map_or((None, None), |h| (h.min_perf_pct, h.scope));
+    if floor.is_none() && config.sets_hwp_floor() && hwp::is_enabled() {
+        build_hwp_min_update(scope.unwrap_or_default(), None, &mut updates);
+    }
+    updates.extend(config.custom_msr.iter().map(|c| Update::MaskedMsr(c.msr, c.mask, c.value, c.scope)));
+    Ok(updates)
+}
+
+/// Reads the MSR behind a capability from the first CPU. If this CPU doesn't have it, the
+/// capability is marked unsupported in the backend registry, so that only that setting is skipped
+/// (now and on later applies) rather than the whole profile failing, and `None` is returned.
+fn read_capability_msr(capability: Capability) -> Result<Option<u64>, Error> {
+    let msr = capability.msr().expect("capability has no MSR");
+    match msr::ReadMsrBuilder::new(msr).read_first() {
+        Ok(v) => Ok(Some(v)),
+        Err(ref e) if msr::is_unsupported(e) => {
+            eprintln!("{} isn't supported by this CPU ({}); ignoring the {} setting", msr::Name(msr), e, capability.name());
+            backends::mark_unsupported(capability);
+            Ok(None)
+        },
+        Err(e) => bail!("error reading {} for the {}: {}", msr::Name(msr), capability.name(), e),
+    }
+}
+
+fn build_updates(conf: &ModeConfig) -> Result<Vec<Update>, Error> {
+    // Build MSR update values.
+    let mut updates: Vec<Update> = vec![];
+
+    let registry = backends::registry();
+
+    // MSR_TEMPERATURE_TARGET: Maximum temperature for the CPU.
+    let max_temp = match conf.maximum_temp_c {
+        Some(_) if registry.best(Capability::TemperatureTarget).is_none() => {
+            eprintln!("maximum_temp_c is set, but the temperature target can't be written here; ignoring it");
+            None
+        },
+        t => t,
+    };
+    let temp_target = match max_temp {
+        Some(t) => read_capability_msr(Capability::TemperatureTarget)?.map(|v| (t, v)),
+        None => None,
+    };
+    if let Some((max_temp, msr_value)) = temp_target {
+        // MSR layout:
+        //
+        //  Reserved    Maximum
+        //    |           CPU
+        //    |        Temperature
+        //    |        (bits 23:16)
+        //    |            |
+        //    v            v
+        //    00 000000 00000000 0000000000000000
+        //         ^                    ^
+        //         |                    |
+        //      Temperature          Reserved
+        //         Trip             (bits 0:15)
+        //         Point
+        //      (bits 29:24)
+        //
+
+        // Work out the offset from the critical temperature, which keeps us away from it.
+        let target = temps::TemperatureTarget::from_raw(msr_value);
+        match target.offset_for(max_temp) {
+            Some(t) => {
+                if t.clamped {
+                    eprintln!("WARNING: maximum_temp_c = {} isn't possible with a critical temperature of {} C; \
+                               using {} C instead", max_temp, target.critical, t.temp_c);
+                }
+
+                // Calculate the value we're going to write back by masking out the bits with our
+                // target value.
+                let new_value = temps::encode_target_offset(msr_value, t.offset);
+
+                println!("MSR_TEMPERATURE_TARGET: old = {:032b}", msr_value);
+                println!("MSR_TEMPERATURE_TARGET: new = {:032b}", new_value);
+
+                // Only the offset is written, so that each package keeps the rest of its own value.
+                updates.push(Update::MaskedMsr(temps::MSR_TEMPERATURE_TARGET, temps::TEMP_TARGET_OFFSET_MASK,
+                                               new_value, msr::scope_of(temps::MSR_TEMPERATURE_TARGET)));
+            },
+            None => eprintln!("MSR_TEMPERATURE_TARGET reports an implausible critical temperature of {} C; \
+                               ignoring maximum_temp_c", target.critical),
+        }
+    }
+
+    let units = rapl::Units::read()?;
+
+    println!("power unit = {}", units.power);
+    println!("time unit  = {}", units.time);
+    if units.encoding != rapl::Encoding::Core {
+        println!("unit encoding = {:?}", units.encoding);
+    }
+
+    // MSR_PKG_POWER_LIMIT brief documentation:
+    //
+    //   Lock     Time
+    //    |       Window
+    //    |        For      Enable                           Package      Package
+    //    |       Power     Power                            Clamping      Power
+    //    |      Limit #2   Limit #2            Reserved    Limitation    Limit #1
+    //    |         |         |                    |             |           |
+    //    v         v         v                    v             v           v
+    //    0 0000000 0000000 0 0 000000000000000 00000000 0000000 0 0 000000000000000
+    //         ^            ^          ^                  ^        ^
+    //         |            |          |                  |        |
+    //       Reserved   Package     Package             Time      Enable
+    //                  Clamping     Power              Window    Power
+    //                 Limitation   Limit #2             For      Limit #1
+    //                                                  Power
+    //                                                 Limit #1
+    //
+    //   Package Power Limit #1 (bits 14:0): Sets the average power usage limit of the package
+    //   domain corresponding to time window # 1. The unit of this field is specified by the
+    //   "Power Units" field of MSR_RAPL_POWER_UNIT.
+    //
+    //   Enable Power Limit #1 (bit 15): 0 = disabled; 1 = enabled.
+    //
+    //   Package Clamping Limitation #1 (bit 16): Allow going below OS-requested P/T state setting
+    //   during time window specified by bits 23:17.
+    //
+    //   Time Window for Power Limit #1 (bits 23:17): Indicates the time window for power limit #1
+    //     Time limit = 2^Y * (1.0 + Z/4.0) * Time_Unit
+    //   Here "Y" is the unsigned integer value represented by bits 21:17, "Z" is an unsigned
+    //   integer represented by bits 23:22. "Time_Unit" is specified by the "Time Units" field of
+    //   MSR_RAPL_POWER_UNIT. This field may have a hard-coded value in hardware and ignores values
+    //   written by software.
+    //
+    //   Package Power Limit #2 (bits 46:32): Sets the average power usage limit of the package
+    //   domain corresponding to time window # 2. The unit of this field is specified by the
+    //   "Power Units" field of MSR_RAPL_POWER_UNIT.
+    //
+    //   Enable Power Limit #2 (bit 47): 0 = disabled; 1 = enabled.
+    //
+    //   Package Clamping Limitation #2 (bit 48): Allow going below OS-requested P/T state setting
+    //   during time window specified by bits 23:17.
+    //
+    //   Time Window for Power Limit #2 (bits 55:49): Indicates the time window for power limit #2
+    //     Time limit = 2^Y * (1.0 + Z/4.0) * Time_Unit
+    //   Here "Y" is the unsigned integer value represented by bits 53:49, "Z" is an unsigned
+    //   integer represented by bits 55:54. "Time_Unit" is specified by the "Time Units" field of
+    //   MSR_RAPL_POWER_UNIT. This field may have a hard-coded value in hardware and ignores values
+    //   written by software.
+    //
+    //   Lock (bit 63): If set, all write attempts to this MSR are ignored until next RESET.
+    //
+
+    let power_limit_backend = registry.best(Capability::PackagePowerLimit);
+    let power_limits_set = conf.pl1_tdp_w.is_some() || conf.pl2_tdp_w.is_some();
+    match power_limit_backend {
+        Some(Backend::Msr) | Some(Backend::Mchbar) => {},
+        Some(Backend::Powercap) => build_powercap_updates(conf, &mut updates),
+        _ if power_limits_set => eprintln!("power limits are set, but nothing can write them here; ignoring them"),
+        _ => {},
+    }
+
+    // Get the initial value for the power limit (MSR_PKG_POWER_LIMIT, or its MCHBAR mirror if we
+    // can only write that)
+    let initial_power_limit = match power_limit_backend {
+        Some(Backend::Mchbar) => mchbar::read_power_limit()?,
+        _ => msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first()?,
+    };
+
+    // TODO: check lock bit
+
+    // This is the value we'll set, if config flags are given.
+    let mut new_power_limit = initial_power_limit;
+
+    // If the hardware ignores the time windows, keep whatever it has so that the write matches
+    // what's read back.
+    let windows_locked = rapl::windows_locked();
+
+    {
+        // Helper function to take a TDP & duration and mask the new_power_limit variable.
+        let mut do_mask = |tdp: u64, duration: f64, offset: u64| -> Result<(), Error> {
+            if !windows_locked {
+                // Find the closest time window that the hardware can express.
+                let (tw, realized) = units.encode_window(duration);
+
+                println!("PL#: time window = {:07b} ({})", tw, duration::format(realized));
+            }
+
+            new_power_limit = rapl::encode_power_limit(
+                new_power_limit, offset, tdp as f64, duration, &units)?;
+            if windows_locked {
+                new_power_limit = (new_power_limit & !rapl::WINDOW_FIELDS) | (initial_power_limit & rapl::WINDOW_FIELDS);
+            }
+            Ok(())
+        };
+
+        // Set PL 1 and 2 if given.
+        match (conf.pl1_tdp_w, conf.pl1_duration) {
+            (Some(tdp), Some(duration)) => {
+                do_mask(tdp, duration, 0)?;
+            },
+            _ => {},
+        }
+        match (conf.pl2_tdp_w, conf.pl2_duration) {
+            (Some(tdp), Some(duration)) => {
+                do_mask(tdp, duration, 32)?;
+            },
+            _ => {},
+        }
+    }
+
+    // Set the MSR update if we've changed anything.
+    if new_power_limit != initial_power_limit {
+        match power_limit_backend {
+            Some(Backend::Msr) => updates.push(Update::Msr(rapl::MSR_PKG_POWER_LIMIT, new_power_limit)),
+            Some(Backend::Mchbar) => updates.push(Update::Mchbar(new_power_limit & !mchbar::LOCK)),
+            _ => {},
+        }
+    }
+
+    // MSR_PP1_POWER_LIMIT: power limit for the integrated GPU. This has the same layout as the
+    // first (PL1) half of MSR_PKG_POWER_LIMIT, with a lock bit at bit 31.
+    let gpu_pl_w = match conf.gpu_pl_w {
+        Some(_) if !rapl::domains().has(rapl::Domain::Graphics) => {
+            eprintln!("gpu_pl_w is set, but this CPU has no graphics RAPL domain; ignoring it");
+            None
+        },
+        Some(_) if registry.best(Capability::GpuPowerLimit).is_none() => {
+            eprintln!("gpu_pl_w is set, but the GPU power limit can't be written here; ignoring it");
+            None
+        },
+        pl => pl,
+    };
+    let gpu_limit = match gpu_pl_w {
+        Some(pl) => read_capability_msr(Capability::GpuPowerLimit)?.map(|v| (pl, v)),
+        None => None,
+    };
+    if let Some((gpu_pl, initial_pp1_limit)) = gpu_limit {
+        if initial_pp1_limit & (1 << 31) != 0 {
+            eprintln!("MSR_PP1_POWER_LIMIT is locked; GPU power limit will be ignored");
+        }
+
+        // Keep the existing time window, and just set the limit and enable bit.
+        let pl = (gpu_pl as f64 / units.power).round() as u64;
+        if pl > rapl::POWER_LIMIT_MASK {
+            bail!("gpu_pl_w = {} doesn't fit in the power limit field", gpu_pl);
+        }
+        let new_pp1_limit = (initial_pp1_limit & !0b1111111111111111) | pl | (1 << 15);
+
+        if new_pp1_limit != initial_pp1_limit {
+            updates.push(Update::Msr(rapl::MSR_PP1_POWER_LIMIT, new_pp1_limit));
+        }
+    }
+
+    // Turbo Boost: the registry prefers the intel_pstate knob if it exists, since the driver will
+    // otherwise overwrite the MSR behind our back.
+    if let Some(turbo_enabled) = conf.turbo_enabled {
+        let backend = registry.best(Capability::Turbo);
+        if backend == Some(Backend::Cpufreq) {
+            let value = if turbo_enabled { "0" } else { "1" };
+            updates.push(Update::Sysfs(INTEL_PSTATE_NO_TURBO.to_string(), value.to_string()));
+        } else if backend.is_none() {
+            eprintln!("turbo_enabled is set, but Turbo Boost can't be toggled here; ignoring it");
+        } else {
+            // CPUID stops reporting Turbo Boost while it's disabled in IA32_MISC_ENABLE, so only
+            // trust it if the bit is clear.
+            if let Some(misc_enable) = read_capability_msr(Capability::Turbo)? {
+                let new_value = if turbo_enabled { 0 } else { turbo::TURBO_DISABLE };
+
+                if misc_enable & turbo::TURBO_DISABLE == 0 && !cpuid::get().turbo_boost {
+                    eprintln!("turbo_enabled is set, but this CPU doesn't have Turbo Boost");
+                } else {
+                    // Only the one bit, since the rest of each CPU's copy isn't ours to change.
+                    updates.push(Update::MaskedMsr(turbo::IA32_MISC_ENABLE, turbo::TURBO_DISABLE, new_value, msr::Scope::Cpu));
+                }
+            }
+        }
+    }
+
+    // IdeaPad charging policy.
+    let charge_knobs = [
+        ("conservation_mode", conf.conservation_mode),
+        ("rapid_charge", conf.rapid_charge),
+    ];
+    for &(name, setting) in charge_knobs.iter() {
+        if let Some(enabled) = setting {
+            match charge::ideapad_attribute(name) {
+                Some(path) => {
+                    let value = if enabled { "1" } else { "0" };
+                    updates.push(Update::Sysfs(path, value.to_string()));
+                },
+                None => eprintln!("{} is set, but ideapad_acpi doesn't support it here", name),
+            }
+        }
+    }
+
+    // Charge thresholds. The kernel refuses a start threshold at or above the stop threshold, so
+    // when raising both, the stop threshold has to be written first.
+    if conf.charge_start_threshold.is_some() || conf.charge_stop_threshold.is_some() {
+        let batteries = charge::threshold_batteries();
+        if batteries.is_empty() {
+            eprintln!("charge thresholds are set, but no battery supports them here");
+        }
+        for battery in batteries {
+            let attr = |name: &str| format!("{}/{}", battery, name);
+            let current_stop = sysfs::read_value(&attr(charge::STOP_THRESHOLD)).ok().and_then(|v| v.parse::<u8>().ok());
+            let start = conf.charge_start_threshold.map(|t| Update::Sysfs(attr(charge::START_THRESHOLD), t.to_string()));
+            let stop = conf.charge_stop_threshold.map(|t| Update::Sysfs(attr(charge::STOP_THRESHOLD), t.to_string()));
+            let stop_first = match (conf.charge_start_threshold, current_stop) {
+                (Some(start), Some(current)) => start >= current,
+                _ => true,
+            };
+            if stop_first {
+                updates.extend(stop.into_iter().chain(start));
+            } else {
+                updates.extend(start.into_iter().chain(stop));
+            }
+        }
+    }
+
+    // Platform power policies.
+    if let Some(policy) = conf.pcie_aspm_policy {
+        if Path::new(platform::PCIE_ASPM_POLICY).exists() {
+            updates.push(Update::Sysfs(platform::PCIE_ASPM_POLICY.to_string(), policy.value().to_string()));
+        } else {
+            eprintln!("pcie_aspm_policy is set, but {} doesn't exist", platform::PCIE_ASPM_POLICY);
+        }
+    }
+    if let Some(policy) = conf.sata_link_policy {
+        let paths = platform::sata_link_policy_attributes();
+        if paths.is_empty() {
+            eprintln!("sata_link_policy is set, but no SATA hosts were found");
+        }
+        for path in paths {
+            updates.push(Update::Sysfs(path, policy.value().to_string()));
+        }
+    }
+    if let Some(ref profile) = conf.platform_profile {
+        let choices = platform::platform_profile_choices();
+        if registry.best(Capability::PlatformProfile).is_none() || choices.is_empty() {
+            eprintln!("platform_profile is set, but the firmware doesn't have a platform profile");
+        } else if !choices.contains(profile) {
+            eprintln!("platform_profile {:?} isn't supported; the choices are: {}", profile, choices.join(", "));
+        } else {
+            updates.push(Update::Sysfs(platform::PLATFORM_PROFILE.to_string(), profile.clone()));
+        }
+    }
+
+    // Backlights.
+    if let Some(pct) = conf.kbd_backlight_pct {
+        let backlights = backlight::keyboard_backlights();
+        if backlights.is_empty() {
+            eprintln!("kbd_backlight_pct is set, but no keyboard backlight was found");
+        }
+        for b in backlights {
+            let level = b.level_for(pct);
+            updates.push(Update::Sysfs(b.brightness, level.to_string()));
+        }
+    }
+    if let Some(pct) = conf.display_brightness_max_pct {
+        let backlights = backlight::display_backlights();
+        if backlights.is_empty() {
+            eprintln!("display_brightness_max_pct is set, but no display backlight was found");
+        }
+        for b in backlights {
+            let level = b.level_for(pct);
+            updates.push(Update::SysfsMax(b.brightness, level));
+        }
+    }
+
+    if let Some(ref p) = conf.intel_pstate {
+        if pstate::is_available() {
+            build_pstate_updates(p, &mut updates);
+        } else {
+            eprintln!("intel_pstate is set, but the intel_pstate driver isn't loaded");
+        }
+    }
+
+    if let Some(ref h) = conf.hwp {
+        build_hwp_updates(h, &mut updates);
+    }
+
+    // TODO: add support for cTDP
+
+    Ok(updates)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "[battery]\npl1_tdp_w = 15\n\n[ac]\npl1_tdp_w = 35\n\n";
+
+    #[test]
+    fn profiles_resolve_their_extends_chain() {
+        let profiles = "[profiles.quiet]\nextends = \"battery\"\npl2_tdp_w = 20\n\n\
+                        [profiles.quieter]\nextends = \"quiet\"\npl1_tdp_w = 10\n";
+        let config = config_from_str(ConfigFormat::Toml, &format!("{}{}", BASE, profiles)).unwrap();
+        let quiet = &config.profiles["quiet"];
+        assert_eq!((quiet.conf.pl1_tdp_w, quiet.conf.pl2_tdp_w), (Some(15), Some(20)));
+        assert_eq!(quiet.extends, None);
+        let quieter = &config.profiles["quieter"];
+        assert_eq!((quieter.conf.pl1_tdp_w, quieter.conf.pl2_tdp_w), (Some(10), Some(20)));
+    }
+
+    #[test]
+    fn quirks_clamp_every_section() {
+        let sections = "[profiles.gaming]\nextends = \"ac\"\npl2_tdp_w = 51\n\n\
+                        [[battery_levels]]\nbelow_percent = 20\npl2_tdp_w = 51\n\n\
+                        [emergency]\nenter_temp_c = 95\nexit_temp_c = 85\npl2_tdp_w = 51\n";
+        let mut config = config_from_str(ConfigFormat::Toml, &format!("{}{}", BASE, sections)).unwrap();
+        let quirk = quirks::Quirk { product: "test", mchbar_required: false, power_limit_locked: false, max_safe_pl2_w: Some(44) };
+        apply_quirk(&quirk, &mut config);
+        assert_eq!(config.profiles["gaming"].conf.pl2_tdp_w, Some(44));
+        assert_eq!(config.battery_levels[0].constraints.pl2_tdp_w, Some(44));
+        assert_eq!(config.emergency.unwrap().constraints.pl2_tdp_w, Some(44));
+    }
+
+    #[test]
+    fn broken_extends_chains_are_rejected() {
+        let broken_battery = BASE.replace("pl1_tdp_w = 15", "pl1_tdp_w = 15\npl1_duration = -1");
+        let cases = [
+            (BASE, "[profiles.a]\nextends = \"b\"\n\n[profiles.b]\nextends = \"a\"\n", "extends itself"),
+            (BASE, "[profiles.a]\nextends = \"a\"\n", "extends itself: a -> a"),
+            (BASE, "[profiles.a]\nextends = \"missing\"\n", "doesn't exist"),
+            (BASE, "[profiles.ac]\npl1_tdp_w = 20\n", "would be confused with [ac]"),
+            (BASE, "[profiles.auto]\nextends = \"ac\"\n", "can't be selected by that name"),
+            (BASE, "[profiles.\"two words\"]\nextends = \"ac\"\n", "can't be selected by that name"),
+            (BASE, "[[rules]]\npower = \"ac\"\nprofile = \"missing\"\n", "isn't a [profiles.*] section"),
+            (&broken_battery, "[profiles.a]\nextends = \"battery\"\n", "extends [battery], which is invalid"),
+        ];
+        for &(base, profiles, expected) in cases.iter() {
+            let err = match config_from_str(ConfigFormat::Toml, &format!("{}{}", base, profiles)) {
+                Ok(_) => panic!("{:?} was accepted", profiles),
+                Err(e) => e.to_string(),
+            };
+            assert!(err.contains(expected), "{:?}: {}", profiles, err);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Checks for configurations that are valid, but probably not what the user meant.
+
+use duration;
+use rapl;
+use {Config, ModeConfig};
+
+
+/// `maximum_temp_c` above this is likely to be uncomfortable, and close to TjMax on most parts.
+const HIGH_TEMP_C: u64 = 95;
+
+
+/// A single problem found in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// What the lint applies to, e.g. "battery" or "battery_levels[0]".
+    pub section: String,
+    /// What's wrong.
+    pub message: String,
+    /// How to fix it.
+    pub suggestion: String,
+}
+
+impl Lint {
+    fn new(section: &str, message: String, suggestion: String) -> Lint {
+        Lint {
+            section: section.to_string(),
+            message,
+            suggestion,
+        }
+    }
+
+    /// Prints the lint as a warning.
+    pub fn report(&self) {
+        eprintln!("WARNING: [{}] {}", self.section, self.message);
+        eprintln!("  suggestion: {}", self.suggestion);
+    }
+}
+
+
+/// Checks a configuration, returning every problem found.
+pub fn check(config: &Config) -> Vec<Lint> {
+    let units = rapl::Units::read()
+        .unwrap_or_else(|_| rapl::Units::from_raw(rapl::DEFAULT_POWER_UNIT, rapl::Encoding::detect()));
+
+    let mut lints = vec![];
+    check_profile("battery", &config.battery, &units, &mut lints);
+    check_profile("ac", &config.ac, &units, &mut lints);
+    for (name, profile) in config.profiles.iter() {
+        check_profile(&format!("profiles.{}", name), &profile.conf, &units, &mut lints);
+    }
+
+    // The levels only make sense layered over their base profile.
+    for (i, level) in config.battery_levels.iter().enumerate() {
+        let section = format!("battery_levels[{}]", i);
+        check_level(&section, "battery", &config.battery, &level.constraints, &units, &mut lints);
+    }
+    for (i, level) in config.charger_levels.iter().enumerate() {
+        let section = format!("charger_levels[{}]", i);
+        check_level(&section, "ac", &config.ac, &level.constraints, &units, &mut lints);
+    }
+    if let Some(ref wear) = config.battery_wear {
+        check_level("battery_wear", "battery", &config.battery, &wear.constraints, &units, &mut lints);
+    }
+
+    // Battery limits above the AC ones are usually a copy-and-paste mistake.
+    let limits = [
+        ("pl1_tdp_w", config.battery.pl1_tdp_w, config.ac.pl1_tdp_w, "W"),
+        ("pl2_tdp_w", config.battery.pl2_tdp_w, config.ac.pl2_tdp_w, "W"),
+        ("gpu_pl_w", config.battery.gpu_pl_w, config.ac.gpu_pl_w, "W"),
+        ("maximum_temp_c", config.battery.maximum_temp_c, config.ac.maximum_temp_c, "C"),
+    ];
+    for &(name, battery, ac, unit) in limits.iter() {
+        if let (Some(battery), Some(ac)) = (battery, ac) {
+            if battery > ac {
+                lints.push(Lint::new(
+                    "battery",
+                    format!("{} ({} {}) is higher than on AC ({} {})", name, battery, unit, ac, unit),
+                    format!("lower the battery {} to at most {}, or swap the two if they're backwards", name, ac),
+                ));
+            }
+        }
+    }
+
+    lints
+}
+
+/// Checks a level layered over its base profile, skipping anything that's inherited from the base
+/// profile, since that's already been reported.
+fn check_level(
+    section: &str,
+    base_name: &str,
+    base: &ModeConfig,
+    constraints: &ModeConfig,
+    units: &rapl::Units,
+    lints: &mut Vec<Lint>,
+) {
+    let mut level_lints = vec![];
+    check_profile(section, &base.constrain(constraints), units, &mut level_lints);
+
+    for l in level_lints {
+        if !lints.iter().any(|b| b.section == base_name && b.message == l.message) {
+            lints.push(l);
+        }
+    }
+}
+
+fn check_profile(section: &str, conf: &ModeConfig, units: &rapl::Units, lints: &mut Vec<Lint>) {
+    if let (Some(pl1), Some(pl2)) = (conf.pl1_tdp_w, conf.pl2_tdp_w) {
+        if pl2 < pl1 {
+            lints.push(Lint::new(
+                section,
+                format!("PL2 ({} W) is below PL1 ({} W), so PL1 will never be reached", pl2, pl1),
+                format!("set pl2_tdp_w to at least {}, or lower pl1_tdp_w to {}", pl1, pl2),
+            ));
+        }
+    }
+
+    let (_, shortest) = units.encode_window(0.0);
+    let (_, longest) = units.encode_window(f64::MAX);
+    for &(name, duration) in [("pl1_duration", conf.pl1_duration), ("pl2_duration", conf.pl2_duration)].iter() {
+        let duration = match duration {
+            Some(d) => d,
+            None => continue,
+        };
+
+        if duration < shortest {
+            lints.push(Lint::new(
+                section,
+                format!("{} ({}) is shorter than the hardware can express", name, duration::format(duration)),
+                format!("set {} to \"{}\" (the shortest window); it will be rounded up to that anyway", name,
+                        duration::format(shortest)),
+            ));
+        } else if duration > longest {
+            lints.push(Lint::new(
+                section,
+                format!("{} ({}) is longer than the hardware can express", name, duration::format(duration)),
+                format!("set {} to \"{}\" (the longest window); it will be clamped to that anyway", name,
+                        duration::format(longest)),
+            ));
+        }
+    }
+
+    if let Some(temp) = conf.maximum_temp_c {
+        if temp > HIGH_TEMP_C {
+            lints.push(Lint::new(
+                section,
+                format!("maximum_temp_c ({} C) is above {} C, which is close to the critical \
+                         temperature of most CPUs", temp, HIGH_TEMP_C),
+                format!("set maximum_temp_c to {} or lower", HIGH_TEMP_C),
+            ));
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! CPU load, sampled from /proc/stat and smoothed, for load-based policies: performance HWP hints
+//! at high load (`hwp_mode`) and the `load_above_percent` rule condition. The idle-aware timer
+//! samples it too, unsmoothed.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::thread;
+use std::time::Duration;
+
+use ::channel;
+
+use ratelimit;
+
+
+/// Measures overall CPU utilisation between successive samples.
+pub struct CpuUsage {
+    last: (u64, u64),
+}
+
+impl CpuUsage {
+    pub fn new() -> io::Result<CpuUsage> {
+        Ok(CpuUsage { last: read_cpu_times()? })
+    }
+
+    /// Returns the fraction (0.0 to 1.0) of time that the CPUs were busy since the last sample.
+    pub fn sample(&mut self) -> io::Result<f64> {
+        let (busy, total) = read_cpu_times()?;
+        let (last_busy, last_total) = self.last;
+        self.last = (busy, total);
+
+        if total <= last_total {
+            return Ok(0.0);
+        }
+        Ok(busy.saturating_sub(last_busy) as f64 / (total - last_total) as f64)
+    }
+}
+
+/// Returns the (busy, total) jiffies summed over all CPUs, from /proc/stat.
+fn read_cpu_times() -> io::Result<(u64, u64)> {
+    let mut line = String::new();
+    BufReader::new(File::open("/proc/stat")?).read_line(&mut line)?;
+
+    // The first line is "cpu user nice system idle iowait irq softirq steal ..."; guest time is
+    // already included in user and nice, so only the first eight fields count.
+    let fields: Vec<u64> = line.split_whitespace()
+        .skip(1)
+        .take(8)
+        .filter_map(|f| f.parse().ok())
+        .collect();
+    if fields.len() < 5 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected /proc/stat format"));
+    }
+
+    let total: u64 = fields.iter().sum();
+    let idle = fields[3] + fields[4];
+    Ok((total - idle, total))
+}
+
+/// An exponentially weighted moving average of samples taken at a fixed interval, where a sample's
+/// weight decays by a factor of e every `window`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Smoothed {
+    /// Weight given to each new sample.
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Smoothed {
+    pub fn new(interval: Duration, window: Duration) -> Smoothed {
+        let alpha = if window.is_zero() { 1.0 } else { 1.0 - (-interval.as_secs_f64() / window.as_secs_f64()).exp() };
+        Smoothed { alpha, value: None }
+    }
+
+    /// Adds a sample, returning the new average. The first sample is taken as is.
+    pub fn add(&mut self, sample: f64) -> f64 {
+        let value = match self.value {
+            Some(v) => v + self.alpha * (sample - v),
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// Returns a channel that emits the smoothed CPU load, in percent, every `interval`.
+pub fn notify_on_load(interval: Duration, window: Duration) -> channel::Receiver<f64> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        let mut usage = match CpuUsage::new() {
+            Ok(u) => u,
+            Err(e) => {
+                eprintln!("error reading CPU usage, load-based policies are disabled: {}", e);
+                return;
+            },
+        };
+        let mut smoothed = Smoothed::new(interval, window);
+        loop {
+            thread::sleep(interval);
+            match usage.sample() {
+                Ok(busy) => {
+                    if send.send(smoothed.add(busy * 100.0)).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => ratelimit::eprintln(format!("error reading CPU usage: {}", e)),
+            }
+        }
+    });
+
+    recv
+}
@@ -0,0 +1,105 @@
+//! The MCHBAR mirror of MSR_PKG_POWER_LIMIT, in the memory controller hub's MMIO space.
+//!
+//! The package enforces the limits in both registers, and some firmware sets the MSR's lock bit
+//! (e.g. on resume from suspend) while leaving the mirror writable, so the mirror is the only way
+//! to keep changing the limits once that happens. It's only reachable through /dev/mem.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use libc;
+
+use privsep;
+
+
+/// PCI config space of the host bridge, which holds the MCHBAR base address.
+const HOST_BRIDGE_CONFIG: &str = "/sys/bus/pci/devices/0000:00:00.0/config";
+
+/// Offset of the MCHBAR register in the host bridge's config space.
+const MCHBAR_REGISTER: u64 = 0x48;
+
+/// Bit of the MCHBAR register that's set when the MMIO range is enabled.
+const MCHBAR_ENABLE: u64 = 1;
+
+/// Bits of the MCHBAR register holding the base address (38:15).
+const MCHBAR_BASE_MASK: u64 = 0x7F_FFFF_8000;
+
+/// Offset of the package power limit mirror from the MCHBAR base. It has the same layout as
+/// MSR_PKG_POWER_LIMIT.
+const PKG_POWER_LIMIT: u64 = 0x59A0;
+
+/// Lock bit of the power limit mirror, as in MSR_PKG_POWER_LIMIT.
+pub const LOCK: u64 = 1 << 63;
+
+const DEV_MEM: &str = "/dev/mem";
+
+
+/// Returns whether the power limit mirror might be reachable; it's only known for sure once
+/// it's been read.
+pub fn is_available() -> bool {
+    Path::new(HOST_BRIDGE_CONFIG).exists() && Path::new(DEV_MEM).exists()
+}
+
+/// Reads the power limit mirror.
+pub fn read_power_limit() -> io::Result<u64> {
+    if privsep::is_active() {
+        return privsep::read_mchbar();
+    }
+
+    read_power_limit_direct()
+}
+
+/// Writes the power limit mirror.
+pub fn write_power_limit(val: u64) -> io::Result<()> {
+    if privsep::is_active() {
+        return privsep::write_mchbar(val);
+    }
+
+    write_power_limit_direct(val)
+}
+
+/// Reads the power limit mirror from this process, bypassing the privileged helper.
+pub fn read_power_limit_direct() -> io::Result<u64> {
+    with_power_limit(false, |p| unsafe { ptr::read_volatile(p) })
+}
+
+/// Writes the power limit mirror from this process, bypassing the privileged helper.
+pub fn write_power_limit_direct(val: u64) -> io::Result<()> {
+    with_power_limit(true, |p| unsafe { ptr::write_volatile(p, val) })
+}
+
+/// Returns the physical address of the power limit mirror.
+fn power_limit_address() -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    File::open(HOST_BRIDGE_CONFIG)?.read_exact_at(&mut bytes, MCHBAR_REGISTER)?;
+    let mchbar = u64::from_le_bytes(bytes);
+    if mchbar & MCHBAR_ENABLE == 0 {
+        return Err(io::Error::other("MCHBAR isn't enabled"));
+    }
+
+    Ok((mchbar & MCHBAR_BASE_MASK) + PKG_POWER_LIMIT)
+}
+
+/// Maps the page holding the power limit mirror, and calls `f` with a pointer to the mirror.
+fn with_power_limit<T, F: FnOnce(*mut u64) -> T>(write: bool, f: F) -> io::Result<T> {
+    let addr = power_limit_address()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let page = addr & !(page_size - 1);
+
+    let file = OpenOptions::new().read(true).write(write).open(DEV_MEM)?;
+    let prot = if write { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+    let map = unsafe {
+        libc::mmap(ptr::null_mut(), page_size as usize, prot, libc::MAP_SHARED, file.as_raw_fd(), page as libc::off_t)
+    };
+    if map == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let res = f(unsafe { (map as *mut u8).add((addr - page) as usize) } as *mut u64);
+    unsafe { libc::munmap(map, page_size as usize) };
+    Ok(res)
+}
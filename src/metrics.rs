@@ -0,0 +1,56 @@
+//! Latency measurements, e.g. from noticing a power source change to having applied the new
+//! profile.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+
+/// How many of the most recent samples to keep.
+const MAX_SAMPLES: usize = 100;
+
+
+/// The most recent latency samples.
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    samples: VecDeque<Duration>,
+}
+
+impl Latencies {
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// Summarizes the samples, or returns `None` if there aren't any yet.
+    pub fn summary(&self) -> Option<Summary> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<Duration> = self.samples.iter().cloned().collect();
+        sorted.sort();
+
+        // Nearest-rank percentiles.
+        let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+        Some(Summary {
+            count: sorted.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// Percentiles of the recent latency samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of samples the percentiles are taken over.
+    pub count: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
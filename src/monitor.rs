@@ -0,0 +1,130 @@
+//! `monitor` subcommand: the read-only features, without root.
+//!
+//! The daemon's status comes from its D-Bus interface, and temperatures and power are read from
+//! the coretemp hwmon driver and powercap when the MSRs can't be opened, so this can run as a
+//! `systemd --user` service (see `dist/lenovo-throttling-monitor.service`) while everything that
+//! writes stays in the system service.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use dbus::{BusType, Connection};
+use failure::Error;
+
+use bus;
+use control::Status;
+#[cfg(feature = "metrics")]
+use prometheus;
+use ratelimit;
+use temps::{self, Temperatures};
+
+
+/// How often the daemon's status is fetched, by default.
+const DEFAULT_INTERVAL_SEC: u64 = 5;
+
+
+/// Options for the monitor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Address to serve Prometheus metrics on; without one, a summary is printed instead.
+    pub metrics: Option<String>,
+    /// How often to fetch the daemon's status.
+    pub interval: Duration,
+}
+
+impl Options {
+    /// Parses the arguments following `monitor`: `--metrics ADDR` and `--interval SECS`.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+        let mut opts = Options { metrics: None, interval: Duration::from_secs(DEFAULT_INTERVAL_SEC) };
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or_else(|| format_err!("{} needs a value", name));
+
+            match arg.as_str() {
+                "--metrics" if cfg!(feature = "metrics") => opts.metrics = Some(value("--metrics")?),
+                "--metrics" => bail!("--metrics needs the metrics server, which this build doesn't include (the \"metrics\" feature)"),
+                "--interval" => {
+                    let v = value("--interval")?;
+                    match v.parse::<u64>() {
+                        Ok(secs) if secs > 0 => opts.interval = Duration::from_secs(secs),
+                        _ => bail!("invalid value for --interval: {}", v),
+                    }
+                },
+                _ => bail!("unknown monitor argument: {} (usage: monitor [--metrics ADDR] [--interval SECS])", arg),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Runs the monitor until it's killed.
+pub fn run(opts: &Options) -> Result<(), Error> {
+    let status = Arc::new(Mutex::new(Status::default()));
+    #[cfg(feature = "metrics")]
+    if let Some(ref addr) = opts.metrics {
+        prometheus::serve(addr, status.clone())
+            .map_err(|e| format_err!("error listening for metrics scrapes on {}: {}", addr, e))?;
+        println!("serving metrics on http://{}/metrics", addr);
+    }
+
+    let mut conn = None;
+    loop {
+        if conn.is_none() {
+            conn = Connection::get_private(BusType::System)
+                .map_err(|e| ratelimit::eprintln(format!("error connecting to the system bus: {}", e)))
+                .ok();
+        }
+
+        if let Some(ref c) = conn {
+            match bus::fetch_status(c) {
+                Ok(s) => *status.lock().unwrap() = s,
+                Err(e) => {
+                    ratelimit::eprintln(format!("error getting the daemon's status (is it running?): {}", e));
+                    // Keep the counters, which only ever go up, but don't report a stale profile.
+                    status.lock().unwrap().profile = None;
+                },
+            }
+        }
+
+        if opts.metrics.is_none() {
+            let status = status.lock().unwrap().clone();
+            println!("{}", summary(&status, Temperatures::read().or_else(|_| temps::read_hwmon()).ok()));
+        }
+
+        thread::sleep(opts.interval);
+    }
+}
+
+/// Returns a one-line summary of the daemon's status and the temperatures.
+fn summary(status: &Status, temps: Option<Temperatures>) -> String {
+    let mut parts = vec![];
+    parts.push(match status.profile {
+        Some(ref p) if status.paused => format!("profile {} (paused)", p),
+        Some(ref p) if status.forced => format!("profile {} (forced)", p),
+        Some(ref p) => format!("profile {}", p),
+        None => "daemon not reachable".to_string(),
+    });
+    if status.emergency {
+        parts.push("emergency constraints applied".to_string());
+    }
+
+    if let Some(t) = temps {
+        if let Some(package) = t.package {
+            parts.push(format!("package {} C", package));
+        }
+        if let Some(hottest) = t.cores.iter().map(|c| c.celsius).max() {
+            parts.push(format!("hottest core {} C", hottest));
+        }
+    }
+
+    if let Some(counts) = status.throttle {
+        let episodes = counts.thermal + counts.prochot + counts.critical + counts.power_limit;
+        parts.push(format!("{} throttling episodes", episodes));
+    }
+    if status.apply_failures > 0 {
+        parts.push(format!("{} of {} applies failed", status.apply_failures, status.applies));
+    }
+
+    parts.join(", ")
+}
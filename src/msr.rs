@@ -1,9 +1,22 @@
-use byteorder::{ReadBytesExt, NativeEndian, WriteBytesExt};
-use num_cpus;
+use byteorder::{NativeEndian, WriteBytesExt};
 
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{self, SeekFrom};
 use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
+
+use libc;
+
+use dump;
+use freq;
+use hwp;
+use paths;
+use privsep;
+use rapl;
+use temps;
+use topology;
+use turbo;
 
 
 /// Builder structure for reading from a MSR (Model-Specific Register).
@@ -46,17 +59,22 @@ impl ReadMsrBuilder {
     /// Read the value from every CPU in the system as an array.
     pub fn read(&self) -> io::Result<Vec<u64>> {
         let mut res = vec![];
-        for i in 0..num_cpus::get() {
-            let val = read_one_msr(i, self.msr)?;
+        for cpu in topology::online_cpus_or_default() {
+            let val = read_one_msr(cpu, self.msr)?;
             res.push(self.extract_bits(val));
         }
 
         Ok(res)
     }
 
-    /// Read the value from the first CPU in the system.
+    /// Read the value from the first CPU in the system that we can use.
     pub fn read_first(&self) -> io::Result<u64> {
-        Ok(self.extract_bits(read_one_msr(0, self.msr)?))
+        self.read_one(first_cpu())
+    }
+
+    /// Read the value from a single CPU in the system.
+    pub fn read_one(&self, cpu: usize) -> io::Result<u64> {
+        Ok(self.extract_bits(read_one_msr(cpu, self.msr)?))
     }
 }
 
@@ -69,7 +87,7 @@ pub struct WriteMsrBuilder {
 impl WriteMsrBuilder {
     /// Creates a new WriteMsrBuilder with the given MSR/value pair.
     ///
-    /// By default, values will be written to every CPU in the system.
+    /// By default, values will be written to every CPU in the MSR's scope (see `scope_of`).
     pub fn new(msr: u64, val: u64) -> WriteMsrBuilder {
         WriteMsrBuilder {
             msr,
@@ -77,16 +95,11 @@ impl WriteMsrBuilder {
         }
     }
 
-    /// Writes the value to all CPUs in the system.
+    /// Writes the value to every CPU in the MSR's scope: all of them for thread- and core-scoped
+    /// MSRs, one in each package (or die) for package- (or die-) scoped ones, and a single CPU for
+    /// platform-scoped ones.
     pub fn write(&self) -> io::Result<()> {
-        for cpu in 0..num_cpus::get() {
-            if let Err(e) = self.write_one(cpu) {
-                eprintln!("error updating cpu {}: {}", cpu, e);
-                return Err(e);
-            }
-        }
-
-        Ok(())
+        for_each_cpu(scope_of(self.msr).cpus(), |cpu| self.write_one(cpu))
     }
 
     /// Writes the value to a single CPU in the system.
@@ -95,18 +108,394 @@ impl WriteMsrBuilder {
     }
 }
 
+/// Which CPUs a MSR write applies to.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Every logical CPU; right for thread- and core-scoped MSRs.
+    #[default]
+    Cpu,
+    /// One CPU in each package; right for package-scoped MSRs.
+    Package,
+    /// One CPU in each die of each package; right for the RAPL MSRs, which parts with several
+    /// dies (tiles) per package have for each die.
+    Die,
+    /// A single CPU; right for MSRs that are shared by the whole platform.
+    Platform,
+}
+
+impl Scope {
+    /// Returns the CPUs that a write in this scope goes to.
+    pub fn cpus(self) -> Vec<usize> {
+        match self {
+            Scope::Cpu => topology::online_cpus_or_default(),
+            Scope::Package => topology::package_leaders_or_default(),
+            Scope::Die => rapl::die_leaders(),
+            Scope::Platform => topology::package_leaders_or_default().into_iter().take(1).collect(),
+        }
+    }
+}
+
+/// A MSR that the daemon knows about: its name in the SDM, the scope the hardware shares it at,
+/// and whether the daemon writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register {
+    pub msr: u64,
+    pub name: &'static str,
+    pub scope: Scope,
+    pub written: bool,
+}
+
+const fn read(msr: u64, name: &'static str, scope: Scope) -> Register {
+    Register { msr, name, scope, written: false }
+}
+
+const fn written(msr: u64, name: &'static str, scope: Scope) -> Register {
+    Register { msr, name, scope, written: true }
+}
+
+/// Every MSR that the daemon reads or writes. Writing a package-scoped MSR on every logical CPU is
+/// harmless when they all get the same value, but a value worked out from one package's registers
+/// mustn't be written to another package's.
+pub const REGISTRY: &[Register] = &[
+    read(dump::IA32_BIOS_SIGN_ID, "IA32_BIOS_SIGN_ID", Scope::Cpu),
+    read(turbo::MSR_PLATFORM_INFO, "MSR_PLATFORM_INFO", Scope::Package),
+    read(freq::MSR_MPERF, "IA32_MPERF", Scope::Cpu),
+    read(freq::MSR_APERF, "IA32_APERF", Scope::Cpu),
+    read(turbo::MSR_FLEX_RATIO, "MSR_FLEX_RATIO", Scope::Package),
+    read(temps::MSR_THERM_STATUS, "IA32_THERM_STATUS", Scope::Cpu),
+    written(turbo::IA32_MISC_ENABLE, "IA32_MISC_ENABLE", Scope::Cpu),
+    written(temps::MSR_TEMPERATURE_TARGET, "MSR_TEMPERATURE_TARGET", Scope::Package),
+    read(turbo::MSR_TURBO_RATIO_LIMIT, "MSR_TURBO_RATIO_LIMIT", Scope::Package),
+    written(temps::MSR_PACKAGE_THERM_STATUS, "IA32_PACKAGE_THERM_STATUS", Scope::Package),
+    read(rapl::MSR_RAPL_POWER_UNIT, "MSR_RAPL_POWER_UNIT", Scope::Die),
+    written(rapl::MSR_PKG_POWER_LIMIT, "MSR_PKG_POWER_LIMIT", Scope::Die),
+    read(rapl::MSR_PKG_ENERGY_STATUS, "MSR_PKG_ENERGY_STATUS", Scope::Die),
+    read(rapl::MSR_PKG_POWER_INFO, "MSR_PKG_POWER_INFO", Scope::Die),
+    read(rapl::MSR_DRAM_ENERGY_STATUS, "MSR_DRAM_ENERGY_STATUS", Scope::Die),
+    read(rapl::MSR_PP0_ENERGY_STATUS, "MSR_PP0_ENERGY_STATUS", Scope::Die),
+    written(rapl::MSR_PP1_POWER_LIMIT, "MSR_PP1_POWER_LIMIT", Scope::Die),
+    read(rapl::MSR_PP1_ENERGY_STATUS, "MSR_PP1_ENERGY_STATUS", Scope::Die),
+    read(turbo::MSR_CONFIG_TDP_CONTROL, "MSR_CONFIG_TDP_CONTROL", Scope::Package),
+    read(rapl::MSR_PLATFORM_ENERGY_STATUS, "MSR_PLATFORM_ENERGY_STATUS", Scope::Platform),
+    read(hwp::MSR_PM_ENABLE, "IA32_PM_ENABLE", Scope::Package),
+    read(hwp::MSR_HWP_CAPABILITIES, "IA32_HWP_CAPABILITIES", Scope::Cpu),
+    written(hwp::MSR_HWP_REQUEST_PKG, "IA32_HWP_REQUEST_PKG", Scope::Package),
+    written(hwp::MSR_HWP_REQUEST, "IA32_HWP_REQUEST", Scope::Cpu),
+];
+
+/// Returns what the daemon knows about a MSR, if anything.
+pub fn register(msr: u64) -> Option<&'static Register> {
+    REGISTRY.iter().find(|r| r.msr == msr)
+}
+
+/// Returns the scope of a MSR that the daemon writes. MSRs that aren't in `REGISTRY` (e.g. ones
+/// set with `[[custom_msr]]`) are assumed to be per-CPU, which is always safe, if redundant.
+pub fn scope_of(msr: u64) -> Scope {
+    register(msr).map_or(Scope::Cpu, |r| r.scope)
+}
+
+/// A MSR address, for messages: it displays as the MSR's name if it's in `REGISTRY` (e.g.
+/// "MSR_PKG_POWER_LIMIT"), and as its address otherwise (e.g. "MSR 0x1FC").
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Name(pub u64);
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match register(self.0) {
+            Some(r) => f.write_str(r.name),
+            None => write!(f, "MSR 0x{:X}", self.0),
+        }
+    }
+}
+
+/// Sets the bits in `mask` of a MSR to `value` on every CPU in the scope, preserving the other
+/// bits of each CPU's current value.
+pub fn update_masked(msr: u64, mask: u64, value: u64, scope: Scope) -> io::Result<()> {
+    for_each_cpu(scope.cpus(), |cpu| update_masked_one(cpu, msr, mask, value))
+}
+
+/// Like `update_masked`, but for a single CPU.
+pub fn update_masked_one(cpu: usize, msr: u64, mask: u64, value: u64) -> io::Result<()> {
+    let old = read_one_msr(cpu, msr)?;
+    let new = (old & !mask) | (value & mask);
+    if new != old {
+        write_one_msr(cpu, msr, new)?;
+    }
+    Ok(())
+}
+
+/// Reads a fixed set of MSRs over and over, for monitoring.
+///
+/// Each CPU's MSR device is opened once and kept open, and each MSR is read with a single
+/// `pread`, so sampling many MSRs on a machine with lots of CPUs stays cheap. With privilege
+/// separation, each CPU's MSRs are read with a single request to the helper instead.
+pub struct Sampler {
+    cpus: Vec<SampledCpu>,
+    /// The latest value of each MSR, in the order the reads were given.
+    values: Vec<u64>,
+}
+
+struct SampledCpu {
+    cpu: usize,
+    /// The CPU's MSR device, unless reads go through the privileged helper.
+    file: Option<File>,
+    msrs: Vec<u64>,
+    /// Where each MSR's value goes in `Sampler::values`.
+    slots: Vec<usize>,
+    /// Scratch space for the helper's response.
+    buf: Vec<u64>,
+}
+
+impl Sampler {
+    /// Prepares to read the given (cpu, MSR) pairs.
+    pub fn new(reads: &[(usize, u64)]) -> io::Result<Sampler> {
+        let mut cpus: Vec<SampledCpu> = vec![];
+        for (slot, &(cpu, msr)) in reads.iter().enumerate() {
+            if let Some(c) = cpus.iter_mut().find(|c| c.cpu == cpu) {
+                c.msrs.push(msr);
+                c.slots.push(slot);
+                continue;
+            }
+
+            let file = if privsep::is_active() {
+                None
+            } else {
+                Some(File::open(paths::get().msr_device(cpu))?)
+            };
+            cpus.push(SampledCpu { cpu, file, msrs: vec![msr], slots: vec![slot], buf: vec![] });
+        }
+
+        for c in cpus.iter_mut() {
+            c.buf = vec![0; c.msrs.len()];
+        }
+
+        Ok(Sampler { cpus, values: vec![0; reads.len()] })
+    }
+
+    /// Reads every MSR, returning the values in the order the reads were given.
+    pub fn sample(&mut self) -> io::Result<&[u64]> {
+        for c in self.cpus.iter_mut() {
+            match c.file {
+                Some(ref file) => {
+                    for (&msr, &slot) in c.msrs.iter().zip(c.slots.iter()) {
+                        self.values[slot] = read_at(file, c.cpu, msr)?;
+                    }
+                },
+                None => {
+                    privsep::read_msrs(c.cpu, &c.msrs, &mut c.buf)?;
+                    for (&v, &slot) in c.buf.iter().zip(c.slots.iter()) {
+                        self.values[slot] = v;
+                    }
+                },
+            }
+        }
+
+        Ok(&self.values)
+    }
+}
+
+/// Returns the first CPU that we can use, for MSRs that read the same on every CPU.
+pub fn first_cpu() -> usize {
+    topology::online_cpus_or_default().first().cloned().unwrap_or(0)
+}
+
+/// Updates each CPU in turn, stopping at the first error, except that a CPU whose MSR device we
+/// can't access (e.g. one that a container doesn't expose) is skipped rather than stopping the
+/// others from being updated. That's only an error if no CPU could be updated at all.
+fn for_each_cpu<F>(cpus: Vec<usize>, mut update: F) -> io::Result<()>
+    where F: FnMut(usize) -> io::Result<()>
+{
+    let mut denied = None;
+    let mut updated = false;
+    for cpu in cpus {
+        match update(cpu) {
+            Ok(()) => updated = true,
+            Err(e) => {
+                eprintln!("error updating cpu {}: {}", cpu, e);
+                if !is_access_error(&e) {
+                    return Err(e);
+                }
+                denied.get_or_insert(e);
+            },
+        }
+    }
+
+    match denied {
+        Some(e) if !updated => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns whether an error from accessing a MSR means that MSRs can't be accessed at all (as
+/// opposed to, say, the CPU not supporting a particular MSR).
+pub fn is_access_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound => true,
+        _ => e.raw_os_error() == Some(libc::ENXIO) || e.raw_os_error() == Some(libc::ENODEV),
+    }
+}
+
+/// Returns whether an error from reading a MSR means that this CPU doesn't have it: the msr driver
+/// turns the fault from reading an unsupported MSR into EIO. A short read is treated the same way,
+/// since there's no value to use either way.
+pub fn is_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EIO) || e.kind() == io::ErrorKind::UnexpectedEof
+}
+
+/// What kind of MSR access is available on this system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether MSRs can be read.
+    pub read: bool,
+    /// Whether MSRs can be written; kernels booted with `msr.allow_writes=off` (or in lockdown
+    /// mode) allow reads but not writes.
+    pub write: bool,
+}
+
+impl Capabilities {
+    /// Probes MSR access by reading IA32_MISC_ENABLE and writing the same value back, which
+    /// doesn't change anything. Not MSR_PKG_POWER_LIMIT, since writes to it fail once the BIOS has
+    /// locked it, while other MSRs (the temperature target, Turbo Boost) can still be written.
+    pub fn probe() -> Capabilities {
+        let cpu = first_cpu();
+        let val = match read_one_msr(cpu, turbo::IA32_MISC_ENABLE) {
+            Ok(v) => v,
+            Err(_) => return Capabilities { read: false, write: false },
+        };
+
+        Capabilities {
+            read: true,
+            write: write_one_msr(cpu, turbo::IA32_MISC_ENABLE, val).is_ok(),
+        }
+    }
+
+    /// Prints a summary of which features are available.
+    pub fn report(&self) {
+        match (self.read, self.write) {
+            (true, true) => println!("MSR access: read/write"),
+            (true, false) => {
+                println!("MSR access: read-only (writes are disabled by the kernel)");
+                println!("  status and monitoring are available; MSR limits will not be applied");
+            },
+            (false, _) => {
+                println!("MSR access: unavailable (is the msr module loaded, and are we root?)");
+            },
+        }
+    }
+}
+
 fn read_one_msr(cpu: usize, msr: u64) -> io::Result<u64> {
-    let mut file = File::open(format!("/dev/cpu/{}/msr", cpu))?;
-    file.seek(SeekFrom::Start(msr))?;
-    file.read_u64::<NativeEndian>()
+    if privsep::is_active() {
+        return privsep::read_msr(cpu, msr);
+    }
+
+    read_one_msr_direct(cpu, msr)
 }
 
 fn write_one_msr(cpu: usize, msr: u64, val: u64) -> io::Result<()> {
+    if privsep::is_active() {
+        return privsep::write_msr(cpu, msr, val);
+    }
+
+    write_one_msr_direct(cpu, msr, val)
+}
+
+/// Reads a MSR on a single CPU from this process, bypassing the privileged helper.
+pub fn read_one_msr_direct(cpu: usize, msr: u64) -> io::Result<u64> {
+    let file = File::open(paths::get().msr_device(cpu))?;
+    read_at(&file, cpu, msr)
+}
+
+/// Reads several MSRs on a single CPU from this process, opening its MSR device only once.
+pub fn read_msrs_direct(cpu: usize, msrs: &[u64]) -> io::Result<Vec<u64>> {
+    let file = File::open(paths::get().msr_device(cpu))?;
+    msrs.iter().map(|&msr| read_at(&file, cpu, msr)).collect()
+}
+
+/// Reads a MSR from a CPU's open MSR device, with a short read reported as an `UnexpectedEof` error
+/// that says which MSR it was, rather than just "failed to fill whole buffer".
+fn read_at(file: &File, cpu: usize, msr: u64) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    match file.read_exact_at(&mut bytes, msr) {
+        Ok(()) => Ok(u64::from_ne_bytes(bytes)),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("short read of {} on CPU {}", Name(msr), cpu),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a MSR on a single CPU from this process, bypassing the privileged helper.
+pub fn write_one_msr_direct(cpu: usize, msr: u64, val: u64) -> io::Result<()> {
+    let device = paths::get().msr_device(cpu);
+    #[cfg(feature = "sim")]
+    let val = ::sim::written_value(::std::path::Path::new(&device), msr, val)?;
     let mut file = OpenOptions::new()
         .write(true)
         .create(false)
-        .open(format!("/dev/cpu/{}/msr", cpu))?;
+        .open(device)?;
     file.seek(SeekFrom::Start(msr))?;
     file.write_u64::<NativeEndian>(val)?;
+    #[cfg(feature = "sim")]
+    ::sim::record(&::sim::Write::Msr { cpu, msr, value: val });
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_msrs_are_listed_once() {
+        for (i, r) in REGISTRY.iter().enumerate() {
+            assert!(REGISTRY[i + 1..].iter().all(|n| n.msr != r.msr), "{} is listed twice", r.name);
+        }
+    }
+
+    #[test]
+    fn managed_msrs_have_the_right_scope() {
+        // MSR_TEMPERATURE_TARGET is shared by the package, MSR_PKG_POWER_LIMIT and
+        // MSR_PP1_POWER_LIMIT by the die; IA32_MISC_ENABLE and IA32_HWP_REQUEST are per logical CPU.
+        assert_eq!(scope_of(0x1A2), Scope::Package);
+        assert_eq!(scope_of(0x610), Scope::Die);
+        assert_eq!(scope_of(0x640), Scope::Die);
+        assert_eq!(scope_of(0x1A0), Scope::Cpu);
+        assert_eq!(scope_of(0x774), Scope::Cpu);
+        assert_eq!(scope_of(0x772), Scope::Package);
+    }
+
+    #[test]
+    fn unknown_msrs_are_per_cpu() {
+        assert_eq!(scope_of(0x1FC), Scope::Cpu);
+    }
+
+    #[test]
+    fn names_fall_back_to_the_address() {
+        assert_eq!(Name(0x610).to_string(), "MSR_PKG_POWER_LIMIT");
+        assert_eq!(Name(0x1FC).to_string(), "MSR 0x1FC");
+    }
+
+    #[test]
+    fn inaccessible_cpus_are_skipped() {
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+
+        let mut updated = vec![];
+        let res = for_each_cpu(vec![0, 1, 2], |cpu| if cpu == 1 { Err(denied()) } else { updated.push(cpu); Ok(()) });
+        assert!(res.is_ok());
+        assert_eq!(updated, vec![0, 2]);
+
+        assert!(for_each_cpu(vec![0, 1], |_| Err(denied())).is_err());
+
+        // Other errors still stop the update.
+        let mut updated = vec![];
+        let res = for_each_cpu(vec![0, 1, 2], |cpu| {
+            updated.push(cpu);
+            if cpu == 1 { Err(io::Error::from_raw_os_error(libc::EIO)) } else { Ok(()) }
+        });
+        assert!(res.is_err());
+        assert_eq!(updated, vec![0, 1]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Locations of the kernel interfaces that we use, so that tests can point the daemon at a fixture
+//! tree and containers can bind-mount the host's sysfs somewhere else, and of our own files, so
+//! that packages (e.g. a NixOS module, where the config lives in the Nix store) can put them
+//! wherever they like.
+//!
+//! Each location can be overridden with a hidden command line flag (e.g. `--msr-path=...`, given
+//! before any subcommand) or an environment variable (e.g. `LT_MSR_PATH=...`); flags win.
+
+use std::env;
+use std::path::Path;
+use std::sync::OnceLock;
+
+
+/// Overridable locations, as (flag, environment variable, default).
+const CONFIG: (&str, &str, &str) = ("--config", "LT_CONFIG", "/etc/lenovo-throttling/config.toml");
+const MSR: (&str, &str, &str) = ("--msr-path", "LT_MSR_PATH", "/dev/cpu/{cpu}/msr");
+const POWER_SUPPLY: (&str, &str, &str) = ("--power-supply-root", "LT_POWER_SUPPLY_ROOT", "/sys/class/power_supply");
+const POWERCAP: (&str, &str, &str) = ("--powercap-root", "LT_POWERCAP_ROOT", "/sys/class/powercap");
+const HWMON: (&str, &str, &str) = ("--hwmon-root", "LT_HWMON_ROOT", "/sys/class/hwmon");
+const STATE: (&str, &str, &str) = ("--state-dir", "LT_STATE_DIR", "/var/lib/lenovo-throttling");
+const RUNTIME: (&str, &str, &str) = ("--runtime-dir", "LT_RUNTIME_DIR", "/run/lenovo-throttling");
+
+const ALL: [(&str, &str, &str); 7] = [CONFIG, MSR, POWER_SUPPLY, POWERCAP, HWMON, STATE, RUNTIME];
+
+/// Where the config used to be read from by default, relative to the working directory. It's
+/// still read from there if the default doesn't exist, with a warning.
+const LEGACY_CONFIG: &str = "config.toml";
+
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+/// Where to find the kernel interfaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    /// The config file; by default, `/etc/lenovo-throttling/config.toml`.
+    pub config: String,
+    /// Template for each CPU's MSR device; `{cpu}` is replaced with the CPU number.
+    pub msr: String,
+    /// Directory containing the power supplies (`/sys/class/power_supply`).
+    pub power_supply: String,
+    /// Directory containing the powercap zones (`/sys/class/powercap`).
+    pub powercap: String,
+    /// Directory containing the hardware monitoring chips (`/sys/class/hwmon`).
+    pub hwmon: String,
+    /// Directory that persistent state is written to.
+    pub state: String,
+    /// Directory that the plain-text state files are written to.
+    pub runtime: String,
+}
+
+impl Paths {
+    /// Returns the MSR device of the given CPU.
+    pub fn msr_device(&self, cpu: usize) -> String {
+        self.msr.replace("{cpu}", &cpu.to_string())
+    }
+
+    /// Returns whether the power supplies are the real ones, which UPower is watching too.
+    #[cfg(feature = "dbus")]
+    pub fn is_real_power_supply(&self) -> bool {
+        self.power_supply == POWER_SUPPLY.2
+    }
+}
+
+/// Sets up the paths from the command line and environment, returning the remaining arguments.
+///
+/// This must be called before anything else in the process looks at the paths.
+pub fn init(args: Vec<String>) -> Vec<String> {
+    let mut rest = vec![];
+    let mut flags: Vec<(String, String)> = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let known = ALL.iter().any(|&(flag, _, _)| arg.starts_with(flag));
+        if !known || !rest.is_empty() {
+            rest.push(arg);
+            continue;
+        }
+
+        // Accept both "--flag=value" and "--flag value".
+        match arg.split_once('=') {
+            Some((flag, value)) => flags.push((flag.to_string(), value.to_string())),
+            None => {
+                if let Some(value) = args.next() {
+                    flags.push((arg, value));
+                }
+            },
+        }
+    }
+
+    let lookup = |(flag, var, default): (&str, &str, &str)| {
+        flags.iter().rev().find(|f| f.0 == flag).map(|f| f.1.clone())
+            .or_else(|| env::var(var).ok())
+            .unwrap_or_else(|| default.to_string())
+    };
+    let paths = Paths {
+        config: if flags.iter().any(|f| f.0 == CONFIG.0) || env::var_os(CONFIG.1).is_some() {
+            lookup(CONFIG)
+        } else {
+            default_config()
+        },
+        msr: lookup(MSR),
+        power_supply: lookup(POWER_SUPPLY),
+        powercap: lookup(POWERCAP),
+        hwmon: lookup(HWMON),
+        state: lookup(STATE),
+        runtime: lookup(RUNTIME),
+    };
+
+    if PATHS.set(paths).is_err() {
+        eprintln!("paths were already set up; ignoring overrides");
+    }
+    rest
+}
+
+/// Returns the default config file, falling back to the one in the working directory.
+fn default_config() -> String {
+    if !Path::new(CONFIG.2).exists() && Path::new(LEGACY_CONFIG).exists() {
+        eprintln!("WARNING: reading config.toml from the working directory, which is deprecated; move it to {} \
+                   or give its path with --config", CONFIG.2);
+        return LEGACY_CONFIG.to_string();
+    }
+    CONFIG.2.to_string()
+}
+
+/// Returns the paths, or the defaults if `init` hasn't been called.
+pub fn get() -> &'static Paths {
+    PATHS.get_or_init(|| {
+        let default = |(_, _, default): (&str, &str, &str)| default.to_string();
+        Paths {
+            config: default(CONFIG),
+            msr: default(MSR),
+            power_supply: default(POWER_SUPPLY),
+            powercap: default(POWERCAP),
+            hwmon: default(HWMON),
+            state: default(STATE),
+            runtime: default(RUNTIME),
+        }
+    })
+}
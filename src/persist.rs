@@ -0,0 +1,115 @@
+//! State that should survive restarts, such as a manually forced profile.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use paths;
+use rules::Profile;
+use runtime;
+use travel;
+
+
+/// Name of the file holding the forced profile, if any.
+const OVERRIDE_FILE: &str = "override";
+
+/// Name of the file that exists while travel mode is on, holding the settings to put back.
+const TRAVEL_FILE: &str = "travel";
+
+
+/// A profile forced by a client, replacing automatic selection until it's cleared or expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub profile: Profile,
+    /// When the override expires, if ever.
+    pub until: Option<SystemTime>,
+}
+
+impl Override {
+    /// Returns whether the override has expired.
+    pub fn is_expired(&self) -> bool {
+        self.until.is_some_and(|t| t <= SystemTime::now())
+    }
+
+    /// Describes the override for status output, e.g. "battery (expires in 25 min)".
+    pub fn describe(&self) -> String {
+        match self.until.map(|t| t.duration_since(SystemTime::now())) {
+            None => format!("{} (until cleared)", self.profile.name()),
+            Some(Ok(remaining)) => format!("{} (expires in {} min)", self.profile.name(), remaining.as_secs().div_ceil(60)),
+            Some(Err(_)) => format!("{} (expired)", self.profile.name()),
+        }
+    }
+}
+
+
+/// Creates the state directory (`/var/lib/lenovo-throttling`, unless it's been overridden).
+///
+/// Like `runtime::prepare`, this must be called before dropping privileges.
+pub fn prepare() -> io::Result<()> {
+    runtime::create_owned_dir(&paths::get().state)
+}
+
+/// Loads the persisted override, ignoring it if it has expired.
+pub fn load_override() -> Option<Override> {
+    let path = Path::new(&paths::get().state).join(OVERRIDE_FILE);
+    let contents = fs::read_to_string(path).ok()?;
+
+    // The file is the profile name, optionally followed by the expiry in seconds since the epoch.
+    // Whether a named profile still exists is up to the daemon to check.
+    let mut parts = contents.split_whitespace();
+    let profile = Profile::parse(parts.next()?);
+    let until = match parts.next() {
+        Some(secs) => Some(UNIX_EPOCH + Duration::from_secs(secs.parse::<u64>().ok()?)),
+        None => None,
+    };
+
+    let o = Override { profile, until };
+    if o.is_expired() { None } else { Some(o) }
+}
+
+/// Persists the override, or removes the persisted one if `None`.
+pub fn save_override(o: Option<&Override>) -> io::Result<()> {
+    let o = match o {
+        Some(o) => o,
+        None => return remove(OVERRIDE_FILE),
+    };
+
+    let mut contents = o.profile.name().to_string();
+    if let Some(until) = o.until {
+        let secs = until.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        contents.push_str(&format!(" {}", secs));
+    }
+
+    write_atomically(OVERRIDE_FILE, &contents)
+}
+
+/// Loads the settings saved when travel mode was switched on, or `None` if it's off.
+pub fn load_travel() -> Option<travel::Saved> {
+    let contents = fs::read_to_string(Path::new(&paths::get().state).join(TRAVEL_FILE)).ok()?;
+    Some(travel::Saved::parse(&contents))
+}
+
+/// Persists that travel mode is on, with the settings to put back, or that it's off if `None`.
+pub fn save_travel(saved: Option<&travel::Saved>) -> io::Result<()> {
+    match saved {
+        Some(s) => write_atomically(TRAVEL_FILE, &s.encode()),
+        None => remove(TRAVEL_FILE),
+    }
+}
+
+fn remove(name: &str) -> io::Result<()> {
+    match fs::remove_file(Path::new(&paths::get().state).join(name)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Replaces a state file atomically, so that a crash can't leave a partial one behind.
+fn write_atomically(name: &str, contents: &str) -> io::Result<()> {
+    let tmp = Path::new(&paths::get().state).join(format!(".{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    writeln!(file, "{}", contents)?;
+    fs::rename(&tmp, Path::new(&paths::get().state).join(name))
+}
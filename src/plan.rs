@@ -0,0 +1,161 @@
+//! Applying a profile's updates as a unit: every write is checked before any of them is made, and
+//! if one fails anyway, the ones before it are undone. Otherwise a failure part way through a
+//! profile switch would leave a mix of the old and new profiles in place.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+use mchbar;
+use msr;
+use privsep;
+use ratelimit;
+use sysfs;
+use topology;
+use Update;
+
+
+/// An update that has been checked, with the values it will overwrite.
+struct Step {
+    update: Update,
+    original: Original,
+}
+
+/// The values an update overwrites, for rolling it back.
+enum Original {
+    /// The MSR's value on each CPU that's written.
+    Msr(u64, Vec<(usize, u64)>),
+    /// The attribute's value.
+    Sysfs(String, String),
+    /// The MCHBAR power limit.
+    Mchbar(u64),
+    /// Nothing to roll back, since the attribute didn't exist.
+    None,
+}
+
+/// Updates that are ready to be committed together.
+pub struct ApplyPlan {
+    steps: Vec<Step>,
+}
+
+impl ApplyPlan {
+    /// Checks that every update can be made, by reading what it will overwrite. Nothing is
+    /// written; if any update fails the check, every failure is returned.
+    pub fn prepare(updates: &[Update]) -> Result<ApplyPlan, Vec<(Update, io::Error)>> {
+        let mut steps = vec![];
+        let mut failures = vec![];
+        for (i, update) in updates.iter().enumerate() {
+            match check(update) {
+                Ok(original) => steps.push(Step { update: update.clone(), original }),
+                // An earlier write to the same driver (e.g. switching intel_pstate to active mode)
+                // can create the attribute, so it can only be checked when it's written.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && creatable(update, &updates[..i]) => {
+                    steps.push(Step { update: update.clone(), original: Original::None });
+                },
+                Err(e) => failures.push((update.clone(), e)),
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(ApplyPlan { steps })
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Makes every update, in order. If one fails, the updates already made are rolled back and
+    /// its error is returned.
+    pub fn commit(&self) -> io::Result<()> {
+        for (i, step) in self.steps.iter().enumerate() {
+            if let Err(e) = step.update.apply() {
+                ratelimit::eprintln(format!("rolling back {} update(s) after a failed write", i));
+                roll_back(&self.steps[..i]);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts back the values that a committed plan overwrote. Every update is rolled back even if
+    /// some fail; the number that failed is returned.
+    pub fn revert(&self) -> usize {
+        roll_back(&self.steps)
+    }
+}
+
+/// Returns the CPUs that an update writes to.
+fn target_cpus(update: &Update) -> Vec<usize> {
+    match *update {
+        Update::Msr(addr, _) => msr::scope_of(addr).cpus(),
+        Update::MaskedMsr(_, _, _, scope) => scope.cpus(),
+        _ => topology::online_cpus_or_default(),
+    }
+}
+
+/// Returns whether a sysfs attribute could be created by one of the earlier updates, i.e. one of
+/// them writes to the same directory.
+fn creatable(update: &Update, earlier: &[Update]) -> bool {
+    let dir = match *update {
+        Update::Sysfs(ref path, _) => Path::new(path).parent(),
+        _ => return false,
+    };
+    earlier.iter().any(|u| match *u {
+        Update::Sysfs(ref path, _) => Path::new(path).parent() == dir,
+        _ => false,
+    })
+}
+
+/// Checks that an update can be made, returning the values it will overwrite.
+fn check(update: &Update) -> io::Result<Original> {
+    match *update {
+        Update::Msr(addr, _) | Update::MaskedMsr(addr, _, _, _) => {
+            let reader = msr::ReadMsrBuilder::new(addr);
+            let mut values = vec![];
+            for cpu in target_cpus(update) {
+                match reader.read_one(cpu) {
+                    Ok(v) => values.push((cpu, v)),
+                    // A CPU that's gone offline since the CPUs were listed (e.g. SMT was switched
+                    // off) won't be written either.
+                    Err(ref e) if msr::is_access_error(e) && !topology::online_cpus_or_default().contains(&cpu) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Original::Msr(addr, values))
+        },
+        Update::Sysfs(ref path, _) | Update::SysfsMax(ref path, _) => {
+            let value = sysfs::read_value(path)?;
+            // The helper does the writing otherwise, and checks the path itself.
+            if !privsep::is_active() {
+                OpenOptions::new().write(true).open(path)?;
+            }
+            Ok(Original::Sysfs(path.clone(), value))
+        },
+        Update::Mchbar(_) => mchbar::read_power_limit().map(Original::Mchbar),
+    }
+}
+
+/// Rolls back steps that were made, most recent first, returning how many failed.
+fn roll_back(steps: &[Step]) -> usize {
+    let mut failed = 0;
+    for done in steps.iter().rev() {
+        if let Err(e) = rollback(&done.original) {
+            eprintln!("error rolling back {:?}: {}", done.update, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+fn rollback(original: &Original) -> io::Result<()> {
+    match *original {
+        Original::Msr(addr, ref values) => {
+            for &(cpu, value) in values.iter() {
+                msr::WriteMsrBuilder::new(addr, value).write_one(cpu)?;
+            }
+            Ok(())
+        },
+        Original::Sysfs(ref path, ref value) => sysfs::write_value(path, value),
+        Original::Mchbar(value) => mchbar::write_power_limit(value),
+        Original::None => Ok(()),
+    }
+}
@@ -0,0 +1,158 @@
+//! Platform power management policies that aren't specific to the CPU.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+
+use ::channel;
+use libc;
+
+use sysfs;
+
+
+/// Path to the PCIe Active State Power Management policy.
+pub const PCIE_ASPM_POLICY: &str = "/sys/module/pcie_aspm/parameters/policy";
+
+/// Directory containing one entry per SCSI host, which includes each SATA port.
+pub const SCSI_HOST_DIR: &str = "/sys/class/scsi_host";
+
+/// Name of the SATA link power management attribute of each SCSI host.
+pub const SATA_LINK_POLICY: &str = "link_power_management_policy";
+
+/// Path to the ACPI platform profile, e.g. "low-power", "balanced" or "performance".
+pub const PLATFORM_PROFILE: &str = "/sys/firmware/acpi/platform_profile";
+
+/// Path to the platform profiles that the firmware supports, separated by spaces.
+pub const PLATFORM_PROFILE_CHOICES: &str = "/sys/firmware/acpi/platform_profile_choices";
+
+/// How long to wait for a platform profile change notification before checking anyway, in
+/// milliseconds, in case the driver doesn't send them.
+const PLATFORM_PROFILE_POLL_MS: libc::c_int = 5000;
+
+
+/// PCIe ASPM policy, as accepted by the pcie_aspm module.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AspmPolicy {
+    /// Use whatever the BIOS configured.
+    Default,
+    /// Disable ASPM.
+    Performance,
+    /// Enable L0s and L1.
+    Powersave,
+    /// Enable L0s and L1, including the L1 substates.
+    Powersupersave,
+}
+
+impl AspmPolicy {
+    /// Returns the value to write to `PCIE_ASPM_POLICY`.
+    pub fn value(&self) -> &'static str {
+        match *self {
+            AspmPolicy::Default => "default",
+            AspmPolicy::Performance => "performance",
+            AspmPolicy::Powersave => "powersave",
+            AspmPolicy::Powersupersave => "powersupersave",
+        }
+    }
+}
+
+/// SATA aggressive link power management policy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SataLinkPolicy {
+    MaxPerformance,
+    MediumPower,
+    MedPowerWithDipm,
+    MinPower,
+}
+
+impl SataLinkPolicy {
+    /// Returns the value to write to each host's `SATA_LINK_POLICY` attribute.
+    pub fn value(&self) -> &'static str {
+        match *self {
+            SataLinkPolicy::MaxPerformance => "max_performance",
+            SataLinkPolicy::MediumPower => "medium_power",
+            SataLinkPolicy::MedPowerWithDipm => "med_power_with_dipm",
+            SataLinkPolicy::MinPower => "min_power",
+        }
+    }
+}
+
+
+/// Returns the paths of the link power management attribute for every SATA host.
+pub fn sata_link_policy_attributes() -> Vec<String> {
+    let entries = match fs::read_dir(SCSI_HOST_DIR) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+
+    // Only SATA (AHCI) hosts have the attribute, so skip any others.
+    let mut paths: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().join(SATA_LINK_POLICY))
+        .filter(|p| p.exists())
+        .map(|p| p.display().to_string())
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Returns the platform profiles that the firmware supports, or an empty list if it doesn't have
+/// any.
+pub fn platform_profile_choices() -> Vec<String> {
+    match sysfs::read_value(PLATFORM_PROFILE_CHOICES) {
+        Ok(choices) => choices.split_whitespace().map(|c| c.to_string()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Returns a channel that emits the platform profile whenever it changes, e.g. because the user
+/// picked a different power mode in their desktop or pressed Fn+L/M/H.
+///
+/// The attribute is opened before returning, so that a missing platform profile can be reported.
+pub fn notify_on_platform_profile() -> io::Result<channel::Receiver<String>> {
+    let f = File::open(PLATFORM_PROFILE)?;
+    let mut current = read_open_attribute(&f)?;
+
+    let (send, recv) = channel::unbounded();
+    thread::spawn(move || {
+        loop {
+            // The kernel signals changes with POLLPRI; the attribute has to be read again to
+            // rearm it, which happens below.
+            let mut fds = libc::pollfd { fd: f.as_raw_fd(), events: libc::POLLPRI | libc::POLLERR, revents: 0 };
+            if unsafe { libc::poll(&mut fds, 1, PLATFORM_PROFILE_POLL_MS) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                eprintln!("error waiting for platform profile changes, they will be ignored: {}", e);
+                return;
+            }
+
+            let profile = match read_open_attribute(&f) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("error reading platform profile, changes will be ignored: {}", e);
+                    return;
+                },
+            };
+            if profile != current {
+                current = profile.clone();
+                if send.send(profile).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(recv)
+}
+
+/// Reads a sysfs attribute from the start of an already-open file.
+fn read_open_attribute(f: &File) -> io::Result<String> {
+    let mut buf = [0u8; 64];
+    let n = f.read_at(&mut buf, 0)?;
+    Ok(String::from_utf8_lossy(&buf[..n]).trim().to_string())
+}
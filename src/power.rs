@@ -1,24 +1,62 @@
+#[cfg(feature = "dbus")]
+use std::cmp;
+#[cfg(feature = "dbus")]
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
 use std::io::ErrorKind;
 use std::{thread, time};
 
 use ::channel;
+#[cfg(feature = "dbus")]
 use dbus::{Connection, BusType};
+#[cfg(feature = "dbus")]
 use dbus::arg::{RefArg, Variant};
 use failure::Error;
 
+use paths;
+use sysfs;
+
+
+/// How many times to try connecting to the system bus to watch UPower, which may not be up yet
+/// when we're started early in boot, before settling for polling sysfs.
+#[cfg(feature = "dbus")]
+const DBUS_ATTEMPTS: u32 = 8;
+
+/// How long to wait after the first failed attempt; the wait doubles after each one, up to
+/// `DBUS_RETRY_MAX`.
+#[cfg(feature = "dbus")]
+const DBUS_RETRY_MIN: time::Duration = time::Duration::from_secs(1);
+#[cfg(feature = "dbus")]
+const DBUS_RETRY_MAX: time::Duration = time::Duration::from_secs(30);
 
 /// Current power state
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PowerState {
-    AC,
+    AC {
+        /// What the charger can supply, in Watts, if it's known. USB-C chargers can change this
+        /// (by renegotiating their PD contract) without the AC state changing.
+        watts: Option<u64>,
+    },
     Battery,
 }
 
+impl PowerState {
+    /// Returns the name of the profile used for this power state.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PowerState::AC { .. } => "ac",
+            PowerState::Battery => "battery",
+        }
+    }
+}
+
 /// Returns the current power status, and a channel that emits power change events.
-pub fn notify_on_power_change() -> Result<(PowerState, channel::Receiver<PowerState>), Error> {
+///
+/// Changes are noticed within half of `budget`, leaving the rest for the daemon to settle and
+/// apply them. D-Bus delivers them straight away, but charger renegotiations and the receiver going
+/// away are only checked when it times out, and the fallback has to poll. If the system bus isn't
+/// up yet, sysfs is polled meanwhile, and switching to D-Bus is retried a few times.
+pub fn notify_on_power_change(budget: time::Duration) -> Result<(PowerState, channel::Receiver<PowerState>), Error> {
     // Get current state first (so we can print diffs)
     let initial_state = is_on_battery()?;
 
@@ -27,27 +65,65 @@ pub fn notify_on_power_change() -> Result<(PowerState, channel::Receiver<PowerSt
         // Track current state so we can only emit events when it's changed.
         let mut current_state = initial_state;
 
-        // Start off by polling with D-Bus. This will only return if something goes wrong.
-        match poll_dbus(&send, &mut current_state) {
-            Ok(_) => {},
-            Err(e) => {
-                // TODO: logging?
-                eprintln!("error in D-Bus polling: {}", e);
-            },
+        // The next attempt at watching UPower, as (attempts so far, when, wait after it fails).
+        // UPower only knows about the real power supplies, so don't bother if they've been
+        // overridden.
+        #[cfg(feature = "dbus")]
+        let mut retry = if paths::get().is_real_power_supply() {
+            Some((0, time::Instant::now(), DBUS_RETRY_MIN))
+        } else {
+            None
         };
 
-        // If we get here, something wonky happened and we got an unexpected message; switch to a
-        // simpler poll-based method.
-        //
-        // TODO(andrew): Allow exiting this somehow.
-        let sleep = time::Duration::from_millis(5000);
+        // Poll sysfs until we can watch UPower, and for good if we give up on it, if something
+        // wonky happens while watching it (e.g. we get an unexpected message), or if we were built
+        // without D-Bus support.
+        let sleep = budget / 2;
         loop {
+            #[cfg(feature = "dbus")]
+            if let Some((attempts, at, wait)) = retry {
+                if time::Instant::now() >= at {
+                    match watch_upower() {
+                        Ok(conn) => {
+                            if attempts > 0 {
+                                println!("connected to the system bus, watching UPower for power changes");
+                            }
+                            // Catch up on anything that changed while we were polling.
+                            if let Ok(new_state) = is_on_battery() {
+                                if new_state != current_state && send.send(new_state).is_err() {
+                                    return;
+                                }
+                                current_state = new_state;
+                            }
+                            // This only returns once the receiver has gone away, or if something
+                            // goes wrong.
+                            match poll_dbus(&conn, &send, &mut current_state, budget / 2) {
+                                Ok(_) => return,
+                                Err(e) => eprintln!("error in D-Bus polling, polling sysfs instead: {}", e),
+                            }
+                            retry = None;
+                        },
+                        Err(e) if attempts + 1 < DBUS_ATTEMPTS => {
+                            eprintln!("error connecting to the system bus, polling sysfs and retrying in {:?}: {}",
+                                      wait, e);
+                            retry = Some((attempts + 1, time::Instant::now() + wait, cmp::min(wait * 2, DBUS_RETRY_MAX)));
+                        },
+                        Err(e) => {
+                            eprintln!("error connecting to the system bus, giving up and polling sysfs: {}", e);
+                            retry = None;
+                        },
+                    }
+                }
+            }
+
             thread::sleep(sleep);
 
             match is_on_battery() {
                 Ok(new_state) => {
                     if new_state != current_state {
-                        send.send(new_state);
+                        if send.send(new_state).is_err() {
+                            return;
+                        }
                         current_state = new_state;
                     }
                 },
@@ -62,18 +138,86 @@ pub fn notify_on_power_change() -> Result<(PowerState, channel::Receiver<PowerSt
     Ok((initial_state, recv))
 }
 
+/// Returns the current battery charge level (in percent), and a channel that emits changes to it.
+///
+/// The level is `None` if the system has no battery.
+pub fn notify_on_battery_level() -> Result<(Option<u8>, channel::Receiver<u8>), Error> {
+    let initial_level = battery_level()?;
+
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        let mut current_level = initial_level;
+
+        // Charge levels change slowly, so there's no need to poll often.
+        let sleep = time::Duration::from_millis(30000);
+        loop {
+            thread::sleep(sleep);
+
+            match battery_level() {
+                Ok(Some(new_level)) => {
+                    if Some(new_level) != current_level {
+                        if send.send(new_level).is_err() {
+                            return;
+                        }
+                        current_level = Some(new_level);
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    // TODO: logging?
+                    eprintln!("error reading battery level: {}", e);
+                },
+            }
+        }
+    });
+
+    Ok((initial_level, recv))
+}
+
+/// Returns a channel that emits the battery discharge rate (in Watts) every `interval`.
+pub fn notify_on_discharge_rate(interval: time::Duration) -> channel::Receiver<f64> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+
+            match discharge_rate() {
+                Ok(rate) => {
+                    if send.send(rate).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => {
+                    // TODO: logging?
+                    eprintln!("error reading battery discharge rate: {}", e);
+                },
+            }
+        }
+    });
+
+    recv
+}
+
+/// Connects to the system bus and subscribes to UPower's power source changes.
+#[cfg(feature = "dbus")]
+fn watch_upower() -> Result<Connection, Error> {
+    let conn = Connection::get_private(BusType::System)?;
+    conn.add_match("interface='org.freedesktop.DBus.Properties',path='/org/freedesktop/UPower/devices/line_power_AC',member='PropertiesChanged'")?;
+    Ok(conn)
+}
+
+/// Watches UPower for power source changes, returning `Ok` once the receiver has gone away.
+#[cfg(feature = "dbus")]
 fn poll_dbus(
+    conn: &Connection,
     sender: &channel::Sender<PowerState>,
     current_state: &mut PowerState,
+    timeout: time::Duration,
 ) -> Result<(), Error> {
 
-    // Create the D-Bus connection.
-    let conn = Connection::get_private(BusType::System)?;
-    conn.add_match("interface='org.freedesktop.DBus.Properties',path='/org/freedesktop/UPower/devices/line_power_AC',member='PropertiesChanged'")?;
-
     // Repeat our dbus loop ~forever
     'outer: loop {
-        for msg in conn.incoming(10000) {
+        for msg in conn.incoming(timeout.as_millis() as u32) {
             // Look for 'PropertiesChanged' events.
             if let Ok((_name, changed)) = msg.read2::<
                 &str,                               // Message name
@@ -83,14 +227,18 @@ fn poll_dbus(
                 // We only care if there's an argument named 'Online' that's an integer.
                 if let Some(val) = changed.get("Online") {
                     if let Some(i) = val.as_i64() {
-                        let new_state = if i == 0 {
-                            PowerState::Battery
-                        } else {
-                            PowerState::AC
+                        // This is only the adapter UPower calls `AC`; another supply (e.g. a
+                        // USB-C charger) may still be online when it goes offline.
+                        let new_state = match is_on_battery() {
+                            Ok(state) => state,
+                            Err(_) if i == 0 => PowerState::Battery,
+                            Err(_) => PowerState::AC { watts: charger_watts() },
                         };
 
                         if new_state != *current_state {
-                            sender.send(new_state);
+                            if sender.send(new_state).is_err() {
+                                return Ok(());
+                            }
                             *current_state = new_state;
                         }
                         continue;
@@ -102,28 +250,208 @@ fn poll_dbus(
                 bail!("unknown message received");
             }
         }
+
+        // UPower doesn't tell us about chargers renegotiating, or about supplies other than `AC`
+        // coming and going, so check for those whenever we time out waiting for a message.
+        if let Ok(new_state) = is_on_battery() {
+            if new_state != *current_state {
+                if sender.send(new_state).is_err() {
+                    return Ok(());
+                }
+                *current_state = new_state;
+            }
+        }
     }
 }
 
-// Returns the current power state of the system.
-fn is_on_battery() -> Result<PowerState, Error> {
-    let mut f = match File::open("/sys/class/power_supply/AC/online") {
-        Ok(f) => f,
-        Err(e) => {
-            // Assume that we're on battery if we don't find an AC supply
-            if e.kind() == ErrorKind::NotFound {
-                return Ok(PowerState::Battery);
+// Returns the maximum power available from any online external power supply, in Watts.
+fn charger_watts() -> Option<u64> {
+    let entries = fs::read_dir(&paths::get().power_supply).ok()?;
+
+    let mut best = None;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = format!("{}", entry.path().display());
+        let read = |name: &str| sysfs::read_value(&format!("{}/{}", path, name));
+
+        match read("type") {
+            Ok(ref t) if t == "Mains" || t.starts_with("USB") => {},
+            _ => continue,
+        }
+        if read("online").ok().as_deref() != Some("1") {
+            continue;
+        }
+
+        // Voltage and current are in microvolts and microamps; most plain (non-USB) adapters
+        // don't report them.
+        let micro = |name: &str| read(name).ok().and_then(|v| v.parse::<f64>().ok());
+        if let (Some(uv), Some(ua)) = (micro("voltage_max"), micro("current_max")) {
+            let watts = (uv * ua / 1e12).round() as u64;
+            if watts > 0 && best.is_none_or(|b| watts > b) {
+                best = Some(watts);
             }
+        }
+    }
+
+    best
+}
+
+// Returns the sysfs directories of all batteries in the system. ThinkPads with a bridge battery
+// have two, and keep the removable one's directory (with `present` at 0) when it's taken out.
+// Batteries with a `scope` of "Device" are in peripherals (e.g. a wireless mouse) rather than
+// powering the system, so they're left out.
+fn batteries() -> Result<Vec<String>, Error> {
+    let mut paths = vec![];
+
+    for entry in fs::read_dir(&paths::get().power_supply)? {
+        let path = format!("{}", entry?.path().display());
+
+        match sysfs::read_value(&format!("{}/type", path)) {
+            Ok(ref t) if t == "Battery" => {},
+            _ => continue,
+        }
+        if sysfs::read_value(&format!("{}/present", path)).ok().as_deref() == Some("0") {
+            continue;
+        }
+        if sysfs::read_value(&format!("{}/scope", path)).ok().as_deref() == Some("Device") {
+            continue;
+        }
+        paths.push(path);
+    }
+
+    paths.sort();
+    Ok(paths)
+}
 
-            return Err(e.into());
-        },
-    };
+// Returns the charge level of all batteries in the system together, in percent.
+//
+// This is the charge left out of the total capacity, rather than the average of their levels, so
+// that e.g. an empty internal battery and a full removable one that's twice its size come to 67%.
+// Batteries that don't report their charge are counted by their level alone.
+fn battery_level() -> Result<Option<u8>, Error> {
+    let (mut now, mut full) = (0.0, 0.0);
+    let mut levels = vec![];
 
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
+    for path in batteries()? {
+        let read = |name: &str| sysfs::read_value(&format!("{}/{}", path, name)).ok().and_then(|v| v.parse::<f64>().ok());
+
+        // Batteries report either charge (in µAh) or energy (in µWh). Charge is only comparable
+        // between batteries of the same voltage, which the batteries in one machine are.
+        let charge = match (read("energy_now"), read("energy_full")) {
+            (Some(n), Some(f)) => Some((n, f)),
+            _ => read("charge_now").and_then(|n| read("charge_full").map(|f| (n, f))),
+        };
+        match charge {
+            Some((n, f)) if f > 0.0 => {
+                now += n.min(f);
+                full += f;
+            },
+            _ => {
+                let capacity = sysfs::read_value(&format!("{}/capacity", path))?;
+                levels.push(capacity.parse::<u64>()?);
+            },
+        }
+    }
+
+    if full > 0.0 {
+        levels.push((now / full * 100.0).round() as u64);
+    }
+    if levels.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some((levels.iter().sum::<u64>() / levels.len() as u64) as u8))
+}
+
+/// Wear of the batteries, from their full charge capacity and charge cycle count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryHealth {
+    /// Full charge capacity as a percentage of the design capacity, for the most worn battery.
+    pub health_pct: f64,
+    /// Highest charge cycle count, if the batteries report one.
+    pub cycles: Option<u32>,
+}
+
+/// Returns the health of the batteries, or `None` if there are none that report their capacity.
+pub fn battery_health() -> Result<Option<BatteryHealth>, Error> {
+    let mut health: Option<BatteryHealth> = None;
+
+    for path in batteries()? {
+        let read = |name: &str| sysfs::read_value(&format!("{}/{}", path, name)).ok().and_then(|v| v.parse::<f64>().ok());
+
+        // Batteries report either charge (in µAh) or energy (in µWh).
+        let capacity = match (read("charge_full"), read("charge_full_design")) {
+            (Some(full), Some(design)) => Some((full, design)),
+            _ => read("energy_full").and_then(|full| read("energy_full_design").map(|design| (full, design))),
+        };
+        let health_pct = match capacity {
+            Some((full, design)) if design > 0.0 => full / design * 100.0,
+            _ => continue,
+        };
+        // Some firmware reports zero when it doesn't count cycles.
+        let cycles = read("cycle_count").map(|c| c as u32).filter(|&c| c > 0);
+
+        health = Some(match health {
+            Some(h) => BatteryHealth {
+                health_pct: h.health_pct.min(health_pct),
+                cycles: h.cycles.max(cycles),
+            },
+            None => BatteryHealth { health_pct, cycles },
+        });
+    }
+
+    Ok(health)
+}
+
+// Returns the total rate at which the batteries are discharging, in Watts. This is zero if none of
+// them are discharging.
+fn discharge_rate() -> Result<f64, Error> {
+    let mut total = 0.0;
+
+    for path in batteries()? {
+        if sysfs::read_value(&format!("{}/status", path))? != "Discharging" {
+            continue;
+        }
+
+        // Some batteries only report current and voltage, rather than power.
+        let read = |name: &str| -> Result<f64, Error> {
+            Ok(sysfs::read_value(&format!("{}/{}", path, name))?.parse::<f64>()?)
+        };
+        let microwatts = match read("power_now") {
+            Ok(p) => p,
+            Err(_) => read("current_now")? * read("voltage_now")? / 1_000_000.0,
+        };
+
+        // Values are unsigned on most machines, but signed on some.
+        total += microwatts.abs() / 1_000_000.0;
+    }
+
+    Ok(total)
+}
+
+// Returns the current power state of the system: on AC if any external supply is online. This
+// doesn't depend on the batteries, or on the adapter being called `AC`; some models call it
+// `ADP1`, and USB-C chargers show up separately.
+fn is_on_battery() -> Result<PowerState, Error> {
+    let mut online = false;
+    for entry in fs::read_dir(&paths::get().power_supply)? {
+        let path = format!("{}", entry?.path().display());
+
+        match sysfs::read_value(&format!("{}/type", path)) {
+            Ok(ref t) if t == "Mains" || t.starts_with("USB") => {},
+            _ => continue,
+        }
+        match sysfs::read_value(&format!("{}/online", path)) {
+            Ok(ref o) if o == "1" => online = true,
+            Ok(_) => {},
+            // Assume that a supply without an online attribute isn't supplying anything.
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
 
-    Ok(if contents == "1\n" {
-        PowerState::AC
+    // No external supply at all means we're on battery.
+    Ok(if online {
+        PowerState::AC { watts: charger_watts() }
     } else {
         PowerState::Battery
     })
@@ -0,0 +1,219 @@
+//! The kernel's powercap interface to RAPL (intel_rapl), which can set the package power limits
+//! when the MSRs can't be written directly, e.g. on kernels that disable MSR writes.
+//!
+//! When the limits are written to the MSR instead, intel_rapl doesn't know about it, and may keep
+//! reporting (or later write back) the constraints it last set; `Sync` picks how to deal with that.
+
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use paths;
+use sysfs;
+
+
+/// Directory of the intel_rapl control type, under the powercap root.
+const CONTROL_TYPE: &str = "intel-rapl";
+
+/// Prefix of the package zones, e.g. "intel-rapl:0".
+const ZONE_PREFIX: &str = "intel-rapl:";
+
+/// Name of the package zone of the first package.
+const PACKAGE_ZONE_NAME: &str = "package-0";
+
+/// Prefix of the names of the first package's zones on parts with several dies (tiles) per
+/// package, which intel_rapl gives a zone each, e.g. "package-0-die-1".
+const DIE_ZONE_PREFIX: &str = "package-0-die-";
+
+/// Names of the zones whose energy counters we read, and the RAPL domain each one measures.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+const ENERGY_ZONES: &[(&str, &str)] = &[
+    (PACKAGE_ZONE_NAME, "package"),
+    ("core", "cores"),
+    ("uncore", "graphics"),
+    ("dram", "dram"),
+    ("psys", "platform"),
+];
+
+/// Attributes of a constraint that we write, after the "constraint_N_" prefix.
+const CONSTRAINT_ATTRIBUTES: &[&str] = &["power_limit_uw", "time_window_us"];
+
+/// Constraint holding PL1 (the "long_term" one).
+pub const PL1: usize = 0;
+
+/// Constraint holding PL2 (the "short_term" one).
+pub const PL2: usize = 1;
+
+/// Whether the control type is switched off, and whether we did that, and so have to switch it
+/// back on when we exit.
+static CONTROL_TYPE_OFF: AtomicBool = AtomicBool::new(false);
+static SWITCHED_OFF: AtomicBool = AtomicBool::new(false);
+
+
+/// How to keep powercap consistent with power limits written to the MSR.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Sync {
+    /// Leave powercap alone.
+    #[default]
+    Off,
+    /// Write the package zone's constraints too, so that tools reading them see the same limits.
+    Mirror,
+    /// Switch off the intel-rapl control type while the daemon runs, so that the kernel leaves
+    /// the limits alone. Not every kernel allows this, in which case the constraints are mirrored.
+    Disable,
+}
+
+impl Sync {
+    /// Returns whether the package zone's constraints should be written along with the MSR.
+    pub fn mirrors(&self) -> bool {
+        match *self {
+            Sync::Off => false,
+            Sync::Mirror => true,
+            Sync::Disable => !CONTROL_TYPE_OFF.load(Ordering::SeqCst),
+        }
+    }
+}
+
+
+/// Returns the directory of the first package's zone, if there is one, or of its first die's on
+/// parts with several.
+pub fn package_zone() -> Option<String> {
+    package_zones().into_iter().next()
+}
+
+/// Returns the directories of the first package's zones: just the one, unless it has several
+/// dies with a zone each.
+pub fn package_zones() -> Vec<String> {
+    let root = &paths::get().powercap;
+    let mut zones: Vec<String> = match fs::read_dir(root) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            // Subzones (e.g. "intel-rapl:0:1") are the cores and graphics domains.
+            .filter(|name| name.strip_prefix(ZONE_PREFIX).is_some_and(|n| !n.contains(':')))
+            .collect(),
+        Err(_) => return vec![],
+    };
+    zones.sort();
+
+    zones.into_iter()
+        .map(|zone| format!("{}/{}", root, zone))
+        .filter(|dir| {
+            sysfs::read_value(&format!("{}/name", dir))
+                .is_ok_and(|n| n == PACKAGE_ZONE_NAME || n.starts_with(DIE_ZONE_PREFIX))
+        })
+        .collect()
+}
+
+/// Returns the zones with an energy counter, as (RAPL domain name, zone directory): the first
+/// package's zone, its subzones, and the top-level DRAM and platform zones. The package comes first.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub fn energy_zones() -> Vec<(&'static str, String)> {
+    let root = &paths::get().powercap;
+    let package = match package_zone() {
+        Some(p) => p,
+        None => return vec![],
+    };
+    let subzone_prefix = format!("{}:", &package[root.len() + 1..]);
+    let mut zones: Vec<String> = match fs::read_dir(root) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.strip_prefix(ZONE_PREFIX).is_some_and(|n| !n.contains(':')) || name.starts_with(&subzone_prefix))
+            .map(|name| format!("{}/{}", root, name))
+            .collect(),
+        Err(_) => return vec![],
+    };
+    zones.sort();
+
+    ENERGY_ZONES.iter()
+        .filter_map(|&(name, domain)| {
+            let zone = zones.iter().find(|dir| sysfs::read_value(&format!("{}/name", dir)).is_ok_and(|n| n == name))?;
+            Some((domain, zone.clone()))
+        })
+        .collect()
+}
+
+/// Reads a zone's energy counter, in microjoules. Since Linux 5.10 only root can read it.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub fn read_energy_uj(zone: &str) -> io::Result<u64> {
+    read_u64(&format!("{}/energy_uj", zone))
+}
+
+/// Reads the value at which a zone's energy counter wraps around, in microjoules.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub fn read_max_energy_range_uj(zone: &str) -> io::Result<u64> {
+    read_u64(&format!("{}/max_energy_range_uj", zone))
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+fn read_u64(path: &str) -> io::Result<u64> {
+    sysfs::read_value(path)?.parse::<u64>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+}
+
+/// Returns the power limit attribute of a constraint.
+pub fn power_limit_attribute(zone: &str, constraint: usize) -> String {
+    format!("{}/constraint_{}_power_limit_uw", zone, constraint)
+}
+
+/// Returns the time window attribute of a constraint.
+pub fn time_window_attribute(zone: &str, constraint: usize) -> String {
+    format!("{}/constraint_{}_time_window_us", zone, constraint)
+}
+
+/// Returns the attribute that switches the intel-rapl control type on and off.
+pub fn control_type_attribute() -> String {
+    format!("{}/{}/enabled", paths::get().powercap, CONTROL_TYPE)
+}
+
+/// Switches the intel-rapl control type back on when it's dropped, if `disable_control_type`
+/// switched it off. That includes early returns and panics, but not SIGKILL or a crash, which
+/// leave it off until it's switched back on by hand or the machine reboots.
+pub struct ControlTypeGuard(());
+
+impl Drop for ControlTypeGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Switches off the intel-rapl control type, unless it's off already, until the returned guard is
+/// dropped.
+pub fn disable_control_type() -> io::Result<ControlTypeGuard> {
+    let attr = control_type_attribute();
+    if sysfs::read_value(&attr)? != "0" {
+        sysfs::write_value(&attr, "0")?;
+        SWITCHED_OFF.store(true, Ordering::SeqCst);
+    }
+    CONTROL_TYPE_OFF.store(true, Ordering::SeqCst);
+    Ok(ControlTypeGuard(()))
+}
+
+/// Switches the intel-rapl control type back on, if `disable_control_type` switched it off.
+fn restore() {
+    if SWITCHED_OFF.swap(false, Ordering::SeqCst) {
+        match sysfs::write_value(&control_type_attribute(), "1") {
+            Ok(()) => println!("switched the intel-rapl powercap control type back on"),
+            Err(e) => eprintln!("error switching the intel-rapl powercap control type back on: {}", e),
+        }
+    }
+}
+
+/// Returns whether a path is one of the constraint attributes that we write, for the privileged
+/// helper to check.
+pub fn is_constraint_attribute(path: &str) -> bool {
+    let root = &paths::get().powercap;
+    let rest = match path.strip_prefix(root.as_str()).and_then(|p| p.strip_prefix('/')) {
+        Some(r) => r,
+        None => return false,
+    };
+    let (zone, attr) = match rest.split_once('/') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let valid_zone = zone.strip_prefix(ZONE_PREFIX).is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+
+    valid_zone && [PL1, PL2].iter().any(|c| {
+        attr.strip_prefix(&format!("constraint_{}_", c)).is_some_and(|a| CONSTRAINT_ATTRIBUTES.contains(&a))
+    })
+}
@@ -0,0 +1,93 @@
+//! Startup checks for the access that the daemon needs, so that we can fail with a useful message
+//! instead of an IO error from deep inside the first MSR read.
+//!
+//! Running as root isn't strictly required: the MSR driver only checks for CAP_SYS_RAWIO, plus
+//! the usual file permissions on `/dev/cpu/*/msr` (which CAP_DAC_OVERRIDE, or a udev rule, can
+//! take care of). The same file permissions apply to the sysfs attributes we write.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::Path;
+
+use failure::Error;
+use libc;
+
+use msr;
+use paths;
+
+
+/// Vendor ID that Intel CPUs report.
+const INTEL_VENDOR: &str = "GenuineIntel";
+
+/// Capability numbers, from linux/capability.h.
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_SYS_RAWIO: u32 = 17;
+
+
+/// Checks that this is an Intel CPU, since the MSRs we write are specific to them.
+pub fn check_cpu() -> Result<(), Error> {
+    let f = File::open("/proc/cpuinfo")?;
+    let vendor = BufReader::new(f).lines()
+        .map_while(Result::ok)
+        .find(|l| l.starts_with("vendor_id"))
+        .and_then(|l| l.split_once(':').map(|(_, v)| v.trim().to_string()));
+
+    match vendor {
+        Some(ref v) if v == INTEL_VENDOR => Ok(()),
+        Some(v) => bail!("unsupported CPU vendor {}; only Intel CPUs can be controlled", v),
+        None => bail!("couldn't find the CPU vendor in /proc/cpuinfo"),
+    }
+}
+
+/// Checks that we can access the MSR devices, returning an explanation of how to fix it if not.
+pub fn check() -> Result<(), Error> {
+    // If we can open the first usable CPU's MSR device, we can (probably) open the others.
+    let msr_device = paths::get().msr_device(msr::first_cpu());
+    let msr_device = msr_device.as_str();
+    if !Path::new(msr_device).exists() {
+        bail!("{} doesn't exist; load the msr kernel module with `modprobe msr`", msr_device);
+    }
+
+    let root = unsafe { libc::geteuid() } == 0;
+    let caps = effective_capabilities().unwrap_or(0);
+    let has = |cap: u32| caps & (1 << cap) != 0;
+
+    let err = match OpenOptions::new().read(true).write(true).open(msr_device) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+
+    let exe = env::current_exe()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "lenovo-throttling-rust".to_string());
+    let setcap = format!("setcap cap_sys_rawio,cap_dac_override+ep {}", exe);
+
+    match err.kind() {
+        ErrorKind::PermissionDenied if !root && !has(CAP_SYS_RAWIO) => {
+            bail!("accessing MSRs requires root or CAP_SYS_RAWIO; run this as root (e.g. with \
+                   sudo), or grant the binary just the capabilities it needs with `{}`", setcap);
+        },
+        ErrorKind::PermissionDenied if !root && !has(CAP_DAC_OVERRIDE) => {
+            bail!("permission denied opening {}; it's normally only accessible by root, so \
+                   either run this as root, add CAP_DAC_OVERRIDE with `{}`, or make the MSR \
+                   devices accessible to this user with a udev rule", msr_device, setcap);
+        },
+        ErrorKind::PermissionDenied => {
+            bail!("permission denied opening {} even though we're privileged; this usually means \
+                   that the kernel is in lockdown mode (e.g. because of Secure Boot): {}",
+                  msr_device, err);
+        },
+        _ => bail!("error opening {}: {}", msr_device, err),
+    }
+}
+
+/// Returns the effective capability set of this process.
+fn effective_capabilities() -> Option<u64> {
+    let f = File::open("/proc/self/status").ok()?;
+
+    BufReader::new(f).lines()
+        .map_while(Result::ok)
+        .find(|l| l.starts_with("CapEff:"))
+        .and_then(|l| u64::from_str_radix(l["CapEff:".len()..].trim(), 16).ok())
+}
@@ -0,0 +1,115 @@
+//! Named profile presets built into the binary, scaled to the detected CPU's TDP.
+//!
+//! A preset can be selected with `preset = "..."` in the `[battery]` or `[ac]` section; anything
+//! else set in the section overrides the preset's value.
+
+use rapl;
+use ModeConfig;
+
+
+/// TDP to scale presets by when it can't be read, in Watts; typical of U-series parts.
+const DEFAULT_TDP_W: f64 = 15.0;
+
+
+/// A built-in preset, with power limits given as multiples of the CPU's TDP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pl1_factor: f64,
+    pl2_factor: f64,
+    pl1_duration: f64,
+    turbo_enabled: bool,
+    maximum_temp_c: Option<u64>,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "stock",
+        description: "Intel's default limits",
+        pl1_factor: 1.0,
+        pl2_factor: 1.25,
+        pl1_duration: 28.0,
+        turbo_enabled: true,
+        maximum_temp_c: None,
+    },
+    Preset {
+        name: "quiet",
+        description: "low limits and no Turbo Boost, for minimal fan noise",
+        pl1_factor: 0.6,
+        pl2_factor: 0.8,
+        pl1_duration: 28.0,
+        turbo_enabled: false,
+        maximum_temp_c: Some(80),
+    },
+    Preset {
+        name: "balanced",
+        description: "stock sustained power, with longer bursts",
+        pl1_factor: 1.0,
+        pl2_factor: 1.5,
+        pl1_duration: 28.0,
+        turbo_enabled: true,
+        maximum_temp_c: Some(90),
+    },
+    Preset {
+        name: "max-performance",
+        description: "as much power as the cooling allows",
+        pl1_factor: 1.6,
+        pl2_factor: 2.0,
+        pl1_duration: 56.0,
+        turbo_enabled: true,
+        maximum_temp_c: Some(95),
+    },
+];
+
+impl Preset {
+    /// Returns the preset's settings for a CPU with the given TDP.
+    pub fn resolve(&self, tdp_w: f64) -> ModeConfig {
+        ModeConfig {
+            pl1_tdp_w: Some((tdp_w * self.pl1_factor).round() as u64),
+            pl1_duration: Some(self.pl1_duration),
+            pl2_tdp_w: Some((tdp_w * self.pl2_factor).round() as u64),
+            pl2_duration: Some(0.002),
+            turbo_enabled: Some(self.turbo_enabled),
+            maximum_temp_c: self.maximum_temp_c,
+            ..ModeConfig::default()
+        }
+    }
+}
+
+/// Looks up a preset by name.
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|p| p.name == name)
+}
+
+/// Returns the TDP to scale presets by, and whether it was actually read from the CPU.
+pub fn tdp_w() -> (f64, bool) {
+    match rapl::read_tdp_w() {
+        Ok(tdp) if tdp > 0.0 => (tdp, true),
+        _ => (DEFAULT_TDP_W, false),
+    }
+}
+
+/// Prints every preset, resolved for this CPU.
+pub fn list() {
+    let (tdp, detected) = tdp_w();
+    if detected {
+        println!("CPU TDP: {} W", tdp);
+    } else {
+        println!("couldn't read the CPU's TDP (are you root?); assuming {} W", tdp);
+    }
+
+    println!();
+    println!("{:<16} {:>6} {:>6} {:>8} {:>6} {:>6}", "preset", "PL1 W", "PL2 W", "PL1 s", "turbo", "max C");
+    for p in PRESETS.iter() {
+        let conf = p.resolve(tdp);
+        println!("{:<16} {:>6} {:>6} {:>8} {:>6} {:>6}  {}",
+                 p.name,
+                 conf.pl1_tdp_w.unwrap_or(0),
+                 conf.pl2_tdp_w.unwrap_or(0),
+                 conf.pl1_duration.unwrap_or(0.0),
+                 if p.turbo_enabled { "on" } else { "off" },
+                 conf.maximum_temp_c.map_or("-".to_string(), |t| t.to_string()),
+                 p.description);
+    }
+}
@@ -0,0 +1,99 @@
+//! Checking a candidate config file against the running configuration, so that config editors can
+//! show what applying it would change before committing to it.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+
+use failure::Error;
+use libc;
+use toml::Value;
+
+use control::Preview;
+use quirks;
+use {Config, ConfigFormat, ModeConfig, apply_quirk, config_from_str};
+
+
+/// Reads and checks the config file at `path` the way a reload would, and compares its resolved
+/// profiles with those of the running configuration.
+///
+/// The path comes from the client, so only regular files are read: a FIFO would hang the daemon,
+/// and a device could be anything.
+pub fn preview(current: &Config, path: &str) -> Result<Preview, Error> {
+    // Opening a FIFO blocks without O_NONBLOCK, until something opens the other end.
+    let mut file = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY).open(path)
+        .map_err(|e| format_err!("{}: {}", path, e))?;
+    if !file.metadata()?.is_file() {
+        bail!("{} isn't a regular file", path);
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    let mut candidate = config_from_str(ConfigFormat::detect(path, &contents), &contents)?;
+    if let Some(q) = quirks::detect() {
+        apply_quirk(q, &mut candidate);
+    }
+
+    let old = resolved(current)?;
+    let new = resolved(&candidate)?;
+
+    let mut changes = vec![];
+    for &(name, ref e) in candidate.profile_errors.iter() {
+        changes.push(format!("[{}] is invalid and won't be applied: {}", name, e));
+    }
+    for (name, profile) in new.iter() {
+        match old.get(name) {
+            Some(before) => diff(name, before, profile, &mut changes),
+            None => changes.push(format!("[{}] added", name)),
+        }
+    }
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        changes.push(format!("[{}] removed", name));
+    }
+
+    let profiles = new.into_iter()
+        .map(|(name, profile)| (name, profile.to_string()))
+        .collect();
+    Ok(Preview { profiles, changes })
+}
+
+/// Returns each of a configuration's profiles by the name of its section.
+fn resolved(config: &Config) -> Result<BTreeMap<String, Value>, Error> {
+    let mut profiles = BTreeMap::new();
+    profiles.insert("battery".to_string(), to_value(&config.battery)?);
+    profiles.insert("ac".to_string(), to_value(&config.ac)?);
+    for (name, profile) in config.profiles.iter() {
+        profiles.insert(format!("profiles.{}", name), to_value(&profile.conf)?);
+    }
+    Ok(profiles)
+}
+
+fn to_value(conf: &ModeConfig) -> Result<Value, Error> {
+    Ok(Value::try_from(conf)?)
+}
+
+/// Describes how the settings in a section changed, recursing into subsections like
+/// `[ac.hwp]`.
+fn diff(section: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    let empty = BTreeMap::new();
+    let (old, new) = (old.as_table().unwrap_or(&empty), new.as_table().unwrap_or(&empty));
+
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        match (old.get(key), new.get(key)) {
+            (Some(a), Some(b)) if a == b => {},
+            (a, b) if a.is_some_and(|v| v.is_table()) || b.is_some_and(|v| v.is_table()) => {
+                diff(&format!("{}.{}", section, key), a.unwrap_or(&Value::Table(empty.clone())),
+                     b.unwrap_or(&Value::Table(empty.clone())), changes);
+            },
+            (a, b) => changes.push(format!("[{}] {}: {} -> {}", section, key, describe(a), describe(b))),
+        }
+    }
+}
+
+fn describe(value: Option<&Value>) -> String {
+    value.map_or("unset".to_string(), |v| v.to_string())
+}
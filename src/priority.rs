@@ -0,0 +1,153 @@
+//! The daemon's own CPU and I/O priority, so that it doesn't compete with the workloads whose
+//! power limits it's managing.
+//!
+//! Linux applies these to single threads, and new threads inherit them from the thread that
+//! starts them, so `apply` has to be called before any threads are started for them to cover the
+//! whole daemon. The privileged helper has been split off by then and keeps the default priority,
+//! but it only runs when the rest of the daemon asks it to.
+
+use std::io;
+use std::time::Duration;
+
+use failure::Error;
+use libc;
+
+use idle;
+
+
+/// ioprio_set(2) arguments, from linux/ioprio.h.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_CLASS_BE: u32 = 2;
+const IOPRIO_CLASS_IDLE: u32 = 3;
+
+/// Timer slack for the telemetry samplers, which don't mind waking up a little late.
+const SAMPLER_TIMER_SLACK: Duration = Duration::from_millis(100);
+
+
+/// A CPU scheduling policy for normal (non-realtime) threads.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedPolicy {
+    /// SCHED_OTHER, the default.
+    Other,
+    /// SCHED_BATCH: never preempts other threads on waking up.
+    Batch,
+    /// SCHED_IDLE: only runs when nothing else wants the CPU.
+    Idle,
+}
+
+/// An I/O scheduling class.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// The default class, at the given level from 0 (highest) to 7.
+    BestEffort,
+    /// Only gets disk time when no one else wants it.
+    Idle,
+}
+
+// The daemon's scheduling priorities.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PriorityConfig {
+    /// Nice value, from -20 to 19; lowering it below 0 doesn't work, since it's set after
+    /// dropping privileges.
+    #[serde(default = "default_nice")]
+    pub nice: i32,
+
+    /// CPU scheduling policy.
+    #[serde(default = "default_sched_policy")]
+    pub sched_policy: SchedPolicy,
+
+    /// I/O scheduling class, and level within the best-effort class.
+    #[serde(default = "default_io_class")]
+    pub io_class: IoClass,
+    #[serde(default = "default_io_level")]
+    pub io_level: u8,
+}
+
+fn default_nice() -> i32 { 5 }
+fn default_sched_policy() -> SchedPolicy { SchedPolicy::Batch }
+fn default_io_class() -> IoClass { IoClass::Idle }
+fn default_io_level() -> u8 { 7 }
+
+impl Default for PriorityConfig {
+    fn default() -> PriorityConfig {
+        PriorityConfig {
+            nice: default_nice(),
+            sched_policy: default_sched_policy(),
+            io_class: default_io_class(),
+            io_level: default_io_level(),
+        }
+    }
+}
+
+impl PriorityConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(-20..=19).contains(&self.nice) {
+            bail!("priority.nice must be between -20 and 19, not {}", self.nice);
+        }
+        if self.io_level > 7 {
+            bail!("priority.io_level must be between 0 and 7, not {}", self.io_level);
+        }
+        Ok(())
+    }
+}
+
+
+/// Sets the calling thread's priorities, which the threads it starts inherit. Failures are
+/// reported rather than returned, since the daemon works the same either way.
+pub fn apply(conf: &PriorityConfig) {
+    if let Err(e) = set_nice(conf.nice) {
+        eprintln!("error setting the daemon's nice value to {}: {}", conf.nice, e);
+    }
+    if let Err(e) = set_sched_policy(conf.sched_policy) {
+        eprintln!("error setting the daemon's scheduling policy to {:?}: {}", conf.sched_policy, e);
+    }
+    if let Err(e) = set_io_class(conf.io_class, conf.io_level) {
+        eprintln!("error setting the daemon's I/O class to {:?}: {}", conf.io_class, e);
+    }
+}
+
+/// Moves the calling thread, a sampler whose readings are only reported to clients, to the idle
+/// scheduling policy and lets its timers be batched with others. The samplers that decide what's
+/// applied (e.g. for the discharge guard or the emergency constraints) keep the daemon's priority,
+/// since they'd otherwise be starved when they matter most.
+pub fn lower_sampler() {
+    let res = set_sched_policy(SchedPolicy::Idle).and_then(|_| idle::set_timer_slack(SAMPLER_TIMER_SLACK));
+    if let Err(e) = res {
+        eprintln!("error lowering the sampler's priority: {}", e);
+    }
+}
+
+fn set_nice(nice: i32) -> io::Result<()> {
+    // With PRIO_PROCESS and 0, this only changes the calling thread on Linux.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as libc::__priority_which_t, 0, nice) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_sched_policy(policy: SchedPolicy) -> io::Result<()> {
+    let policy = match policy {
+        SchedPolicy::Other => libc::SCHED_OTHER,
+        SchedPolicy::Batch => libc::SCHED_BATCH,
+        SchedPolicy::Idle => libc::SCHED_IDLE,
+    };
+    let param = libc::sched_param { sched_priority: 0 };
+    if unsafe { libc::sched_setscheduler(0, policy, &param) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_io_class(class: IoClass, level: u8) -> io::Result<()> {
+    let prio = match class {
+        IoClass::BestEffort => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | u32::from(level),
+        IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+    };
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
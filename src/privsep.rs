@@ -0,0 +1,436 @@
+//! Privilege separation.
+//!
+//! When started as root, the daemon forks into a small privileged helper that only performs
+//! whitelisted MSR and sysfs accesses, and an unprivileged process that does everything else
+//! (config parsing, D-Bus, scheduling). The two talk over a Unix socket pair using a simple
+//! line-based protocol:
+//!
+//!   A <msr>...              -> OK           (extra MSRs to allow; first, and only once)
+//!   R <cpu> <msr>           -> OK <value>
+//!   B <cpu> <msr>...        -> OK <value>...
+//!   W <cpu> <msr> <value>   -> OK
+//!   S <path> <value>        -> OK
+//!   M                       -> OK <value>   (MCHBAR power limit)
+//!   N <value>               -> OK           (MCHBAR power limit)
+//!
+//! MSR addresses and values are in hex. Any failure is reported as `ERR <errno> <message>`, where
+//! errno is 0 if the failure didn't come from the OS.
+
+use std::ffi::CString;
+use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::sync::Mutex;
+
+use failure::Error;
+use libc;
+
+use backlight;
+use charge;
+use freq;
+use hwp;
+use mchbar;
+use msr;
+use platform;
+use powercap;
+use pstate;
+use rapl;
+use sysfs;
+use temps;
+use turbo;
+
+
+/// System user that the unprivileged process runs as, with its primary group. It's a dedicated
+/// user rather than "nobody", since the D-Bus policy lets it own the service's name and it owns the
+/// state directories; see `dist/lenovo-throttling.sysusers.conf`.
+pub const UNPRIVILEGED_USER: &str = "lenovo-throttling";
+
+/// MSRs that the helper will read on behalf of the unprivileged process.
+const READABLE_MSRS: &[u64] = &[
+    turbo::MSR_PLATFORM_INFO,
+    freq::MSR_MPERF,
+    freq::MSR_APERF,
+    temps::MSR_THERM_STATUS,
+    turbo::IA32_MISC_ENABLE,
+    temps::MSR_TEMPERATURE_TARGET,
+    temps::MSR_PACKAGE_THERM_STATUS,
+    rapl::MSR_RAPL_POWER_UNIT,
+    rapl::MSR_PKG_POWER_LIMIT,
+    rapl::MSR_PKG_ENERGY_STATUS,
+    rapl::MSR_PKG_POWER_INFO,
+    rapl::MSR_DRAM_ENERGY_STATUS,
+    rapl::MSR_PP0_ENERGY_STATUS,
+    rapl::MSR_PP1_POWER_LIMIT,
+    rapl::MSR_PP1_ENERGY_STATUS,
+    rapl::MSR_PLATFORM_ENERGY_STATUS,
+    hwp::MSR_PM_ENABLE,
+    hwp::MSR_HWP_CAPABILITIES,
+    hwp::MSR_HWP_REQUEST_PKG,
+    hwp::MSR_HWP_REQUEST,
+];
+
+/// Whether the helper will write a MSR on behalf of the unprivileged process: only the ones that
+/// the daemon itself writes.
+fn writable_msr(msr: u64) -> bool {
+    msr::register(msr).is_some_and(|r| r.written)
+}
+
+/// sysfs attributes that the helper will write on behalf of the unprivileged process.
+const WRITABLE_SYSFS: &[&str] = &[
+    ::INTEL_PSTATE_NO_TURBO,
+    pstate::STATUS,
+    pstate::MIN_PERF_PCT,
+    pstate::MAX_PERF_PCT,
+    pstate::HWP_DYNAMIC_BOOST,
+    platform::PCIE_ASPM_POLICY,
+    platform::PLATFORM_PROFILE,
+];
+
+/// sysfs attributes with variable device names, as (directory prefix, attribute name) pairs; the
+/// path may only have a single device component between the two.
+const WRITABLE_SYSFS_PATTERNS: &[(&str, &str)] = &[
+    (charge::IDEAPAD_ACPI_DRIVER, "conservation_mode"),
+    (charge::IDEAPAD_ACPI_DRIVER, "rapid_charge"),
+    (platform::SCSI_HOST_DIR, platform::SATA_LINK_POLICY),
+    (backlight::LEDS_DIR, backlight::BRIGHTNESS),
+    (backlight::BACKLIGHT_DIR, backlight::BRIGHTNESS),
+];
+
+
+/// Connection to the privileged helper, if we've forked one.
+static HELPER: Mutex<Option<Connection>> = Mutex::new(None);
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+
+/// Splits the process into a privileged helper and an unprivileged worker.
+///
+/// In the helper this never returns; in the worker it returns once privileges have been dropped,
+/// after which all MSR and sysfs writes are transparently routed through the helper. If we're not
+/// running as root there is nothing to drop, and this does nothing.
+///
+/// The helper won't serve anything until the worker has called `allow_msrs`.
+pub fn start() -> Result<(), Error> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let (helper_end, worker_end) = UnixStream::pair()?;
+    let helper = unsafe { libc::getpid() };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+
+        0 => {
+            drop(helper_end);
+
+            drop_privileges()?;
+
+            // Don't outlive the helper. Changing credentials clears this, so it has to come after
+            // dropping privileges; if the helper died in between, it's too late for the signal.
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) };
+            if unsafe { libc::getppid() } != helper {
+                bail!("the privileged helper exited while privileges were being dropped");
+            }
+
+            let reader = BufReader::new(worker_end.try_clone()?);
+            *HELPER.lock().unwrap() = Some(Connection {
+                reader,
+                writer: worker_end,
+            });
+            Ok(())
+        },
+
+        child => {
+            drop(worker_end);
+
+            if let Err(e) = serve(helper_end) {
+                eprintln!("error in privileged helper: {}", e);
+            }
+
+            // The worker has gone away; exit with its status.
+            let mut status = 0;
+            let code = unsafe {
+                libc::waitpid(child, &mut status, 0);
+                if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 1 }
+            };
+            process::exit(code);
+        },
+    }
+}
+
+/// Tells the helper which MSRs to allow reading and writing in addition to the built-in list;
+/// these come from the `custom_msr` config section. This has to be the first request, and is only
+/// accepted once, so it has to be sent before anything untrusted has been looked at.
+pub fn allow_msrs(extra_msrs: &[u64]) -> io::Result<()> {
+    if !is_active() {
+        return Ok(());
+    }
+    let mut line = "A".to_string();
+    for msr in extra_msrs {
+        line.push_str(&format!(" {:x}", msr));
+    }
+    request(&line).map(|_| ())
+}
+
+/// Returns whether MSR and sysfs accesses should go through the privileged helper.
+pub fn is_active() -> bool {
+    HELPER.lock().unwrap().is_some()
+}
+
+/// Reads a MSR on a single CPU via the privileged helper.
+pub fn read_msr(cpu: usize, msr: u64) -> io::Result<u64> {
+    let resp = request(&format!("R {} {:x}", cpu, msr))?;
+    u64::from_str_radix(&resp, 16).map_err(|_| protocol_error())
+}
+
+/// Reads several MSRs on a single CPU via the privileged helper, in one request.
+pub fn read_msrs(cpu: usize, msrs: &[u64], out: &mut [u64]) -> io::Result<()> {
+    let mut line = format!("B {}", cpu);
+    for msr in msrs {
+        line.push_str(&format!(" {:x}", msr));
+    }
+
+    let resp = request(&line)?;
+    let mut values = resp.split(' ');
+    for v in out.iter_mut() {
+        *v = values.next()
+            .and_then(|s| u64::from_str_radix(s, 16).ok())
+            .ok_or_else(protocol_error)?;
+    }
+    Ok(())
+}
+
+/// Writes a MSR on a single CPU via the privileged helper.
+pub fn write_msr(cpu: usize, msr: u64, val: u64) -> io::Result<()> {
+    request(&format!("W {} {:x} {:x}", cpu, msr, val)).map(|_| ())
+}
+
+/// Writes a sysfs attribute via the privileged helper.
+pub fn write_sysfs(path: &str, value: &str) -> io::Result<()> {
+    request(&format!("S {} {}", path, value)).map(|_| ())
+}
+
+/// Reads the MCHBAR power limit via the privileged helper.
+pub fn read_mchbar() -> io::Result<u64> {
+    let resp = request("M")?;
+    u64::from_str_radix(&resp, 16).map_err(|_| protocol_error())
+}
+
+/// Writes the MCHBAR power limit via the privileged helper.
+pub fn write_mchbar(val: u64) -> io::Result<()> {
+    request(&format!("N {:x}", val)).map(|_| ())
+}
+
+fn request(line: &str) -> io::Result<String> {
+    let mut guard = HELPER.lock().unwrap();
+    let conn = match guard.as_mut() {
+        Some(c) => c,
+        None => return Err(io::Error::new(ErrorKind::NotConnected, "privileged helper not running")),
+    };
+
+    writeln!(conn.writer, "{}", line)?;
+
+    let mut resp = String::new();
+    if conn.reader.read_line(&mut resp)? == 0 {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "privileged helper exited"));
+    }
+    let resp = resp.trim_end();
+
+    if resp == "OK" {
+        return Ok(String::new());
+    }
+    if let Some(rest) = resp.strip_prefix("OK ") {
+        return Ok(rest.to_string());
+    }
+
+    // Otherwise this is an error; rebuild the original OS error if there was one.
+    let mut parts = resp.splitn(3, ' ');
+    match (parts.next(), parts.next().and_then(|e| e.parse::<i32>().ok()), parts.next()) {
+        (Some("ERR"), Some(0), Some(msg)) => Err(io::Error::other(msg.to_string())),
+        (Some("ERR"), Some(errno), _) => Err(io::Error::from_raw_os_error(errno)),
+        _ => Err(protocol_error()),
+    }
+}
+
+fn protocol_error() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "invalid response from privileged helper")
+}
+
+/// Looks up the user and group IDs of `UNPRIVILEGED_USER`.
+pub fn unprivileged_ids() -> io::Result<(libc::uid_t, libc::gid_t)> {
+    // The simulated-hardware tests can't count on the system user having been created.
+    #[cfg(feature = "sim")]
+    let user = if ::sim::is_active() { "nobody" } else { UNPRIVILEGED_USER };
+    #[cfg(not(feature = "sim"))]
+    let user = UNPRIVILEGED_USER;
+
+    let name = CString::new(user)?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+    let err = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(ErrorKind::NotFound, format!(
+            "there's no {} user to drop privileges to; create it with dist/lenovo-throttling.sysusers.conf", user)));
+    }
+    if pwd.pw_uid == 0 {
+        return Err(io::Error::other(format!("the {} user mustn't be root", user)));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+fn drop_privileges() -> io::Result<()> {
+    let (uid, gid) = unprivileged_ids()?;
+    unsafe {
+        if libc::setgroups(0, ptr::null()) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Make sure we can't get root back.
+        if libc::setuid(0) == 0 {
+            return Err(io::Error::other("failed to drop privileges"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Services requests from the unprivileged worker until it disconnects.
+fn serve(stream: UnixStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    // Nothing else is served until the worker has said which extra MSRs its config needs.
+    let mut first = String::new();
+    if reader.read_line(&mut first)? == 0 {
+        return Ok(());
+    }
+    let extra_msrs = match parse_allowed_msrs(first.trim_end()) {
+        Some(msrs) => msrs,
+        None => {
+            writeln!(writer, "ERR 0 expected the extra MSRs first: {}", first.trim_end())?;
+            return Err(io::Error::new(ErrorKind::InvalidData, "the worker didn't send its extra MSRs first"));
+        },
+    };
+    writeln!(writer, "OK")?;
+
+    for line in reader.lines() {
+        let line = line?;
+        let resp = match handle(&line, &extra_msrs) {
+            Ok(Some(val)) => format!("OK {}", val),
+            Ok(None) => "OK".to_string(),
+            Err(e) => format!("ERR {} {}", e.raw_os_error().unwrap_or(0), e),
+        };
+        writeln!(writer, "{}", resp)?;
+    }
+
+    Ok(())
+}
+
+/// Parses an `A` request, returning the MSRs in it.
+fn parse_allowed_msrs(line: &str) -> Option<Vec<u64>> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some("A") {
+        return None;
+    }
+    fields.filter(|f| !f.is_empty()).map(|f| u64::from_str_radix(f, 16).ok()).collect()
+}
+
+fn sysfs_writable(path: &str) -> bool {
+    if WRITABLE_SYSFS.contains(&path) || powercap::is_constraint_attribute(path) || path == powercap::control_type_attribute()
+        || charge::is_threshold_attribute(path) {
+        return true;
+    }
+
+    WRITABLE_SYSFS_PATTERNS.iter().any(|&(dir, attr)| {
+        let device = path.strip_prefix(dir)
+            .and_then(|p| p.strip_prefix('/'))
+            .and_then(|p| p.strip_suffix(attr))
+            .and_then(|p| p.strip_suffix('/'));
+
+        match device {
+            Some(d) => !d.is_empty() && !d.contains('/') && d != "." && d != "..",
+            None => false,
+        }
+    })
+}
+
+fn handle(line: &str, extra_msrs: &[u64]) -> io::Result<Option<String>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("invalid request: {}", line));
+    let denied = || io::Error::new(ErrorKind::PermissionDenied, format!("not allowed: {}", line));
+    let parse_hex = |s: Option<&str>| s.and_then(|s| u64::from_str_radix(s, 16).ok());
+    let parse_cpu = |s: Option<&str>| s.and_then(|s| s.parse::<usize>().ok());
+
+    let mut parts = line.splitn(4, ' ');
+    match parts.next() {
+        Some("R") => {
+            let cpu = parse_cpu(parts.next()).ok_or_else(invalid)?;
+            let msr = parse_hex(parts.next()).ok_or_else(invalid)?;
+            if !READABLE_MSRS.contains(&msr) && !extra_msrs.contains(&msr) {
+                return Err(denied());
+            }
+
+            msr::read_one_msr_direct(cpu, msr).map(|v| Some(format!("{:x}", v)))
+        },
+
+        Some("B") => {
+            let mut fields = line.split(' ').skip(1);
+            let cpu = parse_cpu(fields.next()).ok_or_else(invalid)?;
+            let msrs = fields.map(|f| parse_hex(Some(f))).collect::<Option<Vec<u64>>>().ok_or_else(invalid)?;
+            if msrs.is_empty() {
+                return Err(invalid());
+            }
+            if msrs.iter().any(|m| !READABLE_MSRS.contains(m) && !extra_msrs.contains(m)) {
+                return Err(denied());
+            }
+
+            let values = msr::read_msrs_direct(cpu, &msrs)?;
+            let values: Vec<String> = values.iter().map(|v| format!("{:x}", v)).collect();
+            Ok(Some(values.join(" ")))
+        },
+
+        Some("W") => {
+            let cpu = parse_cpu(parts.next()).ok_or_else(invalid)?;
+            let msr = parse_hex(parts.next()).ok_or_else(invalid)?;
+            let val = parse_hex(parts.next()).ok_or_else(invalid)?;
+            if !writable_msr(msr) && !extra_msrs.contains(&msr) {
+                return Err(denied());
+            }
+
+            msr::write_one_msr_direct(cpu, msr, val).map(|_| None)
+        },
+
+        Some("S") => {
+            let mut rest = line.get(2..).unwrap_or("").splitn(2, ' ');
+            let path = rest.next().ok_or_else(invalid)?;
+            let value = rest.next().ok_or_else(invalid)?;
+            if !sysfs_writable(path) {
+                return Err(denied());
+            }
+
+            sysfs::write_value_direct(path, value).map(|_| None)
+        },
+
+        Some("M") => mchbar::read_power_limit_direct().map(|v| Some(format!("{:x}", v))),
+
+        Some("N") => {
+            let val = parse_hex(parts.next()).ok_or_else(invalid)?;
+            mchbar::write_power_limit_direct(val).map(|_| None)
+        },
+
+        _ => Err(invalid()),
+    }
+}
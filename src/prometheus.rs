@@ -0,0 +1,272 @@
+//! Prometheus metrics endpoint, for dashboards.
+//!
+//! Metrics are served over plain HTTP at `/metrics`, in the text exposition format. Every metric is
+//! prefixed with `lenovo_throttling_`, and labels are named after what they break the metric down
+//! by: `cpu`, `core` and `package` for temperatures, `domain` for RAPL power, `reason` for
+//! throttling and `result` for applies. Temperatures and power are sampled when scraped; the
+//! counters come from the daemon's status.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use control::Status;
+use msr;
+use powercap;
+use rapl::{self, Domain};
+use ratelimit;
+use temps::{self, TemperatureSampler};
+
+
+/// How long a scraper may take to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request that we'll read.
+const MAX_REQUEST_LEN: u64 = 4096;
+
+
+/// Starts serving metrics on the given address, e.g. "127.0.0.1:9477".
+pub fn serve(addr: &str, status: Arc<Mutex<Status>>) -> io::Result<()> {
+    // Bind and open the MSRs before returning, since neither is possible once the sandbox is on.
+    let listener = TcpListener::bind(addr)?;
+    let mut sampler = Sampler::new();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.and_then(|s| handle_client(s, &status, &mut sampler));
+            if let Err(e) = res {
+                eprintln!("error serving metrics scrape: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: TcpStream, status: &Arc<Mutex<Status>>, sampler: &mut Sampler) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_LEN));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers; nothing in them matters.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+        line.clear();
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (code, body) = if !request.starts_with("GET ") {
+        ("405 Method Not Allowed", String::new())
+    } else if path == "/metrics" {
+        let status = status.lock().unwrap().clone();
+        ("200 OK", sampler.render(&status))
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let mut writer = &stream;
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}", code, body.len(), body)
+}
+
+
+/// Samples temperatures and RAPL energy for each scrape.
+struct Sampler {
+    /// The MSR sampler, or `None` to read the coretemp hwmon driver instead.
+    temps: Option<TemperatureSampler>,
+    energy: Option<Energy>,
+    /// Names of the RAPL domains, in the order their energy counters are sampled.
+    domains: Vec<&'static str>,
+    /// Energy used by each domain since the first scrape, in Joules.
+    energy_total: Vec<f64>,
+    /// The counters at the previous scrape, to work out power from.
+    last: Option<(Vec<u64>, Instant)>,
+}
+
+/// Where the energy counters are read from.
+enum Energy {
+    /// The RAPL MSRs, which count in `Units::energy` Joules and wrap at 32 bits.
+    Msr(rapl::Units, msr::Sampler),
+    /// The powercap zones, as (directory, wrapping point), which count in microjoules. These don't
+    /// need the MSRs, so they work when running unprivileged on kernels before 5.10.
+    Powercap(Vec<(String, u64)>),
+}
+
+impl Energy {
+    fn read(&mut self) -> io::Result<Vec<u64>> {
+        match *self {
+            Energy::Msr(_, ref mut sampler) => Ok(sampler.sample()?.iter().map(|&e| e & 0xFFFF_FFFF).collect()),
+            Energy::Powercap(ref zones) => zones.iter().map(|(zone, _)| powercap::read_energy_uj(zone)).collect(),
+        }
+    }
+
+    /// Returns the energy used between two readings of the `i`th counter, in Joules.
+    fn joules(&self, i: usize, last: u64, current: u64) -> f64 {
+        match *self {
+            // The counters are 32 bits wide, and wrap every few minutes under load.
+            Energy::Msr(units, _) => (current as u32).wrapping_sub(last as u32) as f64 * units.energy,
+            Energy::Powercap(ref zones) => {
+                let used = if current >= last { current - last } else { current + zones[i].1 - last };
+                used as f64 / 1e6
+            },
+        }
+    }
+}
+
+impl Sampler {
+    fn new() -> Sampler {
+        let temps = TemperatureSampler::new()
+            .map_err(|e| eprintln!("error reading temperatures from the MSRs, falling back to coretemp: {}", e))
+            .ok();
+
+        let present: Vec<Domain> = Domain::ALL.iter().cloned().filter(|&d| rapl::domains().has(d)).collect();
+        let cpu = msr::first_cpu();
+        let mut reads = vec![(cpu, rapl::MSR_PKG_ENERGY_STATUS)];
+        reads.extend(present.iter().map(|d| (cpu, d.energy_status_msr())));
+        let mut domains = vec!["package"];
+        domains.extend(present.iter().map(|d| d.name()));
+
+        let rapl = rapl::Units::read().and_then(|u| Ok((u, msr::Sampler::new(&reads)?)));
+        let energy = match rapl {
+            Ok((u, s)) => Some(Energy::Msr(u, s)),
+            Err(e) => {
+                let zones: Vec<(&'static str, String)> = powercap::energy_zones().into_iter()
+                    .filter(|(_, zone)| powercap::read_energy_uj(zone).is_ok())
+                    .collect();
+                if zones.is_empty() {
+                    eprintln!("error reading RAPL energy counters, power won't be exported \
+                               (powercap's energy_uj is only readable by root since Linux 5.10): {}", e);
+                    None
+                } else {
+                    domains = zones.iter().map(|&(d, _)| d).collect();
+                    let zones = zones.into_iter()
+                        .map(|(_, zone)| {
+                            let range = powercap::read_max_energy_range_uj(&zone).unwrap_or(u64::MAX);
+                            (zone, range)
+                        })
+                        .collect();
+                    Some(Energy::Powercap(zones))
+                }
+            },
+        };
+
+        Sampler { temps, energy, energy_total: vec![0.0; domains.len()], domains, last: None }
+    }
+
+    /// Returns every metric, in the text exposition format.
+    fn render(&mut self, status: &Status) -> String {
+        let mut out = Metrics::default();
+
+        let readings = match self.temps {
+            Some(ref mut s) => s.read(),
+            None => temps::read_hwmon(),
+        };
+        if let Ok(t) = readings {
+            if let Some(package) = t.package {
+                out.metric("package_temperature_celsius", "gauge", "Package temperature.");
+                out.sample("package_temperature_celsius", &[], package);
+            }
+            out.metric("core_temperature_celsius", "gauge", "Temperature of each core.");
+            for c in t.cores.iter() {
+                let labels = [("cpu", c.cpu.to_string()), ("core", c.core.to_string()), ("package", c.package.to_string())];
+                out.sample("core_temperature_celsius", &labels, c.celsius);
+            }
+        }
+
+        if let Some(watts) = self.sample_power() {
+            out.metric("rapl_power_watts", "gauge", "Average power of each RAPL domain since the previous scrape.");
+            for (domain, w) in self.domains.iter().zip(watts.iter()) {
+                out.sample("rapl_power_watts", &[("domain", domain.to_string())], format!("{:.3}", w));
+            }
+            out.metric("rapl_energy_joules_total", "counter", "Energy used by each RAPL domain since the first scrape.");
+            for (domain, j) in self.domains.iter().zip(self.energy_total.iter()) {
+                out.sample("rapl_energy_joules_total", &[("domain", domain.to_string())], format!("{:.3}", j));
+            }
+        }
+
+        if let Some(counts) = status.throttle {
+            out.metric("throttle_episodes_total", "counter", "Throttling episodes seen, by reason.");
+            let reasons = [
+                ("thermal", counts.thermal),
+                ("prochot", counts.prochot),
+                ("critical", counts.critical),
+                ("power_limit", counts.power_limit),
+            ];
+            for &(reason, n) in reasons.iter() {
+                out.sample("throttle_episodes_total", &[("reason", reason.to_string())], n);
+            }
+        }
+
+        out.metric("applies_total", "counter", "Profile applies, by whether they succeeded.");
+        out.sample("applies_total", &[("result", "success".to_string())], status.applies - status.apply_failures);
+        out.sample("applies_total", &[("result", "failure".to_string())], status.apply_failures);
+
+        out.metric("power_limit_changes_total", "counter", "Changes to the power limits made by something else.");
+        out.sample("power_limit_changes_total", &[], status.power_limit_changes);
+
+        if let Some(ref ledger) = status.energy {
+            out.metric("profile_energy_joules_total", "counter", "Package energy used while each profile was applied.");
+            for &(ref profile, usage) in ledger.totals() {
+                out.sample("profile_energy_joules_total", &[("profile", profile.to_string())], format!("{:.3}", usage.joules));
+            }
+        }
+
+        if let Some(ref profile) = status.profile {
+            out.metric("profile", "gauge", "Profile that's currently applied.");
+            out.sample("profile", &[("profile", profile.clone())], 1);
+        }
+
+        out.text
+    }
+
+    /// Reads the energy counters, returning each domain's average power since the last call.
+    fn sample_power(&mut self) -> Option<Vec<f64>> {
+        let energy = self.energy.as_mut()?;
+        let counters = match energy.read() {
+            Ok(v) => v,
+            Err(e) => {
+                ratelimit::eprintln(format!("error reading RAPL energy counters: {}", e));
+                self.last = None;
+                return None;
+            },
+        };
+        let now = Instant::now();
+
+        let mut watts = None;
+        if let Some((ref last, time)) = self.last {
+            let elapsed = now.duration_since(time).as_secs_f64();
+            let joules: Vec<f64> = counters.iter().zip(last.iter()).enumerate()
+                .map(|(i, (&e, &l))| energy.joules(i, l, e))
+                .collect();
+            for (total, j) in self.energy_total.iter_mut().zip(joules.iter()) {
+                *total += j;
+            }
+            watts = Some(joules.iter().map(|j| j / elapsed).collect());
+        }
+        self.last = Some((counters, now));
+        watts
+    }
+}
+
+/// Text exposition format output.
+#[derive(Default)]
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP lenovo_throttling_{} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE lenovo_throttling_{} {}", name, kind);
+    }
+
+    fn sample<T: ToString>(&mut self, name: &str, labels: &[(&str, String)], value: T) {
+        let labels: Vec<String> = labels.iter().map(|&(k, ref v)| format!("{}=\"{}\"", k, v)).collect();
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.join(",")) };
+        let _ = writeln!(self.text, "lenovo_throttling_{}{} {}", name, labels, value.to_string());
+    }
+}
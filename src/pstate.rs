@@ -0,0 +1,52 @@
+//! intel_pstate driver parameters.
+
+use std::path::Path;
+
+use sysfs;
+
+
+/// Directory containing the intel_pstate driver's global attributes.
+const INTEL_PSTATE_DIR: &str = "/sys/devices/system/cpu/intel_pstate";
+
+/// Path to the intel_pstate operation mode ("active", "passive" or "off").
+pub const STATUS: &str = "/sys/devices/system/cpu/intel_pstate/status";
+
+/// Paths to the lower and upper limits on P-states, as a percentage of the maximum.
+pub const MIN_PERF_PCT: &str = "/sys/devices/system/cpu/intel_pstate/min_perf_pct";
+pub const MAX_PERF_PCT: &str = "/sys/devices/system/cpu/intel_pstate/max_perf_pct";
+
+/// Path to the knob that boosts performance after I/O waits; only present in active mode with
+/// HWP.
+pub const HWP_DYNAMIC_BOOST: &str = "/sys/devices/system/cpu/intel_pstate/hwp_dynamic_boost";
+
+
+/// intel_pstate operation mode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// intel_pstate picks P-states itself (or via HWP).
+    Active,
+    /// intel_pstate acts as a scaling driver for the generic cpufreq governors.
+    Passive,
+}
+
+impl Status {
+    /// Returns the value to write to `STATUS`.
+    pub fn value(&self) -> &'static str {
+        match *self {
+            Status::Active => "active",
+            Status::Passive => "passive",
+        }
+    }
+}
+
+
+/// Returns whether the intel_pstate driver is loaded.
+pub fn is_available() -> bool {
+    Path::new(INTEL_PSTATE_DIR).exists()
+}
+
+/// Reads one of the percentage attributes.
+pub fn read_perf_pct(path: &str) -> Option<u8> {
+    sysfs::read_value(path).ok()?.parse::<u8>().ok()
+}
@@ -0,0 +1,130 @@
+//! Known model-specific workarounds, keyed by DMI product, and whether to use any of the
+//! Lenovo-specific behaviour at all.
+
+use std::sync::OnceLock;
+
+use sysfs;
+
+
+/// DMI vendor of the system, which is "LENOVO" on every Lenovo model.
+const SYS_VENDOR: &str = "/sys/class/dmi/id/sys_vendor";
+
+
+/// Known-needed workarounds for a specific model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirk {
+    /// Human-readable model name; this is matched against the DMI product version (where Lenovo
+    /// stores the marketing name) and product name.
+    pub product: &'static str,
+    /// Whether the embedded controller also enforces the limits in the MCHBAR mirror of
+    /// MSR_PKG_POWER_LIMIT, and will revert MSR-only changes.
+    pub mchbar_required: bool,
+    /// Whether the BIOS is known to set the lock bit on MSR_PKG_POWER_LIMIT.
+    pub power_limit_locked: bool,
+    /// Highest PL2 (in Watts) that's known to be safe for this model's power delivery.
+    pub max_safe_pl2_w: Option<u64>,
+}
+
+const QUIRKS: &[Quirk] = &[
+    Quirk {
+        product: "ThinkPad X1 Carbon 6th",
+        mchbar_required: true,
+        power_limit_locked: false,
+        max_safe_pl2_w: Some(44),
+    },
+    Quirk {
+        product: "ThinkPad T480s",
+        mchbar_required: true,
+        power_limit_locked: false,
+        max_safe_pl2_w: Some(44),
+    },
+    Quirk {
+        product: "ThinkPad T480",
+        mchbar_required: true,
+        power_limit_locked: false,
+        max_safe_pl2_w: Some(44),
+    },
+    Quirk {
+        product: "ThinkPad P1 Gen 2",
+        mchbar_required: false,
+        power_limit_locked: true,
+        max_safe_pl2_w: None,
+    },
+];
+
+
+/// Which vendor's workarounds to use.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Vendor {
+    /// Lenovo's on Lenovo machines, and none elsewhere.
+    #[default]
+    Auto,
+    /// Lenovo's, even if the DMI vendor says otherwise (e.g. after a motherboard replacement).
+    Lenovo,
+    /// None: only the standard Intel interfaces are used.
+    Generic,
+}
+
+/// Whether we're in generic mode, once it's been decided.
+static GENERIC: OnceLock<bool> = OnceLock::new();
+
+/// Decides whether to run in generic mode. This has to happen before anything asks, and can only
+/// happen once; without it, the vendor is detected.
+pub fn set_vendor(vendor: Vendor) {
+    let generic = match vendor {
+        Vendor::Auto => !is_lenovo(),
+        Vendor::Lenovo => false,
+        Vendor::Generic => true,
+    };
+    let _ = GENERIC.set(generic);
+}
+
+/// Returns whether to leave out the model quirks and the MCHBAR mirror, which are specific to
+/// Lenovo's firmware and embedded controller.
+pub fn generic() -> bool {
+    *GENERIC.get_or_init(|| !is_lenovo())
+}
+
+fn is_lenovo() -> bool {
+    sysfs::read_value(SYS_VENDOR).is_ok_and(|v| v.eq_ignore_ascii_case("lenovo"))
+}
+
+/// Returns the DMI product names of this machine (version first, then name).
+pub fn dmi_products() -> Vec<String> {
+    ["/sys/class/dmi/id/product_version", "/sys/class/dmi/id/product_name"].iter()
+        .filter_map(|path| sysfs::read_value(path).ok())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Looks up the quirks for this machine, if any are known and we're not in generic mode.
+pub fn detect() -> Option<&'static Quirk> {
+    if generic() {
+        return None;
+    }
+    let products = dmi_products();
+
+    // Match on a whole-word prefix so that e.g. "T480" doesn't match a "T480s".
+    QUIRKS.iter().find(|q| {
+        products.iter().any(|p| {
+            p.starts_with(q.product) && p[q.product.len()..].chars().next().is_none_or(|c| c == ' ')
+        })
+    })
+}
+
+impl Quirk {
+    /// Prints a summary of the workarounds for this model.
+    pub fn report(&self) {
+        println!("detected {}, with known quirks:", self.product);
+        if self.mchbar_required {
+            println!("  - the EC enforces limits via MCHBAR; MSR-only changes may be reverted");
+        }
+        if self.power_limit_locked {
+            println!("  - the BIOS locks MSR_PKG_POWER_LIMIT; power limits may not apply");
+        }
+        if let Some(max) = self.max_safe_pl2_w {
+            println!("  - PL2 is capped at {} W", max);
+        }
+    }
+}
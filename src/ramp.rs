@@ -0,0 +1,82 @@
+//! Stepping the power and temperature limits from one profile's to another's over a few seconds,
+//! since jumping from e.g. 44 W to 15 W at once makes the fans and clocks lurch.
+
+use std::time::{Duration, Instant};
+
+use ModeConfig;
+
+
+/// How often to step the limits during a ramp.
+pub const STEP: Duration = Duration::from_millis(500);
+
+
+/// A ramp in progress. Only PL1, PL2 and the temperature target are ramped; everything else is
+/// switched at the first step.
+#[derive(Debug, Clone)]
+pub struct Ramp {
+    /// The settings that were applied when the ramp started.
+    from: ModeConfig,
+    steps: u32,
+    /// Steps taken so far, including the current one.
+    taken: u32,
+    /// When the next step is due.
+    next: Instant,
+}
+
+impl Ramp {
+    /// Starts a ramp away from `from` that takes `duration`, at its first step. Returns `None` if
+    /// the duration is too short to need more than one step.
+    pub fn start(from: ModeConfig, duration: Duration) -> Option<Ramp> {
+        let steps = (duration.as_secs_f64() / STEP.as_secs_f64()).ceil() as u32;
+        if steps < 2 {
+            return None;
+        }
+        Some(Ramp { from, steps, taken: 1, next: Instant::now() + STEP })
+    }
+
+    /// Returns whether going from `from` to `to` lowers the limits, going by the first limit that
+    /// both set and that differs.
+    pub fn is_down(from: &ModeConfig, to: &ModeConfig) -> bool {
+        let limits = [
+            (from.pl1_tdp_w, to.pl1_tdp_w),
+            (from.pl2_tdp_w, to.pl2_tdp_w),
+            (from.maximum_temp_c, to.maximum_temp_c),
+        ];
+        limits.iter()
+            .filter_map(|&(a, b)| Some((a?, b?)))
+            .find(|&(a, b)| a != b)
+            .is_some_and(|(a, b)| b < a)
+    }
+
+    /// Returns the settings for the current step toward `to`.
+    pub fn current(&self, to: &ModeConfig) -> ModeConfig {
+        let fraction = f64::from(self.taken) / f64::from(self.steps);
+        let step = |from: Option<u64>, to: Option<u64>| match (from, to) {
+            (Some(a), Some(b)) => Some((a as f64 + (b as f64 - a as f64) * fraction).round() as u64),
+            (_, to) => to,
+        };
+
+        ModeConfig {
+            pl1_tdp_w: step(self.from.pl1_tdp_w, to.pl1_tdp_w),
+            pl2_tdp_w: step(self.from.pl2_tdp_w, to.pl2_tdp_w),
+            maximum_temp_c: step(self.from.maximum_temp_c, to.maximum_temp_c),
+            ..to.clone()
+        }
+    }
+
+    /// Moves on to the next step.
+    pub fn advance(&mut self) {
+        self.taken += 1;
+        self.next = Instant::now() + STEP;
+    }
+
+    /// Returns how long until the next step is due.
+    pub fn until_next(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    /// Returns whether the current step is the last, i.e. the target has been reached.
+    pub fn is_done(&self) -> bool {
+        self.taken >= self.steps
+    }
+}
@@ -0,0 +1,650 @@
+//! Running Average Power Limit (RAPL): the units, domains and power limit MSRs, and encoding and
+//! decoding their fields. This is the library's public interface to the limits, and only needs the
+//! msr driver, not any of the daemon's optional dependencies.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ::channel;
+use failure::Error;
+
+use msr;
+use priority;
+use topology;
+
+
+/// Address of MSR_RAPL_POWER_UNIT.
+pub const MSR_RAPL_POWER_UNIT: u64 = 0x606;
+
+/// Typical contents of MSR_RAPL_POWER_UNIT (1/8 W, 61 uJ and 976 us), for when the real units
+/// can't be read.
+pub const DEFAULT_POWER_UNIT: u64 = 0xA0E03;
+
+/// Address of MSR_PKG_POWER_LIMIT.
+pub const MSR_PKG_POWER_LIMIT: u64 = 0x610;
+
+/// Lock bit of MSR_PKG_POWER_LIMIT; once set, the MSR can't be changed until the next reset.
+pub const POWER_LIMIT_LOCK: u64 = 1 << 63;
+
+/// The power limit field of PL1 and PL2, in power units.
+pub const POWER_LIMIT_MASK: u64 = 0x7FFF;
+
+/// Address of MSR_PKG_ENERGY_STATUS, a 32-bit counter of the energy used by the package.
+pub const MSR_PKG_ENERGY_STATUS: u64 = 0x611;
+
+/// Address of MSR_PKG_POWER_INFO, which holds the package's TDP.
+pub const MSR_PKG_POWER_INFO: u64 = 0x614;
+
+/// Address of MSR_PP1_POWER_LIMIT, the power limit for the integrated GPU.
+pub const MSR_PP1_POWER_LIMIT: u64 = 0x640;
+
+/// Address of MSR_DRAM_ENERGY_STATUS.
+pub const MSR_DRAM_ENERGY_STATUS: u64 = 0x619;
+
+/// Address of MSR_PP0_ENERGY_STATUS, for the cores.
+pub const MSR_PP0_ENERGY_STATUS: u64 = 0x639;
+
+/// Address of MSR_PP1_ENERGY_STATUS, for the integrated GPU.
+pub const MSR_PP1_ENERGY_STATUS: u64 = 0x641;
+
+/// Address of MSR_PLATFORM_ENERGY_STATUS, for the whole platform (PSys).
+pub const MSR_PLATFORM_ENERGY_STATUS: u64 = 0x64D;
+
+/// The time window fields of both power limits in MSR_PKG_POWER_LIMIT.
+pub const WINDOW_FIELDS: u64 = (0b1111111 << 17) | (0b1111111 << 49);
+
+/// Set once the hardware has been seen ignoring writes to the time windows.
+static WINDOWS_LOCKED: AtomicBool = AtomicBool::new(false);
+
+static DOMAINS: OnceLock<Domains> = OnceLock::new();
+
+/// Largest value of the "Y" (exponent) part of a time window; it's 5 bits wide.
+const TIME_WINDOW_MAX_Y: u32 = 31;
+
+/// Largest value of the "Z" (fractional) part of a time window; it's 2 bits wide.
+const TIME_WINDOW_MAX_Z: u32 = 3;
+
+/// Largest value of the 7-bit time window field, which Atom parts treat as a plain multiplier.
+const TIME_WINDOW_MAX_ATOM: u64 = 0b1111111;
+
+/// Family 6 models of Atom SoCs that use the Atom unit and time window encodings; this is the same
+/// list that the kernel's intel_rapl driver uses.
+const ATOM_MODELS: &[u32] = &[
+    0x37,   // Silvermont (Bay Trail)
+    0x4A,   // Silvermont (Merrifield)
+    0x4C,   // Airmont (Cherry Trail, Braswell)
+    0x5A,   // Airmont (Moorefield)
+];
+
+
+/// How the CPU encodes RAPL units and time windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// The encoding documented in the SDM and used by Core parts: units are fractions of a
+    /// Watt/Joule, and time windows are `2^Y * (1.0 + Z/4.0)` time units.
+    Core,
+    /// Older Atom SoCs: the power unit is `2^PU` milliwatts, the energy unit is `2^ESU`
+    /// microjoules, and the time window field is simply a multiple of the time unit.
+    Atom,
+}
+
+impl Encoding {
+    /// Works out the encoding used by this machine's CPU, defaulting to `Core` if it can't be
+    /// determined.
+    pub fn detect() -> Encoding {
+        match cpu_model() {
+            Some(model) if ATOM_MODELS.contains(&model) => Encoding::Atom,
+            _ => Encoding::Core,
+        }
+    }
+}
+
+/// Returns the model number of the first CPU, if it's a family 6 Intel CPU.
+fn cpu_model() -> Option<u32> {
+    let f = File::open("/proc/cpuinfo").ok()?;
+
+    let (mut vendor, mut family, mut model) = (None, None, None);
+    for line in BufReader::new(f).lines() {
+        let line = line.ok()?;
+
+        // Only look at the first CPU.
+        if line.is_empty() {
+            break;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim().to_string();
+        match key {
+            "vendor_id" => vendor = Some(value),
+            "cpu family" => family = value.parse::<u32>().ok(),
+            "model" => model = value.parse::<u32>().ok(),
+            _ => {},
+        }
+    }
+
+    match (vendor, family) {
+        (Some(ref v), Some(6)) if v == "GenuineIntel" => model,
+        _ => None,
+    }
+}
+
+
+/// A RAPL power domain, besides the package one that every RAPL CPU has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Domain {
+    /// The cores (PP0).
+    Cores,
+    /// The integrated GPU (PP1); mostly on client parts.
+    Graphics,
+    /// Memory; mostly on server parts.
+    Dram,
+    /// The whole platform (PSys), on Skylake and newer.
+    Platform,
+}
+
+impl Domain {
+    pub const ALL: &'static [Domain] = &[Domain::Cores, Domain::Graphics, Domain::Dram, Domain::Platform];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Domain::Cores => "cores",
+            Domain::Graphics => "graphics",
+            Domain::Dram => "dram",
+            Domain::Platform => "platform",
+        }
+    }
+
+    pub fn energy_status_msr(&self) -> u64 {
+        match *self {
+            Domain::Cores => MSR_PP0_ENERGY_STATUS,
+            Domain::Graphics => MSR_PP1_ENERGY_STATUS,
+            Domain::Dram => MSR_DRAM_ENERGY_STATUS,
+            Domain::Platform => MSR_PLATFORM_ENERGY_STATUS,
+        }
+    }
+}
+
+/// A die (tile) of a package with its own package RAPL domain, on parts with more than one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tile {
+    pub package: u32,
+    pub die: u32,
+    /// The die's online CPUs; its RAPL registers are read on the first.
+    pub cpus: Vec<usize>,
+}
+
+impl Tile {
+    /// Describes the tile for status output, e.g. "package 0 die 1 (CPUs 20-21)".
+    pub fn describe(&self) -> String {
+        format!("package {} die {} (CPUs {})", self.package, self.die, topology::format_cpu_list(&self.cpus))
+    }
+}
+
+/// The optional RAPL domains that this CPU has. Which ones exist varies a lot between parts, and
+/// newer server parts may have domains we don't know about at all; anything that isn't found is
+/// simply skipped.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Domains {
+    present: Vec<Domain>,
+    /// The dies with their own package domain, if there's more than one.
+    tiles: Vec<Tile>,
+}
+
+impl Domains {
+    /// Probes for each domain by reading its energy counter. Like the kernel's intel_rapl
+    /// driver, a domain whose counter can't be read or is zero is treated as missing, since some
+    /// parts have the MSRs without the domain behind them.
+    pub fn probe() -> Domains {
+        let present = Domain::ALL.iter().cloned()
+            .filter(|d| {
+                let energy = msr::ReadMsrBuilder::new(d.energy_status_msr()).read_first();
+                energy.is_ok_and(|v| v & 0xFFFFFFFF != 0)
+            })
+            .collect();
+        Domains { present, tiles: probe_tiles() }
+    }
+
+    pub fn has(&self, domain: Domain) -> bool {
+        self.present.contains(&domain)
+    }
+
+    /// Returns the dies with their own package domain, on parts with more than one.
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    /// Prints the domains, for status output.
+    pub fn report(&self) {
+        let names: Vec<&str> = self.present.iter().map(|d| d.name()).collect();
+        println!("RAPL domains = package{}", names.iter().map(|n| format!(", {}", n)).collect::<String>());
+        for tile in self.tiles.iter() {
+            println!("RAPL package domain on {}", tile.describe());
+        }
+    }
+}
+
+/// Finds the dies that have a package domain of their own, going by the same test as the other
+/// domains. Parts that split the package into tiles don't necessarily give every tile one, and
+/// writing the power limits to a tile without one would only fail.
+fn probe_tiles() -> Vec<Tile> {
+    let dies = match topology::Topology::read() {
+        Ok(t) => t.dies(),
+        Err(_) => return vec![],
+    };
+    if dies.len() < 2 {
+        return vec![];
+    }
+    dies.into_iter()
+        .filter(|(_, _, cpus)| {
+            let energy = msr::ReadMsrBuilder::new(MSR_PKG_ENERGY_STATUS).read_one(cpus[0]);
+            energy.is_ok_and(|v| v & 0xFFFFFFFF != 0)
+        })
+        .map(|(package, die, cpus)| Tile { package, die, cpus })
+        .collect()
+}
+
+/// Returns the optional RAPL domains, probing for them the first time.
+pub fn domains() -> &'static Domains {
+    DOMAINS.get_or_init(Domains::probe)
+}
+
+/// Returns the CPUs to access die-scoped MSRs on: the first online CPU of each die with its own
+/// package domain (see `Domains::tiles`), or of every die if the dies don't have their own.
+pub fn die_leaders() -> Vec<usize> {
+    let tiles = domains().tiles();
+    let dies = match topology::Topology::read() {
+        Ok(ref t) if !t.cpus.is_empty() => t.dies(),
+        _ => return vec![msr::first_cpu()],
+    };
+    let leaders: Vec<usize> = dies.into_iter()
+        .filter(|&(package, die, _)| tiles.is_empty() || tiles.iter().any(|t| (t.package, t.die) == (package, die)))
+        .map(|(_, _, cpus)| cpus[0])
+        .collect();
+    // Every tile with a domain went offline; the MSRs are better tried somewhere than nowhere.
+    if leaders.is_empty() {
+        return vec![msr::first_cpu()];
+    }
+    leaders
+}
+
+
+/// The units used by all RAPL registers, as read from MSR_RAPL_POWER_UNIT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Units {
+    /// Size of one power unit, in Watts.
+    pub power: f64,
+    /// Size of one energy unit, in Joules.
+    pub energy: f64,
+    /// Size of one time unit, in seconds.
+    pub time: f64,
+    /// How the units and time windows are encoded.
+    pub encoding: Encoding,
+}
+
+impl Units {
+    /// Reads the RAPL units from the first CPU in the system.
+    pub fn read() -> io::Result<Units> {
+        let rapl_power_unit = msr::ReadMsrBuilder::new(MSR_RAPL_POWER_UNIT).read_first()?;
+        Ok(Units::from_raw(rapl_power_unit, Encoding::detect()))
+    }
+
+    /// Decodes the RAPL units from a raw MSR_RAPL_POWER_UNIT value.
+    pub fn from_raw(rapl_power_unit: u64, encoding: Encoding) -> Units {
+        // MSR_RAPL_POWER_UNIT brief documentation:
+        //
+        //      Reserved      Reserved   Reserved
+        //          |            |         |
+        //          v            v         v
+        //    000000000000 0000 000 00000 0000 0000
+        //                  ^         ^          ^
+        //                  |         |          |
+        //                Time      Energy     Power
+        //                Units     Status     Units
+        //                          Units
+        //
+        // Per the Intel SDM Volume 3:
+        //
+        //   Time Units (bits 19:16): Time related information (in Seconds) is based on the
+        //   multiplier, 1/ 2^TU; where TU is an unsigned integer represented by bits 19:16.
+        //   Default value is 1010b, indicating time unit is in 976 micro-seconds increment.
+        //
+        //   Energy Status Units (bits 12:8): Energy related information (in Joules) is based on
+        //   the multiplier, 1/2^ESU; where ESU is an unsigned integer represented by bits 12:8.
+        //   Default value is 10000b, indicating energy status unit is in 15.3 micro-Joules
+        //   increment
+        //
+        //   Power Units (bits 3:0): Power related information (in Watts) is based on the
+        //   multiplier, 1/ 2^PU; where PU is an unsigned integer represented by bits 3:0.
+        //   Default value is 0011b, indicating power unit is in 1/8 Watts increment.
+        //
+        let power_unit = rapl_power_unit & 0b1111;
+        let energy_unit = (rapl_power_unit >> 8) & 0b11111;
+        let time_unit = (rapl_power_unit >> 16) & 0b1111;
+
+        // Atom parts use the same fields, but the power and energy units are powers of two of a
+        // milliwatt and microjoule respectively, rather than fractions of a Watt and Joule.
+        let (power, energy) = match encoding {
+            Encoding::Core => (
+                1.0f64 / u64::pow(2, power_unit as u32) as f64,
+                1.0f64 / u64::pow(2, energy_unit as u32) as f64,
+            ),
+            Encoding::Atom => (
+                u64::pow(2, power_unit as u32) as f64 / 1000.0,
+                u64::pow(2, energy_unit as u32) as f64 / 1000000.0,
+            ),
+        };
+
+        Units {
+            power,
+            energy,
+            time: 1.0f64 / u64::pow(2, time_unit as u32) as f64,
+            encoding,
+        }
+    }
+
+    /// Encodes a duration (in seconds) as the 7-bit time window field used by
+    /// MSR_PKG_POWER_LIMIT, returning the field along with the duration it actually represents.
+    pub fn encode_window(&self, seconds: f64) -> (u64, f64) {
+        match self.encoding {
+            Encoding::Core => {
+                let (y, z, realized) = encode_time_window(seconds, self.time);
+                (time_window_field(y, z), realized)
+            },
+            Encoding::Atom => {
+                // Round up, as with the Core encoding; zero isn't a valid window.
+                let field = (seconds / self.time).ceil() as u64;
+                let field = field.clamp(1, TIME_WINDOW_MAX_ATOM);
+                (field, field as f64 * self.time)
+            },
+        }
+    }
+
+    /// Decodes the 7-bit time window field from MSR_PKG_POWER_LIMIT into a duration in seconds.
+    pub fn decode_window(&self, field: u64) -> f64 {
+        match self.encoding {
+            Encoding::Core => decode_time_window_field(field, self.time),
+            Encoding::Atom => cmp::max(field & TIME_WINDOW_MAX_ATOM, 1) as f64 * self.time,
+        }
+    }
+}
+
+/// Encodes a duration (in seconds) as a RAPL time window.
+///
+/// The hardware can only express windows of the form `2^Y * (1.0 + Z/4.0) * time_unit`, so this
+/// returns the `(y, z)` pair for the smallest window that is at least as long as the requested
+/// duration, along with the duration (in seconds) that the pair actually represents. Durations
+/// longer than the hardware can express are clamped to the longest possible window.
+pub fn encode_time_window(seconds: f64, time_unit: f64) -> (u32, u32, f64) {
+    // Note that windows are strictly increasing when ordered by (Y, Z), since the largest
+    // fractional multiplier (1.75) is always smaller than the next power of two.
+    for y in 0..(TIME_WINDOW_MAX_Y + 1) {
+        for z in 0..(TIME_WINDOW_MAX_Z + 1) {
+            let realized = decode_time_window(y, z, time_unit);
+            if seconds <= realized {
+                return (y, z, realized);
+            }
+        }
+    }
+
+    let realized = decode_time_window(TIME_WINDOW_MAX_Y, TIME_WINDOW_MAX_Z, time_unit);
+    (TIME_WINDOW_MAX_Y, TIME_WINDOW_MAX_Z, realized)
+}
+
+/// Decodes a RAPL time window given as a `(y, z)` pair into a duration in seconds.
+pub fn decode_time_window(y: u32, z: u32, time_unit: f64) -> f64 {
+    u64::pow(2, y) as f64 * (1.0f64 + (z as f64) / 4.0) * time_unit
+}
+
+/// Packs a `(y, z)` pair into the 7-bit time window field layout used by MSR_PKG_POWER_LIMIT.
+pub fn time_window_field(y: u32, z: u32) -> u64 {
+    (y | (z << 5)) as u64
+}
+
+/// Decodes the 7-bit time window field from MSR_PKG_POWER_LIMIT into a duration in seconds.
+pub fn decode_time_window_field(field: u64, time_unit: f64) -> f64 {
+    let y = (field & 0b11111) as u32;
+    let z = ((field >> 5) & 0b11) as u32;
+    decode_time_window(y, z, time_unit)
+}
+
+/// Replaces the power limit found at the given bit offset (0 for PL1, 32 for PL2) of a raw
+/// MSR_PKG_POWER_LIMIT value with the given limit and time window, and enables it.
+///
+/// The time window is rounded up to the closest one that the hardware can express. Limits that
+/// don't fit in the 15-bit field are an error, rather than spilling into the enable, clamping and
+/// time window bits (or, for PL2, the lock bit).
+pub fn encode_power_limit(raw: u64, offset: u64, watts: f64, seconds: f64, units: &Units) -> Result<u64, Error> {
+    let (tw, _) = units.encode_window(seconds);
+
+    // The actual power limit is just the number given, in terms of the unit.
+    let pl = (watts / units.power).round();
+    if !(0.0..=POWER_LIMIT_MASK as f64).contains(&pl) {
+        bail!("a power limit of {} W doesn't fit in the power limit field (at most {} W)",
+              watts, POWER_LIMIT_MASK as f64 * units.power);
+    }
+    let pl = pl as u64 & POWER_LIMIT_MASK;
+
+    // The bitmask that we're clearing; these are the Time Window and Package Power Limit fields
+    // for PL1, with an optional offset, then binary negated so that we're keeping everything
+    // *except* these values;
+    let clear: u64 = !(0b111111100111111111111111 << offset);
+
+    // The bitmask that we're setting; as above, the correct values, then shifted.
+    // Note that we also set the "enable" bit.
+    let set: u64 = (pl | (1 << 15) | tw << 17) << offset;
+
+    Ok((raw & clear) | set)
+}
+
+/// The result of comparing a value written to MSR_PKG_POWER_LIMIT with what was read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readback {
+    /// The value stuck, or only differs in the time windows and that's already known.
+    Matches,
+    /// Only the time windows were ignored, which hasn't been seen before.
+    WindowsLocked,
+    /// The given bits (outside the time windows) were ignored.
+    Mismatch(u64),
+}
+
+/// Compares a value written to MSR_PKG_POWER_LIMIT with what was read back.
+///
+/// Newer platforms (Tiger Lake onwards) hard-code the time windows and silently ignore writes to
+/// them, while still honouring the power limits. This is remembered, so that it's only reported
+/// once; see `windows_locked`.
+pub fn check_readback(written: u64, read: u64) -> Readback {
+    let diff = written ^ read;
+    if diff & !WINDOW_FIELDS != 0 {
+        return Readback::Mismatch(diff & !WINDOW_FIELDS);
+    }
+    if diff == 0 || WINDOWS_LOCKED.swap(true, Ordering::Relaxed) {
+        return Readback::Matches;
+    }
+    Readback::WindowsLocked
+}
+
+/// Returns whether the hardware has been seen ignoring writes to the time windows, in which case
+/// there's no point in trying to change them.
+pub fn windows_locked() -> bool {
+    WINDOWS_LOCKED.load(Ordering::Relaxed)
+}
+
+/// Reads the package's TDP ("Thermal Spec Power", bits 14:0 of MSR_PKG_POWER_INFO), in Watts.
+pub fn read_tdp_w() -> io::Result<f64> {
+    let units = Units::read()?;
+    let raw = msr::ReadMsrBuilder::new(MSR_PKG_POWER_INFO).read_first()?;
+    Ok((raw & 0b111111111111111) as f64 * units.power)
+}
+
+/// Highest package power that's plausible, in Watts. Some platforms reset the energy counter
+/// across suspend, which looks like the counter wrapping with almost 2^32 units used.
+const MAX_PLAUSIBLE_PACKAGE_W: f64 = 1000.0;
+
+/// Returns a channel that emits the average package power (in Watts) over every `interval`, as
+/// measured by the energy counter.
+pub fn notify_on_package_power(interval: Duration, units: Units) -> channel::Receiver<f64> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        sample_package_energy(interval, units, |joules, elapsed| send.send(joules / elapsed.as_secs_f64()).is_ok());
+    });
+
+    recv
+}
+
+/// Returns a channel that emits the energy used by the package (in Joules) over every
+/// `interval`, along with the time it was used over. It's only reported to clients, so the
+/// sampler runs at a low priority.
+pub fn notify_on_package_energy(interval: Duration, units: Units) -> channel::Receiver<(f64, Duration)> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        priority::lower_sampler();
+        sample_package_energy(interval, units, |joules, elapsed| send.send((joules, elapsed)).is_ok());
+    });
+
+    recv
+}
+
+/// Reads the package energy counter every `interval`, passing the energy used since the previous
+/// reading and the time it took to `report`. Stops when `report` returns false.
+fn sample_package_energy<F: FnMut(f64, Duration) -> bool>(interval: Duration, units: Units, mut report: F) {
+    let mut sampler = match msr::Sampler::new(&[(msr::first_cpu(), MSR_PKG_ENERGY_STATUS)]) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("error opening MSR device for MSR_PKG_ENERGY_STATUS: {}", e);
+            return;
+        },
+    };
+    let mut read = || sampler.sample().map(|v| v[0] as u32);
+    let mut last = read().ok().map(|e| (e, Instant::now()));
+    loop {
+        thread::sleep(interval);
+
+        let energy = match read() {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("error reading MSR_PKG_ENERGY_STATUS: {}", e);
+                last = None;
+                continue;
+            },
+        };
+        let now = Instant::now();
+
+        // The counter is 32 bits wide, and wraps every few minutes under load.
+        if let Some((last_energy, last_time)) = last {
+            let elapsed = now.duration_since(last_time);
+            let joules = energy.wrapping_sub(last_energy) as f64 * units.energy;
+            if joules / elapsed.as_secs_f64() > MAX_PLAUSIBLE_PACKAGE_W {
+                eprintln!("MSR_PKG_ENERGY_STATUS went from {} to {}, which is implausible; was it reset?",
+                          last_energy, energy);
+            } else if !report(joules, elapsed) {
+                return;
+            }
+        }
+        last = Some((energy, now));
+    }
+}
+
+/// A single decoded power limit from MSR_PKG_POWER_LIMIT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerLimit {
+    /// Power limit, in Watts.
+    pub watts: f64,
+    /// Whether the limit is enabled.
+    pub enabled: bool,
+    /// Whether clamping below the OS-requested P/T state is allowed.
+    pub clamping: bool,
+    /// Time window, in seconds.
+    pub window: f64,
+}
+
+impl PowerLimit {
+    /// Decodes the power limit found at the given bit offset (0 for PL1, 32 for PL2) of a raw
+    /// MSR_PKG_POWER_LIMIT value.
+    pub fn decode(raw: u64, offset: u64, units: &Units) -> PowerLimit {
+        let val = raw >> offset;
+
+        PowerLimit {
+            watts: (val & 0b111111111111111) as f64 * units.power,
+            enabled: (val >> 15) & 1 == 1,
+            clamping: (val >> 16) & 1 == 1,
+            window: units.decode_window((val >> 17) & 0b1111111),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Golden register values: changes to the bit math mustn't change what gets written.
+
+    /// MSR_RAPL_POWER_UNIT on Kaby Lake R: 1/8 W, 1/16384 J and 1/1024 s.
+    const KABY_LAKE_R_POWER_UNIT: u64 = 0x000A_0E03;
+
+    /// A stock MSR_PKG_POWER_LIMIT on the i7-8550U and i5-8250U: 15 W over 28 s with clamping, and
+    /// 44 W over 2.44 ms.
+    const KABY_LAKE_R_POWER_LIMIT: u64 = 0x0042_8160_00DD_8078;
+
+    fn encode(pl1_w: f64, pl2_w: f64) -> u64 {
+        let units = Units::from_raw(KABY_LAKE_R_POWER_UNIT, Encoding::Core);
+        let raw = encode_power_limit(KABY_LAKE_R_POWER_LIMIT, 0, pl1_w, 28.0, &units).unwrap();
+        encode_power_limit(raw, 32, pl2_w, 0.002, &units).unwrap()
+    }
+
+    #[test]
+    fn golden_units() {
+        let units = Units::from_raw(KABY_LAKE_R_POWER_UNIT, Encoding::Core);
+        assert_eq!(units.power, 0.125);
+        assert_eq!(units.energy, 1.0 / 16384.0);
+        assert_eq!(units.time, 1.0 / 1024.0);
+        assert_eq!(units.encode_window(28.0), (0x6E, 28.0));
+        assert_eq!(units.encode_window(0.002), (0x21, 2.5 / 1024.0));
+    }
+
+    #[test]
+    fn displayed_windows_read_back_the_same() {
+        let units = Units::from_raw(KABY_LAKE_R_POWER_UNIT, Encoding::Core);
+        for field in 0..(1 << 7) {
+            let window = units.decode_window(field);
+            let shown = ::duration::format(window);
+            let parsed = ::duration::parse(&shown).unwrap();
+            assert_eq!(units.encode_window(parsed).1, window, "{:#x} shown as {}", field, shown);
+        }
+        assert_eq!(::duration::format(28.0), "28s");
+        assert_eq!(::duration::format(2.5 / 1024.0), "2.441ms");
+        assert_eq!(::duration::format(1.0 / 1024.0 / 2.0), "488.281µs");
+    }
+
+    #[test]
+    fn golden_power_limit_i7_8550u() {
+        assert_eq!(encode(25.0, 44.0), 0x0042_8160_00DD_80C8);
+    }
+
+    #[test]
+    fn golden_power_limit_i5_8250u() {
+        assert_eq!(encode(15.0, 25.0), 0x0042_80C8_00DD_8078);
+    }
+
+    #[test]
+    fn golden_power_limit_keeps_lock_and_clamp_bits() {
+        let units = Units::from_raw(KABY_LAKE_R_POWER_UNIT, Encoding::Core);
+        let raw = KABY_LAKE_R_POWER_LIMIT | (1 << 63) | (1 << 48);
+        assert_eq!(encode_power_limit(raw, 32, 44.0, 0.002, &units).unwrap(), raw);
+    }
+
+    #[test]
+    fn power_limits_that_overflow_the_field_are_rejected() {
+        let units = Units::from_raw(KABY_LAKE_R_POWER_UNIT, Encoding::Core);
+        // (2^28 W at 1/8 W units would otherwise reach bit 63, the lock bit, of PL2.)
+        for &watts in [4096.0, (1u64 << 28) as f64, -1.0, f64::NAN].iter() {
+            assert!(encode_power_limit(KABY_LAKE_R_POWER_LIMIT, 32, watts, 0.002, &units).is_err(), "{} W", watts);
+        }
+        assert!(encode_power_limit(KABY_LAKE_R_POWER_LIMIT, 32, 4095.0, 0.002, &units).is_ok());
+    }
+}
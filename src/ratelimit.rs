@@ -0,0 +1,44 @@
+//! Rate-limiting for errors that would otherwise be logged on every reapply, e.g. a locked MSR
+//! that fails the same way each time the settings are reapplied. An error is logged the first
+//! time it happens; the same error again within `INTERVAL` is only counted, and the count is
+//! logged along with it the next time it comes up after that.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+
+/// How often the same error may be logged.
+pub const INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+
+/// When an error was last logged, and how many times it's been seen since.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    logged: Instant,
+    suppressed: u32,
+}
+
+static SEEN: Mutex<Option<HashMap<String, Entry>>> = Mutex::new(None);
+
+/// Logs an error to stderr, unless the same message was logged within the last `INTERVAL`.
+pub fn eprintln(message: String) {
+    let now = Instant::now();
+    let mut seen = SEEN.lock().unwrap();
+    let seen = seen.get_or_insert_with(HashMap::new);
+
+    // Forget errors that have stopped happening, so that the map doesn't keep growing.
+    seen.retain(|_, e| now.duration_since(e.logged) < INTERVAL || e.suppressed > 0);
+
+    match seen.get_mut(&message) {
+        Some(e) if now.duration_since(e.logged) < INTERVAL => e.suppressed += 1,
+        Some(e) => {
+            eprintln!("{} (repeated {} times in the last {}m)", message, e.suppressed, now.duration_since(e.logged).as_secs() / 60);
+            *e = Entry { logged: now, suppressed: 0 };
+        },
+        None => {
+            eprintln!("{}", message);
+            seen.insert(message, Entry { logged: now, suppressed: 0 });
+        },
+    }
+}
@@ -0,0 +1,114 @@
+//! Noticing when something else changes MSR_PKG_POWER_LIMIT between our writes, and working out
+//! what it probably was, so that users know which component to blame (or configure).
+
+use std::fmt;
+
+use conflicts;
+use quirks;
+use rapl;
+
+
+/// Bits of MSR_PKG_POWER_LIMIT that hold the limits themselves; the lock bit is left out, since
+/// check_resume deals with that.
+const LIMIT_FIELDS: u64 = !rapl::POWER_LIMIT_LOCK;
+
+/// How many of our own writes to remember.
+const HISTORY: usize = 8;
+
+/// Daemons known to change the power limits through powercap.
+const POWERCAP_WRITERS: &[&str] = &["thermald"];
+
+
+/// What probably changed the power limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// This daemon, from an earlier write (e.g. a rollback after a failed apply).
+    Ours,
+    /// The embedded controller, which this model is known to enforce its own limits with.
+    Ec,
+    /// The firmware, restoring the limits it set at boot.
+    Firmware,
+    /// A daemon that writes the limits through powercap.
+    Powercap(&'static str),
+    /// Nothing that we know of.
+    Unknown,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Ours => write!(f, "this daemon"),
+            Source::Ec => write!(f, "the embedded controller"),
+            Source::Firmware => write!(f, "the firmware"),
+            Source::Powercap(name) => write!(f, "{} (via powercap)", name),
+            Source::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A change to the power limits that we didn't make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    /// What we last wrote.
+    pub from: u64,
+    /// What's there now.
+    pub to: u64,
+    pub source: Source,
+}
+
+/// Keeps track of the power limits we've written, to tell when they're changed.
+#[derive(Debug, Clone, Default)]
+pub struct Detector {
+    /// Value of the MSR before we first wrote it.
+    boot: Option<u64>,
+    /// Values we've written, most recent last. They're what the MSR read back as, since the
+    /// hardware can ignore parts of a write.
+    written: Vec<u64>,
+}
+
+impl Detector {
+    /// Creates a detector, given the MSR's value before the daemon has written it.
+    pub fn new(boot: Option<u64>) -> Detector {
+        Detector { boot, written: vec![] }
+    }
+
+    /// Records a value that we've written.
+    pub fn record(&mut self, value: u64) {
+        if self.written.last() == Some(&value) {
+            return;
+        }
+        if self.written.len() == HISTORY {
+            self.written.remove(0);
+        }
+        self.written.push(value);
+    }
+
+    /// Returns the change, if the MSR no longer holds what we last wrote.
+    pub fn check(&self, current: u64) -> Option<Change> {
+        let last = *self.written.last()?;
+        if current & LIMIT_FIELDS == last & LIMIT_FIELDS {
+            return None;
+        }
+        Some(Change { from: last, to: current, source: self.attribute(current) })
+    }
+
+    fn attribute(&self, current: u64) -> Source {
+        let matches = |v: &u64| v & LIMIT_FIELDS == current & LIMIT_FIELDS;
+
+        if self.written[..self.written.len() - 1].iter().any(matches) {
+            Source::Ours
+        } else if self.boot.as_ref().is_some_and(matches) {
+            // Firmware that reverts the limits puts back the ones it set at boot; on models whose
+            // EC enforces limits, that's the EC doing it.
+            if quirks::detect().is_some_and(|q| q.mchbar_required) {
+                Source::Ec
+            } else {
+                Source::Firmware
+            }
+        } else if let Some(name) = POWERCAP_WRITERS.iter().find(|n| conflicts::process_running(n)) {
+            Source::Powercap(name)
+        } else {
+            Source::Unknown
+        }
+    }
+}
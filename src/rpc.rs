@@ -0,0 +1,341 @@
+//! JSON-RPC control interface on a Unix socket, for systems without a D-Bus system bus.
+//!
+//! Requests and responses are JSON-RPC 2.0 objects, one per line, and requests can't be longer
+//! than 4096 bytes. The methods mirror the D-Bus interface:
+//!
+//!   status                              -> object of status fields
+//!   set-profile {"profile": "ac"}       -> "ac", "battery", a [profiles.*] name or "auto",
+//!                                          optionally with "expires_in_sec"
+//!   pause       {"paused": true}
+//!   set-limits  {"pl1_w": 20, "pl2_w": 30}
+//!   reload
+//!   reload-config                       -> null, or an error saying why the config was rejected
+//!   preview-config {"path": "new.toml"} -> {"profiles": {name: TOML}, "changes": [...]}
+//!   travel      {"enabled": true}
+//!   begin-override                      -> token
+//!   override-limits {"token": T, "pl1_w": 20, "pl2_w": 30}
+//!   commit-override {"token": T}
+//!   abort-override  {"token": T}
+//!
+//! Anyone who can connect may read the status, but only root may call the other methods; the
+//! socket itself is world-accessible.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ::channel;
+use libc;
+
+use control::{self, Command, Status};
+use json::{self, Value};
+use rules::Profile;
+
+
+/// How long a client may stay connected, not counting the wait for the daemon to answer its last
+/// request. Clients are served one at a time, so this stops one from blocking everyone else by
+/// sending requests slowly, or one after another.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the daemon to answer set-profile, reload-config, preview-config and the
+/// override methods.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request that we'll read; a client that sends a longer one is disconnected.
+const MAX_REQUEST_LEN: usize = 4096;
+
+// Standard JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Our own error code for callers that aren't allowed to call a method.
+const ACCESS_DENIED: i64 = -32000;
+
+
+/// Starts serving the control interface on the given socket path, returning a channel of
+/// commands received from clients.
+///
+/// As with the D-Bus interface, failing to set up the socket isn't fatal; the error is logged and
+/// the channel is simply disconnected.
+pub fn serve(path: String, status: Arc<Mutex<Status>>) -> channel::Receiver<Command> {
+    let (send, recv) = channel::unbounded();
+
+    // Bind before returning, so that the socket exists by the time anything else is started.
+    let listener = match bind(&path) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("error creating control socket {}: {}", path, e);
+            return recv;
+        },
+    };
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.and_then(|s| handle_client(s, &status, &send));
+            if let Err(e) = res {
+                eprintln!("error serving control socket client: {}", e);
+            }
+        }
+    });
+
+    recv
+}
+
+fn bind(path: &str) -> io::Result<UnixListener> {
+    // Remove a stale socket from a previous run.
+    if Path::new(path).exists() {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+fn handle_client(
+    stream: UnixStream,
+    status: &Arc<Mutex<Status>>,
+    send: &channel::Sender<Command>,
+) -> io::Result<()> {
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let privileged = peer_uid(&stream)? == 0;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        // Time spent waiting for the daemon to answer counts too.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Ok(());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+
+        let mut line = String::new();
+        if (&mut reader).take(MAX_REQUEST_LEN as u64 + 1).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim_end_matches('\n').len() > MAX_REQUEST_LEN {
+            let msg = format!("requests can't be longer than {} bytes", MAX_REQUEST_LEN);
+            writeln!(writer, "{}", error(Value::Null, INVALID_REQUEST, &msg))?;
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = handle_request(&line, privileged, status, send);
+        writeln!(writer, "{}", response)?;
+    }
+}
+
+/// Returns the uid of the process on the other end of the socket.
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Handles a single request line, returning the response object.
+fn handle_request(
+    line: &str,
+    privileged: bool,
+    status: &Arc<Mutex<Status>>,
+    send: &channel::Sender<Command>,
+) -> Value {
+    let request = match json::parse(line) {
+        Ok(r) => r,
+        Err(e) => return error(Value::Null, PARSE_ERROR, &e),
+    };
+
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(|m| m.as_str()) {
+        Some(m) => m,
+        None => return error(id, INVALID_REQUEST, "missing method"),
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Object(vec![]));
+
+    // Everything but reading the status requires root.
+    if method != "status" && !privileged {
+        return error(id, ACCESS_DENIED, "not authorized");
+    }
+
+    let cmd = match method {
+        "status" => {
+            let map = status.lock().unwrap().to_map();
+            let mut members: Vec<(String, Value)> = map.into_iter()
+                .map(|(k, v)| (k, Value::String(v)))
+                .collect();
+            members.sort_by(|a, b| a.0.cmp(&b.0));
+            return result(id, Value::Object(members));
+        },
+
+        "set-profile" => {
+            let profile = match params.get("profile").and_then(|p| p.as_str()) {
+                Some("auto") => None,
+                Some(name) if !name.is_empty() => Some(Profile::parse(name)),
+                _ => return error(id, INVALID_PARAMS, "profile must be \"ac\", \"battery\", a named profile or \"auto\""),
+            };
+            let expires = match params.get("expires_in_sec").map(|v| v.as_u64()) {
+                None => None,
+                Some(Some(secs)) if secs > 0 => Some(Duration::from_secs(secs)),
+                Some(_) => return error(id, INVALID_PARAMS, "expires_in_sec must be a positive integer"),
+            };
+            return match ask(send, |reply| Command::SetProfile(profile, expires, reply)) {
+                Ok(()) => result(id, Value::Null),
+                Err(e) => error(id, INVALID_PARAMS, &e),
+            };
+        },
+
+        "pause" => match params.get("paused").and_then(|p| p.as_bool()) {
+            Some(paused) => Command::Pause(paused),
+            None => return error(id, INVALID_PARAMS, "paused must be a boolean"),
+        },
+
+        "set-limits" => {
+            let watts = |name: &str| params.get(name).map_or(Some(0), |v| v.as_u64());
+            match (watts("pl1_w"), watts("pl2_w")) {
+                (Some(pl1), Some(pl2)) => match control::check_limits(pl1, pl2) {
+                    Ok(()) => Command::SetLimits(pl1, pl2),
+                    Err(e) => return error(id, INVALID_PARAMS, &e),
+                },
+                _ => return error(id, INVALID_PARAMS, "pl1_w and pl2_w must be non-negative integers"),
+            }
+        },
+
+        "reload" => Command::Reload,
+
+        "reload-config" => {
+            let (reply, recv) = channel::bounded(1);
+            if send.send(Command::ReloadConfig(reply)).is_err() {
+                return error(id, INTERNAL_ERROR, "daemon is shutting down");
+            }
+            return match recv.recv_timeout(REPLY_TIMEOUT) {
+                Ok(Ok(())) => result(id, Value::Null),
+                Ok(Err(e)) => error(id, INTERNAL_ERROR, &e),
+                Err(_) => error(id, INTERNAL_ERROR, "daemon didn't answer"),
+            };
+        },
+
+        "preview-config" => {
+            let path = match params.get("path").and_then(|p| p.as_str()) {
+                Some(p) => p.to_string(),
+                None => return error(id, INVALID_PARAMS, "path must be a string"),
+            };
+            let (reply, recv) = channel::bounded(1);
+            if send.send(Command::PreviewConfig(path, reply)).is_err() {
+                return error(id, INTERNAL_ERROR, "daemon is shutting down");
+            }
+            let preview = match recv.recv_timeout(REPLY_TIMEOUT) {
+                Ok(Ok(p)) => p,
+                Ok(Err(e)) => return error(id, INTERNAL_ERROR, &e),
+                Err(_) => return error(id, INTERNAL_ERROR, "daemon didn't answer"),
+            };
+            let profiles = preview.profiles.into_iter().map(|(name, p)| (name, Value::String(p))).collect();
+            let changes = preview.changes.into_iter().map(Value::String).collect();
+            return result(id, Value::Object(vec![
+                ("profiles".to_string(), Value::Object(profiles)),
+                ("changes".to_string(), Value::Array(changes)),
+            ]));
+        },
+
+        "travel" => match params.get("enabled").and_then(|p| p.as_bool()) {
+            Some(enabled) => Command::Travel(enabled),
+            None => return error(id, INVALID_PARAMS, "enabled must be a boolean"),
+        },
+
+        "begin-override" => {
+            return match ask(send, Command::BeginOverride) {
+                Ok(token) => result(id, Value::Number(token as f64)),
+                Err(e) => error(id, INTERNAL_ERROR, &e),
+            };
+        },
+
+        "override-limits" | "commit-override" | "abort-override" => {
+            let token = match params.get("token").and_then(|t| t.as_u64()) {
+                Some(t) => t,
+                None => return error(id, INVALID_PARAMS, "token must be the one begin-override returned"),
+            };
+            let res = match method {
+                "override-limits" => {
+                    let watts = |name: &str| params.get(name).map_or(Some(0), |v| v.as_u64());
+                    match (watts("pl1_w"), watts("pl2_w")) {
+                        (Some(pl1), Some(pl2)) => {
+                            if let Err(e) = control::check_limits(pl1, pl2) {
+                                return error(id, INVALID_PARAMS, &e);
+                            }
+                            ask(send, |reply| Command::OverrideLimits(token, pl1, pl2, reply))
+                        },
+                        _ => return error(id, INVALID_PARAMS, "pl1_w and pl2_w must be non-negative integers"),
+                    }
+                },
+                "commit-override" => ask(send, |reply| Command::CommitOverride(token, reply)),
+                _ => ask(send, |reply| Command::AbortOverride(token, reply)),
+            };
+            return match res {
+                Ok(()) => result(id, Value::Null),
+                Err(e) => error(id, INTERNAL_ERROR, &e),
+            };
+        },
+
+        _ => return error(id, METHOD_NOT_FOUND, "no such method"),
+    };
+
+    if send.send(cmd).is_err() {
+        return error(id, INTERNAL_ERROR, "daemon is shutting down");
+    }
+    result(id, Value::Null)
+}
+
+/// Sends the daemon a command that it answers, and waits for the answer.
+fn ask<T, F>(send: &channel::Sender<Command>, command: F) -> Result<T, String>
+    where F: FnOnce(channel::Sender<Result<T, String>>) -> Command
+{
+    let (reply, recv) = channel::bounded(1);
+    if send.send(command(reply)).is_err() {
+        return Err("daemon is shutting down".to_string());
+    }
+    recv.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|_| Err("daemon didn't answer".to_string()))
+}
+
+fn result(id: Value, result: Value) -> Value {
+    Value::Object(vec![
+        ("jsonrpc".to_string(), Value::String("2.0".to_string())),
+        ("id".to_string(), id),
+        ("result".to_string(), result),
+    ])
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    Value::Object(vec![
+        ("jsonrpc".to_string(), Value::String("2.0".to_string())),
+        ("id".to_string(), id),
+        ("error".to_string(), Value::Object(vec![
+            ("code".to_string(), Value::Number(code as f64)),
+            ("message".to_string(), Value::String(message.to_string())),
+        ])),
+    ])
+}
@@ -0,0 +1,433 @@
+//! Rule-based profile selection.
+//!
+//! Rules are evaluated in the order they're listed, and the first one whose conditions all match
+//! picks the profile. A manual override (from D-Bus or the control socket) always wins, and if no
+//! rule matches, the profile follows the power source as usual.
+//!
+//! Power source, charger and battery changes are noticed immediately, as are changes in the
+//! smoothed CPU load; the lid, time and process conditions are re-checked on every timer tick.
+
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::ptr;
+use std::str::FromStr;
+
+use libc;
+use serde::de::{self, Deserialize, Deserializer};
+
+use power::PowerState;
+
+
+/// Directory containing one entry per lid switch.
+const LID_DIR: &str = "/proc/acpi/button/lid";
+
+/// Process names in `/proc/PID/comm` are truncated to this many bytes.
+const COMM_LEN: usize = 15;
+
+
+/// A profile that a rule (or a client) can select: one of the base profiles, or a
+/// `[profiles.NAME]` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Profile {
+    Ac,
+    Battery,
+    Named(String),
+}
+
+impl Profile {
+    /// Parses a profile name; anything other than "ac" and "battery" names a [profiles.*] section.
+    pub fn parse(name: &str) -> Profile {
+        match name {
+            "ac" => Profile::Ac,
+            "battery" => Profile::Battery,
+            _ => Profile::Named(name.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Profile::Ac => "ac",
+            Profile::Battery => "battery",
+            Profile::Named(ref name) => name,
+        }
+    }
+
+    /// Returns the power state whose constraints (battery and charger levels) go with this
+    /// profile, and the named profile to apply, if it is one. Named profiles get the constraints
+    /// of whichever power source we're on.
+    fn choose(&self, power_state: PowerState) -> (PowerState, Option<String>) {
+        // Keep the charger details when the AC profile is picked while on AC.
+        match *self {
+            Profile::Battery => (PowerState::Battery, None),
+            Profile::Ac if power_state == PowerState::Battery => (PowerState::AC { watts: None }, None),
+            Profile::Ac => (power_state, None),
+            Profile::Named(ref name) => (power_state, Some(name.clone())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Profile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Profile, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(Profile::parse(&s))
+    }
+}
+
+/// A power source condition.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+/// State of the laptop's lid.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LidState {
+    Open,
+    Closed,
+}
+
+/// A range of local time, as "HH:MM-HH:MM"; the range may wrap around midnight, but not be empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    /// Start of the range, inclusive, in minutes after midnight.
+    start: u32,
+    /// End of the range, exclusive, in minutes after midnight.
+    end: u32,
+}
+
+impl TimeRange {
+    fn contains(&self, minutes: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minutes && minutes < self.end
+        } else {
+            minutes >= self.start || minutes < self.end
+        }
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeRange, String> {
+        fn parse_time(t: &str) -> Option<u32> {
+            let mut parts = t.trim().splitn(2, ':');
+            let hours = parts.next()?.parse::<u32>().ok()?;
+            let minutes = parts.next()?.parse::<u32>().ok()?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            Some(hours * 60 + minutes)
+        }
+
+        let mut ends = s.splitn(2, '-');
+        match (ends.next().and_then(parse_time), ends.next().and_then(parse_time)) {
+            (Some(start), Some(end)) if start == end => Err(format!("time range {:?} is empty", s)),
+            (Some(start), Some(end)) => Ok(TimeRange { start, end }),
+            _ => Err(format!("invalid time range {:?}, expected \"HH:MM-HH:MM\"", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TimeRange, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+
+/// A single profile selection rule.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Profile to select when every condition matches.
+    pub profile: Profile,
+
+    /// Matches when running from the given power source.
+    pub power: Option<PowerSource>,
+    /// Matches when on AC with a charger that supplies less than this many Watts.
+    pub charger_below_w: Option<u64>,
+    /// Matches when the lid is in the given state.
+    pub lid: Option<LidState>,
+    /// Matches when the battery charge is below this percentage.
+    pub battery_below_percent: Option<u8>,
+    /// Matches during the given range of local time.
+    pub time: Option<TimeRange>,
+    /// Matches while a process with the given name is running.
+    pub process: Option<String>,
+    /// Matches while the smoothed CPU load is above this percentage.
+    pub load_above_percent: Option<f64>,
+}
+
+impl Rule {
+    fn matches(&self, inputs: &Inputs) -> bool {
+        let power = self.power.is_none_or(|p| matches!(
+            (p, inputs.power_state),
+            (PowerSource::Ac, PowerState::AC { .. }) | (PowerSource::Battery, PowerState::Battery)
+        ));
+        let charger = self.charger_below_w.is_none_or(|below| match inputs.power_state {
+            PowerState::AC { watts: Some(watts) } => watts < below,
+            _ => false,
+        });
+        let lid = self.lid.is_none_or(|l| inputs.lid == Some(l));
+        let battery = self.battery_below_percent.is_none_or(|below| inputs.battery_level.is_some_and(|l| l < below));
+        let time = self.time.is_none_or(|t| inputs.minutes.is_some_and(|m| t.contains(m)));
+        let process = self.process.as_ref().is_none_or(|name| {
+            let name = truncate_comm(name);
+            inputs.processes.iter().any(|p| p == name)
+        });
+        let load = self.load_above_percent.is_none_or(|above| inputs.load.is_some_and(|l| l > above));
+
+        power && charger && lid && battery && time && process && load
+    }
+
+    /// Describes the rule's conditions, for logging.
+    fn describe(&self) -> String {
+        let mut conditions = vec![];
+        if let Some(p) = self.power {
+            conditions.push(format!("power = {:?}", p).to_lowercase());
+        }
+        if let Some(w) = self.charger_below_w {
+            conditions.push(format!("charger below {} W", w));
+        }
+        if let Some(l) = self.lid {
+            conditions.push(format!("lid = {:?}", l).to_lowercase());
+        }
+        if let Some(b) = self.battery_below_percent {
+            conditions.push(format!("battery below {}%", b));
+        }
+        if let Some(t) = self.time {
+            conditions.push(format!("time {}", t));
+        }
+        if let Some(ref p) = self.process {
+            conditions.push(format!("process {:?} running", p));
+        }
+        if let Some(l) = self.load_above_percent {
+            conditions.push(format!("load above {}%", l));
+        }
+
+        if conditions.is_empty() {
+            "always".to_string()
+        } else {
+            conditions.join(", ")
+        }
+    }
+}
+
+/// Returns whether any rule depends on the battery charge level.
+pub fn needs_battery_level(rules: &[Rule]) -> bool {
+    rules.iter().any(|r| r.battery_below_percent.is_some())
+}
+
+/// Returns whether any rule depends on the CPU load.
+pub fn needs_load(rules: &[Rule]) -> bool {
+    rules.iter().any(|r| r.load_above_percent.is_some())
+}
+
+
+/// The current values of everything that rules can depend on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inputs {
+    pub power_state: PowerState,
+    pub battery_level: Option<u8>,
+    pub lid: Option<LidState>,
+    /// Local time, in minutes after midnight.
+    pub minutes: Option<u32>,
+    /// Names of the running processes.
+    pub processes: Vec<String>,
+    /// Smoothed CPU load, in percent.
+    pub load: Option<f64>,
+}
+
+impl Inputs {
+    /// Gathers the inputs, only looking up the ones that some rule actually uses.
+    pub fn gather(rules: &[Rule], power_state: PowerState, battery_level: Option<u8>, load: Option<f64>) -> Inputs {
+        let lid = if rules.iter().any(|r| r.lid.is_some()) { read_lid_state() } else { None };
+        let minutes = if rules.iter().any(|r| r.time.is_some()) { local_minutes() } else { None };
+        let processes = if rules.iter().any(|r| r.process.is_some()) { process_names() } else { vec![] };
+
+        Inputs {
+            power_state,
+            battery_level,
+            lid,
+            minutes,
+            processes,
+            load,
+        }
+    }
+}
+
+/// The outcome of profile selection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    /// The base profile, or for a named profile, the power state whose constraints go with it.
+    pub profile: PowerState,
+    /// The [profiles.*] section to apply instead of the base profile, if one was picked.
+    pub named: Option<String>,
+    /// Why this profile was picked, for logging.
+    pub reason: String,
+}
+
+impl Selection {
+    /// Returns the name of the selected profile.
+    pub fn name(&self) -> &str {
+        self.named.as_deref().unwrap_or_else(|| self.profile.name())
+    }
+}
+
+/// Picks the profile to apply.
+pub fn select(rules: &[Rule], forced: Option<&Profile>, inputs: &Inputs) -> Selection {
+    if let Some(profile) = forced {
+        let (profile, named) = profile.choose(inputs.power_state);
+        return Selection { profile, named, reason: "manual override".to_string() };
+    }
+
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.matches(inputs) {
+            let (profile, named) = rule.profile.choose(inputs.power_state);
+            return Selection { profile, named, reason: format!("rule {} ({})", i + 1, rule.describe()) };
+        }
+    }
+
+    Selection { profile: inputs.power_state, named: None, reason: "power source".to_string() }
+}
+
+
+fn truncate_comm(name: &str) -> &str {
+    if name.len() <= COMM_LEN {
+        return name;
+    }
+
+    let mut end = COMM_LEN;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Reads the state of the first lid switch, if there is one.
+fn read_lid_state() -> Option<LidState> {
+    let mut entries: Vec<_> = fs::read_dir(LID_DIR).ok()?.filter_map(|e| e.ok()).collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    // The file looks like "state:      open".
+    let state = fs::read_to_string(entries.first()?.path().join("state")).ok()?;
+    match state.split(':').nth(1).map(|s| s.trim()) {
+        Some("open") => Some(LidState::Open),
+        Some("closed") => Some(LidState::Closed),
+        _ => None,
+    }
+}
+
+/// Returns the local time of day, in minutes after midnight.
+fn local_minutes() -> Option<u32> {
+    unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return None;
+        }
+        Some(tm.tm_hour as u32 * 60 + tm.tm_min as u32)
+    }
+}
+
+/// Returns the names of every running process.
+fn process_names() -> Vec<String> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit())))
+        .filter_map(|e| fs::read_to_string(e.path().join("comm")).ok())
+        .map(|comm| comm.trim_end().to_string())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(hours: u32, minutes: u32) -> u32 {
+        hours * 60 + minutes
+    }
+
+    fn inputs(power_state: PowerState) -> Inputs {
+        Inputs { power_state, battery_level: None, lid: None, minutes: None, processes: vec![], load: None }
+    }
+
+    #[test]
+    fn rules_and_overrides_pick_named_profiles() {
+        let rule = |profile: &str, power: Option<PowerSource>| Rule {
+            profile: Profile::parse(profile), power, charger_below_w: None, lid: None, battery_below_percent: None,
+            time: None, process: None, load_above_percent: None,
+        };
+        let rules = [rule("gaming", Some(PowerSource::Ac)), rule("ac", None)];
+        let charger = PowerState::AC { watts: Some(65) };
+
+        let s = select(&rules, None, &inputs(charger));
+        assert_eq!((s.profile, s.name()), (charger, "gaming"));
+        let s = select(&rules, None, &inputs(PowerState::Battery));
+        assert_eq!((s.profile, s.name()), (PowerState::AC { watts: None }, "ac"));
+
+        // A named profile keeps the constraints of the power source, even when it's forced.
+        let quiet = Profile::parse("quiet");
+        let s = select(&rules, Some(&quiet), &inputs(PowerState::Battery));
+        assert_eq!((s.profile, s.named.as_deref(), s.reason.as_str()), (PowerState::Battery, Some("quiet"), "manual override"));
+        let s = select(&[], None, &inputs(charger));
+        assert_eq!((s.profile, s.name()), (charger, "ac"));
+    }
+
+    #[test]
+    fn time_ranges_parse() {
+        let cases: &[(&str, Option<(u32, u32)>)] = &[
+            ("09:00-17:30", Some((minutes(9, 0), minutes(17, 30)))),
+            (" 22:00 - 06:00 ", Some((minutes(22, 0), minutes(6, 0)))),
+            ("00:00-23:59", Some((0, minutes(23, 59)))),
+            ("12:00-12:00", None),
+            ("24:00-06:00", None),
+            ("09:60-10:00", None),
+            ("09-17", None),
+            ("09:00", None),
+            ("", None),
+        ];
+        for &(s, expected) in cases.iter() {
+            let parsed = s.parse::<TimeRange>().ok().map(|r| (r.start, r.end));
+            assert_eq!(parsed, expected, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn time_ranges_contain_their_start_but_not_their_end() {
+        let day: TimeRange = "09:00-17:00".parse().unwrap();
+        let night: TimeRange = "22:00-06:00".parse().unwrap();
+        let cases = [
+            (minutes(8, 59), false, false),
+            (minutes(9, 0), true, false),
+            (minutes(16, 59), true, false),
+            (minutes(17, 0), false, false),
+            (minutes(21, 59), false, false),
+            (minutes(22, 0), false, true),
+            (minutes(23, 59), false, true),
+            (0, false, true),
+            (minutes(5, 59), false, true),
+            (minutes(6, 0), false, false),
+        ];
+        for &(m, in_day, in_night) in cases.iter() {
+            assert_eq!(day.contains(m), in_day, "{} in {:?}", m, day);
+            assert_eq!(night.contains(m), in_night, "{} in {:?}", m, night);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Plain-text state files under the runtime directory, so that shell scripts and status bars can
+//! read the daemon's state without needing D-Bus bindings.
+
+use std::ffi::CString;
+use std::fs::{self, DirBuilder, File};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+
+use failure::Error;
+use libc;
+
+use msr;
+use paths;
+use privsep;
+use rapl;
+use temps;
+
+
+/// Creates the runtime directory (`/run/lenovo-throttling`, unless it's been overridden).
+///
+/// This must be called before dropping privileges; the directory is handed over to the
+/// unprivileged user so that the worker can keep it up to date.
+pub fn prepare() -> io::Result<()> {
+    create_owned_dir(&paths::get().runtime)
+}
+
+/// Creates a directory and, when running as root, hands it over to the unprivileged user.
+pub fn create_owned_dir(dir: &str) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+
+    if unsafe { libc::geteuid() } == 0 {
+        let path = CString::new(Path::new(dir).as_os_str().as_bytes())?;
+        let (uid, gid) = privsep::unprivileged_ids()?;
+        if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the active profile and the limits currently programmed into the hardware.
+pub fn export(profile: &str) -> Result<(), Error> {
+    write_file("active_profile", profile)?;
+
+    let units = rapl::Units::read()?;
+    let power_limit = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first()?;
+    let pl1 = rapl::PowerLimit::decode(power_limit, 0, &units);
+    let pl2 = rapl::PowerLimit::decode(power_limit, 32, &units);
+    write_file("pl1_w", &pl1.watts.to_string())?;
+    write_file("pl2_w", &pl2.watts.to_string())?;
+
+    let temp_target = temps::TemperatureTarget::read()?;
+    write_file("temp_target", &temp_target.throttle_temp().to_string())?;
+
+    Ok(())
+}
+
+/// Reads the profile that a running daemon last applied, as exported by `export`.
+pub fn read_active_profile() -> Option<String> {
+    fs::read_to_string(Path::new(&paths::get().runtime).join("active_profile")).ok()
+        .map(|p| p.trim().to_string())
+}
+
+/// Atomically replaces a file in the runtime directory, so readers never see partial contents.
+fn write_file(name: &str, contents: &str) -> io::Result<()> {
+    let dir = Path::new(&paths::get().runtime);
+    let path = dir.join(name);
+    let tmp = dir.join(format!(".{}.tmp", name));
+
+    let mut file = File::create(&tmp)?;
+    writeln!(file, "{}", contents)?;
+    fs::rename(&tmp, &path)
+}
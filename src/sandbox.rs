@@ -0,0 +1,286 @@
+//! Optional seccomp sandbox for the daemon, installed once everything has been set up.
+//!
+//! After initialization the daemon only needs to read and write files it knows about, talk to
+//! D-Bus, accept clients on its control socket and wait on timers, so the filter only allows
+//! syscalls needed for that (plus memory management and the like). Some threads only get around
+//! to connecting to the system bus or creating their timers later, and the UPower watcher keeps
+//! retrying while the bus is down, so those syscalls are allowed too; new sockets are limited to
+//! Unix domain ones. Anything else fails with EPERM, rather than killing the daemon, so a missed
+//! syscall degrades a feature instead of leaving the power limits unmanaged. Notably, network
+//! sockets, exec, ptrace, setuid and friends are all denied.
+//!
+//! This only covers the unprivileged worker; the privileged helper is already limited to the
+//! handful of requests that it validates.
+
+use std::io;
+
+use libc;
+
+
+/// Syscalls that the daemon may make once it's running.
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // Files.
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_open,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_stat,
+    libc::SYS_fstat,
+    libc::SYS_lstat,
+    libc::SYS_newfstatat,
+    SYS_STATX,
+    libc::SYS_getdents64,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_unlink,
+    libc::SYS_unlinkat,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+
+    // D-Bus and the privileged helper, and clients of the control socket. socket(2) is handled
+    // separately, since only Unix domain sockets are allowed.
+    libc::SYS_connect,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_accept4,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+
+    // Waiting.
+    libc::SYS_poll,
+    libc::SYS_ppoll,
+    libc::SYS_select,
+    libc::SYS_pselect6,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+
+    // Memory.
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+
+    // Signals, including those used to abort on a panic.
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+
+    // Process information; libdbus checks credentials.
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getrandom,
+
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// statx(2), which is too new for our version of libc.
+#[cfg(target_arch = "x86_64")]
+const SYS_STATX: libc::c_long = 332;
+
+/// AUDIT_ARCH_X86_64, from linux/audit.h.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000003E;
+
+/// Syscall numbers at or above this are x32 syscalls, which we don't allow.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+
+// Classic BPF instructions and seccomp constants, from linux/filter.h and linux/seccomp.h.
+const BPF_LD_W_ABS: u16 = 0x20;     // BPF_LD | BPF_W | BPF_ABS
+const BPF_JMP_JEQ_K: u16 = 0x15;    // BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35;    // BPF_JMP | BPF_JGE | BPF_K
+const BPF_RET_K: u16 = 0x06;        // BPF_RET | BPF_K
+
+const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_uint = 1;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+const SECCOMP_RET_ERRNO: u32 = 0x00050000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+
+/// Offsets of the fields of struct seccomp_data; the first argument's low 32 bits, on little
+/// endian.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARG0: u32 = 16;
+
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+
+/// Returns the number of instructions to skip to get from the one at `from` to the one at `to`;
+/// BPF can only jump forwards, and not far.
+fn offset(from: usize, to: usize) -> u8 {
+    assert!(to > from && to - from - 1 <= u8::MAX as usize, "BPF jump from {} to {}", from, to);
+    (to - from - 1) as u8
+}
+
+/// Builds the filter program.
+#[cfg(target_arch = "x86_64")]
+fn filter() -> Vec<SockFilter> {
+    // The program is laid out as the checks of the syscall number, followed by the returns that
+    // they jump to and the check of socket(2)'s domain.
+    const CHECKS: usize = 5;
+    let errno = CHECKS + ALLOWED_SYSCALLS.len() + 1;
+    let allow = errno + 1;
+    let kill = allow + 1;
+    let socket = kill + 1;
+
+    let mut filter = vec![
+        // Kill the process if it's somehow making syscalls for another architecture, since the
+        // numbers below would mean something else.
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, offset(4, kill), 0),
+    ];
+
+    // Each allowed syscall jumps forward to the ALLOW; if none match, we fall through to the ERRNO.
+    for &nr in ALLOWED_SYSCALLS.iter() {
+        let at = filter.len();
+        filter.push(jump(BPF_JMP_JEQ_K, nr as u32, offset(at, allow), 0));
+    }
+    let at = filter.len();
+    filter.push(jump(BPF_JMP_JEQ_K, libc::SYS_socket as u32, offset(at, socket), 0));
+
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+
+    filter.push(stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG0));
+    filter.push(jump(BPF_JMP_JEQ_K, libc::AF_UNIX as u32, 0, 1));
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    filter
+}
+
+/// Installs the sandbox in every thread of this process; it can't be removed afterwards.
+#[cfg(target_arch = "x86_64")]
+pub fn install() -> io::Result<()> {
+    let filter = filter();
+    let prog = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // TSYNC applies the filter to every thread, not just this one.
+        let ret = libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const SockFprog,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// The sandbox's syscall list is only written for x86_64.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn install() -> io::Result<()> {
+    Err(io::Error::other("the seccomp sandbox is only supported on x86_64"))
+}
+
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    /// Runs the filter on a syscall, returning the action. This panics if a jump goes past the end
+    /// of the program, or the program runs off it.
+    fn run(filter: &[SockFilter], arch: u32, nr: u32, arg0: u32) -> u32 {
+        let mut pc = 0;
+        let mut acc = 0;
+        loop {
+            let insn = &filter[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS => acc = match insn.k {
+                    SECCOMP_DATA_NR => nr,
+                    SECCOMP_DATA_ARCH => arch,
+                    SECCOMP_DATA_ARG0 => arg0,
+                    k => panic!("load from offset {}", k),
+                },
+                BPF_JMP_JEQ_K => pc += usize::from(if acc == insn.k { insn.jt } else { insn.jf }),
+                BPF_JMP_JGE_K => pc += usize::from(if acc >= insn.k { insn.jt } else { insn.jf }),
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+            assert!(pc < filter.len(), "jump past the end of the program");
+        }
+    }
+
+    #[test]
+    fn jumps_land_on_the_right_returns() {
+        let filter = filter();
+        let errno = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        for &nr in ALLOWED_SYSCALLS.iter() {
+            assert_eq!(run(&filter, AUDIT_ARCH, nr as u32, 0), SECCOMP_RET_ALLOW, "syscall {}", nr);
+            assert_eq!(run(&filter, AUDIT_ARCH, nr as u32 | X32_SYSCALL_BIT, 0), SECCOMP_RET_KILL_PROCESS, "x32 syscall {}", nr);
+        }
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_execve as u32, 0), errno);
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_socket as u32, libc::AF_UNIX as u32), SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_socket as u32, libc::AF_INET as u32), errno);
+        // i386's read(2).
+        assert_eq!(run(&filter, 0x40000003, 3, 0), SECCOMP_RET_KILL_PROCESS);
+    }
+}
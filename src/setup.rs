@@ -0,0 +1,239 @@
+//! Interactive first-run setup.
+
+use std::cmp;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead};
+use std::io::prelude::*;
+use std::path::Path;
+
+use failure::Error;
+use libc;
+
+use msr;
+use paths;
+use privsep;
+use quirks;
+use rapl;
+use temps;
+use ConfigFormat;
+
+
+/// Where the generated systemd unit is installed when running as root.
+const SYSTEMD_UNIT_PATH: &str = "/etc/systemd/system/lenovo-throttling.service";
+
+
+/// What the user wants to optimize for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Goal {
+    BatteryLife,
+    Performance,
+    Silence,
+}
+
+/// The handful of settings that the wizard picks for each power state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Profile {
+    pl1_tdp_w: u64,
+    pl2_tdp_w: u64,
+    maximum_temp_c: u64,
+    turbo_enabled: bool,
+}
+
+/// Hardware limits that the generated profiles need to respect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Hardware {
+    /// PL1 programmed by the BIOS, which is roughly the CPU's rated TDP.
+    default_pl1_w: u64,
+    /// Highest PL2 we're willing to suggest.
+    max_pl2_w: u64,
+    /// Highest temperature limit we're willing to suggest.
+    max_temp_c: u64,
+}
+
+
+/// Runs the setup wizard.
+pub fn run() -> Result<(), Error> {
+    let path = &paths::get().config;
+    if ConfigFormat::detect(path, "") == ConfigFormat::Json {
+        bail!("the setup wizard only writes TOML; give a --config path that doesn't end in .json");
+    }
+    println!("This will ask a few questions and write an initial {} for you.", path);
+    println!();
+
+    let hw = detect_hardware();
+    println!("Detected: default PL1 = {} W, maximum PL2 = {} W, maximum temperature = {} C",
+             hw.default_pl1_w, hw.max_pl2_w, hw.max_temp_c);
+    println!();
+
+    let goal = match ask("What matters most to you?", &[
+        "longer battery life",
+        "maximum performance",
+        "a quiet, cool laptop",
+    ])? {
+        0 => Goal::BatteryLife,
+        1 => Goal::Performance,
+        _ => Goal::Silence,
+    };
+
+    let (battery, ac) = profiles_for(goal, &hw);
+    let config = render_config(&battery, &ac);
+
+    println!();
+    println!("Generated config:");
+    println!();
+    println!("{}", config);
+
+    if Path::new(path).exists() && !confirm(&format!("{} already exists; overwrite it?", path))? {
+        println!("Not writing config.");
+        return Ok(());
+    }
+    if let Some(dir) = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format_err!("{}: {}", dir.display(), e))?;
+    }
+    File::create(path)?.write_all(config.as_bytes())?;
+    println!("Wrote {}", path);
+
+    if confirm("Install a systemd unit?")? {
+        install_unit(path)?;
+    }
+
+    Ok(())
+}
+
+fn detect_hardware() -> Hardware {
+    // The BIOS-programmed PL1 is a good proxy for the rated TDP; fall back to the common 15 W
+    // U-series value if we can't read it.
+    let default_pl1_w = rapl::Units::read().and_then(|units| {
+        let raw = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first()?;
+        Ok(rapl::PowerLimit::decode(raw, 0, &units).watts.round() as u64)
+    }).unwrap_or(15);
+
+    let max_pl2_w = quirks::detect()
+        .and_then(|q| q.max_safe_pl2_w)
+        .unwrap_or(default_pl1_w * 3);
+
+    // Stay a few degrees away from the critical temperature.
+    let max_temp_c = temps::TemperatureTarget::read()
+        .map(|t| t.critical.saturating_sub(3))
+        .unwrap_or(95);
+
+    Hardware {
+        default_pl1_w,
+        max_pl2_w,
+        max_temp_c,
+    }
+}
+
+/// Picks (battery, AC) profiles for the given goal.
+fn profiles_for(goal: Goal, hw: &Hardware) -> (Profile, Profile) {
+    let tdp = hw.default_pl1_w;
+    let clamp = |p: Profile| Profile {
+        pl1_tdp_w: cmp::min(p.pl1_tdp_w, hw.max_pl2_w),
+        pl2_tdp_w: cmp::min(p.pl2_tdp_w, hw.max_pl2_w),
+        maximum_temp_c: cmp::min(p.maximum_temp_c, hw.max_temp_c),
+        turbo_enabled: p.turbo_enabled,
+    };
+
+    let (battery, ac) = match goal {
+        Goal::BatteryLife => (
+            Profile { pl1_tdp_w: tdp, pl2_tdp_w: tdp * 3 / 2, maximum_temp_c: 80, turbo_enabled: true },
+            Profile { pl1_tdp_w: tdp * 2, pl2_tdp_w: tdp * 3, maximum_temp_c: 90, turbo_enabled: true },
+        ),
+        Goal::Performance => (
+            Profile { pl1_tdp_w: tdp * 2, pl2_tdp_w: tdp * 3, maximum_temp_c: 85, turbo_enabled: true },
+            Profile { pl1_tdp_w: tdp * 3, pl2_tdp_w: tdp * 3, maximum_temp_c: 95, turbo_enabled: true },
+        ),
+        Goal::Silence => (
+            Profile { pl1_tdp_w: tdp * 4 / 5, pl2_tdp_w: tdp, maximum_temp_c: 75, turbo_enabled: false },
+            Profile { pl1_tdp_w: tdp, pl2_tdp_w: tdp * 3 / 2, maximum_temp_c: 80, turbo_enabled: true },
+        ),
+    };
+
+    (clamp(battery), clamp(ac))
+}
+
+fn render_config(battery: &Profile, ac: &Profile) -> String {
+    let section = |name: &str, p: &Profile| {
+        let mut s = format!("[{}]\n", name);
+        s += &format!("maximum_temp_c = {}\n\n", p.maximum_temp_c);
+        s += &format!("pl1_tdp_w = {}\npl1_duration = 28\n\n", p.pl1_tdp_w);
+        s += &format!("pl2_tdp_w = {}\npl2_duration = 0.002\n", p.pl2_tdp_w);
+        if !p.turbo_enabled {
+            s += "\nturbo_enabled = false\n";
+        }
+        s
+    };
+
+    format!("{}\n{}", section("battery", battery), section("ac", ac))
+}
+
+fn install_unit(config: &str) -> Result<(), Error> {
+    let exe = env::current_exe()?;
+    // The unit gets the config's absolute path, so it doesn't depend on the working directory.
+    let config = env::current_dir()?.join(config);
+    let unit = format!("[Unit]
+Description=Lenovo throttling fix
+After=dbus.service upower.service
+
+[Service]
+Type=simple
+ExecStart={} --config={}
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+", exe.display(), config.display());
+
+    // Only root can install into /etc; otherwise leave it in the working directory for the user to
+    // install themselves.
+    let path = if unsafe { libc::geteuid() } == 0 {
+        SYSTEMD_UNIT_PATH
+    } else {
+        "lenovo-throttling.service"
+    };
+
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)?
+        .write_all(unit.as_bytes())?;
+    println!("Wrote {}", path);
+    if path == SYSTEMD_UNIT_PATH {
+        println!("Enable it with: systemctl daemon-reload && systemctl enable --now lenovo-throttling");
+        if let Err(e) = privsep::unprivileged_ids() {
+            println!("Before starting it: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks a multiple-choice question, returning the index of the chosen option.
+fn ask(question: &str, options: &[&str]) -> io::Result<usize> {
+    loop {
+        println!("{}", question);
+        for (i, opt) in options.iter().enumerate() {
+            println!("  {}) {}", i + 1, opt);
+        }
+
+        let answer = read_line("> ")?;
+        match answer.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= options.len() => return Ok(n - 1),
+            _ => println!("Please enter a number between 1 and {}.", options.len()),
+        }
+    }
+}
+
+fn confirm(question: &str) -> io::Result<bool> {
+    let answer = read_line(&format!("{} [y/N] ", question))?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+fn read_line(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no input"));
+    }
+    Ok(line.trim().to_string())
+}
@@ -0,0 +1,300 @@
+//! Simulated hardware, for the integration tests in `tests/sim.rs`: a temporary tree of MSR
+//! devices and power supplies that the daemon is pointed at with the path overrides (see `paths`),
+//! a log of every MSR and sysfs write it makes, and a handle for running the daemon against them
+//! and flipping the power source under it. Built with the `sim` feature; nothing here needs root.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as IoWrite};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rapl;
+use temps;
+use topology;
+
+
+/// Environment variable that names the file every write is logged to.
+pub const LOG_VAR: &str = "LT_SIM_LOG";
+
+/// Size of each simulated MSR device. MSRs past the end read short, which the daemon treats like
+/// a MSR that the CPU doesn't have.
+const MSR_DEVICE_SIZE: u64 = 0x2000;
+
+/// How often `Daemon::wait_for` checks the log.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Used to give each machine in a test process its own directory.
+static MACHINES: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Returns whether this process is running against a simulated machine.
+pub fn is_active() -> bool {
+    env::var_os(LOG_VAR).is_some()
+}
+
+/// Logs a write to the file named by `LT_SIM_LOG`, if it's set. Called by the MSR and sysfs
+/// writers, so that tests can check the sequence of writes and not just the final values.
+pub fn record(write: &Write) {
+    let path = match env::var_os(LOG_VAR) {
+        Some(p) => p,
+        None => return,
+    };
+    // Appends of a single line are atomic, so the privileged helper and the worker can share it.
+    let logged = OpenOptions::new().create(true).append(true).open(&path)
+        .and_then(|mut f| f.write_all(format!("{}\n", write.encode()).as_bytes()));
+    if let Err(e) = logged {
+        eprintln!("error logging write to {}: {}", Path::new(&path).display(), e);
+    }
+}
+
+/// Returns what a write to a MSR device leaves in the MSR, which for most MSRs is just the value
+/// written. The log bits of IA32_PACKAGE_THERM_STATUS are cleared by writing 0 and kept by writing
+/// 1, as on real hardware, and the rest of it is read-only. Episodes queued with
+/// `Machine::log_before_next_write` are logged first, as if they'd started just before the write.
+pub fn written_value(device: &Path, msr: u64, value: u64) -> io::Result<u64> {
+    if msr != temps::MSR_PACKAGE_THERM_STATUS {
+        return Ok(value);
+    }
+    let mut bytes = [0u8; 8];
+    File::open(device)?.read_exact_at(&mut bytes, msr)?;
+    let mut current = u64::from_ne_bytes(bytes);
+    let episodes = episodes_file(device);
+    if let Ok(bits) = fs::read_to_string(&episodes) {
+        current |= bits.trim().parse::<u64>().unwrap_or(0);
+        fs::remove_file(&episodes)?;
+    }
+    Ok(current & (value | !temps::THERM_STATUS_LOG_BITS))
+}
+
+fn episodes_file(device: &Path) -> PathBuf {
+    device.with_extension("episodes")
+}
+
+/// A write made by the daemon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Write {
+    Msr { cpu: usize, msr: u64, value: u64 },
+    Sysfs { path: String, value: String },
+}
+
+impl Write {
+    /// Encodes the write as a log line, e.g. "msr 0 0x610 0x42807000dc8078".
+    fn encode(&self) -> String {
+        match *self {
+            Write::Msr { cpu, msr, value } => format!("msr {} 0x{:X} 0x{:X}", cpu, msr, value),
+            Write::Sysfs { ref path, ref value } => format!("sysfs {} {}", path, value),
+        }
+    }
+
+    /// Parses a line written by `encode`.
+    fn parse(line: &str) -> Option<Write> {
+        let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+        let mut fields = line.splitn(4, ' ');
+        match fields.next()? {
+            "msr" => Some(Write::Msr {
+                cpu: fields.next()?.parse().ok()?,
+                msr: hex(fields.next()?)?,
+                value: hex(fields.next()?)?,
+            }),
+            "sysfs" => {
+                let path = fields.next()?.to_string();
+                let value = fields.collect::<Vec<_>>().join(" ");
+                Some(Write::Sysfs { path, value })
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the value written, if this is a write of the given MSR on the first CPU.
+    pub fn msr(&self, msr: u64) -> Option<u64> {
+        match *self {
+            Write::Msr { cpu: 0, msr: m, value } if m == msr => Some(value),
+            _ => None,
+        }
+    }
+}
+
+
+/// A simulated machine: an MSR device for every online CPU, with the RAPL units and a critical
+/// temperature of 100 C filled in, an AC adapter and a battery. It starts out on battery.
+pub struct Machine {
+    root: PathBuf,
+    cpus: Vec<usize>,
+}
+
+impl Machine {
+    /// Lays out a new machine in a temporary directory, which is removed when it's dropped.
+    pub fn new() -> io::Result<Machine> {
+        let id = MACHINES.fetch_add(1, Ordering::SeqCst);
+        let root = env::temp_dir().join(format!("lenovo-throttling-sim-{}-{}", std::process::id(), id));
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        for dir in ["msr", "power_supply/AC", "power_supply/BAT0", "powercap", "hwmon", "state", "run"].iter() {
+            fs::create_dir_all(root.join(dir))?;
+        }
+
+        // The daemon reads the real topology, so there has to be a device for each real CPU.
+        let machine = Machine { root, cpus: topology::online_cpus_or_default() };
+        for &cpu in machine.cpus.iter() {
+            File::create(machine.msr_device(cpu))?.set_len(MSR_DEVICE_SIZE)?;
+            machine.set_msr(cpu, rapl::MSR_RAPL_POWER_UNIT, rapl::DEFAULT_POWER_UNIT)?;
+            // The critical temperature is in bits 23:16.
+            machine.set_msr(cpu, temps::MSR_TEMPERATURE_TARGET, 100 << 16)?;
+        }
+
+        machine.write("power_supply/AC/type", "Mains")?;
+        machine.write("power_supply/BAT0/type", "Battery")?;
+        machine.write("power_supply/BAT0/capacity", "80")?;
+        machine.set_on_ac(false)?;
+        Ok(machine)
+    }
+
+    /// Returns the directory the machine is laid out in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the CPUs that have an MSR device.
+    pub fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+
+    fn msr_device(&self, cpu: usize) -> PathBuf {
+        self.root.join("msr").join(cpu.to_string())
+    }
+
+    fn write(&self, path: &str, contents: &str) -> io::Result<()> {
+        fs::write(self.root.join(path), format!("{}\n", contents))
+    }
+
+    /// Sets a MSR on one CPU.
+    pub fn set_msr(&self, cpu: usize, msr: u64, value: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(self.msr_device(cpu))?.write_all_at(&value.to_ne_bytes(), msr)
+    }
+
+    /// Sets a MSR on every CPU.
+    pub fn set_msr_all(&self, msr: u64, value: u64) -> io::Result<()> {
+        self.cpus.iter().try_for_each(|&cpu| self.set_msr(cpu, msr, value))
+    }
+
+    /// Reads a MSR's current value on one CPU.
+    pub fn msr(&self, cpu: usize, msr: u64) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        File::open(self.msr_device(cpu))?.read_exact_at(&mut bytes, msr)?;
+        Ok(u64::from_ne_bytes(bytes))
+    }
+
+    /// Sets IA32_PACKAGE_THERM_STATUS log bits on one CPU just before the daemon next writes it, i.e.
+    /// between its read and the write that clears the bits that it read.
+    pub fn log_before_next_write(&self, cpu: usize, bits: u64) -> io::Result<()> {
+        fs::write(episodes_file(&self.msr_device(cpu)), bits.to_string())
+    }
+
+    /// Removes a MSR from every CPU, by cutting the devices off just before it. Any MSRs at higher
+    /// addresses go too.
+    pub fn remove_msr(&self, msr: u64) -> io::Result<()> {
+        self.cpus.iter().try_for_each(|&cpu| OpenOptions::new().write(true).open(self.msr_device(cpu))?.set_len(msr))
+    }
+
+    /// Plugs the AC adapter in or pulls it out.
+    pub fn set_on_ac(&self, on_ac: bool) -> io::Result<()> {
+        self.write("power_supply/AC/online", if on_ac { "1" } else { "0" })?;
+        self.write("power_supply/BAT0/status", if on_ac { "Charging" } else { "Discharging" })
+    }
+
+    /// Writes the daemon's config.toml.
+    pub fn write_config(&self, contents: &str) -> io::Result<()> {
+        fs::write(self.root.join("config.toml"), contents)
+    }
+
+    /// Returns the path overrides that point the daemon at this machine.
+    pub fn args(&self) -> Vec<String> {
+        let path = |p: &str| self.root.join(p).display().to_string();
+        vec![
+            format!("--config={}", path("config.toml")),
+            format!("--msr-path={}/{{cpu}}", path("msr")),
+            format!("--power-supply-root={}", path("power_supply")),
+            format!("--powercap-root={}", path("powercap")),
+            format!("--hwmon-root={}", path("hwmon")),
+            format!("--state-dir={}", path("state")),
+            format!("--runtime-dir={}", path("run")),
+        ]
+    }
+
+    /// Starts the daemon (the binary at `exe`) against this machine, in its directory, reading the
+    /// config written by `write_config`.
+    pub fn spawn(&self, exe: &Path) -> io::Result<Daemon> {
+        let log = self.root.join("writes.log");
+        let output = self.root.join("daemon.log");
+        File::create(&log)?;
+        let out = File::create(&output)?;
+
+        let child = Command::new(exe)
+            .args(self.args())
+            .current_dir(&self.root)
+            .env(LOG_VAR, &log)
+            .stdin(Stdio::null())
+            .stdout(out.try_clone()?)
+            .stderr(out)
+            .spawn()?;
+        Ok(Daemon { child, log, output })
+    }
+}
+
+impl Drop for Machine {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+
+/// The daemon, running against a simulated machine. It's killed when this is dropped.
+pub struct Daemon {
+    child: Child,
+    log: PathBuf,
+    output: PathBuf,
+}
+
+impl Daemon {
+    /// Returns every write made so far, in order.
+    pub fn writes(&self) -> Vec<Write> {
+        fs::read_to_string(&self.log).unwrap_or_default().lines().filter_map(Write::parse).collect()
+    }
+
+    /// Returns what the daemon has printed so far.
+    pub fn output(&self) -> String {
+        fs::read_to_string(&self.output).unwrap_or_default()
+    }
+
+    /// Waits until the writes so far satisfy `done`, returning them. Fails with the daemon's output
+    /// if it exits or the timeout passes first.
+    pub fn wait_for<F: Fn(&[Write]) -> bool>(&mut self, timeout: Duration, done: F) -> Result<Vec<Write>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let writes = self.writes();
+            if done(&writes) {
+                return Ok(writes);
+            }
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Err(format!("daemon exited with {}; writes: {:?}\n{}", status, writes, self.output()));
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("timed out; writes: {:?}\n{}", writes, self.output()));
+            }
+            thread::sleep(POLL);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use failure::Error;
+
+use cpuid;
+use duration;
+use freq;
+use msr;
+use persist;
+use power;
+use quirks;
+use rapl;
+use sysfs;
+use temps;
+use turbo;
+
+
+/// How long to measure the effective frequency over.
+const FREQUENCY_SAMPLE_PERIOD: Duration = Duration::from_millis(250);
+
+
+/// Prints the current state of the registers that we manage.
+pub fn run() -> Result<(), Error> {
+    msr::Capabilities::probe().report();
+    cpuid::get().report();
+
+    if let Some(q) = quirks::detect() {
+        q.report();
+    }
+
+    let units = rapl::Units::read()?;
+    let domains = rapl::domains();
+
+    // Parts with several tiles have the power limits on each.
+    let tiles: Vec<(String, usize)> = match domains.tiles() {
+        [] => vec![(String::new(), msr::first_cpu())],
+        tiles => tiles.iter().map(|t| (format!(" on {}", t.describe()), t.cpus[0])).collect(),
+    };
+    for (tile, cpu) in tiles {
+        let power_limit = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_one(cpu)?;
+        println!("MSR_PKG_POWER_LIMIT{} = 0x{:016x}{}",
+                 tile,
+                 power_limit,
+                 if power_limit & (1 << 63) != 0 { " (locked)" } else { "" });
+        print_power_limit("PL1", &rapl::PowerLimit::decode(power_limit, 0, &units));
+        print_power_limit("PL2", &rapl::PowerLimit::decode(power_limit, 32, &units));
+    }
+
+    domains.report();
+
+    // Not every CPU has a PP1 domain, so this is allowed to fail.
+    let pp1_limit = if domains.has(rapl::Domain::Graphics) {
+        msr::ReadMsrBuilder::new(rapl::MSR_PP1_POWER_LIMIT).read_first().ok()
+    } else {
+        None
+    };
+    if let Some(pp1_limit) = pp1_limit {
+        println!("MSR_PP1_POWER_LIMIT = 0x{:016x}{}",
+                 pp1_limit,
+                 if pp1_limit & (1 << 31) != 0 { " (locked)" } else { "" });
+        print_power_limit("GPU", &rapl::PowerLimit::decode(pp1_limit, 0, &units));
+    }
+
+    let temp_target = msr::ReadMsrBuilder::new(temps::MSR_TEMPERATURE_TARGET).read_first()?;
+    let decoded = temps::TemperatureTarget::from_raw(temp_target);
+    println!("MSR_TEMPERATURE_TARGET = 0x{:016x}", temp_target);
+    println!("  critical temperature = {} C", decoded.critical);
+    println!("  throttle temperature = {} C (offset {})", decoded.throttle_temp(), decoded.offset);
+
+    // Older CPUs don't have package thermal status, so this is allowed to fail.
+    if let Ok(therm) = temps::ThermStatus::read() {
+        let names = |flags: &temps::ThermFlags| {
+            if flags.any() { flags.names().join(", ") } else { "none".to_string() }
+        };
+        println!("IA32_PACKAGE_THERM_STATUS");
+        println!("  throttling now  = {}", names(&therm.active));
+        println!("  throttled since last cleared = {}", names(&therm.logged));
+    }
+
+    match temps::Temperatures::read() {
+        Ok(t) => {
+            if let Some(package) = t.package {
+                println!("package temperature = {} C", package);
+            }
+            for core in t.cores.iter() {
+                println!("  package {} core {} (cpu {}) = {} C", core.package, core.core, core.cpu, core.celsius);
+            }
+        },
+        Err(e) => println!("temperatures unavailable: {}", e),
+    }
+
+    match freq::measure(FREQUENCY_SAMPLE_PERIOD) {
+        Ok(f) => {
+            match f.average_mhz {
+                Some(avg) => println!("effective frequency = {:.0} MHz average (busy CPUs, over {} ms)",
+                                      avg, FREQUENCY_SAMPLE_PERIOD.as_millis()),
+                None => println!("effective frequency = idle"),
+            }
+            for c in f.cpus.iter() {
+                println!("  cpu {} = {:.0} MHz", c.cpu, c.mhz);
+            }
+        },
+        Err(e) => println!("effective frequency unavailable: {}", e),
+    }
+
+    // Only some CPUs report turbo ratios, so this is allowed to fail.
+    if let Ok(info) = turbo::TurboInfo::read() {
+        info.report();
+    }
+
+    let turbo_disabled = match sysfs::read_value(::INTEL_PSTATE_NO_TURBO) {
+        Ok(v) => v == "1",
+        Err(_) => msr::ReadMsrBuilder::new(turbo::IA32_MISC_ENABLE).read_first()? & turbo::TURBO_DISABLE != 0,
+    };
+    println!("turbo = {}", if turbo_disabled { "disabled" } else { "enabled" });
+
+    if let Ok(Some(health)) = power::battery_health() {
+        println!("battery health = {:.0}% of design capacity{}", health.health_pct,
+                 health.cycles.map_or(String::new(), |c| format!(", {} cycles", c)));
+    }
+
+    match persist::load_override() {
+        Some(o) => println!("forced profile = {}", o.describe()),
+        None => println!("forced profile = none (automatic selection)"),
+    }
+
+    Ok(())
+}
+
+fn print_power_limit(name: &str, limit: &rapl::PowerLimit) {
+    println!("  {}: {} W over {} ({}{})",
+             name,
+             limit.watts,
+             duration::format(limit.window),
+             if limit.enabled { "enabled" } else { "disabled" },
+             if limit.clamping { ", clamping" } else { "" });
+}
@@ -0,0 +1,319 @@
+//! The `stress-verify` subcommand, which looks for the largest stable undervolt at or above a
+//! candidate offset. The offset is applied to the core and cache planes (which share a voltage on
+//! recent CPUs, so that only the smaller of the two offsets has any effect) and every CPU is kept
+//! busy with a calculation whose result is known; if a result comes out wrong, or the kernel logs a
+//! machine check, the offset is stepped back and the run starts over.
+//!
+//! Errors are only a sign of instability, not the other way around: an offset that passes can still
+//! crash under a different load, so it's worth leaving some margin. The previous offsets are put
+//! back at the end either way, including on Ctrl-C or SIGTERM, and when the package gets within a
+//! few degrees of TjMax; if the machine hangs instead, a power cycle clears the offset.
+
+use std::fs::{File, OpenOptions};
+use std::hint;
+use std::io::{ErrorKind, SeekFrom};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use channel;
+use failure::Error;
+use libc;
+use num_cpus;
+
+use daemon::{self, Event};
+use temps;
+use undervolt::{self, Plane};
+
+
+/// How often to check for errors and sample the temperature.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The planes that the candidate offset is applied to.
+const PLANES: &[Plane] = &[Plane::Core, Plane::Cache];
+
+const DEFAULT_STEP_MV: f64 = 5.0;
+const DEFAULT_DURATION_SEC: u64 = 300;
+
+/// How close the package may get to TjMax, in degrees Celsius, before the run is stopped.
+const TJMAX_MARGIN_C: u64 = 5;
+
+/// TjMax to assume if it can't be read; it's 100 C on most recent mobile CPUs.
+const DEFAULT_TJMAX_C: u64 = 100;
+
+/// Iterations of the calculation between checks of its result.
+const CHUNK: u32 = 1_000_000;
+
+const USAGE: &str = "usage: stress-verify --offset MV [--step MV] [--duration SECS] [--threads N]";
+
+
+/// Options for a verification run, as given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// The candidate offset, in millivolts; negative to undervolt.
+    offset_mv: f64,
+    /// How far to step back towards 0 after errors, in millivolts.
+    step_mv: f64,
+    /// How long each offset has to run without errors to count as stable.
+    duration: Duration,
+    /// Number of threads to load.
+    threads: usize,
+}
+
+impl Options {
+    /// Parses the arguments following `stress-verify`.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+        let mut offset_mv = None;
+        let mut opts = Options {
+            offset_mv: 0.0,
+            step_mv: DEFAULT_STEP_MV,
+            duration: Duration::from_secs(DEFAULT_DURATION_SEC),
+            threads: num_cpus::get(),
+        };
+
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| format_err!("{} needs a value ({})", arg, USAGE))?;
+            let invalid = || format_err!("invalid value for {}: {}", arg, value);
+
+            match arg.as_str() {
+                "--offset" => offset_mv = Some(value.parse::<f64>().map_err(|_| invalid())?),
+                "--step" => opts.step_mv = value.parse::<f64>().map_err(|_| invalid())?,
+                "--duration" => opts.duration = Duration::from_secs(value.parse::<u64>().map_err(|_| invalid())?),
+                "--threads" => opts.threads = value.parse::<usize>().map_err(|_| invalid())?,
+                _ => bail!("unknown stress-verify argument: {} ({})", arg, USAGE),
+            }
+        }
+
+        opts.offset_mv = offset_mv.ok_or_else(|| format_err!("the candidate --offset is needed ({})", USAGE))?;
+        if !(-undervolt::MAX_UNDERVOLT_MV..0.0).contains(&opts.offset_mv) {
+            bail!("--offset must be between -{} and 0 mV, e.g. -80", undervolt::MAX_UNDERVOLT_MV);
+        }
+        if opts.step_mv.is_nan() || opts.step_mv < 1.0 {
+            bail!("--step must be at least 1 mV");
+        }
+        if opts.threads == 0 || opts.duration < SAMPLE_INTERVAL * 2 {
+            bail!("stress-verify needs at least one thread and a duration of at least 2 seconds");
+        }
+        Ok(opts)
+    }
+}
+
+
+/// The outcome of running one offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Attempt {
+    offset_mv: f64,
+    /// How long the load ran for; runs stop at the first error.
+    ran: Duration,
+    /// Calculations whose result came out wrong.
+    wrong_results: u64,
+    /// Hardware error messages in the kernel log.
+    machine_checks: u64,
+    max_temp_c: Option<u64>,
+}
+
+impl Attempt {
+    fn is_stable(&self) -> bool {
+        self.wrong_results == 0 && self.machine_checks == 0
+    }
+}
+
+
+/// Runs the verification and prints a report. The previous offsets are put back even if it fails
+/// part way through.
+pub fn run(opts: &Options) -> Result<(), Error> {
+    let mut previous = vec![];
+    for &plane in PLANES.iter() {
+        previous.push((plane, undervolt::read_offset(plane)?));
+    }
+    let mut checks = MachineChecks::open();
+    if checks.is_none() {
+        eprintln!("WARNING: the kernel log can't be read, so only wrong results will be caught, not machine checks");
+    }
+
+    println!("Running {} thread(s) for up to {}s per offset, from {} mV in {} mV steps; this will make the machine hot and loud.",
+             opts.threads, opts.duration.as_secs(), opts.offset_mv, opts.step_mv);
+    println!("If the machine hangs or reboots, the offset is too large; a power cycle clears it.");
+
+    // Blocked before the load threads are started, so that they inherit the mask and signals are
+    // left to the thread that forwards them.
+    let (signals_tx, signals) = channel::unbounded();
+    daemon::handle_signals(signals_tx);
+    let tjmax = temps::TemperatureTarget::read().map_or(DEFAULT_TJMAX_C, |t| t.critical);
+
+    let mut attempts = vec![];
+    let res = verify(opts, tjmax, &signals, checks.as_mut(), &mut attempts);
+
+    for &(plane, mv) in previous.iter() {
+        if let Err(e) = undervolt::set_offset(plane, mv) {
+            eprintln!("error putting back the {} offset of {} mV: {}", plane.name(), mv, e);
+        }
+    }
+
+    if !attempts.is_empty() {
+        println!();
+        println!("{:>10} {:>8} {:>14} {:>15} {:>6}  result", "offset mV", "ran s", "wrong results", "machine checks", "max C");
+    }
+    for a in attempts.iter() {
+        println!("{:>10} {:>8} {:>14} {:>15} {:>6}  {}",
+                 a.offset_mv, a.ran.as_secs(), a.wrong_results, a.machine_checks,
+                 a.max_temp_c.map_or("-".to_string(), |t| t.to_string()),
+                 if a.is_stable() { "stable" } else { "errors" });
+    }
+    println!();
+    match res? {
+        Some(mv) => println!("{} mV ran for {}s without errors; consider backing off a step or two for margin.",
+                             mv, opts.duration.as_secs()),
+        None => println!("No undervolt down to {} mV ran without errors.", opts.offset_mv),
+    }
+    let previous: Vec<String> = previous.iter().map(|&(plane, mv)| format!("{} {} mV", plane.name(), mv)).collect();
+    println!("The previous offsets ({}) have been put back.", previous.join(", "));
+    Ok(())
+}
+
+/// Tries offsets from the candidate towards 0 until one runs without errors, returning it.
+fn verify(opts: &Options, tjmax: u64, signals: &channel::Receiver<Event>, mut checks: Option<&mut MachineChecks>,
+          attempts: &mut Vec<Attempt>) -> Result<Option<f64>, Error> {
+    let mut offset_mv = opts.offset_mv;
+    while offset_mv < 0.0 {
+        for &plane in PLANES.iter() {
+            undervolt::set_offset(plane, offset_mv)?;
+        }
+        println!("Testing {} mV...", offset_mv);
+        let attempt = stress(opts, offset_mv, tjmax, signals, checks.as_deref_mut())?;
+        attempts.push(attempt);
+        if attempt.is_stable() {
+            return Ok(Some(offset_mv));
+        }
+        println!("{} wrong result(s) and {} machine check(s) at {} mV; stepping back",
+                 attempt.wrong_results, attempt.machine_checks, offset_mv);
+        offset_mv += opts.step_mv;
+    }
+    Ok(None)
+}
+
+/// Keeps `opts.threads` threads busy with checked calculations for `opts.duration`, or until the
+/// first error. Fails if it's interrupted by a signal, or the package gets too close to TjMax.
+fn stress(opts: &Options, offset_mv: f64, tjmax: u64, signals: &channel::Receiver<Event>,
+          mut checks: Option<&mut MachineChecks>) -> Result<Attempt, Error> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let wrong = Arc::new(AtomicU64::new(0));
+
+    // Skip any machine checks logged before this run.
+    if let Some(ref mut c) = checks {
+        c.poll();
+    }
+
+    let workers: Vec<_> = (0..opts.threads).map(|i| {
+        let stop = stop.clone();
+        let wrong = wrong.clone();
+        thread::spawn(move || {
+            // The reference is worked out at the offset under test too, but a wrong result is far
+            // more likely to differ from the rest than to repeat.
+            let seed = 0x9E3779B97F4A7C15 ^ i as u64;
+            let expected = calculate(seed);
+            while !stop.load(Ordering::Relaxed) {
+                if calculate(hint::black_box(seed)) != expected {
+                    wrong.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })
+    }).collect();
+
+    let mut temp_sampler = temps::TemperatureSampler::new().ok();
+    let mut attempt = Attempt { offset_mv, ran: Duration::from_secs(0), wrong_results: 0, machine_checks: 0, max_temp_c: None };
+    let mut aborted = None;
+    let start = Instant::now();
+    while start.elapsed() < opts.duration && attempt.is_stable() {
+        match signals.recv_timeout(SAMPLE_INTERVAL) {
+            // SIGHUP; there's no config to reload.
+            Ok(Event::Reload) | Err(channel::RecvTimeoutError::Timeout) => {},
+            _ => {
+                aborted = Some(format_err!("interrupted while testing {} mV", offset_mv));
+                break;
+            },
+        }
+        attempt.wrong_results = wrong.load(Ordering::Relaxed);
+        if let Some(ref mut c) = checks {
+            attempt.machine_checks += c.poll();
+        }
+        let temp = temp_sampler.as_mut().and_then(|s| s.read().ok()).and_then(|t| t.package);
+        attempt.max_temp_c = attempt.max_temp_c.max(temp);
+        if let Some(t) = temp.filter(|&t| t + TJMAX_MARGIN_C >= tjmax) {
+            aborted = Some(format_err!("the package reached {} C while testing {} mV, within {} C of TjMax ({} C); \
+                                        improve the cooling or use fewer --threads",
+                                       t, offset_mv, TJMAX_MARGIN_C, tjmax));
+            break;
+        }
+    }
+    attempt.ran = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    for w in workers {
+        let _ = w.join();
+    }
+    match aborted {
+        Some(e) => Err(e),
+        None => Ok(attempt),
+    }
+}
+
+/// A calculation that mixes integer multiplies and floating point, with a result that only depends
+/// on the seed.
+fn calculate(seed: u64) -> (u64, u64) {
+    let mut x = seed;
+    let mut y = 1.0f64;
+    for _ in 0..CHUNK {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x = x.wrapping_mul(0x2545F4914F6CDD1D);
+        y = y * 0.999_999 + (x >> 44) as f64 * 1e-6;
+    }
+    (x, y.to_bits())
+}
+
+
+/// Watches the kernel log for hardware errors, which the kernel reports machine checks (and
+/// firmware-first APEI errors) as.
+struct MachineChecks {
+    kmsg: File,
+}
+
+impl MachineChecks {
+    /// Starts watching from the end of the log. Returns `None` if it can't be read, e.g. because of
+    /// kernel.dmesg_restrict without CAP_SYSLOG.
+    fn open() -> Option<MachineChecks> {
+        let mut kmsg = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open("/dev/kmsg").ok()?;
+        kmsg.seek(SeekFrom::End(0)).ok()?;
+        Some(MachineChecks { kmsg })
+    }
+
+    /// Returns the number of hardware error messages logged since the last poll.
+    fn poll(&mut self) -> u64 {
+        // Each read returns one record, e.g. "3,1234,5678,-;mce: [Hardware Error]: CPU 2: ...".
+        let mut buf = [0u8; 8192];
+        let mut count = 0;
+        loop {
+            match self.kmsg.read(&mut buf) {
+                Ok(0) => return count,
+                Ok(n) => {
+                    if String::from_utf8_lossy(&buf[..n]).contains("[Hardware Error]") {
+                        count += 1;
+                    }
+                },
+                // Records were overwritten before they were read; carry on from the next one.
+                Err(ref e) if e.raw_os_error() == Some(libc::EPIPE) => {},
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return count,
+                Err(e) => {
+                    eprintln!("error reading the kernel log: {}", e);
+                    return count;
+                },
+            }
+        }
+    }
+}
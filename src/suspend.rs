@@ -0,0 +1,58 @@
+//! Noticing resumes from suspend. CLOCK_BOOTTIME keeps counting while the system is suspended and
+//! CLOCK_MONOTONIC doesn't, so the gap between them grows by however long each suspend lasted.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use ::channel;
+use libc;
+
+
+/// How often to compare the clocks; resumes are noticed within this long.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Smallest growth in the gap that counts as a suspend, to allow for jitter between the reads.
+const MIN_SUSPEND: Duration = Duration::from_secs(1);
+
+
+/// Returns how long the system has spent suspended since it booted.
+pub fn suspended_time() -> io::Result<Duration> {
+    Ok(read_clock(libc::CLOCK_BOOTTIME)?.saturating_sub(read_clock(libc::CLOCK_MONOTONIC)?))
+}
+
+fn read_clock(clock: libc::clockid_t) -> io::Result<Duration> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Returns a channel that emits how long the system was suspended, after each resume.
+pub fn notify_on_resume() -> io::Result<channel::Receiver<Duration>> {
+    let mut last = suspended_time()?;
+
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+
+            let suspended = match suspended_time() {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("error reading clocks, resumes won't be noticed: {}", e);
+                    return;
+                },
+            };
+            let slept = suspended.saturating_sub(last);
+            last = suspended;
+
+            if slept >= MIN_SUSPEND && send.send(slept).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(recv)
+}
@@ -0,0 +1,36 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+
+use privsep;
+
+
+/// Reads the contents of a sysfs attribute, with any trailing newline removed.
+pub fn read_value(path: &str) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    Ok(contents.trim_end().to_string())
+}
+
+/// Writes a value to a sysfs attribute.
+pub fn write_value(path: &str, value: &str) -> io::Result<()> {
+    if privsep::is_active() {
+        return privsep::write_sysfs(path, value);
+    }
+
+    write_value_direct(path, value)
+}
+
+/// Writes a value to a sysfs attribute from this process, bypassing the privileged helper.
+pub fn write_value_direct(path: &str, value: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(false)
+        .open(path)?;
+    file.write_all(value.as_bytes())?;
+    #[cfg(feature = "sim")]
+    ::sim::record(&::sim::Write::Sysfs { path: path.to_string(), value: value.to_string() });
+    Ok(())
+}
@@ -0,0 +1,511 @@
+use std::cmp;
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use ::channel;
+use msr;
+use paths;
+use priority;
+use ratelimit;
+use sysfs;
+use topology;
+
+
+/// Address of IA32_THERM_STATUS, the per-core thermal status.
+pub const MSR_THERM_STATUS: u64 = 0x19C;
+
+/// Address of MSR_TEMPERATURE_TARGET.
+pub const MSR_TEMPERATURE_TARGET: u64 = 0x1A2;
+
+/// Address of IA32_PACKAGE_THERM_STATUS.
+pub const MSR_PACKAGE_THERM_STATUS: u64 = 0x1B1;
+
+/// How close to the critical temperature we're willing to set the throttle temperature.
+const TEMP_TARGET_MIN_OFFSET: u64 = 3;
+
+/// Largest offset that MSR_TEMPERATURE_TARGET can hold; the field is 6 bits wide.
+const TEMP_TARGET_MAX_OFFSET: u64 = 0b111111;
+
+/// Every sticky log bit in IA32_PACKAGE_THERM_STATUS, including the two threshold logs that we
+/// don't report. Log bits are cleared by writing 0 and left alone by writing 1.
+pub const THERM_STATUS_LOG_BITS: u64 = (1 << 1) | (1 << 3) | (1 << 5) | (1 << 7) | (1 << 9) | (1 << 11);
+
+
+/// Decoded contents of MSR_TEMPERATURE_TARGET.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TemperatureTarget {
+    /// The critical temperature (TjMax) of the CPU, in degrees Celsius.
+    pub critical: u64,
+    /// How far below the critical temperature the CPU starts throttling, in degrees Celsius.
+    pub offset: u64,
+}
+
+impl TemperatureTarget {
+    /// Reads the temperature target from the first CPU in the system.
+    pub fn read() -> io::Result<TemperatureTarget> {
+        let raw = msr::ReadMsrBuilder::new(MSR_TEMPERATURE_TARGET).read_first()?;
+        Ok(TemperatureTarget::from_raw(raw))
+    }
+
+    /// Decodes a raw MSR_TEMPERATURE_TARGET value.
+    pub fn from_raw(raw: u64) -> TemperatureTarget {
+        TemperatureTarget {
+            critical: (raw >> 16) & 0b11111111,
+            offset: (raw >> 24) & 0b111111,
+        }
+    }
+
+    /// Returns the temperature at which the CPU starts throttling, in degrees Celsius.
+    pub fn throttle_temp(&self) -> u64 {
+        self.critical.saturating_sub(self.offset)
+    }
+
+    /// Works out the offset that makes the CPU throttle at the requested temperature.
+    ///
+    /// The result is clamped to stay at least `TEMP_TARGET_MIN_OFFSET` degrees below the critical
+    /// temperature, and to what the offset field can express. Returns `None` if the critical
+    /// temperature is too low to be believable, e.g. under some hypervisors.
+    pub fn offset_for(&self, requested_c: u64) -> Option<TargetOffset> {
+        if self.critical <= TEMP_TARGET_MIN_OFFSET {
+            return None;
+        }
+
+        let highest = self.critical - TEMP_TARGET_MIN_OFFSET;
+        let lowest = self.critical.saturating_sub(TEMP_TARGET_MAX_OFFSET);
+        let temp_c = cmp::max(cmp::min(requested_c, highest), lowest);
+
+        Some(TargetOffset {
+            offset: self.critical - temp_c,
+            temp_c,
+            clamped: temp_c != requested_c,
+        })
+    }
+}
+
+/// The offset field (bits 29:24) of MSR_TEMPERATURE_TARGET.
+pub const TEMP_TARGET_OFFSET_MASK: u64 = TEMP_TARGET_MAX_OFFSET << 24;
+
+/// Replaces the offset field (bits 29:24) of a raw MSR_TEMPERATURE_TARGET value, keeping the
+/// other bits.
+pub fn encode_target_offset(raw: u64, offset: u64) -> u64 {
+    (raw & !(TEMP_TARGET_MAX_OFFSET << 24)) | ((offset & TEMP_TARGET_MAX_OFFSET) << 24)
+}
+
+/// An offset for MSR_TEMPERATURE_TARGET, as worked out by `TemperatureTarget::offset_for`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetOffset {
+    /// Degrees below the critical temperature.
+    pub offset: u64,
+    /// The throttle temperature that the offset gives, in degrees Celsius.
+    pub temp_c: u64,
+    /// Whether this differs from the requested temperature.
+    pub clamped: bool,
+}
+
+/// Throttling conditions reported by IA32_PACKAGE_THERM_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ThermFlags {
+    /// The package reached its throttle temperature.
+    pub thermal: bool,
+    /// PROCHOT# was asserted, e.g. by the embedded controller.
+    pub prochot: bool,
+    /// The package reached its critical temperature.
+    pub critical: bool,
+    /// The package was throttled to stay within its power limits.
+    pub power_limit: bool,
+}
+
+impl ThermFlags {
+    /// Decodes the flags found at the given bit offset of a raw IA32_PACKAGE_THERM_STATUS value;
+    /// offset 0 gives the current status, and 1 gives the sticky log bits.
+    fn from_raw(raw: u64, offset: u64) -> ThermFlags {
+        let bit = |n: u64| (raw >> (n + offset)) & 1 == 1;
+        ThermFlags {
+            thermal: bit(0),
+            prochot: bit(2),
+            critical: bit(4),
+            power_limit: bit(10),
+        }
+    }
+
+    /// Returns the log bits corresponding to the set flags.
+    fn log_bits(&self) -> u64 {
+        (self.thermal as u64) << 1
+            | (self.prochot as u64) << 3
+            | (self.critical as u64) << 5
+            | (self.power_limit as u64) << 11
+    }
+
+    /// Returns whether any flag is set.
+    pub fn any(&self) -> bool {
+        self.thermal || self.prochot || self.critical || self.power_limit
+    }
+
+    /// Returns the names of the set flags, for display.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.thermal, "thermal"),
+            (self.prochot, "prochot"),
+            (self.critical, "critical"),
+            (self.power_limit, "power limit"),
+        ].iter().filter(|&&(set, _)| set).map(|&(_, name)| name).collect()
+    }
+}
+
+/// Decoded contents of IA32_PACKAGE_THERM_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermStatus {
+    /// Conditions that are active right now.
+    pub active: ThermFlags,
+    /// Conditions that have occurred since the log bits were last cleared.
+    pub logged: ThermFlags,
+}
+
+impl ThermStatus {
+    /// Reads the thermal status of the first package in the system.
+    pub fn read() -> io::Result<ThermStatus> {
+        let raw = msr::ReadMsrBuilder::new(MSR_PACKAGE_THERM_STATUS).read_first()?;
+        Ok(ThermStatus::from_raw(raw))
+    }
+
+    /// Decodes a raw IA32_PACKAGE_THERM_STATUS value.
+    pub fn from_raw(raw: u64) -> ThermStatus {
+        ThermStatus {
+            active: ThermFlags::from_raw(raw, 0),
+            logged: ThermFlags::from_raw(raw, 1),
+        }
+    }
+}
+
+/// Decodes the digital readout (bits 22:16) of IA32_THERM_STATUS or IA32_PACKAGE_THERM_STATUS,
+/// which is the number of degrees below TjMax.
+fn digital_readout(raw: u64) -> u64 {
+    (raw >> 16) & 0b1111111
+}
+
+/// A temperature reading from a single core's digital thermal sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreTemperature {
+    /// The CPU that the reading was taken from; hyperthreads share a sensor, so this is the first
+    /// CPU of the core.
+    pub cpu: usize,
+    pub package: u32,
+    pub core: u32,
+    /// Temperature in degrees Celsius.
+    pub celsius: u64,
+}
+
+/// Temperatures read directly from the CPU's digital thermal sensors, so that they're available
+/// even without the coretemp hwmon driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Temperatures {
+    /// Temperature of the package in degrees Celsius, if the CPU reports it.
+    pub package: Option<u64>,
+    /// Temperature of each core with a valid reading.
+    pub cores: Vec<CoreTemperature>,
+}
+
+impl Temperatures {
+    /// Reads the package temperature and the temperature of every online core.
+    pub fn read() -> io::Result<Temperatures> {
+        TemperatureSampler::new()?.read()
+    }
+}
+
+/// Reads the temperatures from the coretemp hwmon driver, which, unlike the MSRs, doesn't need root.
+/// There's one coretemp chip per package, with a "Package id N" sensor and a "Core N" sensor for
+/// each core.
+#[cfg_attr(not(any(feature = "dbus", feature = "metrics")), allow(dead_code))]
+pub fn read_hwmon() -> io::Result<Temperatures> {
+    let root = &paths::get().hwmon;
+    let topology = topology::Topology::read()?;
+    let mut chips: Vec<String> = fs::read_dir(root)?
+        .filter_map(|e| e.ok())
+        .map(|e| format!("{}/{}", root, e.file_name().to_string_lossy()))
+        .filter(|dir| sysfs::read_value(&format!("{}/name", dir)).is_ok_and(|n| n == "coretemp"))
+        .collect();
+    chips.sort();
+    if chips.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "coretemp isn't loaded"));
+    }
+
+    let mut package = None;
+    let mut cores = vec![];
+    for chip in chips {
+        let mut sensors = vec![];
+        for entry in fs::read_dir(&chip)?.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let sensor = match name.strip_suffix("_label") {
+                Some(s) => s.to_string(),
+                None => continue,
+            };
+            let label = sysfs::read_value(&format!("{}/{}", chip, name))?;
+            // Readings are in millidegrees; a sensor that can't be read (e.g. a core that's gone
+            // offline) is skipped.
+            if let Some(millis) = sysfs::read_value(&format!("{}/{}_input", chip, sensor)).ok().and_then(|v| v.parse::<u64>().ok()) {
+                sensors.push((label, millis / 1000));
+            }
+        }
+
+        let chip_package = sensors.iter()
+            .find_map(|&(ref label, c)| Some((label.strip_prefix("Package id ")?.parse::<u32>().ok()?, c)));
+        let id = chip_package.map_or(0, |(id, _)| id);
+        if id == 0 {
+            package = chip_package.map(|(_, c)| c);
+        }
+
+        for &(ref label, celsius) in sensors.iter() {
+            let core = match label.strip_prefix("Core ").and_then(|n| n.parse::<u32>().ok()) {
+                Some(c) => c,
+                None => continue,
+            };
+            if let Some(cpu) = topology.cpus.iter().find(|c| c.package == id && c.core == core) {
+                cores.push(CoreTemperature { cpu: cpu.id, package: id, core, celsius });
+            }
+        }
+    }
+    cores.sort_by_key(|c| c.cpu);
+
+    Ok(Temperatures { package, cores })
+}
+
+/// Reads temperatures repeatedly, for monitoring; the topology and TjMax are only looked up once.
+pub struct TemperatureSampler {
+    tjmax: u64,
+    /// Whether the first value sampled is the package thermal status.
+    has_package: bool,
+    /// The cores being read, in the order their thermal status is sampled.
+    cores: Vec<CoreTemperature>,
+    sampler: msr::Sampler,
+    /// The CPUs that `cores` was worked out from, to notice when they change.
+    cpus: Vec<usize>,
+}
+
+impl TemperatureSampler {
+    pub fn new() -> io::Result<TemperatureSampler> {
+        let tjmax = TemperatureTarget::read()?.critical;
+
+        // Older CPUs don't have package thermal status.
+        let has_package = msr::ReadMsrBuilder::new(MSR_PACKAGE_THERM_STATUS).read_first().is_ok();
+
+        // Hyperthreads share a sensor, so only read the first CPU of each core.
+        let topology = topology::Topology::read()?;
+        let cpus = topology.cpus.iter().map(|c| c.id).collect();
+        let mut cores: Vec<CoreTemperature> = vec![];
+        for cpu in topology.cpus {
+            if !cores.iter().any(|c| c.package == cpu.package && c.core == cpu.core) {
+                cores.push(CoreTemperature { cpu: cpu.id, package: cpu.package, core: cpu.core, celsius: 0 });
+            }
+        }
+
+        let mut reads = vec![];
+        if has_package {
+            reads.push((msr::first_cpu(), MSR_PACKAGE_THERM_STATUS));
+        }
+        reads.extend(cores.iter().map(|c| (c.cpu, MSR_THERM_STATUS)));
+
+        Ok(TemperatureSampler { tjmax, has_package, cores, sampler: msr::Sampler::new(&reads)?, cpus })
+    }
+
+    pub fn read(&mut self) -> io::Result<Temperatures> {
+        // Start over if CPUs have come or gone (e.g. SMT was switched off or on), since each
+        // core's sensor is read through one of its CPUs.
+        if topology::online_cpus_or_default() != self.cpus {
+            *self = TemperatureSampler::new()?;
+        }
+
+        let tjmax = self.tjmax;
+        let values = self.sampler.sample()?;
+        let (package, per_core) = if self.has_package {
+            (Some(tjmax.saturating_sub(digital_readout(values[0]))), &values[1..])
+        } else {
+            (None, values)
+        };
+
+        // The readout is only meaningful when the "reading valid" bit (31) is set.
+        let cores = self.cores.iter().zip(per_core.iter())
+            .filter(|&(_, &raw)| raw & (1 << 31) != 0)
+            .map(|(c, &raw)| CoreTemperature { celsius: tjmax.saturating_sub(digital_readout(raw)), ..*c })
+            .collect();
+
+        Ok(Temperatures { package, cores })
+    }
+}
+
+/// Number of throttling episodes of each kind that have been observed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ThrottleCounts {
+    pub thermal: u64,
+    pub prochot: u64,
+    pub critical: u64,
+    pub power_limit: u64,
+}
+
+impl ThrottleCounts {
+    /// Counts the episodes recorded in a set of log flags.
+    pub fn record(&mut self, flags: &ThermFlags) {
+        self.thermal += flags.thermal as u64;
+        self.prochot += flags.prochot as u64;
+        self.critical += flags.critical as u64;
+        self.power_limit += flags.power_limit as u64;
+    }
+}
+
+/// Returns a channel that emits the package thermal log flags whenever one is seen set, sampling
+/// every `interval`.
+///
+/// If `clear` is set, the log bits are cleared after each sample, so that every emitted set of
+/// flags represents new episodes. Otherwise the flags are only emitted when they change, since
+/// they stay set until something else (e.g. the kernel's thermal interrupt handler) clears them.
+pub fn notify_on_throttle(interval: Duration, clear: bool) -> channel::Receiver<ThermFlags> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        priority::lower_sampler();
+        let mut clear = clear;
+        let mut last = ThermFlags::default();
+        let mut sampler = None;
+        loop {
+            thread::sleep(interval);
+
+            if sampler.is_none() {
+                sampler = msr::Sampler::new(&[(msr::first_cpu(), MSR_PACKAGE_THERM_STATUS)]).map_err(|e| {
+                    eprintln!("error opening MSR device for IA32_PACKAGE_THERM_STATUS: {}", e);
+                }).ok();
+            }
+            let raw = match sampler.as_mut().map(|s| s.sample().map(|v| v[0])) {
+                None => continue,
+                Some(Ok(raw)) => raw,
+                Some(Err(e)) => {
+                    // TODO: logging?
+                    eprintln!("error reading IA32_PACKAGE_THERM_STATUS: {}", e);
+                    continue;
+                },
+            };
+            let logged = ThermStatus::from_raw(raw).logged;
+
+            if clear && logged.any() {
+                // Only clear the bits that we've seen, so that an episode that starts in between
+                // the read and write is still logged on the next sample.
+                let new_value = THERM_STATUS_LOG_BITS & !logged.log_bits();
+                if let Err(e) = msr::WriteMsrBuilder::new(MSR_PACKAGE_THERM_STATUS, new_value).write_one(msr::first_cpu()) {
+                    eprintln!("error clearing IA32_PACKAGE_THERM_STATUS log bits, \
+                               counts will be approximate: {}", e);
+                    clear = false;
+                }
+            }
+
+            if logged.any() && (clear || logged != last) && send.send(logged).is_err() {
+                return;
+            }
+            last = logged;
+        }
+    });
+
+    recv
+}
+
+/// Returns a channel that emits the hottest package or core temperature, in degrees C, every
+/// `interval`.
+pub fn notify_on_temperature(interval: Duration) -> channel::Receiver<u64> {
+    let (send, recv) = channel::bounded(0);
+    thread::spawn(move || {
+        let mut sampler = None;
+        loop {
+            thread::sleep(interval);
+
+            if sampler.is_none() {
+                sampler = TemperatureSampler::new().map_err(|e| {
+                    eprintln!("error opening MSR devices for the temperatures: {}", e);
+                }).ok();
+            }
+            let temps = match sampler.as_mut().map(|s| s.read()) {
+                None => continue,
+                Some(Ok(t)) => t,
+                Some(Err(e)) => {
+                    ratelimit::eprintln(format!("error reading temperatures: {}", e));
+                    continue;
+                },
+            };
+
+            let hottest = temps.package.into_iter().chain(temps.cores.iter().map(|c| c.celsius)).max();
+            if let Some(t) = hottest {
+                if send.send(t).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    recv
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(critical: u64) -> TemperatureTarget {
+        TemperatureTarget { critical, offset: 0 }
+    }
+
+    #[test]
+    fn offset_for_tjmax_90() {
+        // Asking for more than TjMax allows is clamped to TjMax - 3.
+        assert_eq!(target(90).offset_for(95), Some(TargetOffset { offset: 3, temp_c: 87, clamped: true }));
+        assert_eq!(target(90).offset_for(80), Some(TargetOffset { offset: 10, temp_c: 80, clamped: false }));
+    }
+
+    #[test]
+    fn offset_for_tjmax_100() {
+        assert_eq!(target(100).offset_for(95), Some(TargetOffset { offset: 5, temp_c: 95, clamped: false }));
+        assert_eq!(target(100).offset_for(97), Some(TargetOffset { offset: 3, temp_c: 97, clamped: false }));
+        assert_eq!(target(100).offset_for(100), Some(TargetOffset { offset: 3, temp_c: 97, clamped: true }));
+
+        // The offset field is only 6 bits wide.
+        assert_eq!(target(100).offset_for(20), Some(TargetOffset { offset: 63, temp_c: 37, clamped: true }));
+    }
+
+    #[test]
+    fn offset_for_tjmax_105() {
+        assert_eq!(target(105).offset_for(95), Some(TargetOffset { offset: 10, temp_c: 95, clamped: false }));
+        assert_eq!(target(105).offset_for(110), Some(TargetOffset { offset: 3, temp_c: 102, clamped: true }));
+    }
+
+    #[test]
+    fn offset_for_implausible_tjmax() {
+        assert_eq!(target(0).offset_for(95), None);
+        assert_eq!(target(3).offset_for(95), None);
+
+        // Small but believable values still never give a negative temperature.
+        assert_eq!(target(4).offset_for(95), Some(TargetOffset { offset: 3, temp_c: 1, clamped: true }));
+    }
+
+    // Golden register values: changes to the bit math mustn't change what gets written.
+
+    /// MSR_TEMPERATURE_TARGET as shipped on the i7-8550U and i5-8250U: TjMax 100 C, no offset.
+    const KABY_LAKE_R_TEMPERATURE_TARGET: u64 = 0x0064_0000;
+
+    fn encode_temp(raw: u64, temp_c: u64) -> u64 {
+        let offset = TemperatureTarget::from_raw(raw).offset_for(temp_c).unwrap().offset;
+        encode_target_offset(raw, offset)
+    }
+
+    #[test]
+    fn golden_temperature_target_battery_85() {
+        assert_eq!(encode_temp(KABY_LAKE_R_TEMPERATURE_TARGET, 85), 0x0F64_0000);
+    }
+
+    #[test]
+    fn golden_temperature_target_ac_80() {
+        assert_eq!(encode_temp(KABY_LAKE_R_TEMPERATURE_TARGET, 80), 0x1464_0000);
+    }
+
+    #[test]
+    fn golden_temperature_target_keeps_other_bits() {
+        // An existing offset is replaced, and the reserved bits above it are left alone.
+        assert_eq!(encode_temp(0xC564_0000, 85), 0xCF64_0000);
+        assert_eq!(encode_temp(0x0064_0000 | 0xFFFF, 80), 0x1464_FFFF);
+    }
+}
@@ -0,0 +1,219 @@
+//! CPU topology, as described by `/sys/devices/system/cpu`.
+//!
+//! Inside a restricted cpuset (e.g. a privileged container pinned to some CPUs), only some of the
+//! online CPUs are ours to use, and only some of them may have a `/dev/cpu` entry, so the CPUs
+//! that MSRs are accessed on are limited to those that are both allowed and present.
+
+use std::io;
+use std::mem;
+use std::path::Path;
+
+use libc;
+use num_cpus;
+
+use paths;
+use sysfs;
+
+
+/// Directory describing the system's CPUs.
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// On hybrid parts, these list the CPUs of each core type.
+const CPU_CORE_CPUS: &str = "/sys/devices/cpu_core/cpus";
+const CPU_ATOM_CPUS: &str = "/sys/devices/cpu_atom/cpus";
+
+
+/// The kind of core a CPU belongs to on hybrid (e.g. Alder Lake) parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoreType {
+    /// A performance ("big") core.
+    Performance,
+    /// An efficiency ("little") core.
+    Efficiency,
+    /// The system isn't hybrid, or we couldn't tell.
+    Unknown,
+}
+
+/// A single logical CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    /// The logical CPU number, as used by `/dev/cpu/N/msr`.
+    pub id: usize,
+    /// Physical package (socket) that the CPU is in.
+    pub package: u32,
+    /// Die (tile) within the package. Most parts have one; those with more (e.g. Meteor Lake's
+    /// compute and SoC tiles, when the kernel reports them) have RAPL registers for each.
+    pub die: u32,
+    /// Core within the package; hyperthreads share a core ID.
+    pub core: u32,
+    /// Kind of core, on hybrid parts.
+    pub core_type: CoreType,
+}
+
+/// The online CPUs in the system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub cpus: Vec<Cpu>,
+}
+
+impl Topology {
+    /// Reads the topology of every online CPU that we can use (see `online_cpus_or_default`).
+    pub fn read() -> io::Result<Topology> {
+        let performance = read_cpu_list(CPU_CORE_CPUS).unwrap_or_default();
+        let efficiency = read_cpu_list(CPU_ATOM_CPUS).unwrap_or_default();
+
+        let mut cpus = vec![];
+        for id in usable(online_cpus()?) {
+            let topology = |name: &str| -> io::Result<u32> {
+                let path = format!("{}/cpu{}/topology/{}", CPU_DIR, id, name);
+                sysfs::read_value(&path)?.parse::<u32>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+            };
+
+            let core_type = if performance.contains(&id) {
+                CoreType::Performance
+            } else if efficiency.contains(&id) {
+                CoreType::Efficiency
+            } else {
+                CoreType::Unknown
+            };
+
+            // die_id is only there on Linux 5.3 and newer.
+            let die = match topology("die_id") {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                die => die?,
+            };
+
+            cpus.push(Cpu {
+                id,
+                package: topology("physical_package_id")?,
+                die,
+                core: topology("core_id")?,
+                core_type,
+            });
+        }
+
+        Ok(Topology { cpus })
+    }
+
+    /// Returns the first online CPU of each package, which is enough to access package-scoped
+    /// MSRs.
+    pub fn package_leaders(&self) -> Vec<usize> {
+        let mut seen = vec![];
+        let mut leaders = vec![];
+        for cpu in self.cpus.iter() {
+            if !seen.contains(&cpu.package) {
+                seen.push(cpu.package);
+                leaders.push(cpu.id);
+            }
+        }
+        leaders
+    }
+
+    /// Returns the online CPUs of each die, as (package, die, CPUs), in the order they're first
+    /// seen.
+    pub fn dies(&self) -> Vec<(u32, u32, Vec<usize>)> {
+        let mut dies: Vec<(u32, u32, Vec<usize>)> = vec![];
+        for cpu in self.cpus.iter() {
+            match dies.iter_mut().find(|d| (d.0, d.1) == (cpu.package, cpu.die)) {
+                Some(d) => d.2.push(cpu.id),
+                None => dies.push((cpu.package, cpu.die, vec![cpu.id])),
+            }
+        }
+        dies
+    }
+}
+
+/// Returns the IDs of every online CPU.
+pub fn online_cpus() -> io::Result<Vec<usize>> {
+    read_cpu_list(&format!("{}/online", CPU_DIR))
+}
+
+/// Returns the online CPUs that we can use: those in our cpuset that have a MSR device. This falls
+/// back to assuming that CPUs are numbered contiguously if sysfs isn't available.
+pub fn online_cpus_or_default() -> Vec<usize> {
+    usable(online_cpus().unwrap_or_else(|_| (0..num_cpus::get()).collect()))
+}
+
+/// Returns the CPUs that we're allowed to run on, which a cpuset cgroup restricts.
+pub fn allowed_cpus() -> io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize).filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) }).collect())
+}
+
+/// Prints which online CPUs are left out, if any.
+pub fn report() {
+    let online = match online_cpus() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    let usable = usable(online.clone());
+    let skipped: Vec<String> = online.iter().filter(|c| !usable.contains(c)).map(|c| c.to_string()).collect();
+    if !skipped.is_empty() {
+        println!("skipping CPUs {}, which are outside our cpuset or have no MSR device", skipped.join(","));
+    }
+}
+
+/// Drops the CPUs that are outside our cpuset or have no MSR device. If that leaves nothing (e.g.
+/// the msr module isn't loaded), every CPU is kept, so that accessing them fails the usual way.
+fn usable(cpus: Vec<usize>) -> Vec<usize> {
+    let allowed = allowed_cpus().ok();
+    let usable: Vec<usize> = cpus.iter().cloned()
+        .filter(|cpu| allowed.as_ref().is_none_or(|a| a.contains(cpu)))
+        .filter(|&cpu| Path::new(&paths::get().msr_device(cpu)).exists())
+        .collect();
+    if usable.is_empty() {
+        cpus
+    } else {
+        usable
+    }
+}
+
+/// Like `Topology::package_leaders`, but falls back to the first CPU if sysfs isn't available.
+pub fn package_leaders_or_default() -> Vec<usize> {
+    match Topology::read() {
+        Ok(ref t) if !t.cpus.is_empty() => t.package_leaders(),
+        _ => vec![0],
+    }
+}
+
+fn read_cpu_list(path: &str) -> io::Result<Vec<usize>> {
+    parse_cpu_list(&sysfs::read_value(path)?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid CPU list in {}", path)))
+}
+
+/// Formats CPUs as a kernel CPU list, e.g. "0-3,6,8-11".
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &cpu in cpus {
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut end)) if *end + 1 == cpu => *end = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    let ranges: Vec<String> = ranges.iter()
+        .map(|&(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect();
+    ranges.join(",")
+}
+
+/// Parses a kernel CPU list such as "0-3,6,8-11".
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut ends = range.splitn(2, '-');
+        let start = ends.next()?.parse::<usize>().ok()?;
+        let end = match ends.next() {
+            Some(e) => e.parse::<usize>().ok()?,
+            None => start,
+        };
+        if end < start {
+            return None;
+        }
+        cpus.extend(start..(end + 1));
+    }
+    Some(cpus)
+}
@@ -0,0 +1,98 @@
+//! Power limit overrides that clients try out before committing to them, e.g. to test settings
+//! that might hang the machine. They're rolled back unless they're committed, so a client that
+//! crashes (or is killed along with the session) part way through doesn't leave them in place.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+
+/// How long an override lasts without hearing from its client. Each call on it starts this over,
+/// so clients that take longer to decide (e.g. while running a stress test) can keep it alive.
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns `TIMEOUT`, or a much shorter one against simulated hardware, so that the tests don't
+/// have to wait a minute for an override to expire.
+pub fn timeout() -> Duration {
+    #[cfg(feature = "sim")]
+    {
+        if ::sim::is_active() {
+            return Duration::from_secs(2);
+        }
+    }
+    TIMEOUT
+}
+
+
+/// An override that hasn't been committed or aborted yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transaction {
+    /// The token that the client has to give to change, commit or abort the override.
+    token: u64,
+    /// The transient limits to put back if it's rolled back.
+    previous: Option<(u64, u64)>,
+    /// When it's rolled back unless the client calls again.
+    expires: Instant,
+}
+
+impl Transaction {
+    /// Starts an override, which will roll back to the `previous` transient limits.
+    pub fn begin(previous: Option<(u64, u64)>, now: Instant) -> Transaction {
+        Transaction { token: new_token(), previous, expires: now + timeout() }
+    }
+
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    pub fn previous(&self) -> Option<(u64, u64)> {
+        self.previous
+    }
+
+    /// Checks a token given by a client, and starts the timeout over if it's this override's.
+    pub fn touch(&mut self, token: u64, now: Instant) -> Result<(), String> {
+        if token != self.token || self.is_expired(now) {
+            return Err(format!("{} isn't the override in progress", token));
+        }
+        self.expires = now + timeout();
+        Ok(())
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires
+    }
+
+    /// Returns how long is left until it's rolled back.
+    pub fn until_expiry(&self, now: Instant) -> Duration {
+        self.expires.saturating_duration_since(now)
+    }
+}
+
+/// Returns a token that other clients can't guess. This isn't what keeps them from changing the
+/// limits (polkit is), just from committing or aborting an override that isn't theirs by mistake.
+/// Tokens fit in 53 bits, so that they survive being a JSON number on the control socket.
+fn new_token() -> u64 {
+    // The standard library seeds these randomly.
+    RandomState::new().build_hasher().finish() & ((1 << 53) - 1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_keep_the_override_alive() {
+        let start = Instant::now();
+        let mut t = Transaction::begin(Some((15, 25)), start);
+        assert!(t.touch(t.token().wrapping_add(1), start).is_err());
+
+        let later = start + TIMEOUT - Duration::from_secs(1);
+        assert_eq!(t.touch(t.token(), later), Ok(()));
+        assert!(!t.is_expired(start + TIMEOUT));
+        assert!(t.is_expired(later + TIMEOUT));
+        assert!(t.touch(t.token(), later + TIMEOUT).is_err());
+        assert_eq!(t.until_expiry(later), TIMEOUT);
+        assert_eq!(t.until_expiry(later + TIMEOUT * 2), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,141 @@
+//! The `set` subcommand, which applies power limits straight away without touching the config, for
+//! quick what-if experiments. With `--for`, the previous limits are put back afterwards.
+
+use std::time::{Duration, Instant};
+
+use ::channel;
+use failure::Error;
+
+use control;
+use daemon;
+use duration;
+use msr;
+use plan::ApplyPlan;
+use rapl;
+use {ModeConfig, build_updates};
+
+
+const USAGE: &str = "usage: set [--pl1 W] [--pl1-window SECS] [--pl2 W] [--pl2-window SECS] [--for DURATION]";
+
+
+/// Limits to apply, as given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pl1_w: Option<u64>,
+    pl1_window: Option<f64>,
+    pl2_w: Option<u64>,
+    pl2_window: Option<f64>,
+    /// How long to keep the limits for before putting back the previous ones.
+    revert_after: Option<Duration>,
+}
+
+impl Options {
+    /// Parses the arguments following `set`. Windows and `--for` take either a number of seconds or
+    /// a duration with units, like "2ms" or "10m".
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+        let mut opts = Options { pl1_w: None, pl1_window: None, pl2_w: None, pl2_window: None, revert_after: None };
+
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| format_err!("{} needs a value ({})", arg, USAGE))?;
+            let watts = || match value.parse::<u64>() {
+                Ok(w) if (1..=control::MAX_LIMIT_W).contains(&w) => Ok(w),
+                _ => Err(format_err!("invalid value for {}: {} (expected 1 to {} W)", arg, value, control::MAX_LIMIT_W)),
+            };
+            let secs = || parse_secs(&value).map_err(|e| format_err!("invalid value for {}: {}", arg, e));
+
+            match arg.as_str() {
+                "--pl1" => opts.pl1_w = Some(watts()?),
+                "--pl1-window" => opts.pl1_window = Some(secs()?),
+                "--pl2" => opts.pl2_w = Some(watts()?),
+                "--pl2-window" => opts.pl2_window = Some(secs()?),
+                "--for" => {
+                    let secs = secs()?;
+                    match Duration::try_from_secs_f64(secs) {
+                        Ok(d) if secs > 0.0 => opts.revert_after = Some(d),
+                        _ => bail!("invalid value for --for: {} (expected a positive duration)", value),
+                    }
+                },
+                _ => bail!("unknown set argument: {} ({})", arg, USAGE),
+            }
+        }
+
+        if opts.pl1_w.is_none() && opts.pl2_w.is_none() {
+            bail!("nothing to set ({})", USAGE);
+        }
+        if (opts.pl1_window.is_some() && opts.pl1_w.is_none()) || (opts.pl2_window.is_some() && opts.pl2_w.is_none()) {
+            bail!("a window was given without its power limit ({})", USAGE);
+        }
+        for &w in [opts.pl1_window, opts.pl2_window].iter().flatten() {
+            if w <= 0.0 || !w.is_finite() {
+                bail!("time windows must be positive");
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Returns the profile to apply. Windows that aren't given keep their current values.
+    fn mode_config(&self) -> Result<ModeConfig, Error> {
+        let raw = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first()?;
+        let units = rapl::Units::read()?;
+        let current = |offset| rapl::PowerLimit::decode(raw, offset, &units).window;
+
+        Ok(ModeConfig {
+            pl1_tdp_w: self.pl1_w,
+            pl1_duration: self.pl1_w.map(|_| self.pl1_window.unwrap_or_else(|| current(0))),
+            pl2_tdp_w: self.pl2_w,
+            pl2_duration: self.pl2_w.map(|_| self.pl2_window.unwrap_or_else(|| current(32))),
+            ..ModeConfig::default()
+        })
+    }
+}
+
+fn parse_secs(s: &str) -> Result<f64, String> {
+    s.parse::<f64>().or_else(|_| duration::parse(s))
+}
+
+/// Applies the limits, and waits to revert them if asked to.
+pub fn run(opts: &Options) -> Result<(), Error> {
+    let updates = build_updates(&opts.mode_config()?)?;
+    if updates.is_empty() {
+        println!("the limits are already set to those values");
+        return Ok(());
+    }
+
+    let plan = match ApplyPlan::prepare(&updates) {
+        Ok(p) => p,
+        Err(failures) => {
+            let errors: Vec<String> = failures.iter().map(|(u, e)| format!("{:?}: {}", u, e)).collect();
+            bail!("the limits can't be written: {}", errors.join("; "));
+        },
+    };
+    plan.commit()?;
+
+    let revert_after = match opts.revert_after {
+        Some(d) => d,
+        None => {
+            println!("limits set; a running daemon will replace them with the configured profile when it next applies");
+            return Ok(());
+        },
+    };
+
+    // Revert early if we're interrupted, rather than leave the experiment in place.
+    let (events_tx, events) = channel::unbounded();
+    daemon::handle_signals(events_tx);
+    println!("limits set; reverting in {}s (or on Ctrl-C)", revert_after.as_secs());
+
+    let deadline = Instant::now() + revert_after;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(left) {
+            // SIGHUP; there's no config to reload.
+            Ok(daemon::Event::Reload) => continue,
+            _ => break,
+        }
+    }
+
+    match plan.revert() {
+        0 => println!("reverted to the previous limits"),
+        n => bail!("{} update(s) couldn't be reverted", n),
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Travel mode: conservative power limits, Turbo Boost off and the battery kept between 60% and
+//! 80%, switched on and off together. It's layered over whichever profile is active, like the
+//! battery levels, and when it's switched off the settings it changed that the profile doesn't set
+//! are put back the way they were.
+
+use backends::{self, Backend, Capability};
+use charge;
+use msr;
+use sysfs;
+use turbo;
+use {Config, ModeConfig};
+
+
+/// Charge thresholds used unless [travel] sets its own, as (start, stop) percentages.
+const DEFAULT_CHARGE_THRESHOLDS: (u8, u8) = (60, 80);
+
+
+/// Returns the settings to layer over the active profile: the [travel] section, with anything it
+/// leaves unset taken from the defaults, which are the battery profile's power and temperature
+/// limits, the default charge thresholds and Turbo Boost off.
+pub fn overlay(config: &Config) -> ModeConfig {
+    let defaults = ModeConfig {
+        pl1_tdp_w: config.battery.pl1_tdp_w,
+        pl2_tdp_w: config.battery.pl2_tdp_w,
+        maximum_temp_c: config.battery.maximum_temp_c,
+        turbo_enabled: Some(false),
+        charge_start_threshold: Some(DEFAULT_CHARGE_THRESHOLDS.0),
+        charge_stop_threshold: Some(DEFAULT_CHARGE_THRESHOLDS.1),
+        ..ModeConfig::default()
+    };
+    config.travel.or(&defaults)
+}
+
+/// Settings from before travel mode was switched on, to put back when it's switched off. Power and
+/// temperature limits aren't saved, since every profile sets its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Saved {
+    pub turbo_enabled: Option<bool>,
+    /// The first battery's charge thresholds, as (start, stop).
+    pub charge_thresholds: Option<(u8, u8)>,
+}
+
+impl Saved {
+    /// Reads the current values of whatever the overlay changes. Anything that can't be read is
+    /// left unset, and so won't be put back.
+    pub fn read(overlay: &ModeConfig) -> Saved {
+        let turbo_enabled = overlay.turbo_enabled.and_then(|_| read_turbo_enabled());
+        let thresholds = overlay.charge_start_threshold.is_some() || overlay.charge_stop_threshold.is_some();
+        let charge_thresholds = if thresholds { charge::read_thresholds() } else { None };
+        Saved { turbo_enabled, charge_thresholds }
+    }
+
+    /// Returns the saved settings as a profile to fill in the active one with.
+    pub fn as_config(&self) -> ModeConfig {
+        ModeConfig {
+            turbo_enabled: self.turbo_enabled,
+            charge_start_threshold: self.charge_thresholds.map(|t| t.0),
+            charge_stop_threshold: self.charge_thresholds.map(|t| t.1),
+            ..ModeConfig::default()
+        }
+    }
+
+    /// Encodes the settings for persisting, e.g. "turbo_enabled=true charge_thresholds=75,100".
+    pub fn encode(&self) -> String {
+        let mut fields = vec![];
+        if let Some(enabled) = self.turbo_enabled {
+            fields.push(format!("turbo_enabled={}", enabled));
+        }
+        if let Some((start, stop)) = self.charge_thresholds {
+            fields.push(format!("charge_thresholds={},{}", start, stop));
+        }
+        fields.join(" ")
+    }
+
+    /// Parses settings encoded by `encode`, skipping any fields that aren't recognised.
+    pub fn parse(s: &str) -> Saved {
+        let mut saved = Saved::default();
+        for (key, value) in s.split_whitespace().filter_map(|f| f.split_once('=')) {
+            match key {
+                "turbo_enabled" => saved.turbo_enabled = value.parse().ok(),
+                "charge_thresholds" => {
+                    saved.charge_thresholds = value.split_once(',')
+                        .and_then(|(start, stop)| Some((start.parse().ok()?, stop.parse().ok()?)));
+                },
+                _ => {},
+            }
+        }
+        saved
+    }
+}
+
+/// Reads whether Turbo Boost is enabled, from wherever `turbo_enabled` would be written.
+pub fn read_turbo_enabled() -> Option<bool> {
+    match backends::registry().best(Capability::Turbo)? {
+        Backend::Cpufreq => sysfs::read_value(::INTEL_PSTATE_NO_TURBO).ok().map(|v| v == "0"),
+        _ => msr::ReadMsrBuilder::new(turbo::IA32_MISC_ENABLE).read_first().ok().map(|v| v & turbo::TURBO_DISABLE == 0),
+    }
+}
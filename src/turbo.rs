@@ -0,0 +1,105 @@
+//! Turbo ratio and overclocking information, which explains why frequencies on unlocked (e.g.
+//! H-series) parts can differ from the datasheet.
+
+use std::io;
+
+use msr;
+
+
+/// Address of MSR_PLATFORM_INFO.
+pub const MSR_PLATFORM_INFO: u64 = 0xCE;
+
+/// Address of MSR_FLEX_RATIO, which holds the overclocking lock.
+pub const MSR_FLEX_RATIO: u64 = 0x194;
+
+/// Address of MSR_TURBO_RATIO_LIMIT, the maximum ratio for each number of active cores.
+pub const MSR_TURBO_RATIO_LIMIT: u64 = 0x1AD;
+
+/// Address of IA32_MISC_ENABLE, which has the Turbo Boost disable bit.
+pub const IA32_MISC_ENABLE: u64 = 0x1A0;
+
+/// IA32_MISC_ENABLE bit that disables Turbo Boost.
+pub const TURBO_DISABLE: u64 = 1 << 38;
+
+/// Address of MSR_CONFIG_TDP_CONTROL, which selects the configurable TDP level.
+pub const MSR_CONFIG_TDP_CONTROL: u64 = 0x64B;
+
+/// Bus clock that ratios are multiplied by, in MHz.
+const BCLK_MHZ: u64 = 100;
+
+
+/// Decoded turbo and overclocking settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurboInfo {
+    /// Maximum non-turbo ("base") ratio.
+    pub base_ratio: u64,
+    /// Maximum efficiency ratio, i.e. the lowest useful ratio.
+    pub efficiency_ratio: u64,
+    /// Whether the turbo ratio limits are programmable, i.e. the part is unlocked.
+    pub ratios_programmable: bool,
+    /// Whether the TDP limits are programmable.
+    pub tdp_programmable: bool,
+    /// Maximum ratio with 1, 2, ... cores active, if the CPU reports it.
+    pub bins: Vec<u64>,
+    /// Whether overclocking settings are locked until the next reset, if the CPU reports it.
+    pub oc_locked: Option<bool>,
+}
+
+impl TurboInfo {
+    pub fn read() -> io::Result<TurboInfo> {
+        let platform_info = msr::ReadMsrBuilder::new(MSR_PLATFORM_INFO).read_first()?;
+
+        // Not every CPU has these, so they're allowed to fail.
+        let bins = msr::ReadMsrBuilder::new(MSR_TURBO_RATIO_LIMIT).read_first()
+            .map(decode_bins)
+            .unwrap_or_default();
+        let oc_locked = msr::ReadMsrBuilder::new(MSR_FLEX_RATIO).read_first().ok()
+            .map(|raw| raw & (1 << 20) != 0);
+
+        Ok(TurboInfo {
+            base_ratio: (platform_info >> 8) & 0xFF,
+            efficiency_ratio: (platform_info >> 40) & 0xFF,
+            ratios_programmable: platform_info & (1 << 28) != 0,
+            tdp_programmable: platform_info & (1 << 29) != 0,
+            bins,
+            oc_locked,
+        })
+    }
+
+    /// Prints the settings, for status output.
+    pub fn report(&self) {
+        println!("turbo ratios");
+        println!("  base = {}x ({} MHz), efficiency = {}x ({} MHz)",
+                 self.base_ratio, self.base_ratio * BCLK_MHZ,
+                 self.efficiency_ratio, self.efficiency_ratio * BCLK_MHZ);
+        if !self.bins.is_empty() {
+            let bins: Vec<String> = self.bins.iter().enumerate()
+                .map(|(i, r)| format!("{}C {}x", i + 1, r))
+                .collect();
+            println!("  turbo bins = {}", bins.join(", "));
+        }
+        println!("  ratio limits = {}, TDP limits = {}",
+                 if self.ratios_programmable { "programmable (unlocked)" } else { "fixed" },
+                 if self.tdp_programmable { "programmable" } else { "fixed" });
+        match self.oc_locked {
+            Some(true) => println!("  overclocking = locked until reset"),
+            Some(false) => println!("  overclocking = unlocked"),
+            None => {},
+        }
+
+        // Thermal Velocity Boost isn't visible in these MSRs, but the unlocked parts that have it
+        // only reach the top bins while the package is cool.
+        if self.ratios_programmable && !self.bins.is_empty() {
+            println!("  note: with Thermal Velocity Boost, the top bins are only reached while the CPU is cool");
+        }
+    }
+}
+
+/// Decodes MSR_TURBO_RATIO_LIMIT: byte N is the maximum ratio with N+1 cores active. Unused bytes
+/// are zero.
+fn decode_bins(raw: u64) -> Vec<u64> {
+    (0..8)
+        .map(|i| (raw >> (i * 8)) & 0xFF)
+        .take_while(|&r| r != 0)
+        .collect()
+}
@@ -0,0 +1,109 @@
+//! Voltage offsets, set through the overclocking mailbox (MSR 0x150). The daemon doesn't manage
+//! these; they're only written by `stress-verify`, which puts back the previous offsets when it's
+//! done. The CPU forgets them on a power cycle, too.
+//!
+//! Many CPUs have the mailbox locked (by a BIOS update against Plundervolt, or by the vendor), in
+//! which case writes are silently ignored; `set_offset` reads the offset back to catch that.
+
+use std::io;
+
+use failure::Error;
+
+use msr;
+
+
+/// The overclocking mailbox: a command is written, and its result read back from the same MSR.
+pub const MSR_OC_MAILBOX: u64 = 0x150;
+
+/// Set in a command; the CPU clears it once the command has run.
+const MAILBOX_BUSY: u64 = 1 << 63;
+const COMMAND_READ_OFFSET: u64 = 0x10;
+const COMMAND_WRITE_OFFSET: u64 = 0x11;
+
+/// The offset field, bits 31:21, in 1/1024 V steps as an 11-bit two's complement number.
+const OFFSET_SHIFT: u64 = 21;
+const OFFSET_BITS: u64 = 0x7FF;
+
+/// The largest undervolt that we're willing to set; anything beyond this is a typo.
+pub const MAX_UNDERVOLT_MV: f64 = 250.0;
+
+
+/// A voltage domain that the mailbox can offset; there are others (the GPU, uncore and analog
+/// I/O), but stress-verify doesn't load them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plane {
+    Core = 0,
+    Cache = 2,
+}
+
+impl Plane {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Plane::Core => "core",
+            Plane::Cache => "cache",
+        }
+    }
+}
+
+/// Returns the mailbox command that sets a plane's offset, in millivolts (negative to undervolt).
+pub fn encode_offset(plane: Plane, mv: f64) -> u64 {
+    let field = ((mv * 1.024).round() as i64 as u64) & OFFSET_BITS;
+    command(plane, COMMAND_WRITE_OFFSET) | field << OFFSET_SHIFT
+}
+
+/// Decodes the offset, in millivolts, from the mailbox's reply to a read.
+pub fn decode_offset(raw: u64) -> f64 {
+    let field = (raw >> OFFSET_SHIFT) & OFFSET_BITS;
+    // Sign-extend the 11-bit field.
+    let steps = ((field << 53) as i64) >> 53;
+    (steps as f64 / 1.024).round()
+}
+
+fn command(plane: Plane, command: u64) -> u64 {
+    MAILBOX_BUSY | (plane as u64) << 40 | command << 32
+}
+
+/// Reads a plane's offset, in millivolts, from the first CPU.
+pub fn read_offset(plane: Plane) -> io::Result<f64> {
+    let cpu = msr::first_cpu();
+    msr::WriteMsrBuilder::new(MSR_OC_MAILBOX, command(plane, COMMAND_READ_OFFSET)).write_one(cpu)?;
+    Ok(decode_offset(msr::ReadMsrBuilder::new(MSR_OC_MAILBOX).read_one(cpu)?))
+}
+
+/// Sets a plane's offset on every package, and checks that it took. Positive offsets are allowed,
+/// so that an overvolt set by another tool can be put back.
+pub fn set_offset(plane: Plane, mv: f64) -> Result<(), Error> {
+    if mv.is_nan() || mv < -MAX_UNDERVOLT_MV {
+        bail!("undervolts can't be larger than {} mV, not {} mV", MAX_UNDERVOLT_MV, -mv);
+    }
+    for cpu in msr::Scope::Package.cpus() {
+        msr::WriteMsrBuilder::new(MSR_OC_MAILBOX, encode_offset(plane, mv)).write_one(cpu)?;
+    }
+    let applied = read_offset(plane)?;
+    if (applied - mv).abs() > 1.0 {
+        bail!("the {} offset reads back as {} mV rather than {} mV; undervolting is probably locked on this CPU",
+              plane.name(), applied, mv);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The commands that intel-undervolt and the undervolt script write for the same offsets.
+    #[test]
+    fn offsets_encode_like_other_tools() {
+        assert_eq!(encode_offset(Plane::Core, -100.0), 0x8000_0011_F340_0000);
+        assert_eq!(encode_offset(Plane::Cache, -100.0), 0x8000_0211_F340_0000);
+        assert_eq!(encode_offset(Plane::Core, 0.0), 0x8000_0011_0000_0000);
+    }
+
+    #[test]
+    fn offsets_round_trip() {
+        for &mv in [-125.0, -100.0, -50.0, -1.0, 0.0].iter() {
+            assert_eq!(decode_offset(encode_offset(Plane::Core, mv)), mv);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! The `why-throttle` subcommand: samples the power limits, package power, temperatures and the
+//! package's throttling flags for a few seconds, and sums them up in a single verdict, for when the
+//! machine feels slow and the output of `status` is more than anyone wants to read.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+use freq;
+use msr;
+use rapl;
+use runtime;
+use sysfs;
+use temps::{TemperatureSampler, TemperatureTarget, ThermFlags, ThermStatus};
+use turbo;
+use read_config;
+
+
+/// How long to sample for.
+const DURATION: Duration = Duration::from_secs(5);
+
+/// How often to sample.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+/// How close to a limit counts as having reached it.
+const NEAR_LIMIT: f64 = 0.95;
+
+
+/// What was seen over the sampling period.
+#[derive(Debug, Clone, Default)]
+struct Observed {
+    pl1_w: f64,
+    pl2_w: f64,
+    /// Whether MSR_PKG_POWER_LIMIT is locked, so the limits can't be raised until reboot.
+    locked: bool,
+    /// The PL1 that the daemon's active profile sets, if it's running and the profile sets one.
+    configured_pl1_w: Option<u64>,
+    /// Average package power over the whole period, and the highest over any one interval.
+    average_w: Option<f64>,
+    peak_w: Option<f64>,
+    /// Hottest package (or core) temperature seen.
+    max_temp_c: Option<u64>,
+    throttle_temp_c: u64,
+    critical_temp_c: u64,
+    /// Throttling conditions that were active in any sample.
+    flags: ThermFlags,
+    /// Average effective frequency of the busy CPUs.
+    average_mhz: Option<f64>,
+    turbo_disabled: bool,
+}
+
+
+/// Samples for a few seconds and prints what was seen and the verdict.
+pub fn run() -> Result<(), Error> {
+    let units = rapl::Units::read()?;
+    let power_limit = msr::ReadMsrBuilder::new(rapl::MSR_PKG_POWER_LIMIT).read_first()?;
+    let target = TemperatureTarget::read()?;
+
+    let mut observed = Observed {
+        pl1_w: rapl::PowerLimit::decode(power_limit, 0, &units).watts,
+        pl2_w: rapl::PowerLimit::decode(power_limit, 32, &units).watts,
+        locked: power_limit & rapl::POWER_LIMIT_LOCK != 0,
+        configured_pl1_w: configured_pl1_w(),
+        throttle_temp_c: target.throttle_temp(),
+        critical_temp_c: target.critical,
+        turbo_disabled: match sysfs::read_value(::INTEL_PSTATE_NO_TURBO) {
+            Ok(v) => v == "1",
+            Err(_) => msr::ReadMsrBuilder::new(turbo::IA32_MISC_ENABLE).read_first()? & turbo::TURBO_DISABLE != 0,
+        },
+        ..Observed::default()
+    };
+
+    println!("sampling for {} seconds...", DURATION.as_secs());
+    let mut energy = msr::Sampler::new(&[(msr::first_cpu(), rapl::MSR_PKG_ENERGY_STATUS)])?;
+    let mut temperatures = TemperatureSampler::new()?;
+    // Not every CPU has the frequency counters, and the verdict doesn't need them.
+    let mut frequencies = freq::FrequencySampler::new().ok();
+    if let Some(ref mut f) = frequencies {
+        f.sample()?;
+    }
+
+    let start = Instant::now();
+    let first = energy.sample()?[0] as u32;
+    let mut last = (first, start);
+    while start.elapsed() < DURATION {
+        thread::sleep(INTERVAL);
+
+        // The counter is 32 bits wide, and wraps every few minutes under load.
+        let (e, now) = (energy.sample()?[0] as u32, Instant::now());
+        let watts = e.wrapping_sub(last.0) as f64 * units.energy / now.duration_since(last.1).as_secs_f64();
+        observed.peak_w = Some(observed.peak_w.map_or(watts, |p| p.max(watts)));
+        last = (e, now);
+
+        let t = temperatures.read()?;
+        let hottest = t.package.into_iter().chain(t.cores.iter().map(|c| c.celsius)).max();
+        observed.max_temp_c = observed.max_temp_c.max(hottest);
+
+        // Older CPUs don't have package thermal status.
+        if let Ok(status) = ThermStatus::read() {
+            merge(&mut observed.flags, &status.active);
+        }
+    }
+    let elapsed = last.1.duration_since(start).as_secs_f64();
+    observed.average_w = Some(last.0.wrapping_sub(first) as f64 * units.energy / elapsed);
+    if let Some(ref mut f) = frequencies {
+        observed.average_mhz = f.sample()?.and_then(|f| f.average_mhz);
+    }
+
+    report(&observed);
+    println!();
+    println!("{}", verdict(&observed));
+    Ok(())
+}
+
+/// Returns the PL1 that the daemon's active profile sets, going by the profile it exported and
+/// the config file.
+fn configured_pl1_w() -> Option<u64> {
+    let profile = runtime::read_active_profile()?;
+    let config = read_config().ok()?;
+    match profile.as_str() {
+        "ac" => config.ac.pl1_tdp_w,
+        "battery" => config.battery.pl1_tdp_w,
+        _ => None,
+    }
+}
+
+fn merge(flags: &mut ThermFlags, other: &ThermFlags) {
+    flags.thermal |= other.thermal;
+    flags.prochot |= other.prochot;
+    flags.critical |= other.critical;
+    flags.power_limit |= other.power_limit;
+}
+
+/// Prints what was seen.
+fn report(o: &Observed) {
+    println!("PL1 = {} W, PL2 = {} W{}", o.pl1_w, o.pl2_w, if o.locked { " (locked)" } else { "" });
+    if let (Some(average), Some(peak)) = (o.average_w, o.peak_w) {
+        println!("package power = {:.1} W average, {:.1} W peak", average, peak);
+    }
+    if let Some(t) = o.max_temp_c {
+        println!("temperature = {} C at most (throttles at {} C, critical at {} C)", t, o.throttle_temp_c, o.critical_temp_c);
+    }
+    println!("throttling flags = {}", if o.flags.any() { o.flags.names().join(", ") } else { "none".to_string() });
+    if let Some(mhz) = o.average_mhz {
+        println!("effective frequency = {:.0} MHz average", mhz);
+    }
+}
+
+/// Sums up why the CPU is (or isn't) being throttled, most drastic cause first, with what to do
+/// about it.
+fn verdict(o: &Observed) -> String {
+    let temp = o.max_temp_c.map_or("unknown".to_string(), |t| format!("{} C", t));
+
+    if o.flags.critical {
+        return format!("critical temperature reached ({}) - the CPU is throttling hard to protect itself; \
+                        check the fan and heatsink", temp);
+    }
+    if o.flags.prochot {
+        return "PROCHOT asserted - something outside the CPU, usually the embedded controller (e.g. because of \
+                an unrecognised charger or a hot battery), is throttling it; the power limits can't override this"
+            .to_string();
+    }
+    if o.flags.thermal || o.max_temp_c.is_some_and(|t| t >= o.throttle_temp_c) {
+        let fix = if o.throttle_temp_c < o.critical_temp_c {
+            format!("raise maximum_temp_c (up to {} C) or improve cooling", o.critical_temp_c)
+        } else {
+            "improve cooling".to_string()
+        };
+        let seen = if o.max_temp_c.is_some_and(|t| t >= o.throttle_temp_c) {
+            format!("temp {} reached the throttle temperature of {} C", temp, o.throttle_temp_c)
+        } else {
+            // The package sensor may be a little behind the hottest spot on the die.
+            format!("the package reported reaching its throttle temperature of {} C (hottest reading {})",
+                    o.throttle_temp_c, temp)
+        };
+        return format!("{} - thermal throttling; {}", seen, fix);
+    }
+
+    let average = o.average_w.unwrap_or(0.0);
+    let peak = o.peak_w.unwrap_or(0.0);
+    let lowered = match o.configured_pl1_w {
+        Some(c) if (c as f64) > o.pl1_w => format!("; the active profile sets {} W, so something else (the EC?) \
+                                                     has lowered it - check power_limit_changes in GetStatus", c),
+        _ if o.locked => "; MSR_PKG_POWER_LIMIT is locked, so it can't be raised until reboot".to_string(),
+        _ => String::new(),
+    };
+    if o.pl1_w > 0.0 && average >= o.pl1_w * NEAR_LIMIT {
+        return format!("PL1 {} W reached (package at {:.1} W), temp {} - power-limit throttling; raise pl1_tdp_w{}",
+                       o.pl1_w, average, temp, lowered);
+    }
+    if o.pl2_w > 0.0 && peak >= o.pl2_w * NEAR_LIMIT {
+        return format!("PL2 {} W reached in bursts (package peaked at {:.1} W), temp {} - power-limit throttling; \
+                        raise pl2_tdp_w{}", o.pl2_w, peak, temp, lowered);
+    }
+    if o.flags.power_limit {
+        return format!("throttled for power, but below PL1 and PL2 (package at {:.1} W) - probably another limit, \
+                        such as the voltage regulator's current limit or PL4; the power limits can't raise it", average);
+    }
+
+    let mut verdict = format!("no throttling: package at {:.1} W of {} W PL1, temp {} of {} C", average, o.pl1_w, temp,
+                              o.throttle_temp_c);
+    if o.turbo_disabled {
+        verdict += "; Turbo Boost is disabled, though, so clocks stay at or below the base frequency";
+    }
+    verdict
+}
@@ -0,0 +1,373 @@
+//! Runs the daemon against simulated hardware (see `src/sim.rs`). These need the `sim` feature:
+//!
+//!   cargo test --features sim
+
+#![cfg(feature = "sim")]
+
+extern crate lenovo_throttling_rust;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write as IoWrite};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lenovo_throttling_rust::rapl;
+use lenovo_throttling_rust::sim::{Machine, Write};
+
+
+/// How long to wait for the daemon to react to something.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONFIG: &str = r#"
+vendor = "generic"
+power_latency_budget = "200ms"
+
+[battery]
+maximum_temp_c = 85
+pl1_tdp_w = 15
+pl1_duration = 28
+pl2_tdp_w = 25
+pl2_duration = 0.002
+
+[ac]
+maximum_temp_c = 95
+pl1_tdp_w = 35
+pl1_duration = 28
+pl2_tdp_w = 44
+pl2_duration = 0.002
+
+[control]
+dbus = false
+"#;
+
+
+fn exe() -> &'static Path {
+    Path::new(env!("CARGO_BIN_EXE_lenovo-throttling-rust"))
+}
+
+/// Returns the PL1 and PL2 written to MSR_PKG_POWER_LIMIT, in Watts, in the order they were written.
+fn power_limits(writes: &[Write]) -> Vec<(f64, f64)> {
+    let units = rapl::Units::from_raw(rapl::DEFAULT_POWER_UNIT, rapl::Encoding::detect());
+    writes.iter()
+        .filter_map(|w| w.msr(rapl::MSR_PKG_POWER_LIMIT))
+        .map(|v| (rapl::PowerLimit::decode(v, 0, &units).watts, rapl::PowerLimit::decode(v, 32, &units).watts))
+        .collect()
+}
+
+/// Returns the temperature target offsets written to MSR_TEMPERATURE_TARGET, in order.
+fn temperature_offsets(writes: &[Write]) -> Vec<u64> {
+    writes.iter().filter_map(|w| w.msr(0x1A2)).map(|v| (v >> 24) & 0x3F).collect()
+}
+
+#[test]
+fn applies_profiles_as_the_power_source_changes() {
+    let machine = Machine::new().unwrap();
+    machine.write_config(CONFIG).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    let writes = daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    assert_eq!(temperature_offsets(&writes), vec![15], "{}", daemon.output());
+
+    machine.set_on_ac(true).unwrap();
+    let writes = daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(35.0, 44.0))).unwrap();
+    assert_eq!(temperature_offsets(&writes), vec![15, 5], "{}", daemon.output());
+
+    machine.set_on_ac(false).unwrap();
+    let writes = daemon.wait_for(TIMEOUT, |w| power_limits(w).iter().filter(|&&l| l == (15.0, 25.0)).count() == 2).unwrap();
+    let mut limits = power_limits(&writes);
+    limits.dedup();
+    assert_eq!(limits, vec![(15.0, 25.0), (35.0, 44.0), (15.0, 25.0)], "{}", daemon.output());
+    assert_eq!(machine.msr(0, 0x1A2).unwrap() >> 24 & 0x3F, 15);
+}
+
+/// Returns the package that a CPU is in, going by the real topology, which the daemon reads too.
+fn package(cpu: usize) -> String {
+    let path = format!("/sys/devices/system/cpu/cpu{}/topology/physical_package_id", cpu);
+    fs::read_to_string(path).map(|p| p.trim().to_string()).unwrap_or_default()
+}
+
+#[test]
+fn writes_package_msrs_once_per_package() {
+    let machine = Machine::new().unwrap();
+    machine.write_config(&CONFIG.replace("[battery]\n", "[battery]\nturbo_enabled = false\n")).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    let mut packages = vec![];
+    for &cpu in machine.cpus() {
+        // IA32_MISC_ENABLE is per CPU, and MSR_TEMPERATURE_TARGET is only written to the first
+        // CPU of each package.
+        assert_ne!(machine.msr(cpu, 0x1A0).unwrap() & (1 << 38), 0, "cpu {}: {}", cpu, daemon.output());
+        let offset = machine.msr(cpu, 0x1A2).unwrap() >> 24 & 0x3F;
+        if packages.contains(&package(cpu)) {
+            assert_eq!(offset, 0, "cpu {}: {}", cpu, daemon.output());
+        } else {
+            assert_eq!(offset, 15, "cpu {}: {}", cpu, daemon.output());
+            packages.push(package(cpu));
+        }
+    }
+}
+
+#[test]
+fn toggles_only_the_turbo_bit() {
+    let machine = Machine::new().unwrap();
+    let config = CONFIG.replace("[battery]\n", "[battery]\nturbo_enabled = false\n")
+        .replace("[ac]\n", "[ac]\nturbo_enabled = true\n");
+    machine.write_config(&config).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+
+    // Something else (e.g. the kernel) sets another feature bit after the daemon has read the MSR.
+    let cpu = machine.cpus()[0];
+    let fast_strings = 1 << 0;
+    machine.set_msr(cpu, 0x1A0, machine.msr(cpu, 0x1A0).unwrap() | fast_strings).unwrap();
+    machine.set_on_ac(true).unwrap();
+    daemon.wait_for(TIMEOUT, |w| w.iter().filter_map(|w| w.msr(0x1A0)).any(|v| v & (1 << 38) == 0)).unwrap();
+
+    let misc_enable = machine.msr(cpu, 0x1A0).unwrap();
+    assert_eq!(misc_enable & (1 << 38), 0, "{}", daemon.output());
+    assert_ne!(misc_enable & fast_strings, 0, "{}", daemon.output());
+}
+
+/// Returns the minimum performance written to the first CPU's HWP request, in order.
+fn hwp_minimums(writes: &[Write]) -> Vec<u64> {
+    writes.iter().filter_map(|w| w.msr(0x774)).map(|v| v & 0xFF).collect()
+}
+
+#[test]
+fn sets_the_hwp_floor_only_on_ac() {
+    let machine = Machine::new().unwrap();
+    // HWP enabled, with performance levels from 8 to 40. The simulated MSRs are at byte offsets,
+    // so IA32_HWP_REQUEST overlaps the top of IA32_HWP_CAPABILITIES, and starts out with its
+    // minimum at the lowest level; the battery profile has nothing to write, then.
+    machine.set_msr_all(0x770, 1).unwrap();
+    machine.set_msr_all(0x771, 8 << 24 | 40).unwrap();
+    machine.write_config(&CONFIG.replace("[control]\n", "[ac.hwp]\nmin_perf_pct = 50\n\n[control]\n")).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    machine.set_on_ac(true).unwrap();
+    daemon.wait_for(TIMEOUT, |w| hwp_minimums(w).contains(&20)).unwrap();
+    machine.set_on_ac(false).unwrap();
+    let writes = daemon.wait_for(TIMEOUT, |w| hwp_minimums(w).contains(&8)).unwrap();
+    assert_eq!(hwp_minimums(&writes), vec![20, 8], "{}", daemon.output());
+}
+
+#[test]
+fn skips_settings_whose_msr_is_missing() {
+    let machine = Machine::new().unwrap();
+    machine.write_config(&CONFIG.replace("[battery]\n", "[battery]\ngpu_pl_w = 8\n")).unwrap();
+    // MSR_PP1_POWER_LIMIT, and everything after it.
+    machine.remove_msr(rapl::MSR_PP1_POWER_LIMIT).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    let writes = daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    assert!(writes.iter().all(|w| w.msr(rapl::MSR_PP1_POWER_LIMIT).is_none()), "{}", daemon.output());
+}
+
+/// Returns the values written to a powercap attribute of the package zone, in order.
+fn powercap_writes(writes: &[Write], attr: &str) -> Vec<String> {
+    writes.iter().filter_map(|w| match *w {
+        Write::Sysfs { ref path, ref value } if path.ends_with(&format!("/intel-rapl:0/{}", attr)) => Some(value.clone()),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn mirrors_power_limits_to_powercap() {
+    let machine = Machine::new().unwrap();
+    let zone = machine.root().join("powercap/intel-rapl:0");
+    fs::create_dir_all(&zone).unwrap();
+    fs::write(zone.join("name"), "package-0\n").unwrap();
+    for attr in ["constraint_0_power_limit_uw", "constraint_0_time_window_us",
+                 "constraint_1_power_limit_uw", "constraint_1_time_window_us"].iter() {
+        fs::write(zone.join(attr), "0\n").unwrap();
+    }
+    machine.write_config(&CONFIG.replace("[battery]\n", "powercap_sync = \"mirror\"\n\n[battery]\n")).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    let writes = daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    assert_eq!(powercap_writes(&writes, "constraint_0_power_limit_uw"), vec!["15000000"], "{}", daemon.output());
+    assert_eq!(powercap_writes(&writes, "constraint_1_power_limit_uw"), vec!["25000000"], "{}", daemon.output());
+    assert_eq!(powercap_writes(&writes, "constraint_0_time_window_us"), vec!["28000000"], "{}", daemon.output());
+}
+
+#[test]
+fn writes_custom_msrs_through_the_helper() {
+    let machine = Machine::new().unwrap();
+    // MSR_POWER_CTL, which the helper only allows because the config asks for it.
+    machine.set_msr_all(0x1FC, 0x3).unwrap();
+    let custom = "[[custom_msr]]\nmsr = \"0x1FC\"\nmask = \"0x2\"\nvalue = \"0x0\"\nscope = \"package\"\n";
+    machine.write_config(&format!("{}\n{}", CONFIG, custom)).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    daemon.wait_for(TIMEOUT, |w| w.iter().any(|w| w.msr(0x1FC).is_some())).unwrap();
+    assert_eq!(machine.msr(machine.cpus()[0], 0x1FC).unwrap(), 0x1, "{}", daemon.output());
+}
+
+/// Makes a call on the daemon's control socket, returning the response line.
+fn call(socket: &Path, method: &str, params: &str) -> String {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    writeln!(stream, r#"{{"jsonrpc": "2.0", "id": 1, "method": "{}", "params": {}}}"#, method, params).unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    line
+}
+
+/// Waits for the daemon to create its control socket.
+fn wait_for_socket(socket: &Path) {
+    let start = Instant::now();
+    while !socket.exists() {
+        assert!(start.elapsed() < TIMEOUT, "no control socket at {}", socket.display());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn keeps_working_in_the_seccomp_sandbox() {
+    let machine = Machine::new().unwrap();
+    let socket = machine.root().join("run/control.sock");
+    let config = CONFIG.replace("[battery]\n", "seccomp = true\n\n[battery]\n")
+        .replace("[control]\n", &format!("[control]\nsocket = {:?}\n", socket.display().to_string()));
+    machine.write_config(&config).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    machine.set_on_ac(true).unwrap();
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(35.0, 44.0))).unwrap();
+
+    // Forcing a profile saves it to the state directory, and going back to auto removes it.
+    wait_for_socket(&socket);
+    let forced = call(&socket, "set-profile", r#"{"profile": "battery"}"#);
+    assert!(!forced.contains("error"), "{}", forced);
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).iter().filter(|&&l| l == (15.0, 25.0)).count() == 2).unwrap();
+    let auto = call(&socket, "set-profile", r#"{"profile": "auto"}"#);
+    assert!(!auto.contains("error"), "{}", auto);
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).iter().filter(|&&l| l == (35.0, 44.0)).count() == 2).unwrap();
+
+    let output = daemon.output();
+    assert!(output.contains("seccomp sandbox installed"), "{}", output);
+    assert!(!output.contains("Operation not permitted"), "{}", output);
+}
+
+#[test]
+fn applies_named_profiles() {
+    let machine = Machine::new().unwrap();
+    let socket = machine.root().join("run/control.sock");
+    let gaming = "[profiles.gaming]\nextends = \"battery\"\npl1_tdp_w = 20\n";
+    let config = format!("{}\n{}", CONFIG, gaming)
+        .replace("[control]\n", &format!("[control]\nsocket = {:?}\n", socket.display().to_string()));
+    machine.write_config(&config).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    wait_for_socket(&socket);
+
+    let forced = call(&socket, "set-profile", r#"{"profile": "gaming"}"#);
+    assert!(!forced.contains("error"), "{}", forced);
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(20.0, 25.0))).unwrap();
+
+    let missing = call(&socket, "set-profile", r#"{"profile": "missing"}"#);
+    assert!(missing.contains("no [profiles.missing] section"), "{}", missing);
+    let status = call(&socket, "status", "{}");
+    assert!(status.contains(r#""profile":"gaming""#), "{}", status);
+}
+
+#[test]
+fn disconnects_clients_that_send_overlong_requests() {
+    let machine = Machine::new().unwrap();
+    let socket = machine.root().join("run/control.sock");
+    machine.write_config(&CONFIG.replace("[control]\n", &format!("[control]\nsocket = {:?}\n", socket.display().to_string()))).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    wait_for_socket(&socket);
+
+    // Split at 4096 bytes, the tail would have been taken as a second request.
+    let long = format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "status", "params": {{"padding": "{}"}}}}"#, "x".repeat(4096));
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    writeln!(stream, "{}", long).unwrap();
+    let mut response = String::new();
+    let mut reader = BufReader::new(stream);
+    reader.read_line(&mut response).unwrap();
+    assert!(response.contains("longer than 4096 bytes"), "{}", response);
+    response.clear();
+    assert_eq!(reader.read_line(&mut response).unwrap(), 0, "{}", response);
+
+    assert!(call(&socket, "status", "{}").contains("result"));
+}
+
+#[test]
+fn previews_only_regular_files() {
+    let machine = Machine::new().unwrap();
+    let socket = machine.root().join("run/control.sock");
+    machine.write_config(&CONFIG.replace("[control]\n", &format!("[control]\nsocket = {:?}\n", socket.display().to_string()))).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    wait_for_socket(&socket);
+
+    // Reading a FIFO would block until something wrote to it.
+    let fifo = machine.root().join("config.fifo");
+    assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+    let params = |path: &Path| format!("{{\"path\": {:?}}}", path.display().to_string());
+    let response = call(&socket, "preview-config", &params(&fifo));
+    assert!(response.contains("isn't a regular file"), "{}", response);
+
+    let response = call(&socket, "preview-config", &params(&machine.root().join("config.toml")));
+    assert!(response.contains("\"changes\":[]"), "{}", response);
+}
+
+#[test]
+fn ignores_peripheral_batteries() {
+    let machine = Machine::new().unwrap();
+    // A nearly flat wireless mouse, which would bring the level below 20% if it were averaged in.
+    fs::write(machine.root().join("power_supply/BAT0/capacity"), "30").unwrap();
+    let mouse = machine.root().join("power_supply/hidpp_battery_0");
+    fs::create_dir_all(&mouse).unwrap();
+    for &(attr, value) in [("type", "Battery"), ("scope", "Device"), ("capacity", "5")].iter() {
+        fs::write(mouse.join(attr), value).unwrap();
+    }
+    let levels = "[[battery_levels]]\nbelow_percent = 20\npl1_tdp_w = 10\n\n[control]\n";
+    machine.write_config(&CONFIG.replace("[control]\n", levels)).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    let writes = daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    assert!(!power_limits(&writes).contains(&(10.0, 25.0)), "{}", daemon.output());
+}
+
+#[test]
+fn rolls_back_overrides_that_expire() {
+    let machine = Machine::new().unwrap();
+    let socket = machine.root().join("run/control.sock");
+    machine.write_config(&CONFIG.replace("[control]\n", &format!("[control]\nsocket = {:?}\n", socket.display().to_string()))).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(15.0, 25.0))).unwrap();
+    wait_for_socket(&socket);
+
+    let begin = call(&socket, "begin-override", "{}");
+    let token: String = begin.split("\"result\":").nth(1).unwrap_or_else(|| panic!("{}", begin))
+        .chars().take_while(|c| c.is_ascii_digit()).collect();
+    let limits = call(&socket, "override-limits", &format!(r#"{{"token": {}, "pl1_w": 20, "pl2_w": 30}}"#, token));
+    assert!(!limits.contains("error"), "{}", limits);
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).contains(&(20.0, 30.0))).unwrap();
+
+    // The override expires after 2 seconds against simulated hardware, well before the timer's
+    // 30 second period is up, and nothing else happens that would make the daemon look at it.
+    daemon.wait_for(TIMEOUT, |w| power_limits(w).ends_with(&[(20.0, 30.0), (15.0, 25.0)])).unwrap();
+}
+
+#[test]
+fn keeps_throttle_episodes_logged_while_clearing() {
+    let machine = Machine::new().unwrap();
+    machine.write_config(&CONFIG.replace("[control]\n", "[therm_log]\ninterval_sec = 1\n\n[control]\n")).unwrap();
+    let cpu = machine.cpus()[0];
+    let (thermal_log, power_limit_log) = (1 << 1, 1 << 11);
+    machine.set_msr(cpu, 0x1B1, thermal_log).unwrap();
+    // A power limit episode that starts after the daemon has read the log bits, but before it
+    // clears the ones that it read.
+    machine.log_before_next_write(cpu, power_limit_log).unwrap();
+    let mut daemon = machine.spawn(exe()).unwrap();
+
+    daemon.wait_for(TIMEOUT, |w| w.iter().any(|w| w.msr(0x1B1).is_some())).unwrap();
+    assert_eq!(machine.msr(cpu, 0x1B1).unwrap(), power_limit_log, "{}", daemon.output());
+}